@@ -1,18 +1,202 @@
-use std::{cmp::min, num::NonZeroU32};
+use std::{cmp::min, num::NonZeroU32, time::Duration};
 
-use serde::{Deserialize, Serialize};
-use serde_with::{serde_as, DisplayFromStr, FromInto, TryFromInto};
+use clap::{Args, ValueEnum};
+use reqwest::Url;
+use serde::{de, Deserialize, Deserializer, Serialize};
+use serde_with::{serde_as, DisplayFromStr, TryFromInto};
 use shakmaty::{
-    fen::Fen,
+    fen::{Fen, ParseFenError},
     uci::{IllegalUciMoveError, UciMove},
     variant::{Variant, VariantPosition},
-    CastlingMode, EnPassantMode, Position as _, PositionError,
+    zobrist::{Zobrist64, ZobristHash as _},
+    Bitboard, Board, CastlingMode, Color, EnPassantMode, Move, Position as _, PositionError,
+    PositionErrorKinds, Setup,
 };
 use thiserror::Error;
+use tokio::time::Instant;
+use utoipa::{OpenApi, ToSchema};
 
-use crate::model::{ClientSecret, Engine, JobId, MultiPv, ProviderSecret, SessionId, UciVariant};
+use crate::{
+    audit::AuditEntry,
+    challenge::Nonce,
+    model::{
+        record_rejection, ClientSecret, Engine, EngineConfig, EngineId, JobId, MultiPv,
+        ProviderSecret, ProviderSelector, Rejection, SessionId, StrengthLimit, UciVariant,
+    },
+    uci::UciOption,
+};
+
+const DEFAULT_MAX_BATCH_SIZE: usize = 64;
+
+const DEFAULT_MAX_REDISPATCHES: u32 = 2;
+
+const DEFAULT_MAX_LINE_LEN: usize = 16 * 1024;
+
+const DEFAULT_MAX_PV_LEN: usize = 30;
+
+const DEFAULT_ACQUIRE_TIMEOUT: u64 = 10;
+
+const DEFAULT_MATCH_TIMEOUT: u64 = 15;
+
+const DEFAULT_ACQUIRE_KEEP_ALIVE: u64 = 5;
+
+const DEFAULT_START_TIMEOUT: u64 = 30;
+
+const DEFAULT_MIN_CLIENT_SECRET_LEN: usize = 16;
+
+const DEFAULT_MAX_MALFORMED_LINES: u32 = 5;
+
+const DEFAULT_ANALYSIS_TAGS: [&str; 3] = ["study", "game", "puzzle"];
+
+#[derive(Args, Debug, Clone)]
+pub struct WorkOpt {
+    /// Allow clients to request result webhooks to this domain (and its
+    /// subdomains). Can be given multiple times.
+    #[arg(long = "callback-domain")]
+    pub callback_domains: Vec<String>,
+    /// Maximum number of positions in a single batch analysis request.
+    #[arg(long, default_value_t = DEFAULT_MAX_BATCH_SIZE)]
+    pub max_batch_size: usize,
+    /// Maximum number of times work with `ensureDepth` is handed to another
+    /// provider, if the previous one finished early.
+    #[arg(long, default_value_t = DEFAULT_MAX_REDISPATCHES)]
+    pub max_redispatches: u32,
+    /// Maximum length of a single line submitted by a provider, in bytes.
+    /// Longer lines are dropped.
+    #[arg(long, default_value_t = DEFAULT_MAX_LINE_LEN)]
+    pub max_line_len: usize,
+    /// Number of malformed lines after which a job is stopped. Fewer are
+    /// skipped.
+    #[arg(long, default_value_t = DEFAULT_MAX_MALFORMED_LINES)]
+    pub max_malformed_lines: u32,
+    /// Maximum number of moves forwarded per principal variation. Longer
+    /// lines are truncated.
+    #[arg(long, default_value_t = DEFAULT_MAX_PV_LEN)]
+    pub max_pv_len: usize,
+    /// Seconds a provider waits for work in a single acquire request.
+    #[arg(long, default_value_t = DEFAULT_ACQUIRE_TIMEOUT)]
+    pub acquire_timeout: u64,
+    /// Seconds analysis requests wait for a provider to pick up their work.
+    /// Clients can choose a shorter `matchTimeout`. Once picked up, analysis
+    /// is only bounded by the search limits, `--analysis-timeout` and
+    /// `Request-Deadline`.
+    #[arg(long, default_value_t = DEFAULT_MATCH_TIMEOUT)]
+    pub match_timeout: u64,
+    /// Seconds a provider has from picking up work until it completes the
+    /// analysis. Otherwise it is stopped, and the stream ends with an error.
+    /// Clients can choose a shorter `analysisTimeout`. Unlimited by default.
+    #[arg(long)]
+    pub analysis_timeout: Option<u64>,
+    /// Seconds between keep-alives sent to providers that wait for work with
+    /// `keepAlive`.
+    #[arg(long, default_value_t = DEFAULT_ACQUIRE_KEEP_ALIVE)]
+    pub acquire_keep_alive: u64,
+    /// Seconds a provider has from acquiring a job until it submits the
+    /// first analysis. Otherwise the job is handed to another provider.
+    #[arg(long, default_value_t = DEFAULT_START_TIMEOUT)]
+    pub start_timeout: u64,
+    /// Minimum length of client secrets. Requests with shorter secrets are
+    /// rejected before looking up the engine.
+    #[arg(long, default_value_t = DEFAULT_MIN_CLIENT_SECRET_LEN)]
+    pub min_client_secret_len: usize,
+    /// Depth at which providers are stopped, even if the work asks for
+    /// more. Unlimited by default.
+    #[arg(long)]
+    pub max_depth: Option<u32>,
+    /// Status of acquire responses if no work arrived in time.
+    #[arg(long, value_enum, default_value_t = AcquireEmptyStatus::NoContent)]
+    pub acquire_empty_status: AcquireEmptyStatus,
+    /// Maximum number of analysis frames forwarded per second and job.
+    /// Frames in between are coalesced into the latest. Unlimited by
+    /// default.
+    #[arg(long)]
+    pub max_info_rate: Option<NonZeroU32>,
+    /// Allowed values of the `tag` of work, used to label metrics. Can be
+    /// given multiple times.
+    #[arg(long = "analysis-tag", default_values_t = DEFAULT_ANALYSIS_TAGS.map(String::from))]
+    pub analysis_tags: Vec<String>,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcquireEmptyStatus {
+    /// `204 No Content`.
+    #[value(name = "204")]
+    NoContent,
+    /// `200 OK` with an empty JSON object, for HTTP clients that handle 204
+    /// poorly.
+    #[value(name = "200")]
+    Ok,
+}
+
+impl Default for WorkOpt {
+    fn default() -> WorkOpt {
+        WorkOpt {
+            callback_domains: Vec::new(),
+            max_batch_size: DEFAULT_MAX_BATCH_SIZE,
+            max_redispatches: DEFAULT_MAX_REDISPATCHES,
+            max_line_len: DEFAULT_MAX_LINE_LEN,
+            max_malformed_lines: DEFAULT_MAX_MALFORMED_LINES,
+            max_pv_len: DEFAULT_MAX_PV_LEN,
+            acquire_timeout: DEFAULT_ACQUIRE_TIMEOUT,
+            match_timeout: DEFAULT_MATCH_TIMEOUT,
+            analysis_timeout: None,
+            acquire_keep_alive: DEFAULT_ACQUIRE_KEEP_ALIVE,
+            start_timeout: DEFAULT_START_TIMEOUT,
+            min_client_secret_len: DEFAULT_MIN_CLIENT_SECRET_LEN,
+            max_depth: None,
+            acquire_empty_status: AcquireEmptyStatus::NoContent,
+            max_info_rate: None,
+            analysis_tags: DEFAULT_ANALYSIS_TAGS.map(String::from).to_vec(),
+        }
+    }
+}
+
+impl WorkOpt {
+    pub fn accepts_client_secret(&self, secret: &ClientSecret) -> bool {
+        secret.char_count() >= self.min_client_secret_len
+    }
+
+    /// How long to wait for a provider to pick up the work.
+    pub fn match_timeout(&self, work: &Work) -> Duration {
+        let max = Duration::from_secs(self.match_timeout);
+        work.match_timeout.map_or(max, |requested| {
+            min(Duration::from_millis(u64::from(requested.get())), max)
+        })
+    }
+
+    /// How long a provider may take from picking up the work until it
+    /// completes the analysis, if limited.
+    pub fn analysis_timeout(&self, work: &Work) -> Option<Duration> {
+        let max = self.analysis_timeout.map(Duration::from_secs);
+        let requested = work
+            .analysis_timeout
+            .map(|requested| Duration::from_millis(u64::from(requested.get())));
+        match (requested, max) {
+            (Some(requested), Some(max)) => Some(min(requested, max)),
+            (requested, max) => requested.or(max),
+        }
+    }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+    /// Minimum interval between analysis frames of a job.
+    pub fn info_interval(&self) -> Option<Duration> {
+        self.max_info_rate
+            .map(|rate| Duration::from_secs(1) / rate.get())
+    }
+
+    fn allows_callback(&self, url: &Url) -> bool {
+        matches!(url.scheme(), "http" | "https")
+            && url.host_str().is_some_and(|host| {
+                self.callback_domains.iter().any(|domain| {
+                    host == domain
+                        || host
+                            .strip_suffix(domain.as_str())
+                            .is_some_and(|sub| sub.ends_with('.'))
+                })
+            })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
 #[serde(rename_all = "camelCase")]
 pub enum Search {
     Movetime(u32),
@@ -20,99 +204,1947 @@ pub enum Search {
     Nodes(u64),
 }
 
+impl From<StrengthLimit> for Search {
+    fn from(limit: StrengthLimit) -> Search {
+        match limit {
+            StrengthLimit::Depth(depth) => Search::Depth(depth),
+            StrengthLimit::Movetime(movetime) => Search::Movetime(movetime),
+        }
+    }
+}
+
+/// Clock of the game the position is from, in milliseconds, for providers
+/// that manage their time like in `go wtime .. btime .. winc .. binc ..`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+pub struct ClockInfo {
+    #[schema(example = 180000)]
+    wtime: i64,
+    #[schema(example = 175000)]
+    btime: i64,
+    #[serde(default)]
+    #[schema(example = 2000)]
+    winc: i64,
+    #[serde(default)]
+    #[schema(example = 2000)]
+    binc: i64,
+}
+
+impl ClockInfo {
+    fn is_valid(&self) -> bool {
+        self.wtime >= 0 && self.btime >= 0 && self.winc >= 0 && self.binc >= 0
+    }
+}
+
+/// Notation of castling moves in the analysis sent to the client.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum CastlingNotation {
+    /// The king moves onto the castling rook, e.g. `e1h1`.
+    #[default]
+    Chess960,
+    /// The king moves to its destination square, e.g. `e1g1`.
+    Standard,
+}
+
+impl From<CastlingNotation> for CastlingMode {
+    fn from(notation: CastlingNotation) -> CastlingMode {
+        match notation {
+            CastlingNotation::Chess960 => CastlingMode::Chess960,
+            CastlingNotation::Standard => CastlingMode::Standard,
+        }
+    }
+}
+
+/// Point of view of the scores in the analysis sent to the client.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum Perspective {
+    /// Positive scores favor the side to move, like in UCI.
+    #[default]
+    #[serde(alias = "side_to_move")]
+    SideToMove,
+    /// Positive scores favor White.
+    White,
+}
+
+/// A `setoption name <name> value <value>` command for the provider.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+pub struct SetOption {
+    #[schema(example = "UCI_ShowWDL")]
+    pub name: String,
+    #[schema(example = "true")]
+    pub value: String,
+}
+
+impl SetOption {
+    fn new(name: &str, value: &str) -> SetOption {
+        SetOption {
+            name: name.to_owned(),
+            value: value.to_owned(),
+        }
+    }
+}
+
+/// Report win/draw/loss statistics and probe tablebases at all depths.
+/// Options that the engine did not report in its handshake are left out,
+/// unless it reported none.
+fn tablebase_options(engine: &Engine) -> Vec<SetOption> {
+    [
+        SetOption::new("UCI_ShowWDL", "true"),
+        SetOption::new("SyzygyProbeDepth", "1"),
+    ]
+    .into_iter()
+    .filter(|option| {
+        engine.config.allowed_options.is_empty()
+            || engine
+                .config
+                .allowed_options
+                .iter()
+                .any(|allowed| allowed.name.eq_ignore_ascii_case(&option.name))
+    })
+    .collect()
+}
+
 #[serde_as]
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct Work {
+    #[serde(alias = "session_id")]
     session_id: SessionId,
-    threads: NonZeroU32,
-    hash: NonZeroU32,
+    /// Defaults to the engine configuration, or a suggestion for the
+    /// variant.
+    #[serde(default, deserialize_with = "deserialize_at_least_one")]
+    #[schema(value_type = Option<u32>, minimum = 1, example = 4)]
+    threads: Option<NonZeroU32>,
+    /// Hash table size in MiB. Defaults to the engine configuration, or a
+    /// suggestion for the variant.
+    #[serde(default, deserialize_with = "deserialize_at_least_one")]
+    #[schema(value_type = Option<u32>, minimum = 1, example = 256)]
+    hash: Option<NonZeroU32>,
+    /// Defaults to the `depth` configured for the engine, if any.
     #[serde(flatten)]
-    search: Search,
-    #[serde_as(as = "TryFromInto<u32>")]
-    multi_pv: MultiPv,
-    #[serde_as(as = "FromInto<UciVariant>")]
-    variant: Variant,
-    #[serde_as(as = "DisplayFromStr")]
-    initial_fen: Fen,
+    search: Option<Search>,
+    /// Named strength level of the engine, instead of `depth`, `movetime`
+    /// or `nodes`.
+    #[serde(default, skip_serializing)]
+    #[schema(example = "2500")]
+    strength: Option<String>,
+    /// Defaults to the engine configuration, or 1.
+    #[serde_as(as = "Option<TryFromInto<u32>>")]
+    #[serde(default, skip_serializing_if = "Option::is_none", alias = "multi_pv")]
+    #[schema(value_type = Option<u32>, minimum = 1, maximum = 5, example = 1)]
+    multi_pv: Option<MultiPv>,
+    /// Inferred from `initialFen` if absent: crazyhouse if it has pockets,
+    /// three-check if it has remaining checks, otherwise standard chess.
+    #[serde_as(as = "Option<TryFromInto<UciVariant>>")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<UciVariant>)]
+    variant: Option<Variant>,
+    /// FEN of the initial position. Parsed in `sanitize` after checking its
+    /// length.
+    #[schema(example = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1")]
+    #[serde(alias = "initial_fen")]
+    initial_fen: String,
+    /// Replaces the castling rights of `initialFen`, in FEN notation like
+    /// `KQkq`, `HAha` or `-`, e.g. to explore what if castling were still
+    /// available.
+    #[serde(default, skip_serializing, alias = "castling_rights")]
+    #[schema(example = "KQkq")]
+    castling_rights: Option<String>,
     #[serde_as(as = "Vec<DisplayFromStr>")]
+    #[schema(value_type = Vec<String>, example = json!(["e2e4", "c7c5"]))]
     moves: Vec<UciMove>,
+    /// Expected reply in the final position. The provider searches the
+    /// position after it with `go ponder`, and continues on `ponderhit` if
+    /// the client plays it. Requires an engine that supports pondering.
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<String>, example = "g1f3")]
+    ponder: Option<UciMove>,
+    /// Restrict the search to these moves from the final position, after
+    /// `ponder` if any.
+    #[serde_as(as = "Option<Vec<DisplayFromStr>>")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<Vec<String>>, example = json!(["g1f3", "d2d4"]))]
+    searchmoves: Option<Vec<UciMove>>,
+    /// Random seed for engines that support one. Output is only
+    /// reproducible if the engine itself is deterministic, which usually
+    /// also requires a single thread.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(maximum = 2147483647)]
+    seed: Option<u32>,
+    /// Prefer tablebase results. Requires an engine with tablebase support.
+    /// Providers receive the corresponding `setoptions`.
+    #[serde(default, skip_serializing_if = "is_false")]
+    tablebase: bool,
+    /// Options the provider should set with `setoption` before searching.
+    /// Derived by the server, e.g. from `tablebase`, and never taken from
+    /// clients.
+    #[serde(default, skip_deserializing, skip_serializing_if = "Vec::is_empty")]
+    #[schema(read_only)]
+    setoptions: Vec<SetOption>,
+    /// Forwarded to the provider only if present.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    clock: Option<ClockInfo>,
+    /// If present, the request is answered with `202` right away, and the
+    /// terminal frame is posted to this URL once the job ends, whichever
+    /// way it ends. It carries the last analysis frame as `analysis`.
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default, skip_serializing, alias = "callback_url")]
+    #[schema(value_type = Option<String>, example = "https://example.org/callback")]
+    callback_url: Option<Url>,
+    /// If a provider stops before reaching the requested `depth`, hand the
+    /// work to another provider to continue.
+    #[serde(default, skip_serializing, alias = "ensure_depth")]
+    ensure_depth: bool,
+    /// Opaque reference that is echoed in the acquired and done frames.
+    #[serde(default, skip_serializing, alias = "client_ref")]
+    #[schema(max_length = 64, example = "board-1")]
+    client_ref: Option<String>,
+    /// Kind of analysis, from the allowlist of the server, to segment
+    /// metrics.
+    #[serde(default, skip_serializing)]
+    #[schema(example = "study")]
+    tag: Option<String>,
+    /// Moves sent to the provider always use Chess960 notation.
+    #[serde(default, skip_serializing)]
+    castling: CastlingNotation,
+    /// Providers always report scores from the point of view of the side to
+    /// move.
+    #[serde(default, skip_serializing)]
+    perspective: Perspective,
+    /// Also send principal variations in SAN.
+    #[serde(default, skip_serializing)]
+    san: bool,
+    /// Also list the legal moves of the position to analyse in the acquired
+    /// frame.
+    #[serde(default, skip_serializing, alias = "legal_moves")]
+    legal_moves: bool,
+    /// Milliseconds to wait for a provider to pick up the work, if shorter
+    /// than the limit of the server.
+    #[serde(
+        default,
+        skip_serializing,
+        alias = "match_timeout",
+        deserialize_with = "deserialize_at_least_one"
+    )]
+    #[schema(value_type = Option<u32>, minimum = 1, example = 5000)]
+    match_timeout: Option<NonZeroU32>,
+    /// Milliseconds the provider has from picking up the work until it
+    /// completes the analysis, if shorter than the limit of the server.
+    #[serde(
+        default,
+        skip_serializing,
+        alias = "analysis_timeout",
+        deserialize_with = "deserialize_at_least_one"
+    )]
+    #[schema(value_type = Option<u32>, minimum = 1, example = 30000)]
+    analysis_timeout: Option<NonZeroU32>,
+    /// Move number of the initial position, for game fragments whose FEN
+    /// does not carry it. Only affects reported metadata.
+    #[serde(default, skip_serializing, alias = "start_move_number")]
+    #[schema(minimum = 1, example = 25)]
+    start_move_number: Option<u32>,
+    /// Maximum number of analysis frames to forward, to bound bandwidth.
+    /// The done frame is always delivered.
+    #[serde(default, skip_serializing, alias = "max_info_frames")]
+    #[schema(minimum = 1, example = 20)]
+    max_info_frames: Option<u32>,
+    /// Move number of the position to analyse, counted from
+    /// `start_move_number`.
+    #[serde(skip)]
+    move_number: Option<u32>,
+    #[serde(skip)]
+    clamped: Clamped,
+    #[serde(skip)]
+    ponder_move: Option<Move>,
+    /// How often the position to analyse occurred since the last capture or
+    /// pawn move.
+    #[serde(skip)]
+    repetitions: usize,
+    /// Hard cap from the `Request-Deadline` header.
+    #[serde(skip)]
+    deadline: Option<Instant>,
 }
 
+/// Requested values that `sanitize` had to reduce to the limits of the
+/// engine.
+#[derive(Serialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct Clamped {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    threads: Option<NonZeroU32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hash: Option<NonZeroU32>,
+    /// Reduced to the number of legal moves.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    multi_pv: Option<u32>,
+}
+
+impl Clamped {
+    pub fn is_empty(&self) -> bool {
+        self.threads.is_none() && self.hash.is_none() && self.multi_pv.is_none()
+    }
+}
+
+fn is_false(flag: &bool) -> bool {
+    !flag
+}
+
+const MAX_CLIENT_REF_LEN: usize = 64;
+
+/// Generous bound for the length of `initialFen`, even for crazyhouse
+/// positions with full pockets and promoted pieces.
+const MAX_FEN_LEN: usize = 255;
+
 #[derive(Error, Debug)]
 pub enum InvalidWorkError {
+    #[error("initial position too long")]
+    FenTooLong,
+    #[error("invalid initial position: {0}")]
+    Fen(#[from] ParseFenError),
     #[error("illegal initial position: {0}")]
-    Position(#[from] PositionError<VariantPosition>),
-    #[error("illegal uci move: {0}")]
-    IllegalUciMove(#[from] IllegalUciMoveError),
+    Position(#[from] Box<PositionError<VariantPosition>>),
+    #[error("invalid castlingRights")]
+    InvalidCastlingRights,
+    #[error("castlingRights not possible in the initial position")]
+    IllegalCastlingRights,
+    /// The offending move is at index `ply` of `moves`. For `searchmoves`,
+    /// `ply` is the number of moves.
+    #[error("illegal uci move {uci} at ply {ply}")]
+    IllegalUciMove { ply: usize, uci: UciMove },
+    /// Like `IllegalUciMove`, but the move starts on a piece of the side
+    /// that is not to move, e.g. because the client is out of sync with the
+    /// side to move of `initialFen`.
+    #[error("illegal uci move {uci} at ply {ply}: moves a {} piece, but {turn} is to move", .turn.other())]
+    WrongSideToMove {
+        ply: usize,
+        uci: UciMove,
+        turn: Color,
+    },
     #[error("too many moves")]
     TooManyMoves,
     #[error("unsupported variant")]
     UnsupportedVariant,
+    #[error("threads, hash and timeouts must be at least 1")]
+    NotAtLeastOne,
+    #[error("callbackUrl not allowed")]
+    CallbackUrlNotAllowed,
+    #[error("duplicate move in searchmoves")]
+    DuplicateSearchmove,
+    #[error("seed out of range")]
+    SeedOutOfRange,
+    #[error("startMoveNumber must be positive")]
+    InvalidStartMoveNumber,
+    #[error("maxInfoFrames must be positive")]
+    InvalidMaxInfoFrames,
+    #[error("clientRef too long")]
+    ClientRefTooLong,
+    #[error("tag not allowed")]
+    DisallowedTag,
+    #[error("engine does not support tablebases")]
+    TablebaseUnsupported,
+    #[error("engine does not support pondering")]
+    PonderUnsupported,
+    #[error("initial position not allowed for this engine")]
+    DisallowedPosition,
+    #[error("session not allowed for this engine")]
+    DisallowedSession,
+    #[error("clock must not be negative")]
+    NegativeClock,
+    #[error("one of depth, movetime or nodes required")]
+    MissingSearch,
+    #[error("strength cannot be combined with depth, movetime or nodes")]
+    AmbiguousSearch,
+    #[error("unknown strength level for this engine")]
+    UnknownStrength,
+}
+
+impl InvalidWorkError {
+    /// Stable name of the variant, for metrics labels.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            InvalidWorkError::FenTooLong => "fenTooLong",
+            InvalidWorkError::Fen(_) => "fen",
+            InvalidWorkError::Position(_) => "position",
+            InvalidWorkError::InvalidCastlingRights => "invalidCastlingRights",
+            InvalidWorkError::IllegalCastlingRights => "illegalCastlingRights",
+            InvalidWorkError::IllegalUciMove { .. } => "illegalUci",
+            InvalidWorkError::WrongSideToMove { .. } => "wrongSideToMove",
+            InvalidWorkError::TooManyMoves => "tooManyMoves",
+            InvalidWorkError::UnsupportedVariant => "unsupportedVariant",
+            InvalidWorkError::NotAtLeastOne => "notAtLeastOne",
+            InvalidWorkError::CallbackUrlNotAllowed => "callbackUrlNotAllowed",
+            InvalidWorkError::DuplicateSearchmove => "duplicateSearchmove",
+            InvalidWorkError::SeedOutOfRange => "seedOutOfRange",
+            InvalidWorkError::InvalidStartMoveNumber => "invalidStartMoveNumber",
+            InvalidWorkError::InvalidMaxInfoFrames => "invalidMaxInfoFrames",
+            InvalidWorkError::ClientRefTooLong => "clientRefTooLong",
+            InvalidWorkError::DisallowedTag => "disallowedTag",
+            InvalidWorkError::TablebaseUnsupported => "tablebaseUnsupported",
+            InvalidWorkError::PonderUnsupported => "ponderUnsupported",
+            InvalidWorkError::DisallowedPosition => "disallowedPosition",
+            InvalidWorkError::DisallowedSession => "disallowedSession",
+            InvalidWorkError::NegativeClock => "negativeClock",
+            InvalidWorkError::MissingSearch => "missingSearch",
+            InvalidWorkError::AmbiguousSearch => "ambiguousSearch",
+            InvalidWorkError::UnknownStrength => "unknownStrength",
+        }
+    }
+}
+
+/// Explains why `uci` is not legal in `pos`.
+fn illegal_move(pos: &VariantPosition, ply: usize, uci: UciMove) -> InvalidWorkError {
+    let turn = pos.turn();
+    match uci {
+        UciMove::Normal { from, .. } if pos.board().color_at(from) == Some(turn.other()) => {
+            InvalidWorkError::WrongSideToMove { ply, uci, turn }
+        }
+        _ => InvalidWorkError::IllegalUciMove { ply, uci },
+    }
+}
+
+fn deserialize_at_least_one<'de, D>(deserializer: D) -> Result<Option<NonZeroU32>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Option::<u32>::deserialize(deserializer)?
+        .map(|n| {
+            NonZeroU32::new(n).ok_or_else(|| {
+                record_rejection(Rejection::NotAtLeastOne);
+                de::Error::custom(InvalidWorkError::NotAtLeastOne)
+            })
+        })
+        .transpose()
+}
+
+/// Suggested `(threads, hash)` for clients that do not specify them.
+fn variant_defaults(variant: Variant) -> (NonZeroU32, NonZeroU32) {
+    let (threads, hash) = match variant {
+        Variant::Chess => (2, 256),
+        Variant::Crazyhouse => (4, 512),
+        Variant::Atomic | Variant::KingOfTheHill | Variant::ThreeCheck => (2, 128),
+        Variant::Antichess | Variant::Horde | Variant::RacingKings => (1, 128),
+    };
+    (
+        NonZeroU32::new(threads).expect("threads"),
+        NonZeroU32::new(hash).expect("hash"),
+    )
+}
+
+/// Guesses the variant of a FEN without an explicit variant, from features
+/// that only some variants have.
+fn infer_variant(setup: &Setup) -> Variant {
+    if setup.pockets.is_some() {
+        Variant::Crazyhouse
+    } else if setup.remaining_checks.is_some() {
+        Variant::ThreeCheck
+    } else {
+        Variant::Chess
+    }
+}
+
+/// Parses castling rights in FEN notation for the pieces on `board`.
+fn parse_castling_rights(board: &Board, castling_rights: &str) -> Option<Bitboard> {
+    if castling_rights.len() > 4
+        || !castling_rights
+            .bytes()
+            .all(|ch| ch.is_ascii_alphabetic() || ch == b'-')
+    {
+        return None;
+    }
+    let fen = format!("{} w {castling_rights}", board.board_fen(Bitboard::EMPTY));
+    Fen::from_ascii(fen.as_bytes())
+        .ok()
+        .map(|fen| fen.0.castling_rights)
+}
+
+/// Whether the normalized `setup` is one of the `allowed` positions, ignoring
+/// move counters.
+fn is_allowed_start(allowed: &[Fen], variant: Variant, setup: &Setup) -> bool {
+    let without_counters = |setup: Setup| Setup {
+        halfmoves: 0,
+        fullmoves: NonZeroU32::MIN,
+        ..setup
+    };
+    let setup = without_counters(setup.clone());
+    allowed.iter().any(|fen| {
+        VariantPosition::from_setup(variant, fen.as_setup().clone(), CastlingMode::Chess960)
+            .is_ok_and(|pos| without_counters(pos.into_setup(EnPassantMode::Legal)) == setup)
+    })
 }
 
 impl Work {
-    pub fn sanitize(self, engine: &Engine) -> Result<(Work, VariantPosition), InvalidWorkError> {
-        if !engine
-            .config
-            .variants
-            .iter()
-            .copied()
-            .any(|v| v == self.variant)
-        {
+    pub fn session_id(&self) -> &SessionId {
+        &self.session_id
+    }
+
+    /// Always known after `sanitize`.
+    pub fn variant(&self) -> Variant {
+        self.variant.unwrap_or_default()
+    }
+
+    pub fn moves(&self) -> &[UciMove] {
+        &self.moves
+    }
+
+    pub fn callback_url(&self) -> Option<&Url> {
+        self.callback_url.as_ref()
+    }
+
+    pub fn client_ref(&self) -> Option<&str> {
+        self.client_ref.as_deref()
+    }
+
+    pub fn tag(&self) -> Option<&str> {
+        self.tag.as_deref()
+    }
+
+    pub fn max_info_frames(&self) -> Option<u32> {
+        self.max_info_frames
+    }
+
+    pub fn castling(&self) -> CastlingNotation {
+        self.castling
+    }
+
+    pub fn perspective(&self) -> Perspective {
+        self.perspective
+    }
+
+    pub fn san(&self) -> bool {
+        self.san
+    }
+
+    pub fn legal_moves(&self) -> bool {
+        self.legal_moves
+    }
+
+    pub fn clamped(&self) -> &Clamped {
+        &self.clamped
+    }
+
+    /// Move number of the position to analyse, if `startMoveNumber` was
+    /// given.
+    pub fn move_number(&self) -> Option<u32> {
+        self.move_number
+    }
+
+    /// Whether the position to analyse occurred three times, so that a draw
+    /// can be claimed.
+    pub fn is_threefold_repetition(&self) -> bool {
+        self.repetitions >= 3
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    pub fn set_deadline(&mut self, deadline: Instant) {
+        self.deadline = Some(deadline);
+    }
+
+    /// The move the provider is pondering on.
+    pub fn ponder(&self) -> Option<&Move> {
+        self.ponder_move.as_ref()
+    }
+
+    pub fn node_budget(&self) -> Option<u64> {
+        match self.search {
+            Some(Search::Nodes(nodes)) => Some(nodes),
+            _ => None,
+        }
+    }
+
+    /// The depth to search to, if the search is limited by depth.
+    pub fn depth(&self) -> Option<u32> {
+        match self.search {
+            Some(Search::Depth(depth)) => Some(depth),
+            _ => None,
+        }
+    }
+
+    /// Continues a depth-limited search to a greater depth.
+    pub fn set_depth(&mut self, depth: u32) {
+        self.search = Some(Search::Depth(depth));
+    }
+
+    /// The depth that should be reached, even if it takes multiple providers.
+    pub fn ensure_depth(&self) -> Option<u32> {
+        match self.search {
+            Some(Search::Depth(depth)) if self.ensure_depth => Some(depth),
+            _ => None,
+        }
+    }
+
+    pub fn sanitize(
+        self,
+        engine: &Engine,
+        opt: &WorkOpt,
+    ) -> Result<(Work, VariantPosition), InvalidWorkError> {
+        let initial_fen = self.initial_fen()?;
+        let variant = self
+            .variant
+            .unwrap_or_else(|| infer_variant(initial_fen.as_setup()));
+        self.check_capabilities(engine, opt, variant)?;
+        self.check_parameters()?;
+        let search = self.search(engine)?;
+        let played = self.play(engine, variant, initial_fen)?;
+        let limits = self.limits(engine, variant, &played.pos);
+
+        Ok((
+            Work {
+                session_id: self.session_id,
+                threads: Some(limits.threads),
+                hash: Some(limits.hash),
+                search: Some(search),
+                strength: self.strength,
+                multi_pv: Some(limits.multi_pv),
+                variant: Some(variant),
+                initial_fen: played.initial_fen,
+                // Already applied to `initial_fen`.
+                castling_rights: None,
+                moves: played.moves,
+                ponder: played
+                    .ponder_move
+                    .as_ref()
+                    .map(|m| m.to_uci(CastlingMode::Chess960)),
+                searchmoves: played.searchmoves,
+                seed: self.seed,
+                tablebase: self.tablebase,
+                setoptions: if self.tablebase {
+                    tablebase_options(engine)
+                } else {
+                    Vec::new()
+                },
+                clock: self.clock,
+                callback_url: self.callback_url,
+                ensure_depth: self.ensure_depth,
+                client_ref: self.client_ref,
+                tag: self.tag,
+                castling: self.castling,
+                perspective: self.perspective,
+                san: self.san,
+                legal_moves: self.legal_moves,
+                match_timeout: self.match_timeout,
+                analysis_timeout: self.analysis_timeout,
+                start_move_number: self.start_move_number,
+                max_info_frames: self.max_info_frames,
+                move_number: played.move_number,
+                clamped: limits.clamped,
+                ponder_move: played.ponder_move,
+                repetitions: played.repetitions,
+                deadline: self.deadline,
+            },
+            played.pos,
+        ))
+    }
+
+    /// The initial position as given, with `castlingRights` applied.
+    fn initial_fen(&self) -> Result<Fen, InvalidWorkError> {
+        if self.initial_fen.len() > MAX_FEN_LEN {
+            return Err(InvalidWorkError::FenTooLong);
+        }
+        let mut initial_fen = Fen::from_ascii(self.initial_fen.as_bytes())?;
+        if let Some(ref castling_rights) = self.castling_rights {
+            initial_fen.0.castling_rights =
+                parse_castling_rights(&initial_fen.0.board, castling_rights)
+                    .ok_or(InvalidWorkError::InvalidCastlingRights)?;
+        }
+        Ok(initial_fen)
+    }
+
+    /// Checks that the engine and the server support what is requested.
+    fn check_capabilities(
+        &self,
+        engine: &Engine,
+        opt: &WorkOpt,
+        variant: Variant,
+    ) -> Result<(), InvalidWorkError> {
+        if !engine.config.variants.iter().copied().any(|v| v == variant) {
             return Err(InvalidWorkError::UnsupportedVariant);
         }
 
-        let mut pos = VariantPosition::from_setup(
-            self.variant,
-            self.initial_fen.into_setup(),
-            CastlingMode::Chess960,
-        )?;
-        let initial_fen = Fen(pos.clone().into_setup(EnPassantMode::Legal));
+        if self.tablebase && !engine.config.tablebase {
+            return Err(InvalidWorkError::TablebaseUnsupported);
+        }
+
+        if self.ponder.is_some() && !engine.config.supports_ponder {
+            return Err(InvalidWorkError::PonderUnsupported);
+        }
+
+        if self
+            .tag
+            .as_ref()
+            .is_some_and(|tag| !opt.analysis_tags.contains(tag))
+        {
+            return Err(InvalidWorkError::DisallowedTag);
+        }
+
+        if self
+            .callback_url
+            .as_ref()
+            .is_some_and(|url| !opt.allows_callback(url))
+        {
+            return Err(InvalidWorkError::CallbackUrlNotAllowed);
+        }
+
+        if !engine.config.allows_session(&self.session_id) {
+            return Err(InvalidWorkError::DisallowedSession);
+        }
+        Ok(())
+    }
+
+    /// Checks parameters that are only passed through.
+    fn check_parameters(&self) -> Result<(), InvalidWorkError> {
+        // Engines typically expose the seed as a signed 32 bit spin option.
+        if self.seed.is_some_and(|seed| seed > i32::MAX as u32) {
+            return Err(InvalidWorkError::SeedOutOfRange);
+        }
+
+        if self.start_move_number == Some(0) {
+            return Err(InvalidWorkError::InvalidStartMoveNumber);
+        }
+
+        if self.max_info_frames == Some(0) {
+            return Err(InvalidWorkError::InvalidMaxInfoFrames);
+        }
+
+        if self
+            .client_ref
+            .as_ref()
+            .is_some_and(|client_ref| client_ref.len() > MAX_CLIENT_REF_LEN)
+        {
+            return Err(InvalidWorkError::ClientRefTooLong);
+        }
+
+        if self.clock.as_ref().is_some_and(|clock| !clock.is_valid()) {
+            return Err(InvalidWorkError::NegativeClock);
+        }
+        Ok(())
+    }
+
+    /// The search as requested, by strength level, or the engine default.
+    fn search(&self, engine: &Engine) -> Result<Search, InvalidWorkError> {
+        match (&self.search, self.strength.as_deref()) {
+            (Some(_), Some(_)) => Err(InvalidWorkError::AmbiguousSearch),
+            (Some(search), None) => Ok(search.clone()),
+            (None, Some(level)) => engine
+                .config
+                .strength_levels
+                .get(level)
+                .copied()
+                .map(Search::from)
+                .ok_or(InvalidWorkError::UnknownStrength),
+            (None, None) => engine
+                .config
+                .defaults
+                .depth
+                .map(Search::Depth)
+                .ok_or(InvalidWorkError::MissingSearch),
+        }
+    }
+
+    /// Plays the moves from the initial position, normalizing them along
+    /// with the moves to ponder on and to search.
+    fn play(
+        &self,
+        engine: &Engine,
+        variant: Variant,
+        initial_fen: Fen,
+    ) -> Result<Played, InvalidWorkError> {
+        let mut pos =
+            VariantPosition::from_setup(variant, initial_fen.into_setup(), CastlingMode::Chess960)
+                .map_err(|err| {
+                    if self.castling_rights.is_some()
+                        && err
+                            .kinds()
+                            .contains(PositionErrorKinds::INVALID_CASTLING_RIGHTS)
+                    {
+                        InvalidWorkError::IllegalCastlingRights
+                    } else {
+                        Box::new(err).into()
+                    }
+                })?;
+        let initial_setup = pos.clone().into_setup(EnPassantMode::Legal);
+        let initial_fullmoves = initial_setup.fullmoves;
+        if engine
+            .config
+            .allowed_fens
+            .as_ref()
+            .is_some_and(|allowed| !is_allowed_start(allowed, variant, &initial_setup))
+        {
+            return Err(InvalidWorkError::DisallowedPosition);
+        }
+        let initial_fen = Fen(initial_setup).to_string();
 
         if self.moves.len() > 600 {
             return Err(InvalidWorkError::TooManyMoves);
         }
         let mut moves = Vec::with_capacity(self.moves.len());
-        for uci in self.moves {
-            let m = uci.to_move(&pos)?;
+        // Positions since the last irreversible move.
+        let mut history = vec![pos.zobrist_hash::<Zobrist64>(EnPassantMode::Legal)];
+        for (ply, uci) in self.moves.iter().enumerate() {
+            let m = uci
+                .to_move(&pos)
+                .map_err(|_: IllegalUciMoveError| illegal_move(&pos, ply, uci.clone()))?;
             moves.push(m.to_uci(CastlingMode::Chess960));
+            if m.is_zeroing() {
+                history.clear();
+            }
             pos.play_unchecked(&m);
+            history.push(pos.zobrist_hash(EnPassantMode::Legal));
         }
 
-        Ok((
-            Work {
-                session_id: self.session_id,
-                threads: min(self.threads, engine.config.max_threads),
-                hash: min(self.hash, engine.config.max_hash),
-                search: self.search,
-                multi_pv: self.multi_pv,
-                variant: self.variant,
-                initial_fen,
-                moves,
-            },
+        let ponder_move = self
+            .ponder
+            .as_ref()
+            .map(|uci| {
+                uci.to_move(&pos)
+                    .map_err(|_: IllegalUciMoveError| illegal_move(&pos, moves.len(), uci.clone()))
+            })
+            .transpose()?;
+        if let Some(ref m) = ponder_move {
+            if m.is_zeroing() {
+                history.clear();
+            }
+            pos.play_unchecked(m);
+            history.push(pos.zobrist_hash(EnPassantMode::Legal));
+        }
+        let move_number = self
+            .start_move_number
+            .map(|start| start.saturating_add(pos.fullmoves().get() - initial_fullmoves.get()));
+        let repetitions = history
+            .iter()
+            .filter(|hash| history.last() == Some(hash))
+            .count();
+
+        let searchmoves = match self.searchmoves {
+            Some(ref searchmoves) if !searchmoves.is_empty() => {
+                let mut normalized = Vec::with_capacity(searchmoves.len());
+                for uci in searchmoves {
+                    let m = uci.to_move(&pos).map_err(|_: IllegalUciMoveError| {
+                        illegal_move(
+                            &pos,
+                            moves.len() + usize::from(ponder_move.is_some()),
+                            uci.clone(),
+                        )
+                    })?;
+                    let uci = m.to_uci(CastlingMode::Chess960);
+                    if normalized.contains(&uci) {
+                        return Err(InvalidWorkError::DuplicateSearchmove);
+                    }
+                    normalized.push(uci);
+                }
+                Some(normalized)
+            }
+            _ => None,
+        };
+
+        Ok(Played {
             pos,
-        ))
+            initial_fen,
+            moves,
+            ponder_move,
+            searchmoves,
+            move_number,
+            repetitions,
+        })
+    }
+
+    /// Resources for the search, within what the engine allows.
+    fn limits(&self, engine: &Engine, variant: Variant, pos: &VariantPosition) -> Limits {
+        let defaults = &engine.config.defaults;
+        let (default_threads, default_hash) = variant_defaults(variant);
+
+        // Engines would report fewer lines than requested, or repeat
+        // lines, so ask only for as many as there are moves.
+        let requested_multi_pv = self.multi_pv.or(defaults.multi_pv).unwrap_or_default();
+        let legal_moves = u32::try_from(pos.legal_moves().len()).unwrap_or(u32::MAX);
+        let multi_pv = if u32::from(requested_multi_pv) > legal_moves {
+            MultiPv::try_from(legal_moves).expect("fewer legal moves than valid multipv")
+        } else {
+            requested_multi_pv
+        };
+
+        Limits {
+            threads: min(
+                self.threads.or(defaults.threads).unwrap_or(default_threads),
+                engine.config.max_threads,
+            ),
+            hash: min(
+                self.hash.or(defaults.hash).unwrap_or(default_hash),
+                engine.config.max_hash,
+            ),
+            multi_pv,
+            clamped: Clamped {
+                threads: self
+                    .threads
+                    .filter(|threads| *threads > engine.config.max_threads)
+                    .map(|_| engine.config.max_threads),
+                hash: self
+                    .hash
+                    .filter(|hash| *hash > engine.config.max_hash)
+                    .map(|_| engine.config.max_hash),
+                multi_pv: (multi_pv != requested_multi_pv).then(|| u32::from(multi_pv)),
+            },
+        }
     }
 }
 
-#[derive(Deserialize, Debug)]
+/// The position to analyse, reached by the validated moves.
+struct Played {
+    pos: VariantPosition,
+    initial_fen: String,
+    moves: Vec<UciMove>,
+    ponder_move: Option<Move>,
+    searchmoves: Option<Vec<UciMove>>,
+    move_number: Option<u32>,
+    repetitions: usize,
+}
+
+struct Limits {
+    threads: NonZeroU32,
+    hash: NonZeroU32,
+    multi_pv: MultiPv,
+    clamped: Clamped,
+}
+
+#[derive(Deserialize, Debug, ToSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct AnalyseRequest {
+    #[serde(alias = "client_secret")]
     pub client_secret: ClientSecret,
     pub work: Work,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct AnalyseBatchRequest {
+    #[serde(alias = "client_secret")]
+    pub client_secret: ClientSecret,
+    pub work: Vec<Work>,
+}
+
+/// The same work for two engines of the same user, whose analysis is
+/// streamed side by side.
+#[derive(Deserialize, Debug, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct CompareRequest {
+    pub engines: [CompareEngine; 2],
+    pub work: Work,
+}
+
+#[derive(Deserialize, Debug, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct CompareEngine {
+    pub id: EngineId,
+    #[serde(alias = "client_secret")]
+    pub client_secret: ClientSecret,
+}
+
+/// Authenticates a provider, either with the plain `providerSecret`, or by
+/// answering a challenge.
+#[derive(Deserialize, Debug, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ProviderAuth {
+    #[serde(default)]
+    pub provider_secret: Option<ProviderSecret>,
+    #[serde(default)]
+    pub challenge: Option<ChallengeAnswer>,
+}
+
+/// Proves possession of the provider key without sending it.
+#[derive(Deserialize, Debug, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ChallengeAnswer {
+    pub provider_selector: ProviderSelector,
+    /// As issued by `/api/external-engine/challenge`.
+    pub nonce: Nonce,
+    /// Hex encoded HMAC-SHA256 of the nonce, keyed with the provider key.
+    pub signature: String,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ChallengeResponse {
+    pub nonce: Nonce,
+}
+
+#[serde_as]
+#[derive(Deserialize, Debug, ToSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct AcquireRequest {
-    pub provider_secret: ProviderSecret,
+    #[serde(flatten)]
+    pub auth: ProviderAuth,
+    /// Only acquire work for these variants. Defaults to all variants.
+    #[serde_as(as = "Option<Vec<TryFromInto<UciVariant>>>")]
+    #[schema(value_type = Option<Vec<UciVariant>>)]
+    pub variants: Option<Vec<Variant>>,
+    /// Stream the response, sending blank lines while waiting, so that
+    /// intermediaries do not close the idle connection. The work follows as
+    /// a single line, or the stream ends without it if there was none.
+    #[serde(default)]
+    pub keep_alive: bool,
+    /// Describes the engine that the provider actually runs, to be checked
+    /// against its registrations.
+    pub handshake: Option<ProviderHandshake>,
+}
+
+/// What a provider reports about its engine.
+#[serde_as]
+#[derive(Deserialize, Debug, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ProviderHandshake {
+    /// As reported by `id name`.
+    #[schema(example = "Stockfish 17")]
+    pub name: String,
+    #[schema(example = "17")]
+    pub version: Option<String>,
+    /// Unknown variants are ignored.
+    pub variants: Vec<UciVariant>,
+    #[schema(value_type = u32, minimum = 1, example = 8)]
+    pub max_threads: NonZeroU32,
+    #[schema(value_type = u32, minimum = 1, example = 2048)]
+    pub max_hash: NonZeroU32,
+    #[serde(default)]
+    pub tablebase: bool,
+    #[serde(default)]
+    pub supports_ponder: bool,
+    /// `option` lines of the engine, as reported in response to `uci`.
+    /// Recorded as the allowed options of the engines.
+    #[serde_as(as = "Vec<DisplayFromStr>")]
+    #[serde(default)]
+    #[schema(value_type = Vec<String>, example = json!(["option name Hash type spin default 16 min 1 max 33554432"]))]
+    pub options: Vec<UciOption>,
+}
+
+#[derive(Error, Debug)]
+pub enum HandshakeMismatch {
+    #[error("registered with variant {0}, which the engine does not support")]
+    Variant(&'static str),
+    #[error("registered with {registered} threads, but the engine supports {reported}")]
+    MaxThreads {
+        registered: NonZeroU32,
+        reported: NonZeroU32,
+    },
+    #[error("registered with {registered} MiB hash, but the engine supports {reported}")]
+    MaxHash {
+        registered: NonZeroU32,
+        reported: NonZeroU32,
+    },
+    #[error("registered with tablebases, but the engine has none")]
+    Tablebase,
+    #[error("registered with ponder support, but the engine does not ponder")]
+    Ponder,
+}
+
+impl ProviderHandshake {
+    /// Checks that the engine can serve all work allowed by the
+    /// registration, in the variants the provider acquires. The name is not
+    /// compared, since registrations may be named freely.
+    pub fn check(
+        &self,
+        config: &EngineConfig,
+        req: &AcquireRequest,
+    ) -> Result<(), HandshakeMismatch> {
+        if let Some(variant) = config
+            .variants
+            .iter()
+            .filter(|v| req.accepts_variant(**v))
+            .find(|v| !self.variants.contains(&UciVariant::from(**v)))
+        {
+            return Err(HandshakeMismatch::Variant(variant.uci()));
+        }
+        if config.max_threads > self.max_threads {
+            return Err(HandshakeMismatch::MaxThreads {
+                registered: config.max_threads,
+                reported: self.max_threads,
+            });
+        }
+        if config.max_hash > self.max_hash {
+            return Err(HandshakeMismatch::MaxHash {
+                registered: config.max_hash,
+                reported: self.max_hash,
+            });
+        }
+        if config.tablebase && !self.tablebase {
+            return Err(HandshakeMismatch::Tablebase);
+        }
+        if config.supports_ponder && !self.supports_ponder {
+            return Err(HandshakeMismatch::Ponder);
+        }
+        Ok(())
+    }
+}
+
+impl AcquireRequest {
+    pub fn accepts(&self, work: &Work) -> bool {
+        self.accepts_variant(work.variant())
+    }
+
+    fn accepts_variant(&self, variant: Variant) -> bool {
+        self.variants
+            .as_ref()
+            .is_none_or(|variants| variants.contains(&variant))
+    }
+
+    /// Whether the provider acquires any work for the engine. A provider
+    /// secret may be shared by engines for different variants.
+    pub fn serves(&self, config: &EngineConfig) -> bool {
+        config.variants.iter().any(|v| self.accepts_variant(*v))
+    }
+}
+
+#[derive(Deserialize, Debug, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct CancelSessionRequest {
+    #[serde(alias = "client_secret")]
+    pub client_secret: ClientSecret,
+}
+
+#[derive(Deserialize, Debug, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct SubscribeRequest {
+    /// Client secret of the engine that runs the job.
+    #[serde(alias = "client_secret")]
+    pub client_secret: ClientSecret,
+}
+
+#[derive(Deserialize, Debug, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct DeeperRequest {
+    /// Client secret of the engine that runs the job.
+    #[serde(alias = "client_secret")]
+    pub client_secret: ClientSecret,
+    /// Greater than the depth of the job.
+    pub depth: u32,
+}
+
+#[serde_as]
+#[derive(Deserialize, Debug, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct PlayRequest {
+    #[serde(alias = "client_secret")]
+    pub client_secret: ClientSecret,
+    /// The move that was actually played, in either castling notation.
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(rename = "move")]
+    #[schema(value_type = String, example = "g1f3")]
+    pub uci: UciMove,
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Deserialize, Debug, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct PonderRequest {
+    #[serde(flatten)]
+    pub auth: ProviderAuth,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct PonderResponse {
+    /// Send `ponderhit` if `true`, otherwise `stop` the search.
+    pub ponderhit: bool,
+}
+
+#[derive(Deserialize, Debug, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct HeartbeatRequest {
+    #[serde(flatten)]
+    pub auth: ProviderAuth,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct AcquireResponse {
     pub id: JobId,
     pub work: Work,
     pub engine: Engine,
+    /// Milliseconds the work waited for a provider.
+    #[schema(example = 120)]
+    pub queued_ms: u64,
+    /// Milliseconds left until the deadline of the requester, if any.
+    /// Providers under load may prefer to skip work that is about to
+    /// expire.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(example = 9880)]
+    pub deadline_ms: Option<u64>,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct StatsResponse {
+    /// Number of completed analyses.
+    pub analyses: u64,
+    /// Milliseconds since the Unix epoch, if the engine was ever used.
+    #[schema(example = 1760486400000_i64)]
+    pub last_used: Option<i64>,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct RotateSecretResponse {
+    /// Replaces the previous secret, which stops working immediately. Only
+    /// returned once.
+    pub client_secret: ClientSecret,
+}
+
+const DEFAULT_HEALTH_LIMIT: usize = 100;
+
+const MAX_HEALTH_LIMIT: usize = 1000;
+
+#[derive(Deserialize, Debug)]
+pub struct HealthQuery {
+    #[serde(default)]
+    pub offset: usize,
+    #[serde(default = "default_health_limit")]
+    limit: usize,
+}
+
+fn default_health_limit() -> usize {
+    DEFAULT_HEALTH_LIMIT
+}
+
+impl HealthQuery {
+    pub fn limit(&self) -> usize {
+        min(self.limit, MAX_HEALTH_LIMIT)
+    }
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct HealthResponse {
+    /// Number of known selectors, across all pages.
+    pub total: usize,
+    pub providers: Vec<ProviderHealth>,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ProviderHealth {
+    pub selector: ProviderSelector,
+    /// Whether a provider is currently waiting for work.
+    pub connected: bool,
+    /// Milliseconds since the Unix epoch.
+    pub last_seen: Option<u64>,
+    pub queued: usize,
+    /// Share of recent jobs that the provider completed.
+    #[schema(minimum = 0, maximum = 1)]
+    pub completion_rate: Option<f64>,
+    /// Whether providers are not handed work for completing too few jobs.
+    pub paused: bool,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct RecentJobsResponse {
+    pub jobs: Vec<AuditEntry>,
+}
+
+const DEFAULT_PURGE_DAYS: u32 = 365;
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PurgeQuery {
+    /// Purge engines that were not used in this many days.
+    #[serde(default = "default_purge_days")]
+    pub days: u32,
+    /// Only count the engines that would be purged.
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+fn default_purge_days() -> u32 {
+    DEFAULT_PURGE_DAYS
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct PurgeResponse {
+    /// Number of engines removed, or that would be removed in a dry run.
+    pub purged: usize,
+    pub dry_run: bool,
+}
+
+#[derive(Deserialize, Serialize, Debug, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct MaintenanceRequest {
+    /// Whether to reject new analysis requests.
+    pub enabled: bool,
+}
+
+#[derive(Deserialize, Serialize, Debug, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct SetEnabledRequest {
+    /// Whether the engine accepts new analysis requests.
+    pub enabled: bool,
+}
+
+#[derive(OpenApi)]
+#[openapi(components(schemas(
+    AnalyseRequest,
+    AnalyseBatchRequest,
+    AcquireRequest,
+    AcquireResponse,
+    CancelSessionRequest,
+    ChallengeResponse,
+    CompareRequest,
+    DeeperRequest,
+    HealthResponse,
+    HeartbeatRequest,
+    MaintenanceRequest,
+    PlayRequest,
+    ProviderHandshake,
+    PonderRequest,
+    PonderResponse,
+    PurgeResponse,
+    RecentJobsResponse,
+    RotateSecretResponse,
+    SetEnabledRequest,
+    StatsResponse,
+    SubscribeRequest,
+    Work
+)))]
+pub struct ApiDoc;
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    pub fn engine() -> Engine {
+        Engine {
+            id: EngineId("eei_test".to_owned()),
+            config: serde_json::from_value(json!({
+                "name": "Stockfish",
+                "clientSecret": "ees_clientsecret",
+                "userId": "user",
+                "maxThreads": 8,
+                "maxHash": 512,
+                "variants": ["chess", "crazyhouse"],
+            }))
+            .unwrap(),
+        }
+    }
+
+    pub fn work(extra: Value) -> Work {
+        let mut work = json!({
+            "sessionId": "session",
+            "threads": 4,
+            "hash": 128,
+            "depth": 20,
+            "multiPv": 1,
+            "variant": "chess",
+            "initialFen": "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+            "moves": [],
+        });
+        work.as_object_mut()
+            .unwrap()
+            .extend(extra.as_object().unwrap().clone());
+        serde_json::from_value(work).unwrap()
+    }
+
+    #[test]
+    fn test_openapi_schemas() {
+        let doc = serde_json::to_value(ApiDoc::openapi()).unwrap();
+        let schemas = &doc["components"]["schemas"];
+        for name in [
+            "AnalyseRequest",
+            "AcquireRequest",
+            "AcquireResponse",
+            "Work",
+        ] {
+            assert!(schemas[name].is_object(), "missing schema {name}");
+        }
+        let work = serde_json::to_string(&schemas["Work"]).unwrap();
+        for field in [
+            "sessionId",
+            "threads",
+            "hash",
+            "multiPv",
+            "initialFen",
+            "moves",
+        ] {
+            assert!(work.contains(field), "missing field {field}");
+        }
+        for secret in ["ClientSecret", "ProviderSecret"] {
+            assert!(schemas[secret].get("example").is_none());
+        }
+        assert!(schemas["UciVariant"].is_object());
+        assert!(!serde_json::to_string(&schemas["UciVariant"])
+            .unwrap()
+            .contains("Unknown"));
+    }
+
+    #[test]
+    fn test_variant_defaults() {
+        let opt = WorkOpt::default();
+        let (standard, _) = work(json!({ "threads": null, "hash": null }))
+            .sanitize(&engine(), &opt)
+            .unwrap();
+        assert_eq!(standard.threads, NonZeroU32::new(2));
+        assert_eq!(standard.hash, NonZeroU32::new(256));
+
+        let (crazyhouse, _) = work(json!({
+            "variant": "crazyhouse",
+            "initialFen": "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR[] w KQkq - 0 1",
+            "threads": null,
+            "hash": null,
+        }))
+        .sanitize(&engine(), &opt)
+        .unwrap();
+        assert_eq!(crazyhouse.threads, NonZeroU32::new(4));
+        assert_eq!(crazyhouse.hash, NonZeroU32::new(512));
+
+        let (explicit, _) = work(json!({ "threads": 16, "hash": 64 }))
+            .sanitize(&engine(), &opt)
+            .unwrap();
+        assert_eq!(explicit.threads, NonZeroU32::new(8));
+        assert_eq!(explicit.hash, NonZeroU32::new(64));
+    }
+
+    #[test]
+    fn test_engine_defaults() {
+        let opt = WorkOpt::default();
+        let mut configured = engine();
+        configured.config.defaults = serde_json::from_value(json!({
+            "threads": 6,
+            "hash": 1,
+            "multiPv": 3,
+            "depth": 30,
+        }))
+        .unwrap();
+
+        let omitting = |extra: Value| -> Work {
+            let mut work = json!({
+                "sessionId": "session",
+                "variant": "chess",
+                "initialFen": "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+                "moves": [],
+            });
+            work.as_object_mut()
+                .unwrap()
+                .extend(extra.as_object().unwrap().clone());
+            serde_json::from_value(work).unwrap()
+        };
+        let (defaults, _) = omitting(json!({})).sanitize(&configured, &opt).unwrap();
+        let value = serde_json::to_value(&defaults).unwrap();
+        assert_eq!(value["threads"], 6);
+        assert_eq!(value["hash"], 1);
+        assert_eq!(value["multiPv"], 3);
+        assert_eq!(value["depth"], 30);
+
+        // Explicit values take precedence.
+        let (explicit, _) = omitting(json!({ "threads": 2, "multiPv": 1, "nodes": 1000 }))
+            .sanitize(&configured, &opt)
+            .unwrap();
+        let value = serde_json::to_value(&explicit).unwrap();
+        assert_eq!(value["threads"], 2);
+        assert_eq!(value["multiPv"], 1);
+        assert_eq!(value["nodes"], 1000);
+        assert!(value.get("depth").is_none());
+
+        // Without defaults, a search limit is required.
+        assert!(matches!(
+            omitting(json!({})).sanitize(&engine(), &opt),
+            Err(InvalidWorkError::MissingSearch)
+        ));
+    }
+
+    #[test]
+    fn test_infer_variant() {
+        let opt = WorkOpt::default();
+        let without_variant = |fen: &str| -> Work {
+            serde_json::from_value(json!({
+                "sessionId": "session",
+                "depth": 20,
+                "initialFen": fen,
+                "moves": [],
+            }))
+            .unwrap()
+        };
+
+        let (crazyhouse, pos) =
+            without_variant("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR[] w KQkq - 0 1")
+                .sanitize(&engine(), &opt)
+                .unwrap();
+        assert_eq!(crazyhouse.variant(), Variant::Crazyhouse);
+        assert_eq!(pos.variant(), Variant::Crazyhouse);
+        assert_eq!(
+            serde_json::to_value(&crazyhouse).unwrap()["variant"],
+            "crazyhouse"
+        );
+
+        let (standard, _) =
+            without_variant("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1")
+                .sanitize(&engine(), &opt)
+                .unwrap();
+        assert_eq!(standard.variant(), Variant::Chess);
+
+        // The inferred variant must still be supported.
+        assert!(matches!(
+            without_variant("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 3+3 0 1")
+                .sanitize(&engine(), &opt),
+            Err(InvalidWorkError::UnsupportedVariant)
+        ));
+    }
+
+    #[test]
+    fn test_strength_levels() {
+        let opt = WorkOpt::default();
+        let mut configured = engine();
+        configured.config.strength_levels = serde_json::from_value(json!({
+            "1500": { "depth": 5 },
+            "2500": { "movetime": 1000 },
+        }))
+        .unwrap();
+
+        let at = |strength: &str| -> Work {
+            serde_json::from_value(json!({
+                "sessionId": "session",
+                "variant": "chess",
+                "initialFen": "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+                "moves": [],
+                "strength": strength,
+            }))
+            .unwrap()
+        };
+        let (weak, _) = at("1500").sanitize(&configured, &opt).unwrap();
+        let value = serde_json::to_value(&weak).unwrap();
+        assert_eq!(value["depth"], 5);
+        assert!(value.get("strength").is_none());
+
+        let (strong, _) = at("2500").sanitize(&configured, &opt).unwrap();
+        let value = serde_json::to_value(&strong).unwrap();
+        assert_eq!(value["movetime"], 1000);
+        assert!(value.get("depth").is_none());
+
+        assert!(matches!(
+            at("3000").sanitize(&configured, &opt),
+            Err(InvalidWorkError::UnknownStrength)
+        ));
+        assert!(matches!(
+            work(json!({ "strength": "1500" })).sanitize(&configured, &opt),
+            Err(InvalidWorkError::AmbiguousSearch)
+        ));
+    }
+
+    #[test]
+    fn test_searchmoves() {
+        let opt = WorkOpt::default();
+        let (legal, _) = work(json!({ "moves": ["e2e4"], "searchmoves": ["e7e5", "c7c5"] }))
+            .sanitize(&engine(), &opt)
+            .unwrap();
+        assert_eq!(
+            serde_json::to_value(&legal).unwrap()["searchmoves"],
+            json!(["e7e5", "c7c5"])
+        );
+
+        assert!(matches!(
+            work(json!({ "moves": ["e2e4"], "searchmoves": ["e2e4"] })).sanitize(&engine(), &opt),
+            Err(InvalidWorkError::IllegalUciMove { ply: 1, .. })
+        ));
+
+        assert!(matches!(
+            work(json!({ "searchmoves": ["e2e4", "e2e4"] })).sanitize(&engine(), &opt),
+            Err(InvalidWorkError::DuplicateSearchmove)
+        ));
+
+        let (empty, _) = work(json!({ "searchmoves": [] }))
+            .sanitize(&engine(), &opt)
+            .unwrap();
+        assert!(empty.searchmoves.is_none());
+        assert!(serde_json::to_value(&empty)
+            .unwrap()
+            .get("searchmoves")
+            .is_none());
+    }
+
+    #[test]
+    fn test_multi_pv_clamped_to_legal_moves() {
+        let opt = WorkOpt::default();
+        // Only Kg2 and Kh2 are legal.
+        let fen = "7k/8/8/8/8/8/8/5r1K w - - 0 1";
+        let (clamped, _) = work(json!({ "initialFen": fen, "multiPv": 5 }))
+            .sanitize(&engine(), &opt)
+            .unwrap();
+        assert_eq!(serde_json::to_value(&clamped).unwrap()["multiPv"], 2);
+        assert_eq!(
+            serde_json::to_value(clamped.clamped()).unwrap(),
+            json!({ "multiPv": 2 })
+        );
+
+        let (unchanged, _) = work(json!({ "multiPv": 5 }))
+            .sanitize(&engine(), &opt)
+            .unwrap();
+        assert_eq!(serde_json::to_value(&unchanged).unwrap()["multiPv"], 5);
+        assert!(unchanged.clamped().is_empty());
+
+        // Still at least one line if the game is over.
+        let (checkmate, _) = work(json!({
+            "initialFen": "rnb1kbnr/pppp1ppp/8/4p3/6Pq/5P2/PPPPP2P/RNBQKBNR w KQkq - 1 3",
+            "multiPv": 3,
+        }))
+        .sanitize(&engine(), &opt)
+        .unwrap();
+        assert_eq!(serde_json::to_value(&checkmate).unwrap()["multiPv"], 1);
+    }
+
+    #[test]
+    fn test_illegal_move_reports_ply() {
+        let err = work(json!({ "moves": ["e2e4", "e7e5", "e4e5"] }))
+            .sanitize(&engine(), &WorkOpt::default())
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            InvalidWorkError::IllegalUciMove { ply: 2, ref uci } if uci.to_string() == "e4e5"
+        ));
+        assert_eq!(err.to_string(), "illegal uci move e4e5 at ply 2");
+    }
+
+    #[test]
+    fn test_snake_case_analyse_request() {
+        let camel: AnalyseRequest = serde_json::from_value(json!({
+            "clientSecret": "ees_clientsecret",
+            "work": {
+                "sessionId": "board",
+                "multiPv": 2,
+                "depth": 20,
+                "initialFen": "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+                "moves": ["e2e4"],
+                "clientRef": "board-1",
+                "legalMoves": true,
+                "perspective": "sideToMove",
+                "startMoveNumber": 25,
+            },
+        }))
+        .unwrap();
+        let snake: AnalyseRequest = serde_json::from_value(json!({
+            "client_secret": "ees_clientsecret",
+            "work": {
+                "session_id": "board",
+                "multi_pv": 2,
+                "depth": 20,
+                "initial_fen": "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+                "moves": ["e2e4"],
+                "client_ref": "board-1",
+                "legal_moves": true,
+                "perspective": "side_to_move",
+                "start_move_number": 25,
+            },
+        }))
+        .unwrap();
+        assert_eq!(format!("{snake:?}"), format!("{camel:?}"));
+
+        // Output stays camelCase.
+        let work = serde_json::to_value(&snake.work).unwrap();
+        assert_eq!(work["sessionId"], "board");
+        assert!(work.get("session_id").is_none());
+    }
+
+    #[test]
+    fn test_wrong_side_to_move() {
+        let err = work(json!({
+            "initialFen": "rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1",
+            "moves": ["d2d4"],
+        }))
+        .sanitize(&engine(), &WorkOpt::default())
+        .unwrap_err();
+        assert!(matches!(
+            err,
+            InvalidWorkError::WrongSideToMove { ply: 0, turn: Color::Black, ref uci }
+                if uci.to_string() == "d2d4"
+        ));
+        assert_eq!(
+            err.to_string(),
+            "illegal uci move d2d4 at ply 0: moves a white piece, but black is to move"
+        );
+    }
+
+    #[test]
+    fn test_castling_rights_override() {
+        let sanitized = |extra: Value| {
+            work(extra)
+                .sanitize(&engine(), &WorkOpt::default())
+                .map(|(work, pos)| {
+                    (
+                        serde_json::to_value(work).unwrap()["initialFen"].clone(),
+                        pos,
+                    )
+                })
+        };
+
+        let (fen, pos) = sanitized(json!({
+            "initialFen": "r3k2r/8/8/8/8/8/8/R3K2R w - - 0 1",
+            "castlingRights": "KQkq",
+        }))
+        .unwrap();
+        assert_eq!(fen, "r3k2r/8/8/8/8/8/8/R3K2R w KQkq - 0 1");
+        assert!(pos.legal_moves().iter().any(|m| m.is_castle()));
+
+        let (fen, pos) = sanitized(json!({ "castlingRights": "-" })).unwrap();
+        assert_eq!(fen, "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w - - 0 1");
+        assert!(pos.castles().is_empty());
+        let (fen, _) = sanitized(json!({ "castlingRights": "Kq" })).unwrap();
+        assert_eq!(
+            fen,
+            "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w Kq - 0 1"
+        );
+
+        assert!(matches!(
+            sanitized(json!({
+                "initialFen": "4k3/8/8/8/8/8/8/4K3 w - - 0 1",
+                "castlingRights": "KQ",
+            })),
+            Err(InvalidWorkError::IllegalCastlingRights)
+        ));
+        for castling_rights in ["KQkqK", "KQ k", "1"] {
+            assert!(matches!(
+                sanitized(json!({ "castlingRights": castling_rights })),
+                Err(InvalidWorkError::InvalidCastlingRights)
+            ));
+        }
+    }
+
+    #[test]
+    fn test_seed() {
+        let opt = WorkOpt::default();
+        let (seeded, _) = work(json!({ "seed": 42 }))
+            .sanitize(&engine(), &opt)
+            .unwrap();
+        assert_eq!(serde_json::to_value(&seeded).unwrap()["seed"], 42);
+
+        let (unseeded, _) = work(json!({})).sanitize(&engine(), &opt).unwrap();
+        assert!(serde_json::to_value(&unseeded)
+            .unwrap()
+            .get("seed")
+            .is_none());
+
+        assert!(matches!(
+            work(json!({ "seed": 1u64 << 31 })).sanitize(&engine(), &opt),
+            Err(InvalidWorkError::SeedOutOfRange)
+        ));
+    }
+
+    #[test]
+    fn test_start_move_number_positive() {
+        assert!(matches!(
+            work(json!({ "startMoveNumber": 0 })).sanitize(&engine(), &WorkOpt::default()),
+            Err(InvalidWorkError::InvalidStartMoveNumber)
+        ));
+    }
+
+    #[test]
+    fn test_max_info_frames_positive() {
+        assert!(matches!(
+            work(json!({ "maxInfoFrames": 0 })).sanitize(&engine(), &WorkOpt::default()),
+            Err(InvalidWorkError::InvalidMaxInfoFrames)
+        ));
+        let (work, _) = work(json!({ "maxInfoFrames": 3 }))
+            .sanitize(&engine(), &WorkOpt::default())
+            .unwrap();
+        assert_eq!(work.max_info_frames(), Some(3));
+    }
+
+    #[test]
+    fn test_tablebase() {
+        let opt = WorkOpt::default();
+        assert!(matches!(
+            work(json!({ "tablebase": true })).sanitize(&engine(), &opt),
+            Err(InvalidWorkError::TablebaseUnsupported)
+        ));
+
+        let mut engine = engine();
+        engine.config.tablebase = true;
+        let (sanitized, _) = work(json!({ "tablebase": true }))
+            .sanitize(&engine, &opt)
+            .unwrap();
+        let value = serde_json::to_value(&sanitized).unwrap();
+        assert_eq!(value["tablebase"], true);
+        assert_eq!(
+            value["setoptions"],
+            json!([
+                { "name": "UCI_ShowWDL", "value": "true" },
+                { "name": "SyzygyProbeDepth", "value": "1" },
+            ])
+        );
+
+        // Only options that the engine reported.
+        engine.config.allowed_options = vec![
+            "option name SyzygyProbeDepth type spin default 1 min 1 max 100"
+                .parse()
+                .unwrap(),
+        ];
+        let (sanitized, _) = work(json!({ "tablebase": true }))
+            .sanitize(&engine, &opt)
+            .unwrap();
+        assert_eq!(
+            serde_json::to_value(&sanitized).unwrap()["setoptions"],
+            json!([{ "name": "SyzygyProbeDepth", "value": "1" }])
+        );
+
+        // Not taken from clients.
+        let (sanitized, _) = work(json!({
+            "setoptions": [{ "name": "Threads", "value": "1024" }],
+        }))
+        .sanitize(&engine, &opt)
+        .unwrap();
+        assert!(serde_json::to_value(&sanitized)
+            .unwrap()
+            .get("setoptions")
+            .is_none());
+    }
+
+    #[test]
+    fn test_allowed_fens() {
+        let opt = WorkOpt::default();
+        let mut engine = engine();
+        engine.config.allowed_fens = Some(vec![Fen::default()]);
+
+        let (startpos, _) = work(json!({ "moves": ["e2e4"] }))
+            .sanitize(&engine, &opt)
+            .unwrap();
+        assert_eq!(
+            startpos.initial_fen,
+            "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1"
+        );
+
+        // Move counters do not matter.
+        let restarted = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 3 40";
+        assert!(work(json!({ "initialFen": restarted }))
+            .sanitize(&engine, &opt)
+            .is_ok());
+
+        let custom = "rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1";
+        assert!(matches!(
+            work(json!({ "initialFen": custom })).sanitize(&engine, &opt),
+            Err(InvalidWorkError::DisallowedPosition)
+        ));
+    }
+
+    #[test]
+    fn test_ponder() {
+        let opt = WorkOpt::default();
+        assert!(matches!(
+            work(json!({ "ponder": "e2e4" })).sanitize(&engine(), &opt),
+            Err(InvalidWorkError::PonderUnsupported)
+        ));
+
+        let mut engine = engine();
+        engine.config.supports_ponder = true;
+        assert!(matches!(
+            work(json!({ "moves": ["e2e4"], "ponder": "e2e4" })).sanitize(&engine, &opt),
+            Err(InvalidWorkError::IllegalUciMove { ply: 1, .. })
+        ));
+
+        // Searchmoves apply to the position after the ponder move.
+        let (work, pos) =
+            work(json!({ "moves": ["e2e4"], "ponder": "e7e5", "searchmoves": ["g1f3"] }))
+                .sanitize(&engine, &opt)
+                .unwrap();
+        assert_eq!(serde_json::to_value(&work).unwrap()["ponder"], "e7e5");
+        assert_eq!(pos.fullmoves().get(), 2);
+        assert!(work.ponder().is_some());
+    }
+
+    #[test]
+    fn test_clock() {
+        let opt = WorkOpt::default();
+        let clock = json!({ "wtime": 180000, "btime": 175000, "winc": 2000, "binc": 2000 });
+        let (timed, _) = work(json!({ "clock": clock }))
+            .sanitize(&engine(), &opt)
+            .unwrap();
+        assert_eq!(serde_json::to_value(&timed).unwrap()["clock"], clock);
+
+        let (untimed, _) = work(json!({})).sanitize(&engine(), &opt).unwrap();
+        assert!(serde_json::to_value(&untimed)
+            .unwrap()
+            .get("clock")
+            .is_none());
+
+        let (no_increment, _) = work(json!({ "clock": { "wtime": 0, "btime": 60000 } }))
+            .sanitize(&engine(), &opt)
+            .unwrap();
+        assert_eq!(
+            serde_json::to_value(&no_increment).unwrap()["clock"],
+            json!({ "wtime": 0, "btime": 60000, "winc": 0, "binc": 0 })
+        );
+
+        assert!(matches!(
+            work(json!({ "clock": { "wtime": -1, "btime": 60000 } })).sanitize(&engine(), &opt),
+            Err(InvalidWorkError::NegativeClock)
+        ));
+    }
+
+    #[test]
+    fn test_client_ref_too_long() {
+        let opt = WorkOpt::default();
+        assert!(work(json!({ "clientRef": "a".repeat(MAX_CLIENT_REF_LEN) }))
+            .sanitize(&engine(), &opt)
+            .is_ok());
+        assert!(matches!(
+            work(json!({ "clientRef": "a".repeat(MAX_CLIENT_REF_LEN + 1) }))
+                .sanitize(&engine(), &opt),
+            Err(InvalidWorkError::ClientRefTooLong)
+        ));
+    }
+
+    #[test]
+    fn test_fen_too_long() {
+        let opt = WorkOpt::default();
+        let fen = format!(
+            "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR[{}] w KQkq - 0 1",
+            "Q".repeat(1000)
+        );
+        assert!(matches!(
+            work(json!({ "variant": "crazyhouse", "initialFen": fen })).sanitize(&engine(), &opt),
+            Err(InvalidWorkError::FenTooLong)
+        ));
+        assert!(matches!(
+            work(json!({ "initialFen": "not a fen" })).sanitize(&engine(), &opt),
+            Err(InvalidWorkError::Fen(_))
+        ));
+    }
+
+    #[test]
+    fn test_callback_url_allowlist() {
+        let opt = WorkOpt {
+            callback_domains: vec!["example.org".to_owned()],
+            ..WorkOpt::default()
+        };
+        for url in ["https://example.org/cb", "http://api.example.org/cb"] {
+            assert!(work(json!({ "callbackUrl": url }))
+                .sanitize(&engine(), &opt)
+                .is_ok());
+        }
+        for url in [
+            "https://evilexample.org/cb",
+            "https://example.com/cb",
+            "ftp://example.org/cb",
+        ] {
+            assert!(matches!(
+                work(json!({ "callbackUrl": url })).sanitize(&engine(), &opt),
+                Err(InvalidWorkError::CallbackUrlNotAllowed)
+            ));
+        }
+        assert!(matches!(
+            work(json!({ "callbackUrl": "https://example.org/cb" }))
+                .sanitize(&engine(), &WorkOpt::default()),
+            Err(InvalidWorkError::CallbackUrlNotAllowed)
+        ));
+    }
+
+    #[test]
+    fn test_tag_allowlist() {
+        let (tagged, _) = work(json!({ "tag": "puzzle" }))
+            .sanitize(&engine(), &WorkOpt::default())
+            .unwrap();
+        assert_eq!(tagged.tag(), Some("puzzle"));
+        assert!(matches!(
+            work(json!({ "tag": "user-123" })).sanitize(&engine(), &WorkOpt::default()),
+            Err(InvalidWorkError::DisallowedTag)
+        ));
+    }
 }
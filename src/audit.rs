@@ -0,0 +1,85 @@
+use std::{collections::VecDeque, sync::Mutex};
+
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::{
+    model::{EngineId, JobId},
+    summary::Reason,
+};
+
+/// A finished job, as kept in the audit log. Holds no secrets.
+#[derive(Serialize, Debug, Clone, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct AuditEntry {
+    /// Absent for jobs that were never picked up by a provider.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<JobId>,
+    pub engine: EngineId,
+    #[schema(value_type = String, example = "chess")]
+    pub variant: &'static str,
+    pub outcome: Reason,
+    pub duration_ms: u64,
+}
+
+/// The most recently finished jobs, for debugging without external logging
+/// infrastructure. The oldest entries are dropped once `capacity` is
+/// reached.
+pub struct AuditLog {
+    capacity: usize,
+    entries: Mutex<VecDeque<AuditEntry>>,
+}
+
+impl AuditLog {
+    pub fn new(capacity: usize) -> AuditLog {
+        AuditLog {
+            capacity,
+            entries: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    pub fn record(&self, entry: AuditEntry) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+
+    /// Newest first.
+    pub fn recent(&self) -> Vec<AuditEntry> {
+        self.entries.lock().unwrap().iter().rev().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(n: usize) -> AuditEntry {
+        AuditEntry {
+            id: Some(JobId::random()),
+            engine: EngineId(format!("eei_{n}")),
+            variant: "chess",
+            outcome: Reason::Bestmove,
+            duration_ms: 100,
+        }
+    }
+
+    #[test]
+    fn test_capacity() {
+        let log = AuditLog::new(3);
+        for n in 0..5 {
+            log.record(entry(n));
+        }
+        let engines: Vec<String> = log.recent().into_iter().map(|e| e.engine.0).collect();
+        assert_eq!(engines, ["eei_4", "eei_3", "eei_2"]);
+
+        let disabled = AuditLog::new(0);
+        disabled.record(entry(0));
+        assert!(disabled.recent().is_empty());
+    }
+}
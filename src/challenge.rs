@@ -0,0 +1,133 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+    time::Duration,
+};
+
+use rand::{
+    distributions::{Alphanumeric, DistString},
+    thread_rng,
+};
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+use utoipa::ToSchema;
+
+/// How long a provider has to answer a challenge.
+const CHALLENGE_TTL: Duration = Duration::from_secs(60);
+
+/// Maximum number of outstanding challenges. Issuing requires no
+/// authentication, so the oldest are evicted beyond this.
+const MAX_OUTSTANDING: usize = 10_000;
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, Hash, ToSchema)]
+#[schema(value_type = String)]
+pub struct Nonce(String);
+
+impl Nonce {
+    fn random() -> Nonce {
+        Nonce(Alphanumeric.sample_string(&mut thread_rng(), 32))
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        self.0.as_bytes()
+    }
+}
+
+/// Nonces issued to providers that authenticate by signing them with their
+/// provider key. Each nonce can be redeemed only once.
+pub struct Challenges {
+    capacity: usize,
+    issued: Mutex<Issued>,
+}
+
+#[derive(Default)]
+struct Issued {
+    by_nonce: HashMap<Nonce, Instant>,
+    /// In the order of issuance, including nonces that were already
+    /// redeemed.
+    by_age: VecDeque<(Instant, Nonce)>,
+}
+
+impl Default for Challenges {
+    fn default() -> Challenges {
+        Challenges::new(MAX_OUTSTANDING)
+    }
+}
+
+impl Challenges {
+    pub fn new(capacity: usize) -> Challenges {
+        Challenges {
+            capacity,
+            issued: Mutex::default(),
+        }
+    }
+
+    pub fn issue(&self) -> Nonce {
+        let now = Instant::now();
+        let nonce = Nonce::random();
+        let mut issued = self.issued.lock().unwrap();
+        while let Some((at, oldest)) = issued.by_age.front() {
+            if now.duration_since(*at) < CHALLENGE_TTL && issued.by_age.len() < self.capacity {
+                break;
+            }
+            let oldest = oldest.clone();
+            issued.by_nonce.remove(&oldest);
+            issued.by_age.pop_front();
+        }
+        issued.by_nonce.insert(nonce.clone(), now);
+        issued.by_age.push_back((now, nonce.clone()));
+        nonce
+    }
+
+    /// Returns `true` if the nonce was issued recently and not yet redeemed.
+    pub fn redeem(&self, nonce: &Nonce) -> bool {
+        self.issued
+            .lock()
+            .unwrap()
+            .by_nonce
+            .remove(nonce)
+            .is_some_and(|at| at.elapsed() < CHALLENGE_TTL)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::time::sleep;
+
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_redeem_once() {
+        let challenges = Challenges::default();
+        assert!(!challenges.redeem(&Nonce::random()));
+
+        let nonce = challenges.issue();
+        assert!(challenges.redeem(&nonce));
+        assert!(!challenges.redeem(&nonce));
+
+        let expired = challenges.issue();
+        sleep(CHALLENGE_TTL).await;
+        assert!(!challenges.redeem(&expired));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_bounded() {
+        let challenges = Challenges::new(2);
+        let evicted = challenges.issue();
+        let redeemed = challenges.issue();
+        assert!(challenges.redeem(&redeemed));
+        let kept = challenges.issue();
+        let latest = challenges.issue();
+        assert!(!challenges.redeem(&evicted));
+        assert!(challenges.redeem(&kept));
+        assert!(challenges.redeem(&latest));
+
+        // Expired challenges are pruned on the next issue.
+        challenges.issue();
+        sleep(CHALLENGE_TTL).await;
+        challenges.issue();
+        let issued = challenges.issued.lock().unwrap();
+        assert_eq!(issued.by_nonce.len(), 1);
+        assert_eq!(issued.by_age.len(), 1);
+    }
+}
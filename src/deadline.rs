@@ -0,0 +1,64 @@
+use std::time::{Duration, SystemTime};
+
+use axum_extra::headers::{self, Header, HeaderName, HeaderValue};
+
+static REQUEST_DEADLINE: HeaderName = HeaderName::from_static("request-deadline");
+
+/// The `Request-Deadline` header, as the time remaining when the request was
+/// received. Like `Retry-After`, it is either a number of seconds or an
+/// HTTP-date.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct RequestDeadline(pub Duration);
+
+impl Header for RequestDeadline {
+    fn name() -> &'static HeaderName {
+        &REQUEST_DEADLINE
+    }
+
+    fn decode<'i, I>(values: &mut I) -> Result<RequestDeadline, headers::Error>
+    where
+        I: Iterator<Item = &'i HeaderValue>,
+    {
+        let value = values
+            .next()
+            .and_then(|value| value.to_str().ok())
+            .ok_or_else(headers::Error::invalid)?
+            .trim();
+        if let Ok(secs) = value.parse::<u64>() {
+            return Ok(RequestDeadline(Duration::from_secs(secs)));
+        }
+        let at = httpdate::parse_http_date(value).map_err(|_| headers::Error::invalid())?;
+        Ok(RequestDeadline(
+            at.duration_since(SystemTime::now()).unwrap_or_default(),
+        ))
+    }
+
+    fn encode<E: Extend<HeaderValue>>(&self, values: &mut E) {
+        values.extend([HeaderValue::from(self.0.as_secs())]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(value: &str) -> Result<RequestDeadline, headers::Error> {
+        RequestDeadline::decode(&mut [HeaderValue::from_str(value).unwrap()].iter())
+    }
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(
+            decode("30").unwrap(),
+            RequestDeadline(Duration::from_secs(30))
+        );
+        assert_eq!(
+            decode("Sun, 06 Nov 1994 08:49:37 GMT").unwrap(),
+            RequestDeadline(Duration::ZERO)
+        );
+        let later = httpdate::fmt_http_date(SystemTime::now() + Duration::from_secs(120));
+        assert!(decode(&later).unwrap().0 > Duration::from_secs(100));
+        assert!(decode("soon").is_err());
+        assert!(decode("-1").is_err());
+    }
+}
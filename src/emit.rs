@@ -1,11 +1,14 @@
-use std::{cmp::min, time::Duration};
+use std::{cmp::min, mem, time::Duration};
 
-use serde::Serialize;
+use serde::{Serialize, Serializer};
 use serde_with::{serde_as, DisplayFromStr, DurationMilliSeconds};
-use shakmaty::{uci::UciMove, variant::VariantPosition, CastlingMode, Position};
+use sha2::{Digest as _, Sha256};
+use shakmaty::{san::SanPlus, uci::UciMove, variant::VariantPosition, Position};
+use tokio::time::Instant;
 
 use crate::{
-    model::MultiPv,
+    api::{CastlingNotation, Clamped, Perspective, Work},
+    model::{Engine, EngineId, JobId, MultiPv},
     uci::{Eval, UciOut},
 };
 
@@ -14,13 +17,29 @@ use crate::{
 struct EmitPv {
     #[serde_as(as = "Vec<DisplayFromStr>")]
     moves: Vec<UciMove>,
+    /// The same moves in SAN, if requested.
+    #[serde_as(as = "Option<Vec<DisplayFromStr>>")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    san: Option<Vec<SanPlus>>,
     #[serde(flatten)]
     eval: Eval,
+    #[serde(skip_serializing_if = "is_false")]
+    lowerbound: bool,
+    #[serde(skip_serializing_if = "is_false")]
+    upperbound: bool,
     depth: u32,
 }
 
+fn is_false(flag: &bool) -> bool {
+    !flag
+}
+
 impl EmitPv {
-    fn extract(uci: &UciOut, pos: &VariantPosition) -> (MultiPv, Option<EmitPv>) {
+    fn extract(
+        uci: &UciOut,
+        pos: &VariantPosition,
+        notation: &Notation,
+    ) -> (MultiPv, Option<EmitPv>) {
         let multi_pv = match *uci {
             UciOut::Info {
                 multipv: Some(multipv),
@@ -38,10 +57,20 @@ impl EmitPv {
                     pv: Some(ref pv),
                     ..
                 } => (multi_pv > MultiPv::default() || (!score.lowerbound && !score.upperbound))
-                    .then(|| EmitPv {
-                        moves: normalize_pv(pv, pos.clone()),
-                        eval: pos.turn().fold_wb(score.eval, -score.eval),
-                        depth,
+                    .then(|| {
+                        let score = match notation.perspective {
+                            Perspective::SideToMove => score.clone(),
+                            Perspective::White => pos.turn().fold_wb(score.clone(), -score.clone()),
+                        };
+                        let (moves, san) = normalize_pv(pv, pos.clone(), notation);
+                        EmitPv {
+                            moves,
+                            san,
+                            eval: score.eval,
+                            lowerbound: score.lowerbound,
+                            upperbound: score.upperbound,
+                            depth,
+                        }
                     }),
                 _ => None,
             },
@@ -49,32 +78,111 @@ impl EmitPv {
     }
 }
 
-fn normalize_pv(pv: &[UciMove], mut pos: VariantPosition) -> Vec<UciMove> {
+/// Walks the principal variation from `pos`, stopping at the first illegal
+/// move.
+fn normalize_pv(
+    pv: &[UciMove],
+    mut pos: VariantPosition,
+    notation: &Notation,
+) -> (Vec<UciMove>, Option<Vec<SanPlus>>) {
     let mut moves = Vec::new();
-    for uci in pv.iter().take(30) {
+    let mut san = notation.san.then(Vec::new);
+    for uci in pv.iter().take(notation.max_pv_len) {
         let m = match uci.to_move(&pos) {
             Ok(m) => m,
             Err(_) => break,
         };
-        moves.push(m.to_uci(CastlingMode::Chess960));
-        pos.play_unchecked(&m);
+        moves.push(m.to_uci(notation.castling.into()));
+        match san {
+            Some(ref mut san) => san.push(SanPlus::from_move_and_play_unchecked(&mut pos, &m)),
+            None => pos.play_unchecked(&m),
+        }
+    }
+    (moves, san)
+}
+
+/// How the analysis is presented to the requester.
+#[derive(Clone, Debug)]
+struct Notation {
+    castling: CastlingNotation,
+    perspective: Perspective,
+    san: bool,
+    /// Longer principal variations are truncated.
+    max_pv_len: usize,
+}
+
+/// Engines may report lines with equal scores in any order, so that they
+/// would swap places from one depth to the next. Consecutive lines with equal
+/// scores are ordered by their moves instead.
+fn ordered_pvs(pvs: &[Option<EmitPv>]) -> Vec<Option<&EmitPv>> {
+    let mut pvs: Vec<Option<&EmitPv>> = pvs.iter().map(Option::as_ref).collect();
+    for tied in pvs.chunk_by_mut(|a, b| matches!((a, b), (Some(a), Some(b)) if a.eval == b.eval)) {
+        tied.sort_by_cached_key(|pv| {
+            pv.map(|pv| pv.moves.iter().map(UciMove::to_string).collect::<Vec<_>>())
+        });
     }
-    moves
+    pvs
+}
+
+fn serialize_pvs<S: Serializer>(pvs: &[Option<EmitPv>], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(ordered_pvs(pvs))
 }
 
 #[serde_as]
-#[derive(Clone, Debug, Default, Serialize)]
+#[derive(Clone, Debug, Serialize)]
 pub struct Emit {
     #[serde_as(as = "DurationMilliSeconds")]
     time: Duration,
     depth: u32,
     nodes: u64,
+    /// Left of the node budget of the work, if any.
+    #[serde(rename = "nodesRemaining", skip_serializing_if = "Option::is_none")]
+    nodes_remaining: Option<u64>,
+    #[serde(serialize_with = "serialize_pvs")]
     pvs: Vec<Option<EmitPv>>,
+    #[serde(skip)]
+    node_budget: Option<u64>,
+    #[serde(skip)]
+    notation: Notation,
 }
 
 impl Emit {
+    pub fn new(work: &Work, max_pv_len: usize) -> Emit {
+        Emit {
+            time: Duration::ZERO,
+            depth: 0,
+            nodes: 0,
+            nodes_remaining: work.node_budget(),
+            pvs: Vec::new(),
+            node_budget: work.node_budget(),
+            notation: Notation {
+                castling: work.castling(),
+                perspective: work.perspective(),
+                san: work.san(),
+                max_pv_len,
+            },
+        }
+    }
+
+    /// Keeps lines of an earlier emit for slots that have none yet, e.g.
+    /// right after the first line of a new depth.
+    fn backfill(&mut self, earlier: Emit) {
+        if self.pvs.len() < earlier.pvs.len() {
+            self.pvs.resize(earlier.pvs.len(), None);
+        }
+        for (pv, earlier) in self.pvs.iter_mut().zip(earlier.pvs) {
+            if pv.is_none() {
+                *pv = earlier;
+            }
+        }
+    }
+
     pub fn update(&mut self, uci: &UciOut, pos: &VariantPosition) {
-        let (multi_pv, emit_pv) = EmitPv::extract(uci, pos);
+        // Keep the final lines once the provider sends its best move.
+        if !matches!(uci, UciOut::Info { .. }) {
+            return;
+        }
+        let (multi_pv, emit_pv) = EmitPv::extract(uci, pos, &self.notation);
         if multi_pv <= MultiPv::default() {
             if let UciOut::Info {
                 time: Some(time), ..
@@ -93,6 +201,7 @@ impl Emit {
             } = *uci
             {
                 self.nodes = nodes;
+                self.nodes_remaining = self.node_budget.map(|budget| budget.saturating_sub(nodes));
             }
             for pv in &mut self.pvs {
                 *pv = None;
@@ -114,7 +223,1035 @@ impl Emit {
         }
     }
 
+    pub fn depth(&self) -> u32 {
+        self.depth
+    }
+
+    /// Short hash of the lines and their scores, equal for equal results
+    /// regardless of the order of tied lines.
+    pub fn digest(&self) -> String {
+        let mut hasher = Sha256::new();
+        for pv in ordered_pvs(&self.pvs).into_iter().flatten() {
+            for m in &pv.moves {
+                hasher.update(m.to_string());
+                hasher.update(" ");
+            }
+            hasher.update(match pv.eval {
+                Eval::Cp(cp) => format!("cp {cp}\n"),
+                Eval::Mate(mate) => format!("mate {mate}\n"),
+            });
+        }
+        hex::encode(&hasher.finalize()[..8])
+    }
+
+    /// The first move of the best line so far.
+    pub fn best_move(&self) -> Option<&UciMove> {
+        self.pvs.first()?.as_ref()?.moves.first()
+    }
+
     pub fn should_emit(&self) -> bool {
         !self.pvs.is_empty() && self.pvs.iter().all(|pv| pv.is_some())
     }
 }
+
+/// Limits how often analysis frames of a job are forwarded. Frames in
+/// between are coalesced, so that the latest is sent once the interval
+/// passed. Once the optional budget of frames is used up, further analysis
+/// frames are suppressed.
+pub struct Throttle {
+    interval: Option<Duration>,
+    last_sent: Option<Instant>,
+    pending: bool,
+    remaining: Option<u32>,
+}
+
+impl Throttle {
+    pub fn new(interval: Option<Duration>) -> Throttle {
+        Throttle {
+            interval,
+            last_sent: None,
+            pending: false,
+            remaining: None,
+        }
+    }
+
+    pub fn with_max_frames(mut self, max_frames: Option<u32>) -> Throttle {
+        self.remaining = max_frames;
+        self
+    }
+
+    /// Returns whether a new frame may be sent now. Otherwise it is pending
+    /// until `due`, unless the budget is exhausted.
+    pub fn admit(&mut self) -> bool {
+        if self.remaining == Some(0) {
+            return false;
+        }
+        let now = Instant::now();
+        if self.next().is_some_and(|next| now < next) {
+            self.pending = true;
+            return false;
+        }
+        self.last_sent = Some(now);
+        self.pending = false;
+        self.spend();
+        true
+    }
+
+    /// When the pending frame may be sent, if any.
+    pub fn due(&self) -> Option<Instant> {
+        self.next().filter(|_| self.pending)
+    }
+
+    /// Returns whether a frame was pending, marking it as sent.
+    pub fn take_pending(&mut self) -> bool {
+        if self.pending {
+            self.last_sent = Some(Instant::now());
+            self.spend();
+        }
+        std::mem::take(&mut self.pending)
+    }
+
+    /// Frames that may still be sent, if limited.
+    pub fn remaining(&self) -> Option<u32> {
+        self.remaining
+    }
+
+    fn next(&self) -> Option<Instant> {
+        Some(self.last_sent? + self.interval?)
+    }
+
+    fn spend(&mut self) {
+        if let Some(remaining) = &mut self.remaining {
+            *remaining = remaining.saturating_sub(1);
+        }
+    }
+}
+
+/// Frame kind of the binary format: any frame as its JSON object.
+pub const BINARY_JSON: u8 = 0;
+
+/// Frame kind of the binary format: analysis in the compact layout.
+pub const BINARY_EMIT: u8 = 1;
+
+/// Flags of a line in the binary format.
+const BINARY_PRESENT: u8 = 1 << 0;
+const BINARY_MATE: u8 = 1 << 1;
+const BINARY_LOWERBOUND: u8 = 1 << 2;
+const BINARY_UPPERBOUND: u8 = 1 << 3;
+
+impl Emit {
+    /// Payload of the compact layout, all integers little-endian:
+    ///
+    /// * `u32` time in milliseconds, `u16` depth, `u64` nodes,
+    ///   `u8` number of lines, followed by each line:
+    /// * `u8` flags: present, mate, lowerbound, upperbound (from the least
+    ///   significant bit). Absent lines end here.
+    /// * `i32` centipawns or moves to mate, `u16` depth, `u8` number of
+    ///   moves, followed by a `u16` for each move.
+    ///
+    /// Moves are packed as origin square (bits 0-5), destination square
+    /// (bits 6-11) and promotion role (bits 12-14, 0 for none). Drops set
+    /// bit 15, with the dropped role in bits 12-14. Squares count from a1 to
+    /// h8 and roles from pawn (1) to king (6). SAN is not included. Values
+    /// that do not fit are saturated.
+    fn to_binary(&self) -> Vec<u8> {
+        let saturate_u16 = |n: u32| u16::try_from(n).unwrap_or(u16::MAX);
+        let mut buf = Vec::new();
+        buf.extend_from_slice(
+            &u32::try_from(self.time.as_millis())
+                .unwrap_or(u32::MAX)
+                .to_le_bytes(),
+        );
+        buf.extend_from_slice(&saturate_u16(self.depth).to_le_bytes());
+        buf.extend_from_slice(&self.nodes.to_le_bytes());
+        let pvs = ordered_pvs(&self.pvs);
+        buf.push(u8::try_from(pvs.len()).unwrap_or(u8::MAX));
+        for pv in pvs.into_iter().take(usize::from(u8::MAX)) {
+            let Some(pv) = pv else {
+                buf.push(0);
+                continue;
+            };
+            let (mate, score) = match pv.eval {
+                Eval::Cp(cp) => (false, cp.clamp(i32::MIN.into(), i32::MAX.into()) as i32),
+                Eval::Mate(mate) => (true, mate),
+            };
+            let mut flags = BINARY_PRESENT;
+            for (set, flag) in [
+                (mate, BINARY_MATE),
+                (pv.lowerbound, BINARY_LOWERBOUND),
+                (pv.upperbound, BINARY_UPPERBOUND),
+            ] {
+                if set {
+                    flags |= flag;
+                }
+            }
+            buf.push(flags);
+            buf.extend_from_slice(&score.to_le_bytes());
+            buf.extend_from_slice(&saturate_u16(pv.depth).to_le_bytes());
+            let moves = &pv.moves[..min(pv.moves.len(), usize::from(u8::MAX))];
+            buf.push(moves.len() as u8);
+            for uci in moves {
+                buf.extend_from_slice(&pack_move(uci).to_le_bytes());
+            }
+        }
+        buf
+    }
+}
+
+fn pack_move(uci: &UciMove) -> u16 {
+    match *uci {
+        UciMove::Normal {
+            from,
+            to,
+            promotion,
+        } => from as u16 | (to as u16) << 6 | promotion.map_or(0, |role| role as u16) << 12,
+        UciMove::Put { role, to } => 1 << 15 | (role as u16) << 12 | (to as u16) << 6,
+        UciMove::Null => 0,
+    }
+}
+
+/// A line of the analysis stream sent to the requester.
+#[derive(Clone, Debug, Serialize)]
+#[serde(untagged, rename_all_fields = "camelCase")]
+pub enum Frame {
+    /// Sent once a provider has picked up the job.
+    Acquired {
+        acquired: bool,
+        /// For further clients of the engine to subscribe to the job.
+        job_id: JobId,
+        engine: String,
+        position: PositionInfo,
+        #[serde(skip_serializing_if = "Clamped::is_empty")]
+        clamped: Clamped,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        client_ref: Option<String>,
+    },
+    Emit(Emit),
+    /// Sent when the provider completed the analysis.
+    Done {
+        done: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        bestmove: Option<String>,
+        /// See `Emit::digest`.
+        digest: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        client_ref: Option<String>,
+    },
+    /// Sent instead of any analysis when the position to analyse has no
+    /// legal moves, without involving a provider.
+    GameOver {
+        done: bool,
+        /// `1-0`, `0-1` or `1/2-1/2`.
+        outcome: &'static str,
+        termination: Termination,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        client_ref: Option<String>,
+    },
+    /// Sent instead of `Done` when the deadline of the request passed.
+    Timeout {
+        timeout: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        client_ref: Option<String>,
+    },
+    /// Sent instead of `Done` when the analysis failed after it was
+    /// acquired.
+    Error {
+        error: String,
+        code: StreamError,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        client_ref: Option<String>,
+    },
+}
+
+/// Reasons for a stream to end with an error frame.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum StreamError {
+    /// Reading from the provider failed, e.g. because its connection timed
+    /// out.
+    Provider,
+    /// The provider sent output that could not be parsed.
+    Protocol,
+    /// The provider disconnected before sending `bestmove`.
+    Disconnected,
+    /// The work could not be handed to another provider.
+    Redispatch,
+    /// The provider did not start the analysis in time, and the work could
+    /// not be handed to another provider.
+    StartTimeout,
+    /// The provider did not complete the analysis within the analysis
+    /// timeout.
+    AnalysisTimeout,
+    /// No provider analysed the work, e.g. because none picked it up in
+    /// time. Only posted to callbacks, since streams are answered with an
+    /// error status instead.
+    Unavailable,
+}
+
+/// Why the position to analyse has no legal moves.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Termination {
+    Checkmate,
+    Stalemate,
+    /// The game ended by a rule of the variant, e.g. an exploded king.
+    VariantEnd,
+}
+
+/// Most pieces (including kings) covered by tablebases.
+const MAX_TABLEBASE_PIECES: usize = 7;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GamePhase {
+    Opening,
+    Middlegame,
+    Endgame,
+}
+
+/// Facts about the analysed position, to help clients present the
+/// analysis.
+#[serde_as]
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PositionInfo {
+    /// Material on the board in pawn units, for white and black.
+    material: [u32; 2],
+    phase: GamePhase,
+    /// Few enough pieces to be covered by standard chess tablebases.
+    tablebase: bool,
+    /// The position occurred three times.
+    repetition: bool,
+    /// Half-moves since the last capture or pawn move, towards the 50-move
+    /// rule.
+    halfmove_clock: u32,
+    /// Counted from `startMoveNumber` of the work, if given.
+    move_number: u32,
+    /// Only if requested with `legalMoves`.
+    #[serde_as(as = "Option<Vec<DisplayFromStr>>")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    legal_moves: Option<Vec<UciMove>>,
+}
+
+impl PositionInfo {
+    pub fn new(work: &Work, pos: &VariantPosition) -> PositionInfo {
+        let board = pos.board();
+        let material = board.material().map(|pieces| {
+            u32::from(pieces.pawn)
+                + 3 * u32::from(pieces.knight + pieces.bishop)
+                + 5 * u32::from(pieces.rook)
+                + 9 * u32::from(pieces.queen)
+        });
+        let pawns = u32::from(board.material().white.pawn + board.material().black.pawn);
+        let pieces = material.white + material.black - pawns;
+        let pieces_count = board.occupied().count();
+        PositionInfo {
+            material: [material.white, material.black],
+            // Heuristic: endgame once most pieces are traded, opening while
+            // early with nearly all pieces left.
+            phase: if pieces <= 26 {
+                GamePhase::Endgame
+            } else if pos.fullmoves().get() <= 12 && pieces >= 56 {
+                GamePhase::Opening
+            } else {
+                GamePhase::Middlegame
+            },
+            tablebase: matches!(pos, VariantPosition::Chess(_))
+                && pieces_count <= MAX_TABLEBASE_PIECES
+                && pos.castles().is_empty(),
+            repetition: work.is_threefold_repetition(),
+            halfmove_clock: pos.halfmoves(),
+            move_number: work.move_number().unwrap_or(pos.fullmoves().get()),
+            legal_moves: work.legal_moves().then(|| {
+                pos.legal_moves()
+                    .iter()
+                    .map(|m| m.to_uci(work.castling().into()))
+                    .collect()
+            }),
+        }
+    }
+}
+
+impl Frame {
+    pub fn acquired(id: &JobId, engine: &Engine, work: &Work, pos: &VariantPosition) -> Frame {
+        Frame::Acquired {
+            acquired: true,
+            job_id: id.clone(),
+            engine: engine.config.name.clone(),
+            position: PositionInfo::new(work, pos),
+            clamped: work.clamped().clone(),
+            client_ref: work.client_ref().map(str::to_owned),
+        }
+    }
+
+    pub fn done(
+        bestmove: Option<&UciMove>,
+        emit: &Emit,
+        pos: &VariantPosition,
+        work: &Work,
+    ) -> Frame {
+        Frame::Done {
+            done: true,
+            bestmove: bestmove
+                .and_then(|uci| uci.to_move(pos).ok())
+                .map(|m| m.to_uci(work.castling().into()).to_string()),
+            digest: emit.digest(),
+            client_ref: work.client_ref().map(str::to_owned),
+        }
+    }
+
+    /// The result of the game, if the position has no legal moves.
+    pub fn game_over(pos: &VariantPosition, work: &Work) -> Option<Frame> {
+        if !pos.legal_moves().is_empty() {
+            return None;
+        }
+        let termination = if pos.is_variant_end() {
+            Termination::VariantEnd
+        } else if pos.is_check() {
+            Termination::Checkmate
+        } else {
+            Termination::Stalemate
+        };
+        Some(Frame::GameOver {
+            done: true,
+            outcome: pos.outcome()?.as_str(),
+            termination,
+            client_ref: work.client_ref().map(str::to_owned),
+        })
+    }
+
+    pub fn timeout(work: &Work) -> Frame {
+        Frame::Timeout {
+            timeout: true,
+            client_ref: work.client_ref().map(str::to_owned),
+        }
+    }
+
+    pub fn error(code: StreamError, error: String, work: &Work) -> Frame {
+        Frame::Error {
+            error,
+            code,
+            client_ref: work.client_ref().map(str::to_owned),
+        }
+    }
+}
+
+impl Frame {
+    /// Encodes the frame for the binary stream format: a `u8` kind, the
+    /// `u32` little-endian length of the payload, and the payload. Analysis
+    /// uses the compact layout of `BINARY_EMIT`, other frames are rare and
+    /// sent as their JSON object with `BINARY_JSON`.
+    pub fn to_binary(&self) -> Vec<u8> {
+        let (kind, payload) = match self {
+            Frame::Emit(emit) => (BINARY_EMIT, emit.to_binary()),
+            frame => (
+                BINARY_JSON,
+                serde_json::to_vec(frame).expect("serialize frame"),
+            ),
+        };
+        let mut buf = Vec::with_capacity(5 + payload.len());
+        buf.push(kind);
+        buf.extend_from_slice(
+            &u32::try_from(payload.len())
+                .expect("frame length")
+                .to_le_bytes(),
+        );
+        buf.extend(payload);
+        buf
+    }
+}
+
+impl From<Emit> for Frame {
+    fn from(emit: Emit) -> Frame {
+        Frame::Emit(emit)
+    }
+}
+
+impl Frame {
+    /// Merges `next` into this queued frame if both are emits, keeping the
+    /// latest line for each multipv slot. Gives back other frames, which
+    /// must be queued in order.
+    pub fn coalesce(&mut self, next: Frame) -> Option<Frame> {
+        match (self, next) {
+            (Frame::Emit(queued), Frame::Emit(emit)) => {
+                let earlier = mem::replace(queued, emit);
+                queued.backfill(earlier);
+                None
+            }
+            (_, next) => Some(next),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct BatchEmit {
+    index: usize,
+    #[serde(flatten)]
+    item: BatchItem,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+enum BatchItem {
+    Frame(Frame),
+    Error { error: String },
+}
+
+impl BatchEmit {
+    pub fn frame(index: usize, frame: Frame) -> BatchEmit {
+        BatchEmit {
+            index,
+            item: BatchItem::Frame(frame),
+        }
+    }
+
+    pub fn error(index: usize, error: String) -> BatchEmit {
+        BatchEmit {
+            index,
+            item: BatchItem::Error { error },
+        }
+    }
+}
+
+/// A line of a comparison stream, tagged with the engine it is from.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CompareEmit {
+    engine_id: EngineId,
+    #[serde(flatten)]
+    item: BatchItem,
+}
+
+impl CompareEmit {
+    pub fn frame(engine: EngineId, frame: Frame) -> CompareEmit {
+        CompareEmit {
+            engine_id: engine,
+            item: BatchItem::Frame(frame),
+        }
+    }
+
+    pub fn error(engine: EngineId, error: String) -> CompareEmit {
+        CompareEmit {
+            engine_id: engine,
+            item: BatchItem::Error { error },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+    use shakmaty::{fen::Fen, variant::Variant, CastlingMode, Role, Square};
+    use tokio::time::{sleep, sleep_until};
+
+    use super::*;
+    use crate::api::{
+        tests::{engine, work},
+        WorkOpt,
+    };
+
+    fn pos(fen: &str) -> VariantPosition {
+        let fen: Fen = fen.parse().unwrap();
+        VariantPosition::from_setup(Variant::Chess, fen.into_setup(), CastlingMode::Chess960)
+            .unwrap()
+    }
+
+    fn emit(pos: &VariantPosition, lines: &[&str]) -> Value {
+        emit_with(json!({}), pos, lines)
+    }
+
+    fn emit_with(extra: Value, pos: &VariantPosition, lines: &[&str]) -> Value {
+        let mut emit = Emit::new(&work(extra), WorkOpt::default().max_pv_len);
+        for line in lines {
+            emit.update(&UciOut::from_line(line).unwrap().unwrap(), pos);
+        }
+        serde_json::to_value(emit).unwrap()
+    }
+
+    #[test]
+    fn test_emit_scores() {
+        let white = pos("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1");
+        let black = pos("rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1");
+
+        let frame = emit(&white, &["info depth 10 score mate 3 pv e2e4"]);
+        assert_eq!(frame["pvs"][0]["mate"], 3);
+        assert!(frame["pvs"][0].get("cp").is_none());
+
+        let frame = emit(&black, &["info depth 10 score mate -3 pv e7e5"]);
+        assert_eq!(frame["pvs"][0]["mate"], -3);
+
+        let frame = emit(&black, &["info depth 10 score cp 34 pv e7e5"]);
+        assert_eq!(frame["pvs"][0]["cp"], 34);
+        assert!(frame["pvs"][0].get("lowerbound").is_none());
+        assert!(frame["pvs"][0].get("upperbound").is_none());
+    }
+
+    #[test]
+    fn test_emit_white_perspective() {
+        let white = pos("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1");
+        let black = pos("rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1");
+        let from_white = |pos, lines| emit_with(json!({ "perspective": "white" }), pos, lines);
+
+        let frame = from_white(&white, &["info depth 10 score cp 34 pv e2e4"]);
+        assert_eq!(frame["pvs"][0]["cp"], 34);
+
+        let frame = from_white(&black, &["info depth 10 score mate -3 pv e7e5"]);
+        assert_eq!(frame["pvs"][0]["mate"], 3);
+
+        let frame = from_white(&black, &["info depth 10 score cp 34 pv e7e5"]);
+        assert_eq!(frame["pvs"][0]["cp"], -34);
+
+        let frame = from_white(
+            &black,
+            &[
+                "info multipv 1 depth 10 score cp 20 pv e7e5",
+                "info multipv 2 depth 10 score cp 34 lowerbound pv c7c5",
+            ],
+        );
+        assert_eq!(
+            frame["pvs"][1],
+            json!({ "moves": ["c7c5"], "cp": -34, "upperbound": true, "depth": 10 })
+        );
+    }
+
+    #[test]
+    fn test_emit_bounds() {
+        let black = pos("rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1");
+        let frame = emit(
+            &black,
+            &[
+                "info multipv 1 depth 10 score cp 20 pv e7e5",
+                "info multipv 2 depth 10 score cp 34 lowerbound pv c7c5",
+            ],
+        );
+        assert_eq!(
+            frame["pvs"][1],
+            json!({ "moves": ["c7c5"], "cp": 34, "lowerbound": true, "depth": 10 })
+        );
+
+        // Bounded scores of the principal variation are not forwarded.
+        let frame = emit(&black, &["info depth 10 score cp 34 lowerbound pv e7e5"]);
+        assert_eq!(frame["pvs"], json!([null]));
+    }
+
+    #[test]
+    fn test_emit_castling_notation() {
+        let pos = pos("4k3/8/8/8/8/8/8/RK6 w A - 0 1");
+        let lines = ["info depth 10 score cp 0 pv b1a1 e8e7"];
+
+        let frame = emit_with(json!({ "castling": "chess960" }), &pos, &lines);
+        assert_eq!(frame["pvs"][0]["moves"], json!(["b1a1", "e8e7"]));
+
+        let frame = emit_with(json!({ "castling": "standard" }), &pos, &lines);
+        assert_eq!(frame["pvs"][0]["moves"], json!(["b1c1", "e8e7"]));
+    }
+
+    #[test]
+    fn test_emit_san() {
+        let pos = pos("r1bqk1nr/pppp1ppp/2n5/2b1p3/2B1P3/5N2/PPPP1PPP/RNBQK2R w KQkq - 4 4");
+        let lines = ["info depth 10 score cp 0 pv c4f7 e8f7 e1h1"];
+
+        let frame = emit_with(json!({ "san": true }), &pos, &lines);
+        assert_eq!(frame["pvs"][0]["moves"], json!(["c4f7", "e8f7", "e1h1"]));
+        assert_eq!(frame["pvs"][0]["san"], json!(["Bxf7+", "Kxf7", "O-O"]));
+
+        let frame = emit(&pos, &lines);
+        assert!(frame["pvs"][0].get("san").is_none());
+    }
+
+    #[test]
+    fn test_emit_truncates_pv() {
+        let pos = pos("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1");
+        let pv = ["g1f3", "g8f6", "f3g1", "f6g8"].repeat(15).join(" ");
+        let line = format!("info depth 42 score cp 17 pv {pv}");
+
+        let mut emit = Emit::new(&work(json!({})), 8);
+        emit.update(&UciOut::from_line(&line).unwrap().unwrap(), &pos);
+        let frame = serde_json::to_value(emit).unwrap();
+        assert_eq!(
+            frame["pvs"][0]["moves"],
+            json!(["g1f3", "g8f6", "f3g1", "f6g8", "g1f3", "g8f6", "f3g1", "f6g8"])
+        );
+        assert_eq!(frame["pvs"][0]["cp"], 17);
+        assert_eq!(frame["pvs"][0]["depth"], 42);
+        assert_eq!(frame["depth"], 42);
+    }
+
+    #[test]
+    fn test_coalesce() {
+        let pos = pos("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1");
+        let mut emit = Emit::new(&work(json!({})), WorkOpt::default().max_pv_len);
+        let mut queued: Option<Frame> = None;
+        let mut update = |line: &str| {
+            emit.update(&UciOut::from_line(line).unwrap().unwrap(), &pos);
+            let frame = Frame::from(emit.clone());
+            match queued {
+                Some(ref mut queued) => assert!(queued.coalesce(frame).is_none()),
+                None => queued = Some(frame),
+            }
+        };
+        for depth in 1..=5 {
+            update(&format!("info multipv 1 depth {depth} score cp 30 pv e2e4"));
+            update(&format!("info multipv 2 depth {depth} score cp 20 pv d2d4"));
+        }
+        // Starts a new depth, without a line for the second slot yet.
+        update("info multipv 1 depth 6 score cp 35 pv e2e4");
+
+        let mut queued = queued.unwrap();
+        let done = Frame::Done {
+            done: true,
+            bestmove: Some("e2e4".to_owned()),
+            digest: String::new(),
+            client_ref: None,
+        };
+        assert!(matches!(queued.coalesce(done), Some(Frame::Done { .. })));
+
+        let Frame::Emit(latest) = queued else {
+            panic!("expected emit");
+        };
+        let latest = serde_json::to_value(latest).unwrap();
+        assert_eq!(latest["pvs"][0]["depth"], 6);
+        assert_eq!(latest["pvs"][0]["cp"], 35);
+        assert_eq!(latest["pvs"][1]["depth"], 5);
+    }
+
+    #[test]
+    fn test_emit_equal_scores_stable() {
+        let pos = pos("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1");
+        for lines in [
+            [
+                "info multipv 1 depth 10 score cp 30 pv g1f3",
+                "info multipv 2 depth 10 score cp 20 pv e2e4",
+                "info multipv 3 depth 10 score cp 20 pv d2d4",
+            ],
+            [
+                "info multipv 1 depth 11 score cp 30 pv g1f3",
+                "info multipv 2 depth 11 score cp 20 pv d2d4",
+                "info multipv 3 depth 11 score cp 20 pv e2e4",
+            ],
+        ] {
+            let frame = emit(&pos, &lines);
+            let first_moves: Vec<&Value> = frame["pvs"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|pv| &pv["moves"][0])
+                .collect();
+            assert_eq!(
+                first_moves,
+                [&json!("g1f3"), &json!("d2d4"), &json!("e2e4")]
+            );
+        }
+    }
+
+    #[test]
+    fn test_emit_nodes_remaining() {
+        let pos = pos("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1");
+        let mut budgeted = serde_json::to_value(work(json!({}))).unwrap();
+        let fields = budgeted.as_object_mut().unwrap();
+        fields.remove("depth");
+        fields.insert("nodes".to_owned(), json!(100_000));
+        let mut emit = Emit::new(&serde_json::from_value(budgeted).unwrap(), 30);
+        assert_eq!(
+            serde_json::to_value(&emit).unwrap()["nodesRemaining"],
+            100_000
+        );
+
+        let mut remaining = Vec::new();
+        for line in [
+            "info depth 1 nodes 20 score cp 20 pv e2e4",
+            "info depth 5 nodes 30000 score cp 25 pv e2e4",
+            "info depth 9 nodes 99000 score cp 30 pv d2d4",
+            "info depth 10 nodes 100500 score cp 30 pv d2d4",
+        ] {
+            emit.update(&UciOut::from_line(line).unwrap().unwrap(), &pos);
+            remaining.push(serde_json::to_value(&emit).unwrap()["nodesRemaining"].clone());
+        }
+        assert_eq!(
+            remaining,
+            [json!(99_980), json!(70_000), json!(1000), json!(0)]
+        );
+
+        // Only with a node budget.
+        let frame = emit_with(
+            json!({}),
+            &pos,
+            &["info depth 1 nodes 20 score cp 20 pv e2e4"],
+        );
+        assert!(frame.get("nodesRemaining").is_none());
+    }
+
+    #[test]
+    fn test_position_info_move_number() {
+        let info = |extra: Value| {
+            let (work, pos) = work(extra)
+                .sanitize(&engine(), &WorkOpt::default())
+                .unwrap();
+            serde_json::to_value(PositionInfo::new(&work, &pos)).unwrap()
+        };
+
+        let moves = json!(["e2e4", "e7e5", "g1f3"]);
+        assert_eq!(info(json!({ "moves": moves }))["moveNumber"], 2);
+        assert_eq!(
+            info(json!({ "moves": moves, "startMoveNumber": 25 }))["moveNumber"],
+            26
+        );
+        assert_eq!(
+            info(json!({
+                "initialFen": "rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1",
+                "moves": ["e7e5"],
+                "startMoveNumber": 25,
+            }))["moveNumber"],
+            26
+        );
+    }
+
+    #[test]
+    fn test_digest() {
+        let pos = pos("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1");
+        let digest = |lines: &[&str]| {
+            let mut emit = Emit::new(&work(json!({ "multiPv": 2 })), 30);
+            for line in lines {
+                emit.update(&UciOut::from_line(line).unwrap().unwrap(), &pos);
+            }
+            emit.digest()
+        };
+
+        let first = digest(&[
+            "info multipv 1 depth 10 score cp 20 pv e2e4 e7e5",
+            "info multipv 2 depth 10 score cp 20 pv d2d4 d7d5",
+        ]);
+        assert_eq!(first.len(), 16);
+        assert_eq!(
+            first,
+            digest(&[
+                "info multipv 1 depth 10 score cp 20 pv e2e4 e7e5",
+                "info multipv 2 depth 10 score cp 20 pv d2d4 d7d5",
+            ])
+        );
+        // Tied lines in a different order, and at a different depth.
+        assert_eq!(
+            first,
+            digest(&[
+                "info multipv 1 depth 12 score cp 20 pv d2d4 d7d5",
+                "info multipv 2 depth 12 score cp 20 pv e2e4 e7e5",
+            ])
+        );
+        assert_ne!(
+            first,
+            digest(&[
+                "info multipv 1 depth 10 score cp 21 pv e2e4 e7e5",
+                "info multipv 2 depth 10 score cp 20 pv d2d4 d7d5",
+            ])
+        );
+        assert_ne!(
+            first,
+            digest(&[
+                "info multipv 1 depth 10 score cp 20 pv e2e4 e7e6",
+                "info multipv 2 depth 10 score cp 20 pv d2d4 d7d5",
+            ])
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_throttle() {
+        let mut unlimited = Throttle::new(None);
+        assert!(unlimited.admit());
+        assert!(unlimited.admit());
+        assert_eq!(unlimited.due(), None);
+
+        let start = Instant::now();
+        let mut throttle = Throttle::new(Some(Duration::from_millis(500)));
+        assert!(throttle.admit());
+        assert!(!throttle.admit());
+        assert!(!throttle.admit());
+        assert_eq!(throttle.due(), Some(start + Duration::from_millis(500)));
+
+        sleep_until(throttle.due().unwrap()).await;
+        assert!(throttle.take_pending());
+        assert!(!throttle.take_pending());
+        assert_eq!(throttle.due(), None);
+        assert!(!throttle.admit());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_throttle_max_frames() {
+        let mut limited = Throttle::new(None).with_max_frames(Some(2));
+        assert!(limited.admit());
+        assert!(limited.admit());
+        assert!(!limited.admit());
+        assert_eq!(limited.due(), None);
+
+        // A pending frame uses up the budget when it is finally sent.
+        let mut throttle = Throttle::new(Some(Duration::from_millis(500))).with_max_frames(Some(2));
+        assert!(throttle.admit());
+        assert!(!throttle.admit());
+        sleep_until(throttle.due().unwrap()).await;
+        assert!(throttle.take_pending());
+        sleep(Duration::from_secs(1)).await;
+        assert!(!throttle.admit());
+        assert_eq!(throttle.due(), None);
+        assert!(!throttle.take_pending());
+    }
+
+    /// Reads a little-endian integer of `N` bytes.
+    fn take<const N: usize>(buf: &mut &[u8]) -> [u8; N] {
+        let (head, tail) = buf.split_at(N);
+        *buf = tail;
+        head.try_into().unwrap()
+    }
+
+    #[test]
+    fn test_position_info() {
+        let info =
+            |fen| serde_json::to_value(PositionInfo::new(&work(json!({})), &pos(fen))).unwrap();
+
+        let start = info("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1");
+        assert_eq!(start["material"], json!([39, 39]));
+        assert_eq!(start["phase"], "opening");
+        assert_eq!(start["tablebase"], false);
+
+        let middlegame = info("r1bq1rk1/pp2bppp/2n1pn2/3p4/3P4/2NBPN2/PP3PPP/R2QK2R w KQ - 0 15");
+        assert_eq!(middlegame["phase"], "middlegame");
+        assert_eq!(middlegame["tablebase"], false);
+
+        let endgame = info("8/8/4k3/8/2R5/4K3/4P3/6r1 w - - 0 60");
+        assert_eq!(endgame["material"], json!([6, 5]));
+        assert_eq!(endgame["phase"], "endgame");
+        assert_eq!(endgame["tablebase"], true);
+    }
+
+    #[test]
+    fn test_game_over() {
+        let game_over = |extra: Value| {
+            let (work, pos) = work(extra)
+                .sanitize(&engine(), &WorkOpt::default())
+                .unwrap();
+            Frame::game_over(&pos, &work).map(|frame| serde_json::to_value(frame).unwrap())
+        };
+
+        assert_eq!(game_over(json!({})), None);
+        assert_eq!(
+            game_over(json!({ "moves": ["f2f3", "e7e5", "g2g4", "d8h4"], "clientRef": "a" })),
+            Some(json!({
+                "done": true,
+                "outcome": "0-1",
+                "termination": "checkmate",
+                "clientRef": "a",
+            }))
+        );
+        assert_eq!(
+            game_over(json!({ "initialFen": "7k/5Q2/6K1/8/8/8/8/8 b - - 0 1" })),
+            Some(json!({
+                "done": true,
+                "outcome": "1/2-1/2",
+                "termination": "stalemate",
+            }))
+        );
+    }
+
+    #[test]
+    fn test_position_info_legal_moves() {
+        let info = |extra: Value| {
+            let (work, pos) = work(extra)
+                .sanitize(&engine(), &WorkOpt::default())
+                .unwrap();
+            serde_json::to_value(PositionInfo::new(&work, &pos)).unwrap()
+        };
+
+        assert!(info(json!({})).get("legalMoves").is_none());
+        let start = info(json!({ "legalMoves": true }));
+        assert_eq!(start["legalMoves"].as_array().unwrap().len(), 20);
+
+        let moves = json!(["e2e4", "e7e5", "g1f3", "b8c6", "f1c4", "g8f6"]);
+        let (work, pos) = work(json!({ "moves": moves, "legalMoves": true }))
+            .sanitize(&engine(), &WorkOpt::default())
+            .unwrap();
+        let legal = serde_json::to_value(PositionInfo::new(&work, &pos)).unwrap()["legalMoves"]
+            .as_array()
+            .unwrap()
+            .len();
+        assert_eq!(legal, pos.legal_moves().len());
+        assert!(
+            info(json!({ "moves": moves, "legalMoves": true, "castling": "standard" }))
+                ["legalMoves"]
+                .as_array()
+                .unwrap()
+                .contains(&json!("e1g1"))
+        );
+    }
+
+    #[test]
+    fn test_position_info_repetition() {
+        let info = |moves: &[&str]| {
+            let (work, pos) = work(json!({ "moves": moves }))
+                .sanitize(&engine(), &WorkOpt::default())
+                .unwrap();
+            serde_json::to_value(PositionInfo::new(&work, &pos)).unwrap()
+        };
+        let shuffle = ["g1f3", "g8f6", "f3g1", "f6g8"];
+
+        let twofold = info(&shuffle);
+        assert_eq!(twofold["repetition"], false);
+        assert_eq!(twofold["halfmoveClock"], 4);
+
+        let threefold = info(&[shuffle, shuffle].concat());
+        assert_eq!(threefold["repetition"], true);
+        assert_eq!(threefold["halfmoveClock"], 8);
+
+        // Pawn moves are irreversible.
+        let reset = info(&[&shuffle[..], &["e2e4", "e7e5"], &shuffle[..]].concat());
+        assert_eq!(reset["repetition"], false);
+        assert_eq!(reset["halfmoveClock"], 4);
+    }
+
+    #[test]
+    fn test_emit_binary() {
+        let mut emit = Emit::new(
+            &work(json!({ "multiPv": 2 })),
+            WorkOpt::default().max_pv_len,
+        );
+        let pos = pos("8/P7/8/8/8/8/8/k6K w - - 0 1");
+        for line in [
+            "info depth 12 time 250 nodes 123456 multipv 1 score mate 3 pv a7a8q a1b2",
+            "info depth 11 multipv 2 score cp -34 upperbound pv h1g2",
+        ] {
+            emit.update(&UciOut::from_line(line).unwrap().unwrap(), &pos);
+        }
+        let encoded = Frame::from(emit).to_binary();
+
+        let mut buf = &encoded[..];
+        assert_eq!(take::<1>(&mut buf), [BINARY_EMIT]);
+        assert_eq!(u32::from_le_bytes(take(&mut buf)) as usize, buf.len());
+        assert_eq!(u32::from_le_bytes(take(&mut buf)), 250);
+        assert_eq!(u16::from_le_bytes(take(&mut buf)), 11);
+        assert_eq!(u64::from_le_bytes(take(&mut buf)), 123456);
+        assert_eq!(take::<1>(&mut buf), [2]);
+
+        assert_eq!(take::<1>(&mut buf), [BINARY_PRESENT | BINARY_MATE]);
+        assert_eq!(i32::from_le_bytes(take(&mut buf)), 3);
+        assert_eq!(u16::from_le_bytes(take(&mut buf)), 12);
+        assert_eq!(take::<1>(&mut buf), [2]);
+        let a7a8q = u16::from_le_bytes(take(&mut buf));
+        assert_eq!(a7a8q & 0x3f, Square::A7 as u16);
+        assert_eq!(a7a8q >> 6 & 0x3f, Square::A8 as u16);
+        assert_eq!(a7a8q >> 12, Role::Queen as u16);
+        let a1b2 = u16::from_le_bytes(take(&mut buf));
+        assert_eq!(a1b2, Square::A1 as u16 | (Square::B2 as u16) << 6);
+
+        assert_eq!(take::<1>(&mut buf), [BINARY_PRESENT | BINARY_UPPERBOUND]);
+        assert_eq!(i32::from_le_bytes(take(&mut buf)), -34);
+        assert_eq!(u16::from_le_bytes(take(&mut buf)), 11);
+        assert_eq!(take::<1>(&mut buf), [1]);
+        take::<2>(&mut buf);
+        assert!(buf.is_empty());
+
+        // Other frames are sent as JSON.
+        let encoded = Frame::timeout(&work(json!({}))).to_binary();
+        assert_eq!(encoded[0], BINARY_JSON);
+        assert_eq!(
+            serde_json::from_slice::<Value>(&encoded[5..]).unwrap(),
+            json!({ "timeout": true })
+        );
+    }
+}
@@ -0,0 +1,461 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt,
+    sync::{Arc, Mutex, Weak},
+};
+
+use tokio::{pin, sync::Notify};
+
+/// Creates a channel that delivers every value to every receiver, like a
+/// broadcast channel. Values are kept until all receivers have seen them.
+/// Once more than `capacity` values are queued because a receiver falls
+/// behind, adjacent values are merged with `coalesce`, oldest first, so that
+/// the receiver skips only values that were merged into later ones. If
+/// nothing can be merged, the receivers that are furthest behind are
+/// dropped.
+pub fn channel<T: Clone>(capacity: usize, coalesce: Coalesce<T>) -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            values: VecDeque::new(),
+            end: 0,
+            cursors: BTreeMap::from([(0, 1)]),
+            evicted: 0,
+            receivers: 1,
+            senders: 1,
+        }),
+        changed: Notify::new(),
+        capacity,
+        coalesce,
+    });
+    (
+        Sender {
+            shared: Arc::clone(&shared),
+        },
+        Receiver { shared, next: 0 },
+    )
+}
+
+/// Merges a value into the queued value before it, or gives it back if
+/// both have to be delivered on their own.
+pub type Coalesce<T> = fn(&mut T, T) -> Option<T>;
+
+struct Shared<T> {
+    state: Mutex<State<T>>,
+    /// Wakes receivers when a value is sent or the last sender is gone, and
+    /// senders when a receiver is gone.
+    changed: Notify,
+    capacity: usize,
+    coalesce: Coalesce<T>,
+}
+
+struct State<T> {
+    /// Values that not every receiver has seen yet, by ascending sequence
+    /// number. Values that were merged into later ones leave gaps.
+    values: VecDeque<(u64, T)>,
+    /// Sequence number of the next value.
+    end: u64,
+    /// Number of receivers for each next sequence number.
+    cursors: BTreeMap<u64, usize>,
+    /// Receivers with a lower next sequence number were dropped for falling
+    /// behind.
+    evicted: u64,
+    receivers: usize,
+    senders: usize,
+}
+
+impl<T> State<T> {
+    fn add_cursor(&mut self, next: u64) {
+        *self.cursors.entry(next).or_default() += 1;
+    }
+
+    fn remove_cursor(&mut self, next: u64) {
+        if let Some(count) = self.cursors.get_mut(&next) {
+            *count -= 1;
+            if *count == 0 {
+                self.cursors.remove(&next);
+            }
+        }
+        self.trim();
+    }
+
+    /// Forgets values that every receiver has seen.
+    fn trim(&mut self) {
+        let min = self.cursors.keys().next().copied().unwrap_or(self.end);
+        while self.values.front().is_some_and(|&(seq, _)| seq < min) {
+            self.values.pop_front();
+        }
+    }
+
+    fn push(&mut self, value: T, capacity: usize, coalesce: Coalesce<T>) {
+        self.values.push_back((self.end, value));
+        self.end += 1;
+        while self.values.len() > capacity {
+            if !self.merge_oldest(coalesce) {
+                self.evict_slowest();
+            }
+        }
+    }
+
+    /// Merges the oldest pair of adjacent values that can be merged. The
+    /// result takes the place of the later value.
+    fn merge_oldest(&mut self, coalesce: Coalesce<T>) -> bool {
+        for i in 1..self.values.len() {
+            let (seq, value) = self.values.remove(i).expect("value in queue");
+            match coalesce(&mut self.values[i - 1].1, value) {
+                None => {
+                    self.values[i - 1].0 = seq;
+                    return true;
+                }
+                Some(value) => self.values.insert(i, (seq, value)),
+            }
+        }
+        false
+    }
+
+    fn evict_slowest(&mut self) {
+        if let Some((next, count)) = self.cursors.pop_first() {
+            self.receivers -= count;
+            self.evicted = next + 1;
+        }
+        self.trim();
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum TryRecvError {
+    Empty,
+    Closed,
+}
+
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T: Clone> Sender<T> {
+    /// Queues the value for all current receivers. Fails if there are none.
+    pub fn send(&self, value: T) -> Result<(), SendError<T>> {
+        let mut state = self.shared.state.lock().unwrap();
+        if state.receivers == 0 {
+            return Err(SendError(value));
+        }
+        state.push(value, self.shared.capacity, self.shared.coalesce);
+        drop(state);
+        self.shared.changed.notify_waiters();
+        Ok(())
+    }
+
+    /// Like `send`, but merges the value into the last queued value, as
+    /// long as no receiver has seen that yet.
+    pub fn send_merged(&self, value: T) -> Result<(), SendError<T>> {
+        let mut state = self.shared.state.lock().unwrap();
+        if state.receivers == 0 {
+            return Err(SendError(value));
+        }
+        let unseen = !state.cursors.contains_key(&state.end);
+        let value = match state.values.back_mut() {
+            Some((_, last)) if unseen => (self.shared.coalesce)(last, value),
+            _ => Some(value),
+        };
+        if let Some(value) = value {
+            state.push(value, self.shared.capacity, self.shared.coalesce);
+        }
+        drop(state);
+        self.shared.changed.notify_waiters();
+        Ok(())
+    }
+
+    /// A new receiver for values sent from now on.
+    pub fn subscribe(&self) -> Receiver<T> {
+        let mut state = self.shared.state.lock().unwrap();
+        let next = state.end;
+        state.add_cursor(next);
+        state.receivers += 1;
+        Receiver {
+            shared: Arc::clone(&self.shared),
+            next,
+        }
+    }
+
+    pub fn receiver_count(&self) -> usize {
+        self.shared.state.lock().unwrap().receivers
+    }
+
+    /// Completes once there are no receivers.
+    pub async fn closed(&self) {
+        loop {
+            let notified = self.shared.changed.notified();
+            pin!(notified);
+            notified.as_mut().enable();
+            if self.receiver_count() == 0 {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    /// A handle that does not keep the channel open.
+    pub fn downgrade(&self) -> WeakSender<T> {
+        WeakSender {
+            shared: Arc::downgrade(&self.shared),
+        }
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Sender<T> {
+        self.shared.state.lock().unwrap().senders += 1;
+        Sender {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.senders -= 1;
+        if state.senders == 0 {
+            drop(state);
+            self.shared.changed.notify_waiters();
+        }
+    }
+}
+
+pub struct WeakSender<T> {
+    shared: Weak<Shared<T>>,
+}
+
+impl<T> WeakSender<T> {
+    /// The sender, unless all senders are gone.
+    pub fn upgrade(&self) -> Option<Sender<T>> {
+        let shared = self.shared.upgrade()?;
+        let mut state = shared.state.lock().unwrap();
+        if state.senders == 0 {
+            return None;
+        }
+        state.senders += 1;
+        drop(state);
+        Some(Sender { shared })
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.shared
+            .upgrade()
+            .is_none_or(|shared| shared.state.lock().unwrap().senders == 0)
+    }
+}
+
+impl<T> Clone for WeakSender<T> {
+    fn clone(&self) -> WeakSender<T> {
+        WeakSender {
+            shared: Weak::clone(&self.shared),
+        }
+    }
+}
+
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+    next: u64,
+}
+
+impl<T: Clone> Receiver<T> {
+    /// Fails with `Closed` also if the receiver was dropped for falling
+    /// behind.
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        let mut state = self.shared.state.lock().unwrap();
+        if self.next < state.evicted {
+            return Err(TryRecvError::Closed);
+        }
+        let index = state.values.partition_point(|&(seq, _)| seq < self.next);
+        if let Some((seq, value)) = state.values.get(index).cloned() {
+            state.add_cursor(seq + 1);
+            state.remove_cursor(self.next);
+            self.next = seq + 1;
+            Ok(value)
+        } else if state.senders == 0 {
+            Err(TryRecvError::Closed)
+        } else {
+            Err(TryRecvError::Empty)
+        }
+    }
+
+    /// Waits for the next value. Returns `None` once all senders are gone
+    /// and every value was received.
+    pub async fn recv(&mut self) -> Option<T> {
+        let shared = Arc::clone(&self.shared);
+        loop {
+            let notified = shared.changed.notified();
+            pin!(notified);
+            notified.as_mut().enable();
+            match self.try_recv() {
+                Ok(value) => return Some(value),
+                Err(TryRecvError::Closed) => return None,
+                Err(TryRecvError::Empty) => notified.await,
+            }
+        }
+    }
+
+    /// A new receiver for values sent from now on.
+    #[cfg(test)]
+    pub fn resubscribe(&self) -> Receiver<T> {
+        let mut state = self.shared.state.lock().unwrap();
+        let next = state.end;
+        state.add_cursor(next);
+        state.receivers += 1;
+        Receiver {
+            shared: Arc::clone(&self.shared),
+            next,
+        }
+    }
+}
+
+impl<T> fmt::Debug for Receiver<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Receiver")
+            .field("next", &self.next)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        if self.next >= state.evicted {
+            state.receivers -= 1;
+            state.remove_cursor(self.next);
+        }
+        drop(state);
+        self.shared.changed.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sum(last: &mut u32, value: u32) -> Option<u32> {
+        *last += value;
+        None
+    }
+
+    fn separate(_: &mut u32, value: u32) -> Option<u32> {
+        Some(value)
+    }
+
+    #[tokio::test]
+    async fn test_lossless() {
+        let (tx, mut slow) = channel(1000, separate);
+        let mut fast = tx.subscribe();
+        for i in 0..1000 {
+            tx.send(i).unwrap();
+            assert_eq!(fast.recv().await, Some(i));
+        }
+        drop(tx);
+        for i in 0..1000 {
+            assert_eq!(slow.recv().await, Some(i));
+        }
+        assert_eq!(slow.recv().await, None);
+        assert_eq!(fast.try_recv(), Err(TryRecvError::Closed));
+    }
+
+    #[tokio::test]
+    async fn test_trimmed_once_seen() {
+        let (tx, mut first) = channel(16, separate);
+        let mut second = first.resubscribe();
+        tx.send(1).unwrap();
+        tx.send(2).unwrap();
+        assert_eq!(first.recv().await, Some(1));
+        assert_eq!(tx.shared.state.lock().unwrap().values.len(), 2);
+        assert_eq!(second.recv().await, Some(1));
+        assert_eq!(tx.shared.state.lock().unwrap().values.len(), 1);
+        drop(first);
+        drop(second);
+        assert!(tx.shared.state.lock().unwrap().values.is_empty());
+        assert_eq!(tx.send(3), Err(SendError(3)));
+    }
+
+    #[tokio::test]
+    async fn test_send_merged() {
+        let (tx, mut first) = channel(16, sum);
+        tx.send_merged(1).unwrap();
+        tx.send_merged(2).unwrap();
+        let mut second = first.resubscribe();
+        assert_eq!(first.recv().await, Some(3));
+        // Seen by the first receiver, and not for the second.
+        tx.send_merged(4).unwrap();
+        tx.send_merged(5).unwrap();
+        tx.send(6).unwrap();
+        drop(tx);
+        assert_eq!(first.recv().await, Some(9));
+        assert_eq!(first.recv().await, Some(6));
+        assert_eq!(first.recv().await, None);
+        assert_eq!(second.recv().await, Some(9));
+        assert_eq!(second.recv().await, Some(6));
+        assert_eq!(second.recv().await, None);
+    }
+
+    #[tokio::test]
+    async fn test_stalled_receiver_coalesced() {
+        let (tx, mut stalled) = channel(4, sum);
+        let mut fast = tx.subscribe();
+        for i in 1..=100 {
+            tx.send(i).unwrap();
+            assert_eq!(fast.recv().await, Some(i));
+            assert!(tx.shared.state.lock().unwrap().values.len() <= 4);
+        }
+        drop(tx);
+
+        // Nothing is lost, but the oldest values arrive merged.
+        let mut received = Vec::new();
+        while let Some(value) = stalled.recv().await {
+            received.push(value);
+        }
+        assert_eq!(received, [(1..=97).sum(), 98, 99, 100]);
+    }
+
+    #[tokio::test]
+    async fn test_stalled_receiver_dropped() {
+        let (tx, mut stalled) = channel(4, separate);
+        let mut fast = tx.subscribe();
+        for i in 0..4 {
+            tx.send(i).unwrap();
+            assert_eq!(fast.recv().await, Some(i));
+        }
+        assert_eq!(tx.receiver_count(), 2);
+
+        // Nothing can be merged, so the receiver that is furthest behind
+        // is dropped.
+        tx.send(4).unwrap();
+        assert_eq!(tx.receiver_count(), 1);
+        assert!(tx.shared.state.lock().unwrap().values.len() <= 1);
+        assert_eq!(stalled.try_recv(), Err(TryRecvError::Closed));
+        drop(stalled);
+        assert_eq!(tx.receiver_count(), 1);
+        assert_eq!(fast.recv().await, Some(4));
+        drop(fast);
+        tx.closed().await;
+    }
+
+    #[tokio::test]
+    async fn test_closed() {
+        let (tx, rx) = channel::<u32>(16, separate);
+        let weak = tx.downgrade();
+        let late = weak.upgrade().unwrap().subscribe();
+        drop(rx);
+        assert_eq!(tx.receiver_count(), 1);
+        {
+            let closed = tx.closed();
+            pin!(closed);
+            assert!(futures::poll!(closed.as_mut()).is_pending());
+            drop(late);
+            closed.await;
+        }
+
+        drop(tx);
+        assert!(weak.is_closed());
+        assert!(weak.upgrade().is_none());
+    }
+}
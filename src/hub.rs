@@ -6,43 +6,219 @@ use std::{
     time::Duration,
 };
 
-use tokio::{sync::Notify, time::sleep};
+use tokio::{
+    pin, select,
+    sync::Notify,
+    time::{sleep, sleep_until, Instant},
+};
+use tokio_util::sync::CancellationToken;
 
 const NUM_SHARDS: usize = 64;
 
 const MAX_ITEMS: usize = 1024;
 
+/// Providers that were not seen for this long are considered offline.
+const OFFLINE_AFTER: Duration = Duration::from_secs(30);
+
+/// Number of recent jobs considered for the completion rate.
+const RECENT_OUTCOMES: usize = 100;
+
+/// Number of recent jobs needed before a selector can be paused for its
+/// completion rate.
+const MIN_OUTCOMES: usize = 10;
+
 pub trait IsValid {
     fn is_valid(&self) -> bool;
 }
 
+#[derive(Debug)]
+pub struct QueueFull;
+
+/// Keeps a selector online while a provider works on a job, even if it sends
+/// no heartbeats in the meantime.
+pub struct Busy {
+    _token: Arc<()>,
+}
+
+/// Snapshot of the queue of a single selector.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueueHealth {
+    /// Whether a provider is currently waiting for work.
+    pub connected: bool,
+    pub last_seen: Option<Instant>,
+    pub queued: usize,
+    /// Share of recent jobs that the provider completed, if any.
+    pub completion_rate: Option<f64>,
+    /// Whether providers are not handed work for completing too few jobs.
+    pub paused: bool,
+}
+
+/// Why a provider cannot acquire an item right now.
+enum Pending {
+    /// No matching item, until the signal of the queue.
+    Empty(Arc<Notify>),
+    /// Cooling down or paused until then. Holding the signal keeps the
+    /// waiting provider online meanwhile.
+    NotReady(Instant, Arc<Notify>),
+}
+
 pub struct Hub<S, R> {
     random_state: RandomState,
     shards: [Mutex<Shard<S, R>>; NUM_SHARDS],
+    cooldown: Duration,
+    quarantine: Option<Quarantine>,
+    shutdown: CancellationToken,
+}
+
+/// Pauses selectors whose providers complete too few of the jobs they
+/// acquire.
+#[derive(Debug, Copy, Clone)]
+struct Quarantine {
+    min_rate: f64,
+    pause: Duration,
 }
 
 impl<S: Hash + Eq, R: IsValid> Default for Hub<S, R> {
     fn default() -> Hub<S, R> {
+        Hub::with_cooldown(Duration::ZERO)
+    }
+}
+
+impl<S: Hash + Eq, R: IsValid> Hub<S, R> {
+    /// After acquiring an item, a provider for the same selector has to wait
+    /// for `cooldown` before it can acquire the next one.
+    pub fn with_cooldown(cooldown: Duration) -> Hub<S, R> {
         Hub {
             random_state: RandomState::new(),
             shards: array::from_fn(|_| Mutex::new(Shard::new())),
+            cooldown,
+            quarantine: None,
+            shutdown: CancellationToken::new(),
         }
     }
+
+    /// Once providers for a selector complete less than `min_rate` of their
+    /// recent jobs, they are not handed work for `pause`. Outcomes are not
+    /// told apart per provider, so this pauses every provider of the
+    /// selector. Waiting providers run into their acquire timeout and
+    /// reconnect, and queued jobs wait or time out.
+    pub fn with_min_completion_rate(mut self, min_rate: f64, pause: Duration) -> Hub<S, R> {
+        self.quarantine = Some(Quarantine { min_rate, pause });
+        self
+    }
 }
 
 impl<S: Hash + Eq + Clone, R: IsValid> Hub<S, R> {
-    pub fn submit(&self, selector: S, data: R) {
+    pub fn submit(&self, selector: S, data: R) -> Result<(), QueueFull> {
+        let shard = self.shard(&selector);
+        shard.lock().unwrap().submit(selector, data)
+    }
+
+    /// Records that a provider for `selector` is alive, even if idle.
+    pub fn heartbeat(&self, selector: S) {
+        let shard = self.shard(&selector);
+        shard.lock().unwrap().heartbeat(selector);
+    }
+
+    /// Marks a provider for `selector` as busy until the returned guard is
+    /// dropped.
+    pub fn busy(&self, selector: S) -> Busy {
         let shard = self.shard(&selector);
-        shard.lock().unwrap().submit(selector, data);
+        let mut shard = shard.lock().unwrap();
+        Busy {
+            _token: Arc::clone(&shard.map.entry(selector).or_default().busy),
+        }
+    }
+
+    /// Whether a provider for `selector` is waiting, busy, or was seen
+    /// recently.
+    pub fn is_online(&self, selector: &S) -> bool {
+        let shard = self.shard(selector);
+        let shard = shard.lock().unwrap();
+        shard.map.get(selector).is_some_and(Queue::is_online)
+    }
+
+    /// Whether providers for `selector` are paused for completing too few
+    /// jobs.
+    pub fn is_paused(&self, selector: &S) -> bool {
+        let shard = self.shard(selector);
+        let shard = shard.lock().unwrap();
+        shard.map.get(selector).is_some_and(Queue::is_paused)
     }
 
-    pub async fn acquire(&self, selector: S) -> R {
+    /// Records whether a provider for `selector` completed a job it acquired.
+    pub fn record_outcome(&self, selector: S, completed: bool) {
         let shard = self.shard(&selector);
+        let mut shard = shard.lock().unwrap();
+        let queue = shard.map.entry(selector).or_default();
+        if queue.outcomes.len() >= RECENT_OUTCOMES {
+            queue.outcomes.pop_front();
+        }
+        queue.outcomes.push_back(completed);
+        if let Some(quarantine) = self.quarantine {
+            if queue.outcomes.len() >= MIN_OUTCOMES
+                && queue
+                    .completion_rate()
+                    .is_some_and(|rate| rate < quarantine.min_rate)
+            {
+                queue.paused_until = Some(Instant::now() + quarantine.pause);
+                // Start over once the pause is served.
+                queue.outcomes.clear();
+            }
+        }
+    }
+
+    /// Waits for the oldest item for `selector` that matches `filter`.
+    /// Returns `None` once the hub is shut down.
+    ///
+    /// All items for a selector share a single queue, so a provider that
+    /// accepts several variants receives work in order of submission across
+    /// all of them, rather than draining one variant first.
+    pub async fn acquire<F>(&self, selector: S, filter: F) -> Option<R>
+    where
+        F: Fn(&R) -> bool,
+    {
+        select! {
+            biased;
+            _ = self.shutdown.cancelled() => None,
+            item = self.wait_for(selector, filter) => Some(item),
+        }
+    }
+
+    async fn wait_for<F>(&self, selector: S, filter: F) -> R
+    where
+        F: Fn(&R) -> bool,
+    {
+        let shard = self.shard(&selector);
+        // Readiness is checked before every attempt, because other providers
+        // for the selector may acquire items, and pauses may be recorded,
+        // while this one waits.
         loop {
-            let res = shard.lock().unwrap().acquire(selector.clone());
+            let res = shard
+                .lock()
+                .unwrap()
+                .acquire(selector.clone(), &filter, self.cooldown);
+            let signal = match res {
+                Ok(item) => return item,
+                Err(Pending::NotReady(ready_at, _signal)) => {
+                    sleep_until(ready_at).await;
+                    continue;
+                }
+                Err(Pending::Empty(signal)) => signal,
+            };
+            // Register before checking again, so that no submission between
+            // the two checks can be missed.
+            let notified = signal.notified();
+            pin!(notified);
+            notified.as_mut().enable();
+            let res = shard
+                .lock()
+                .unwrap()
+                .acquire(selector.clone(), &filter, self.cooldown);
             match res {
                 Ok(item) => return item,
-                Err(signal) => signal.notified().await,
+                Err(Pending::NotReady(..)) => continue,
+                Err(Pending::Empty(_)) => notified.await,
             }
         }
     }
@@ -52,6 +228,32 @@ impl<S: Hash + Eq + Clone, R: IsValid> Hub<S, R> {
     }
 }
 
+impl<S, R> Hub<S, R> {
+    /// Releases all providers that are waiting for work, and any that try
+    /// to acquire work from now on.
+    pub fn shutdown(&self) {
+        self.shutdown.cancel();
+    }
+}
+
+impl<S: Clone, R: IsValid> Hub<S, R> {
+    /// Snapshot of all known selectors, in no particular order.
+    pub fn health(&self) -> Vec<(S, QueueHealth)> {
+        self.shards
+            .iter()
+            .flat_map(|shard| {
+                shard
+                    .lock()
+                    .unwrap()
+                    .map
+                    .iter()
+                    .map(|(selector, queue)| (selector.clone(), queue.health()))
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+}
+
 impl<S, R: IsValid> Hub<S, R> {
     pub async fn garbage_collect(&self) {
         loop {
@@ -74,45 +276,436 @@ impl<S: Eq + Hash, R: IsValid> Shard<S, R> {
         }
     }
 
-    fn submit(&mut self, selector: S, data: R) {
+    fn submit(&mut self, selector: S, data: R) -> Result<(), QueueFull> {
         let entry = self.map.entry(selector).or_default();
         if entry.inner.len() < MAX_ITEMS {
             entry.inner.push_back(data);
-            entry.signal.notify_one();
+            // Not every waiter may accept this item, so wake all of them.
+            entry.signal.notify_waiters();
+            Ok(())
+        } else {
+            Err(QueueFull)
         }
     }
 
-    fn acquire(&mut self, selector: S) -> Result<R, Arc<Notify>> {
+    fn acquire<F>(&mut self, selector: S, filter: F, cooldown: Duration) -> Result<R, Pending>
+    where
+        F: Fn(&R) -> bool,
+    {
+        let now = Instant::now();
         let entry = self.map.entry(selector).or_default();
-        loop {
-            match entry.inner.pop_front() {
-                Some(item) if item.is_valid() => return Ok(item),
-                Some(_) => continue,
-                None => return Err(Arc::clone(&entry.signal)),
+        entry.last_seen = Some(now);
+        if let Some(ready_at) = entry.ready_at(cooldown) {
+            return Err(Pending::NotReady(ready_at, Arc::clone(&entry.signal)));
+        }
+        entry.inner.retain(|item| item.is_valid());
+        match entry.inner.iter().position(filter) {
+            Some(index) => {
+                entry.last_acquired = Some(now);
+                Ok(entry.inner.remove(index).expect("item"))
             }
+            None => Err(Pending::Empty(Arc::clone(&entry.signal))),
         }
     }
 }
 
+impl<S: Eq + Hash, R> Shard<S, R> {
+    fn heartbeat(&mut self, selector: S) {
+        self.map.entry(selector).or_default().last_seen = Some(Instant::now());
+    }
+}
+
 impl<S, R: IsValid> Shard<S, R> {
     fn garbage_collect(&mut self) {
         self.map.retain(|_, queue| {
             queue.inner.retain(|item| item.is_valid());
-            !queue.inner.is_empty()
+            // Keep queues with waiters, or they would never be notified,
+            // and paused queues, or the pause would end early.
+            !queue.inner.is_empty() || queue.is_online() || queue.is_paused()
         });
     }
 }
 
 struct Queue<R> {
     signal: Arc<Notify>,
+    /// Held by every job that a provider is working on.
+    busy: Arc<()>,
     inner: VecDeque<R>,
+    last_seen: Option<Instant>,
+    last_acquired: Option<Instant>,
+    outcomes: VecDeque<bool>,
+    /// Not handed work until then, for completing too few jobs.
+    paused_until: Option<Instant>,
+}
+
+impl<R> Queue<R> {
+    fn is_online(&self) -> bool {
+        Arc::strong_count(&self.signal) > 1
+            || Arc::strong_count(&self.busy) > 1
+            || self
+                .last_seen
+                .is_some_and(|last_seen| last_seen.elapsed() < OFFLINE_AFTER)
+    }
+
+    fn is_paused(&self) -> bool {
+        self.paused_until
+            .is_some_and(|paused_until| paused_until > Instant::now())
+    }
+
+    fn ready_at(&self, cooldown: Duration) -> Option<Instant> {
+        self.last_acquired
+            .map(|last_acquired| last_acquired + cooldown)
+            .max(self.paused_until)
+            .filter(|ready_at| *ready_at > Instant::now())
+    }
+
+    fn completion_rate(&self) -> Option<f64> {
+        (!self.outcomes.is_empty()).then(|| {
+            self.outcomes.iter().filter(|completed| **completed).count() as f64
+                / self.outcomes.len() as f64
+        })
+    }
+}
+
+impl<R: IsValid> Queue<R> {
+    fn health(&self) -> QueueHealth {
+        QueueHealth {
+            connected: Arc::strong_count(&self.signal) > 1,
+            last_seen: self.last_seen,
+            queued: self.inner.iter().filter(|item| item.is_valid()).count(),
+            completion_rate: self.completion_rate(),
+            paused: self.is_paused(),
+        }
+    }
 }
 
 impl<R> Default for Queue<R> {
     fn default() -> Queue<R> {
         Queue {
             signal: Arc::new(Notify::new()),
+            busy: Arc::new(()),
             inner: VecDeque::new(),
+            last_seen: None,
+            last_acquired: None,
+            outcomes: VecDeque::new(),
+            paused_until: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use shakmaty::variant::Variant;
+    use tokio::time::timeout;
+
+    use super::*;
+
+    impl IsValid for Variant {
+        fn is_valid(&self) -> bool {
+            true
+        }
+    }
+
+    #[tokio::test]
+    async fn test_acquire_filtered() {
+        let hub = Hub::<&str, Variant>::default();
+        hub.submit("provider", Variant::Crazyhouse).unwrap();
+
+        let standard_only = |v: &Variant| *v == Variant::Chess;
+        assert!(timeout(
+            Duration::from_millis(50),
+            hub.acquire("provider", standard_only)
+        )
+        .await
+        .is_err());
+
+        let (standard, ()) = tokio::join!(hub.acquire("provider", standard_only), async {
+            hub.submit("provider", Variant::Chess).unwrap();
+        });
+        assert_eq!(standard, Some(Variant::Chess));
+
+        let crazyhouse = hub
+            .acquire("provider", |v: &Variant| *v == Variant::Crazyhouse)
+            .await
+            .unwrap();
+        assert_eq!(crazyhouse, Variant::Crazyhouse);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_heartbeat() {
+        let hub = Hub::<&str, Variant>::default();
+        assert!(!hub.is_online(&"provider"));
+
+        hub.heartbeat("provider");
+        assert!(hub.is_online(&"provider"));
+
+        sleep(OFFLINE_AFTER).await;
+        assert!(!hub.is_online(&"provider"));
+
+        let waiting = hub.acquire("provider", |_| true);
+        pin!(waiting);
+        assert!(timeout(Duration::from_millis(1), waiting.as_mut())
+            .await
+            .is_err());
+        sleep(OFFLINE_AFTER).await;
+        assert!(hub.is_online(&"provider"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_busy() {
+        let hub = Hub::<&str, Variant>::default();
+        hub.submit("provider", Variant::Chess).unwrap();
+        hub.acquire("provider", |_| true).await.unwrap();
+        let busy = hub.busy("provider");
+
+        // Still online while working on a long job without heartbeats.
+        sleep(OFFLINE_AFTER * 2).await;
+        for shard in &hub.shards {
+            shard.lock().unwrap().garbage_collect();
+        }
+        assert!(hub.is_online(&"provider"));
+
+        drop(busy);
+        assert!(!hub.is_online(&"provider"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_health() {
+        let hub = Hub::<&str, Variant>::default();
+        hub.submit("provider", Variant::Chess).unwrap();
+        hub.submit("provider", Variant::Atomic).unwrap();
+        hub.acquire("provider", |_| true).await.unwrap();
+        hub.record_outcome("provider", true);
+        hub.record_outcome("provider", false);
+
+        let waiting = hub.acquire("other", |_| true);
+        pin!(waiting);
+        assert!(timeout(Duration::from_millis(1), waiting.as_mut())
+            .await
+            .is_err());
+
+        let mut health = hub.health();
+        health.sort_by_key(|(selector, _)| *selector);
+        assert_eq!(
+            health,
+            [
+                (
+                    "other",
+                    QueueHealth {
+                        connected: true,
+                        last_seen: health[0].1.last_seen,
+                        queued: 0,
+                        completion_rate: None,
+                        paused: false,
+                    }
+                ),
+                (
+                    "provider",
+                    QueueHealth {
+                        connected: false,
+                        last_seen: health[1].1.last_seen,
+                        queued: 1,
+                        completion_rate: Some(0.5),
+                        paused: false,
+                    }
+                ),
+            ]
+        );
+        assert!(health.iter().all(|(_, queue)| queue.last_seen.is_some()));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_shutdown() {
+        let hub = Hub::<&str, Variant>::with_cooldown(Duration::from_secs(60));
+        let waiting = hub.acquire("provider", |_| true);
+        pin!(waiting);
+        assert!(timeout(Duration::from_millis(1), waiting.as_mut())
+            .await
+            .is_err());
+
+        let started = Instant::now();
+        hub.shutdown();
+        assert_eq!(waiting.await, None);
+        assert_eq!(started.elapsed(), Duration::ZERO);
+
+        // Also when work is available.
+        hub.submit("provider", Variant::Chess).unwrap();
+        assert_eq!(hub.acquire("provider", |_| true).await, None);
+    }
+
+    #[test]
+    fn test_queue_full() {
+        let hub = Hub::<&str, Variant>::default();
+        for _ in 0..MAX_ITEMS {
+            hub.submit("provider", Variant::Chess).unwrap();
+        }
+        assert!(hub.submit("provider", Variant::Chess).is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_acquire_cooldown() {
+        let hub = Hub::<&str, Variant>::with_cooldown(Duration::from_secs(2));
+        hub.submit("provider", Variant::Chess).unwrap();
+        hub.submit("provider", Variant::Chess).unwrap();
+
+        let started = Instant::now();
+        hub.acquire("provider", |_| true).await.unwrap();
+        assert_eq!(started.elapsed(), Duration::ZERO);
+
+        // Other providers are not affected.
+        hub.submit("other", Variant::Chess).unwrap();
+        hub.acquire("other", |_| true).await.unwrap();
+        assert_eq!(started.elapsed(), Duration::ZERO);
+
+        hub.acquire("provider", |_| true).await.unwrap();
+        assert_eq!(started.elapsed(), Duration::from_secs(2));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_acquire_cooldown_concurrent() {
+        let hub = Hub::<&str, Variant>::with_cooldown(Duration::from_secs(2));
+        let started = Instant::now();
+        let first = hub.acquire("provider", |_| true);
+        let second = hub.acquire("provider", |_| true);
+        pin!(first);
+        pin!(second);
+        assert!(timeout(Duration::from_millis(1), first.as_mut())
+            .await
+            .is_err());
+        assert!(timeout(Duration::from_millis(1), second.as_mut())
+            .await
+            .is_err());
+
+        // Both waiters are woken, but only one may acquire before the
+        // cooldown has passed.
+        hub.submit("provider", Variant::Chess).unwrap();
+        hub.submit("provider", Variant::Atomic).unwrap();
+        let (first, second) = tokio::join!(
+            async {
+                first.await.unwrap();
+                started.elapsed()
+            },
+            async {
+                second.await.unwrap();
+                started.elapsed()
+            }
+        );
+        let mut elapsed = [first, second];
+        elapsed.sort();
+        assert_eq!(
+            elapsed,
+            [Duration::from_millis(2), Duration::from_millis(2002)]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_min_completion_rate() {
+        let hub =
+            Hub::<&str, Variant>::default().with_min_completion_rate(0.5, Duration::from_secs(60));
+        hub.submit("provider", Variant::Chess).unwrap();
+        hub.submit("other", Variant::Chess).unwrap();
+
+        // Not judged on too few jobs.
+        for _ in 0..MIN_OUTCOMES - 1 {
+            hub.record_outcome("provider", false);
+            hub.record_outcome("other", true);
+        }
+        let started = Instant::now();
+        hub.acquire("provider", |_| true).await.unwrap();
+        assert_eq!(started.elapsed(), Duration::ZERO);
+
+        // Chronically failing providers are skipped for a while, others are
+        // not affected.
+        hub.record_outcome("provider", false);
+        hub.record_outcome("other", false);
+        hub.submit("provider", Variant::Chess).unwrap();
+        assert!(
+            timeout(Duration::from_secs(10), hub.acquire("provider", |_| true))
+                .await
+                .is_err()
+        );
+        hub.acquire("other", |_| true).await.unwrap();
+        assert_eq!(started.elapsed(), Duration::from_secs(10));
+
+        hub.acquire("provider", |_| true).await.unwrap();
+        assert_eq!(started.elapsed(), Duration::from_secs(60));
+
+        // Judged afresh after the pause.
+        hub.record_outcome("provider", false);
+        hub.submit("provider", Variant::Chess).unwrap();
+        hub.acquire("provider", |_| true).await.unwrap();
+        assert_eq!(started.elapsed(), Duration::from_secs(60));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_pause_while_waiting() {
+        let hub =
+            Hub::<&str, Variant>::default().with_min_completion_rate(0.5, Duration::from_secs(60));
+        let waiting = hub.acquire("provider", |_| true);
+        pin!(waiting);
+        assert!(timeout(Duration::from_millis(1), waiting.as_mut())
+            .await
+            .is_err());
+
+        // Paused while already waiting.
+        let started = Instant::now();
+        for _ in 0..MIN_OUTCOMES {
+            hub.record_outcome("provider", false);
+        }
+        hub.submit("provider", Variant::Chess).unwrap();
+        assert!(timeout(Duration::from_secs(10), waiting.as_mut())
+            .await
+            .is_err());
+        assert!(hub.is_paused(&"provider"));
+        assert!(hub.is_online(&"provider"));
+        assert_eq!(waiting.await, Some(Variant::Chess));
+        assert_eq!(started.elapsed(), Duration::from_secs(60));
+        assert!(!hub.is_paused(&"provider"));
+
+        // Not collected while paused, even without providers or items.
+        for _ in 0..MIN_OUTCOMES {
+            hub.record_outcome("other", false);
+        }
+        sleep(OFFLINE_AFTER).await;
+        for shard in &hub.shards {
+            shard.lock().unwrap().garbage_collect();
+        }
+        assert!(!hub.is_online(&"other"));
+        assert!(hub.is_paused(&"other"));
+    }
+
+    #[tokio::test]
+    async fn test_acquire_oldest_across_variants() {
+        let hub = Hub::<&str, Variant>::default();
+        for variant in [
+            Variant::Crazyhouse,
+            Variant::Chess,
+            Variant::Atomic,
+            Variant::Crazyhouse,
+            Variant::Chess,
+        ] {
+            hub.submit("provider", variant).unwrap();
+        }
+
+        let chess_or_crazyhouse = |v: &Variant| matches!(v, Variant::Chess | Variant::Crazyhouse);
+        let mut acquired = Vec::new();
+        for _ in 0..4 {
+            acquired.push(hub.acquire("provider", chess_or_crazyhouse).await.unwrap());
         }
+        assert_eq!(
+            acquired,
+            [
+                Variant::Crazyhouse,
+                Variant::Chess,
+                Variant::Crazyhouse,
+                Variant::Chess
+            ]
+        );
+        assert_eq!(
+            hub.acquire("provider", |_| true).await.unwrap(),
+            Variant::Atomic
+        );
     }
 }
@@ -0,0 +1,141 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+
+/// Bounds the number of concurrently open analysis streams.
+pub struct StreamLimit {
+    open: AtomicUsize,
+    max: usize,
+}
+
+impl StreamLimit {
+    pub fn new(max: usize) -> StreamLimit {
+        StreamLimit {
+            open: AtomicUsize::new(0),
+            max,
+        }
+    }
+
+    pub fn open(&self) -> usize {
+        self.open.load(Ordering::Relaxed)
+    }
+
+    /// Reserves a slot, which is released when the returned permit is
+    /// dropped.
+    pub fn try_acquire(&'static self) -> Option<StreamPermit> {
+        self.open
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |open| {
+                (open < self.max).then_some(open + 1)
+            })
+            .ok()
+            .map(|_| StreamPermit { limit: self })
+    }
+}
+
+pub struct StreamPermit {
+    limit: &'static StreamLimit,
+}
+
+impl Drop for StreamPermit {
+    fn drop(&mut self) {
+        self.limit.open.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Bounds the number of concurrent provider requests from a single IP
+/// address, so that one misbehaving host cannot hold thousands of
+/// connections.
+pub struct PeerLimit {
+    open: Mutex<HashMap<IpAddr, usize>>,
+    max: usize,
+    exempt: Vec<IpAddr>,
+}
+
+impl PeerLimit {
+    pub fn new(max: usize, exempt: Vec<IpAddr>) -> PeerLimit {
+        PeerLimit {
+            open: Mutex::default(),
+            max,
+            exempt,
+        }
+    }
+
+    /// Reserves a slot for the address, which is released when the returned
+    /// permit is dropped. Exempt addresses always get a permit.
+    pub fn try_acquire(&'static self, ip: IpAddr) -> Option<PeerPermit> {
+        if self.exempt.contains(&ip) {
+            return Some(PeerPermit { limit: None, ip });
+        }
+        let mut open = self.open.lock().unwrap();
+        let count = open.entry(ip).or_default();
+        if *count >= self.max {
+            return None;
+        }
+        *count += 1;
+        Some(PeerPermit {
+            limit: Some(self),
+            ip,
+        })
+    }
+}
+
+pub struct PeerPermit {
+    limit: Option<&'static PeerLimit>,
+    ip: IpAddr,
+}
+
+impl Drop for PeerPermit {
+    fn drop(&mut self) {
+        if let Some(limit) = self.limit {
+            let mut open = limit.open.lock().unwrap();
+            if let Some(count) = open.get_mut(&self.ip) {
+                *count -= 1;
+                if *count == 0 {
+                    open.remove(&self.ip);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stream_limit() {
+        let limit: &'static StreamLimit = Box::leak(Box::new(StreamLimit::new(2)));
+        let first = limit.try_acquire().expect("first");
+        let _second = limit.try_acquire().expect("second");
+        assert!(limit.try_acquire().is_none());
+        assert_eq!(limit.open(), 2);
+
+        drop(first);
+        assert_eq!(limit.open(), 1);
+        assert!(limit.try_acquire().is_some());
+    }
+
+    #[test]
+    fn test_peer_limit() {
+        let exempt: IpAddr = "10.0.0.1".parse().unwrap();
+        let limit: &'static PeerLimit = Box::leak(Box::new(PeerLimit::new(1, vec![exempt])));
+        let host: IpAddr = "192.0.2.1".parse().unwrap();
+        let other: IpAddr = "192.0.2.2".parse().unwrap();
+
+        let first = limit.try_acquire(host).expect("first");
+        assert!(limit.try_acquire(host).is_none());
+        assert!(limit.try_acquire(other).is_some());
+        let _exempted: Vec<PeerPermit> = (0..3)
+            .map(|_| limit.try_acquire(exempt).expect("exempt"))
+            .collect();
+
+        drop(first);
+        assert!(limit.try_acquire(host).is_some());
+        assert!(limit.open.lock().unwrap().is_empty());
+    }
+}
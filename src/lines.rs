@@ -0,0 +1,90 @@
+use std::io;
+
+use tokio::io::{AsyncBufRead, AsyncBufReadExt as _};
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct LineTooLong;
+
+/// Splits a reader into lines like `AsyncBufReadExt::lines`, but skips over
+/// lines longer than `max_len` bytes instead of buffering them.
+pub struct BoundedLines<R> {
+    reader: R,
+    buf: Vec<u8>,
+    max_len: usize,
+}
+
+impl<R: AsyncBufRead + Unpin> BoundedLines<R> {
+    pub fn new(reader: R, max_len: usize) -> BoundedLines<R> {
+        BoundedLines {
+            reader,
+            buf: Vec::new(),
+            max_len,
+        }
+    }
+
+    pub async fn next_line(&mut self) -> io::Result<Option<Result<String, LineTooLong>>> {
+        self.buf.clear();
+        let mut too_long = false;
+        let mut empty = true;
+        loop {
+            let available = self.reader.fill_buf().await?;
+            if available.is_empty() {
+                if empty {
+                    return Ok(None);
+                }
+                break;
+            }
+            empty = false;
+            let (chunk, consumed, complete) = match available.iter().position(|b| *b == b'\n') {
+                Some(i) => (&available[..i], i + 1, true),
+                None => (available, available.len(), false),
+            };
+            if !too_long {
+                if self.buf.len() + chunk.len() > self.max_len {
+                    too_long = true;
+                    self.buf.clear();
+                } else {
+                    self.buf.extend_from_slice(chunk);
+                }
+            }
+            self.reader.consume(consumed);
+            if complete {
+                break;
+            }
+        }
+        if too_long {
+            return Ok(Some(Err(LineTooLong)));
+        }
+        if self.buf.last() == Some(&b'\r') {
+            self.buf.pop();
+        }
+        String::from_utf8(std::mem::take(&mut self.buf))
+            .map(|line| Some(Ok(line)))
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_bounded_lines() {
+        let input = "short\r\nthis line is too long\nafter\nlast";
+        let mut lines = BoundedLines::new(input.as_bytes(), 10);
+        assert_eq!(
+            lines.next_line().await.unwrap(),
+            Some(Ok("short".to_owned()))
+        );
+        assert_eq!(lines.next_line().await.unwrap(), Some(Err(LineTooLong)));
+        assert_eq!(
+            lines.next_line().await.unwrap(),
+            Some(Ok("after".to_owned()))
+        );
+        assert_eq!(
+            lines.next_line().await.unwrap(),
+            Some(Ok("last".to_owned()))
+        );
+        assert_eq!(lines.next_line().await.unwrap(), None);
+    }
+}
@@ -1,58 +1,115 @@
-use std::{convert::Infallible, io, net::SocketAddr, path::PathBuf, time::Duration};
+use std::{
+    convert::Infallible,
+    fmt,
+    future::{self, Future, IntoFuture},
+    io,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU32, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 use axum::{
     body::Body,
-    extract::{FromRef, Json, State},
-    http::StatusCode,
+    extract::{
+        connect_info::Connected, rejection::JsonRejection, ConnectInfo, FromRef, FromRequest,
+        FromRequestParts, Query, Request, State,
+    },
+    http::{header, request::Parts, HeaderMap, StatusCode},
+    middleware::{self, Next},
     response::{IntoResponse, Response},
-    Router,
+    serve::{IncomingStream, Listener},
+    Json as JsonResponse, Router,
 };
 use axum_extra::{
+    either::Either,
+    headers::{authorization::Bearer, Authorization},
     json_lines,
     json_lines::JsonLines,
     routing::{RouterExt, TypedPath},
+    TypedHeader,
 };
 use clap::{builder::PathBufValueParser, Parser};
 use futures::Stream;
-use futures_util::stream::{StreamExt, TryStreamExt};
+use futures_util::{
+    future::FutureExt,
+    stream,
+    stream::{StreamExt, TryStreamExt},
+};
 use listenfd::ListenFd;
-use serde::Deserialize;
-use shakmaty::variant::VariantPosition;
+use mongodb::bson::DateTime;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use shakmaty::{uci::UciMove, variant::VariantPosition};
 use thiserror::Error;
 use tikv_jemallocator::Jemalloc;
 use tokio::{
-    io::AsyncBufReadExt,
     net::{TcpListener, UnixListener},
     select,
     sync::{
-        mpsc,
         oneshot::{self, error::RecvError},
+        watch, Mutex,
     },
     task,
-    time::{error::Elapsed, timeout},
+    time::{interval_at, sleep, sleep_until, timeout, timeout_at, Instant},
 };
-use tokio_stream::wrappers::ReceiverStream;
 use tokio_util::io::StreamReader;
 use tower_http::{cors::CorsLayer, trace::TraceLayer};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+use utoipa::OpenApi as _;
 
 use crate::{
-    api::{AcquireRequest, AcquireResponse, AnalyseRequest, InvalidWorkError, Work},
-    emit::Emit,
-    hub::{Hub, IsValid},
-    model::{Engine, EngineId, JobId, ProviderSelector},
+    api::{
+        AcquireEmptyStatus, AcquireRequest, AcquireResponse, AnalyseBatchRequest, AnalyseRequest,
+        ApiDoc, CancelSessionRequest, ChallengeResponse, CompareEngine, CompareRequest,
+        DeeperRequest, HandshakeMismatch, HealthQuery, HealthResponse, HeartbeatRequest,
+        InvalidWorkError, MaintenanceRequest, PlayRequest, PonderRequest, PonderResponse,
+        ProviderAuth, ProviderHandshake, ProviderHealth, PurgeQuery, PurgeResponse,
+        RecentJobsResponse, RotateSecretResponse, SetEnabledRequest, StatsResponse,
+        SubscribeRequest, Work, WorkOpt,
+    },
+    audit::{AuditEntry, AuditLog},
+    challenge::Challenges,
+    deadline::RequestDeadline,
+    emit::{BatchEmit, CompareEmit, Emit, Frame, StreamError, Throttle},
+    hub::{Busy, Hub, IsValid, QueueFull},
+    limit::{PeerLimit, StreamLimit},
+    lines::BoundedLines,
+    metrics::Metrics,
+    model::{
+        recording_rejection, AdminToken, ClientSecret, EmptySecretError, Engine, EngineId, JobId,
+        JobIdSource, ProviderSelector, RandomJobIds, Rejection, SessionId,
+    },
     ongoing::Ongoing,
-    repo::Repo,
+    repo::{EngineStore, Repo},
+    session::{Ponders, Session, Sessions},
+    shutdown::{InFlight, Maintenance, RemoteShutdown},
+    summary::{JobSummary, Reason},
     uci::UciOut,
+    webhook::Webhooks,
 };
 
 mod api;
+mod audit;
+mod challenge;
+mod deadline;
 mod emit;
+mod feed;
 mod hub;
+mod limit;
+mod lines;
+mod metrics;
 mod model;
 mod ongoing;
 mod repo;
+mod session;
+mod shutdown;
+mod summary;
 mod uci;
+mod webhook;
 
 #[global_allocator]
 static GLOBAL: Jemalloc = Jemalloc;
@@ -71,30 +128,185 @@ struct Opt {
     /// Private key for HTTPS server.
     #[arg(long, value_parser = PathBufValueParser::new())]
     pub key_pem: Option<PathBuf>,
+    /// Maximum number of concurrently open analysis streams.
+    #[arg(long, default_value_t = 10_000)]
+    pub max_streams: usize,
+    /// Maximum number of concurrent provider requests from a single IP
+    /// address. Not limited by default, since all providers may appear to
+    /// connect from the same address, e.g. behind a proxy.
+    #[arg(long)]
+    pub max_provider_connections_per_ip: Option<usize>,
+    /// IP address exempt from the provider connection limit. Can be given
+    /// multiple times.
+    #[arg(long = "provider-connection-exempt")]
+    pub provider_connection_exempt: Vec<IpAddr>,
+    /// Take the client address from the `X-Forwarded-For` header, for
+    /// per-IP limits and logs. Only enable behind a reverse proxy that sets
+    /// it.
+    #[arg(long)]
+    pub trust_proxy: bool,
+    /// Minimum delay between two jobs acquired by the same provider, in
+    /// milliseconds. Keeps aggressively reconnecting providers from starving
+    /// others.
+    #[arg(long, default_value_t = 0)]
+    pub acquire_cooldown_ms: u64,
+    /// Pause providers of a selector once they complete less than this share
+    /// of their recent jobs (between 0 and 1). The pause applies to all
+    /// providers of the selector, i.e. the whole engine, so queued work waits
+    /// or times out. Disabled by default.
+    #[arg(long)]
+    pub min_completion_rate: Option<f64>,
+    /// Seconds that providers with a low completion rate are not handed
+    /// work.
+    #[arg(long, default_value_t = 60)]
+    pub unhealthy_pause: u64,
+    /// Seconds to wait for jobs in flight to complete on shutdown.
+    #[arg(long, default_value_t = 30)]
+    pub shutdown_grace: u64,
+    /// Bearer token for administrative endpoints. They are disabled if not
+    /// given.
+    #[arg(long)]
+    pub admin_token: Option<AdminToken>,
+    /// Allow shutting down with `POST /api/admin/shutdown`, e.g. to tear down
+    /// test deployments.
+    #[arg(long)]
+    pub allow_remote_shutdown: bool,
+    /// Number of recently finished jobs to keep for
+    /// `GET /api/admin/jobs/recent`.
+    #[arg(long, default_value_t = 100)]
+    pub audit_log_size: usize,
+    #[command(flatten)]
+    pub work: WorkOpt,
 }
 
 struct Job {
-    tx: oneshot::Sender<mpsc::Receiver<Emit>>,
+    tx: oneshot::Sender<feed::Receiver<Frame>>,
     pos: VariantPosition,
     engine: Engine,
     work: Work,
+    selector: ProviderSelector,
+    redispatches: u32,
+    session: Arc<Session>,
+    played: watch::Receiver<Option<UciMove>>,
+    /// When the job was submitted to the hub.
+    queued_at: Instant,
+    /// Analysis frames that may still be forwarded, if `maxInfoFrames` was
+    /// given. Carried over when the job is handed to another provider.
+    info_frames_left: Option<u32>,
+    /// Depth that clients asked to continue to while the job runs, or 0.
+    /// Shared by all providers that the job is handed to.
+    deeper: Arc<AtomicU32>,
 }
 
 impl IsValid for Job {
     fn is_valid(&self) -> bool {
         !self.tx.is_closed()
+            && !self.session.is_cancelled()
+            && self
+                .work
+                .deadline()
+                .is_none_or(|deadline| Instant::now() < deadline)
+    }
+}
+
+/// A job that was picked up by a provider, with the stream to the requester
+/// already established. Any number of receivers may be subscribed to the
+/// analysis, and the job stays alive as long as at least one remains.
+///
+/// Once the last subscriber is gone, the job becomes invalid and the provider
+/// is told to stop: `submit` responds immediately, or with 410 Gone if the
+/// job was collected before the provider started submitting. The same
+/// happens when the session of the job is cancelled.
+struct AcquiredJob {
+    tx: feed::Sender<Frame>,
+    pos: VariantPosition,
+    engine: Engine,
+    work: Work,
+    selector: ProviderSelector,
+    redispatches: u32,
+    session: Arc<Session>,
+    acquired_at: Instant,
+    /// The connection that the provider acquired the job on.
+    connection: Option<ConnectionId>,
+    info_frames_left: Option<u32>,
+    deeper: Arc<AtomicU32>,
+    /// Keeps the provider online until the job ends.
+    _busy: Busy,
+}
+
+/// Lets further clients of the engine subscribe to a job while it runs, or
+/// continue it deeper.
+#[derive(Clone)]
+struct Subscription {
+    tx: feed::WeakSender<Frame>,
+    client_secret: ClientSecret,
+    depth: Option<u32>,
+    deeper: Arc<AtomicU32>,
+}
+
+impl IsValid for Subscription {
+    fn is_valid(&self) -> bool {
+        !self.tx.is_closed()
+    }
+}
+
+impl IsValid for AcquiredJob {
+    fn is_valid(&self) -> bool {
+        self.tx.receiver_count() > 0 && !self.session.is_cancelled()
+    }
+}
+
+/// Frames queued for a stream that falls behind, before its analysis frames
+/// are coalesced.
+const MAX_QUEUED_FRAMES: usize = 64;
+
+impl Job {
+    fn start(self, id: &JobId, busy: Busy) -> AcquiredJob {
+        let (tx, rx) = feed::channel(MAX_QUEUED_FRAMES, Frame::coalesce);
+        let _: Result<_, _> = tx.send(Frame::acquired(id, &self.engine, &self.work, &self.pos));
+        let _: Result<(), _> = self.tx.send(rx);
+        AcquiredJob {
+            tx,
+            pos: self.pos,
+            engine: self.engine,
+            work: self.work,
+            selector: self.selector,
+            redispatches: self.redispatches,
+            session: self.session,
+            acquired_at: Instant::now(),
+            connection: None,
+            info_frames_left: self.info_frames_left,
+            deeper: self.deeper,
+            _busy: busy,
+        }
     }
 }
 
 #[derive(Clone)]
 struct AppState {
-    repo: &'static Repo,
+    repo: &'static dyn EngineStore,
     hub: &'static Hub<ProviderSelector, Job>,
-    ongoing: &'static Ongoing<JobId, Job>,
+    ongoing: &'static Ongoing<JobId, AcquiredJob>,
+    subscriptions: &'static Ongoing<JobId, Subscription>,
+    job_ids: &'static dyn JobIdSource,
+    sessions: &'static Sessions,
+    ponders: &'static Ponders,
+    challenges: &'static Challenges,
+    streams: &'static StreamLimit,
+    peers: Option<&'static PeerLimit>,
+    in_flight: &'static InFlight,
+    maintenance: &'static Maintenance,
+    webhooks: &'static Webhooks,
+    metrics: &'static Metrics,
+    audit: &'static AuditLog,
+    work_opt: &'static WorkOpt,
+    admin_token: Option<&'static AdminToken>,
+    remote_shutdown: Option<&'static RemoteShutdown>,
+    trust_proxy: bool,
 }
 
-impl FromRef<AppState> for &'static Repo {
-    fn from_ref(state: &AppState) -> &'static Repo {
+impl FromRef<AppState> for &'static dyn EngineStore {
+    fn from_ref(state: &AppState) -> &'static dyn EngineStore {
         state.repo
     }
 }
@@ -105,9 +317,174 @@ impl FromRef<AppState> for &'static Hub<ProviderSelector, Job> {
     }
 }
 
-impl FromRef<AppState> for &'static Ongoing<JobId, Job> {
-    fn from_ref(state: &AppState) -> &'static Ongoing<JobId, Job> {
-        state.ongoing
+impl FromRef<AppState> for &'static Ongoing<JobId, Subscription> {
+    fn from_ref(state: &AppState) -> &'static Ongoing<JobId, Subscription> {
+        state.subscriptions
+    }
+}
+
+impl FromRef<AppState> for &'static dyn JobIdSource {
+    fn from_ref(state: &AppState) -> &'static dyn JobIdSource {
+        state.job_ids
+    }
+}
+
+impl FromRef<AppState> for &'static Sessions {
+    fn from_ref(state: &AppState) -> &'static Sessions {
+        state.sessions
+    }
+}
+
+impl FromRef<AppState> for &'static Ponders {
+    fn from_ref(state: &AppState) -> &'static Ponders {
+        state.ponders
+    }
+}
+
+impl FromRef<AppState> for &'static Challenges {
+    fn from_ref(state: &AppState) -> &'static Challenges {
+        state.challenges
+    }
+}
+
+impl FromRef<AppState> for &'static StreamLimit {
+    fn from_ref(state: &AppState) -> &'static StreamLimit {
+        state.streams
+    }
+}
+
+impl FromRef<AppState> for &'static InFlight {
+    fn from_ref(state: &AppState) -> &'static InFlight {
+        state.in_flight
+    }
+}
+
+impl FromRef<AppState> for &'static Maintenance {
+    fn from_ref(state: &AppState) -> &'static Maintenance {
+        state.maintenance
+    }
+}
+
+impl FromRef<AppState> for &'static Webhooks {
+    fn from_ref(state: &AppState) -> &'static Webhooks {
+        state.webhooks
+    }
+}
+
+impl FromRef<AppState> for &'static Metrics {
+    fn from_ref(state: &AppState) -> &'static Metrics {
+        state.metrics
+    }
+}
+
+impl FromRef<AppState> for &'static AuditLog {
+    fn from_ref(state: &AppState) -> &'static AuditLog {
+        state.audit
+    }
+}
+
+impl FromRef<AppState> for &'static WorkOpt {
+    fn from_ref(state: &AppState) -> &'static WorkOpt {
+        state.work_opt
+    }
+}
+
+impl FromRef<AppState> for Option<&'static AdminToken> {
+    fn from_ref(state: &AppState) -> Option<&'static AdminToken> {
+        state.admin_token
+    }
+}
+
+impl FromRef<AppState> for Option<&'static RemoteShutdown> {
+    fn from_ref(state: &AppState) -> Option<&'static RemoteShutdown> {
+        state.remote_shutdown
+    }
+}
+
+/// State of the endpoints that dispatch analysis on behalf of clients.
+#[derive(Clone, Copy)]
+struct Clients {
+    hub: &'static Hub<ProviderSelector, Job>,
+    sessions: &'static Sessions,
+    repo: &'static dyn EngineStore,
+    streams: &'static StreamLimit,
+    maintenance: &'static Maintenance,
+    webhooks: &'static Webhooks,
+    metrics: &'static Metrics,
+    audit: &'static AuditLog,
+    work_opt: &'static WorkOpt,
+}
+
+impl FromRef<AppState> for Clients {
+    fn from_ref(state: &AppState) -> Clients {
+        Clients {
+            hub: state.hub,
+            sessions: state.sessions,
+            repo: state.repo,
+            streams: state.streams,
+            maintenance: state.maintenance,
+            webhooks: state.webhooks,
+            metrics: state.metrics,
+            audit: state.audit,
+            work_opt: state.work_opt,
+        }
+    }
+}
+
+/// State of the endpoints that providers use to acquire and run jobs.
+#[derive(Clone, Copy)]
+struct Providers {
+    hub: &'static Hub<ProviderSelector, Job>,
+    repo: &'static dyn EngineStore,
+    ongoing: &'static Ongoing<JobId, AcquiredJob>,
+    subscriptions: &'static Ongoing<JobId, Subscription>,
+    ponders: &'static Ponders,
+    metrics: &'static Metrics,
+    audit: &'static AuditLog,
+    work_opt: &'static WorkOpt,
+}
+
+impl FromRef<AppState> for Providers {
+    fn from_ref(state: &AppState) -> Providers {
+        Providers {
+            hub: state.hub,
+            repo: state.repo,
+            ongoing: state.ongoing,
+            subscriptions: state.subscriptions,
+            ponders: state.ponders,
+            metrics: state.metrics,
+            audit: state.audit,
+            work_opt: state.work_opt,
+        }
+    }
+}
+
+/// Like `axum::Json`, but with rejections mapped to `Error`. Rejections
+/// recorded while deserializing get their own error, and invalid work among
+/// them is counted in the metrics, like invalid work that is found later.
+struct Json<T>(T);
+
+impl<T, S> FromRequest<S> for Json<T>
+where
+    axum::Json<T>: FromRequest<S, Rejection = JsonRejection>,
+    &'static Metrics: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = Error;
+
+    async fn from_request(req: Request, state: &S) -> Result<Json<T>, Error> {
+        let (res, recorded) = recording_rejection(axum::Json::from_request(req, state)).await;
+        res.map(|axum::Json(value)| Json(value))
+            .map_err(|rejection| {
+                let err = match (recorded, rejection) {
+                    (Some(recorded), JsonRejection::JsonDataError(_)) => Error::from(recorded),
+                    (_, rejection) => Error::Json(rejection),
+                };
+                if let Error::InvalidWork(ref err) = err {
+                    <&'static Metrics>::from_ref(state).record_invalid_work(err);
+                }
+                err
+            })
     }
 }
 
@@ -117,27 +494,150 @@ enum Error {
     MongoDb(#[from] mongodb::error::Error),
     #[error("engine not found or invalid clientSecret")]
     EngineNotFound,
-    #[error("work not found or cancelled or expired")]
+    #[error("work not found or expired")]
     WorkNotFound,
+    #[error("no work available")]
+    NoWork,
+    #[error("work already completed or cancelled")]
+    WorkGone,
     #[error("i/o error: {0}")]
     Io(#[from] io::Error),
     #[error("uci protocol error: {0}")]
     Protocol(#[from] uci::ProtocolError),
     #[error("invalid work: {0}")]
     InvalidWork(#[from] InvalidWorkError),
+    #[error("handshake mismatch: {0}")]
+    HandshakeMismatch(#[from] HandshakeMismatch),
     #[error("recv: {0}")]
     Recv(#[from] RecvError),
-    #[error("provider did not pick up work")]
-    ProviderTimeout,
+    #[error("{0}")]
+    Unavailable(Unavailable),
+    #[error("too many positions in batch")]
+    BatchTooLarge,
+    #[error("invalid request: depth must exceed the depth of the job")]
+    NotDeeper,
+    #[error("invalid request: {0}")]
+    EmptySecret(#[from] EmptySecretError),
+    #[error("invalid request: clientSecret too short")]
+    ShortClientSecret,
+    #[error("invalid request: providerSecret or challenge required")]
+    MissingProviderAuth,
+    #[error("invalid or expired challenge signature")]
+    InvalidSignature,
+    #[error("admin token required")]
+    Forbidden,
+    #[error("deadline passed before a provider picked up the work")]
+    DeadlineExceeded,
+    #[error("too many connections from this address")]
+    TooManyConnections,
+    #[error("{}", .0.body_text())]
+    Json(JsonRejection),
+}
+
+/// Reasons for `503 Service Unavailable`, so that clients can tell an
+/// offline engine from a busy one.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum Unavailable {
+    NoProvider,
+    ProviderPaused,
+    #[serde(rename = "provider-unavailable")]
+    NotPickedUp,
+    QueueFull,
+    TooManyStreams,
+    Maintenance,
+    EngineDisabled,
+}
+
+impl Unavailable {
+    fn retry_after(self) -> Duration {
+        match self {
+            Unavailable::NoProvider => Duration::from_secs(30),
+            Unavailable::ProviderPaused => Duration::from_secs(60),
+            Unavailable::NotPickedUp => Duration::from_secs(10),
+            Unavailable::QueueFull => Duration::from_secs(5),
+            Unavailable::TooManyStreams => Duration::from_secs(10),
+            Unavailable::Maintenance => Duration::from_secs(60),
+            Unavailable::EngineDisabled => Duration::from_secs(60),
+        }
+    }
+}
+
+impl fmt::Display for Unavailable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Unavailable::NoProvider => "no provider online",
+            Unavailable::ProviderPaused => "provider paused for completing too few jobs",
+            Unavailable::NotPickedUp => "provider did not pick up work in time",
+            Unavailable::QueueFull => "too much work queued for provider",
+            Unavailable::TooManyStreams => "too many open analysis streams",
+            Unavailable::Maintenance => "down for maintenance, try again later",
+            Unavailable::EngineDisabled => "engine disabled",
+        })
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct UnavailableBody {
+    error: String,
+    code: Unavailable,
+    retry_after: u64,
+}
+
+impl From<QueueFull> for Error {
+    fn from(_: QueueFull) -> Error {
+        Error::Unavailable(Unavailable::QueueFull)
+    }
+}
+
+impl From<Rejection> for Error {
+    fn from(rejection: Rejection) -> Error {
+        match rejection {
+            Rejection::NotAtLeastOne => Error::InvalidWork(InvalidWorkError::NotAtLeastOne),
+            Rejection::UnsupportedVariant => {
+                Error::InvalidWork(InvalidWorkError::UnsupportedVariant)
+            }
+            Rejection::EmptySecret => Error::EmptySecret(EmptySecretError),
+        }
+    }
 }
 
 impl IntoResponse for Error {
     fn into_response(self) -> Response {
         let status = match self {
             Error::MongoDb(_) | Error::Recv(_) => StatusCode::INTERNAL_SERVER_ERROR,
-            Error::Io(_) | Error::Protocol(_) | Error::InvalidWork(_) => StatusCode::BAD_REQUEST,
+            Error::InvalidWork(InvalidWorkError::DisallowedSession) => StatusCode::FORBIDDEN,
+            Error::Io(_)
+            | Error::Protocol(_)
+            | Error::InvalidWork(_)
+            | Error::BatchTooLarge
+            | Error::NotDeeper
+            | Error::EmptySecret(_)
+            | Error::ShortClientSecret
+            | Error::MissingProviderAuth => StatusCode::BAD_REQUEST,
+            Error::InvalidSignature => StatusCode::UNAUTHORIZED,
+            Error::Forbidden => StatusCode::FORBIDDEN,
+            Error::HandshakeMismatch(_) => StatusCode::CONFLICT,
+            Error::DeadlineExceeded => StatusCode::GATEWAY_TIMEOUT,
+            Error::TooManyConnections => StatusCode::TOO_MANY_REQUESTS,
             Error::EngineNotFound | Error::WorkNotFound => StatusCode::NOT_FOUND,
-            Error::ProviderTimeout => StatusCode::SERVICE_UNAVAILABLE,
+            Error::NoWork => return StatusCode::NO_CONTENT.into_response(),
+            Error::WorkGone => StatusCode::GONE,
+            Error::Unavailable(cause) => {
+                let retry_after = cause.retry_after().as_secs();
+                return (
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    [(header::RETRY_AFTER, retry_after.to_string())],
+                    JsonResponse(UnavailableBody {
+                        error: cause.to_string(),
+                        code: cause,
+                        retry_after,
+                    }),
+                )
+                    .into_response();
+            }
+            Error::Json(ref rejection) => rejection.status(),
         };
         (status, self.to_string()).into_response()
     }
@@ -155,36 +655,277 @@ async fn main() {
 
     let opt = Opt::parse();
 
+    let mut hub = Hub::with_cooldown(Duration::from_millis(opt.acquire_cooldown_ms));
+    if let Some(min_rate) = opt.min_completion_rate {
+        hub = hub.with_min_completion_rate(min_rate, Duration::from_secs(opt.unhealthy_pause));
+    }
+
     let state = AppState {
         repo: Box::leak(Box::new(Repo::new(&opt.mongodb).await)),
-        hub: Box::leak(Box::new(Hub::default())),
+        hub: Box::leak(Box::new(hub)),
         ongoing: Box::leak(Box::new(Ongoing::default())),
+        subscriptions: Box::leak(Box::new(Ongoing::default())),
+        job_ids: &RandomJobIds,
+        sessions: Box::leak(Box::default()),
+        ponders: Box::leak(Box::default()),
+        challenges: Box::leak(Box::default()),
+        streams: Box::leak(Box::new(StreamLimit::new(opt.max_streams))),
+        peers: opt.max_provider_connections_per_ip.map(|max| {
+            &*Box::leak(Box::new(PeerLimit::new(
+                max,
+                opt.provider_connection_exempt,
+            )))
+        }),
+        in_flight: Box::leak(Box::default()),
+        maintenance: Box::leak(Box::default()),
+        webhooks: Box::leak(Box::new(Webhooks::default())),
+        metrics: Box::leak(Box::default()),
+        audit: Box::leak(Box::new(AuditLog::new(opt.audit_log_size))),
+        work_opt: Box::leak(Box::new(opt.work)),
+        admin_token: opt.admin_token.map(|token| &*Box::leak(Box::new(token))),
+        remote_shutdown: opt
+            .allow_remote_shutdown
+            .then(|| &*Box::leak(Box::default())),
+        trust_proxy: opt.trust_proxy,
     };
 
     task::spawn(state.hub.garbage_collect());
     task::spawn(state.ongoing.garbage_collect());
+    task::spawn(state.subscriptions.garbage_collect());
 
-    let app = Router::new()
-        .typed_post(analyse)
-        .typed_post(acquire)
-        .typed_post(submit)
-        .layer(CorsLayer::permissive().max_age(Duration::from_secs(60 * 60 * 24)))
-        .layer(TraceLayer::new_for_http())
-        .with_state(state);
+    let app = app(state.clone());
 
+    let grace = Duration::from_secs(opt.shutdown_grace);
     let mut fds = ListenFd::from_env();
     if let Ok(Some(uds)) = fds.take_unix_listener(0) {
         uds.set_nonblocking(true).expect("set nonblocking");
         let listener = UnixListener::from_std(uds).expect("listener");
-        axum::serve(listener, app).await.expect("serve");
+        serve(listener, app, &state, grace).await;
     } else if let Ok(Some(tcp)) = fds.take_tcp_listener(0) {
         tcp.set_nonblocking(true).expect("set nonblocking");
         let listener = TcpListener::from_std(tcp).expect("listener");
-        axum::serve(listener, app).await.expect("serve");
+        serve(listener, app, &state, grace).await;
     } else {
         let listener = TcpListener::bind(&opt.bind).await.expect("bind");
-        axum::serve(listener, app).await.expect("serve");
+        serve(listener, app, &state, grace).await;
+    }
+}
+
+fn app(state: AppState) -> Router {
+    let mut providers = Router::new()
+        .typed_post(acquire)
+        .typed_post(submit)
+        .typed_post(heartbeat)
+        .typed_post(ponder);
+    if let Some(peers) = state.peers {
+        providers = providers.route_layer(middleware::from_fn_with_state(
+            (peers, state.trust_proxy),
+            limit_peer_connections,
+        ));
+    }
+    let trust_proxy = state.trust_proxy;
+    Router::new()
+        .typed_post(analyse)
+        .typed_post(analyse_batch)
+        .typed_post(compare)
+        .typed_post(challenge)
+        .merge(providers)
+        .typed_post(subscribe)
+        .typed_post(deeper)
+        .typed_post(cancel_session)
+        .typed_post(play)
+        .typed_get(stats)
+        .typed_post(rotate_secret)
+        .typed_post(set_enabled)
+        .typed_get(health)
+        .typed_get(recent_jobs)
+        .typed_post(maintenance)
+        .typed_post(remote_shutdown)
+        .typed_post(purge)
+        .typed_get(metrics)
+        .typed_get(openapi)
+        .layer(CorsLayer::permissive().max_age(Duration::from_secs(60 * 60 * 24)))
+        .layer(middleware::from_fn_with_state(trust_proxy, log_client))
+        .layer(TraceLayer::new_for_http())
+        .with_state(state)
+}
+
+/// Logs the client of each request, which may be behind a trusted proxy.
+async fn log_client(State(trust_proxy): State<bool>, req: Request, next: Next) -> Response {
+    log::debug!(
+        "{} {} from {:?}",
+        req.method(),
+        req.uri(),
+        client_ip(&req, trust_proxy)
+    );
+    next.run(req).await
+}
+
+/// Identifies an accepted connection for as long as the server runs.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+struct ConnectionId(u64);
+
+impl ConnectionId {
+    fn next() -> ConnectionId {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        ConnectionId(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+/// The peer of a connection. The address is only known when connected via
+/// TCP.
+#[derive(Debug, Copy, Clone)]
+struct PeerAddr {
+    ip: Option<IpAddr>,
+    connection: ConnectionId,
+}
+
+impl Connected<IncomingStream<'_, TcpListener>> for PeerAddr {
+    fn connect_info(stream: IncomingStream<'_, TcpListener>) -> PeerAddr {
+        PeerAddr {
+            ip: Some(stream.remote_addr().ip()),
+            connection: ConnectionId::next(),
+        }
+    }
+}
+
+impl Connected<IncomingStream<'_, UnixListener>> for PeerAddr {
+    fn connect_info(_: IncomingStream<'_, UnixListener>) -> PeerAddr {
+        PeerAddr {
+            ip: None,
+            connection: ConnectionId::next(),
+        }
+    }
+}
+
+/// The connection that a request arrived on, if served with connect info.
+struct Connection(Option<ConnectionId>);
+
+impl<S: Send + Sync> FromRequestParts<S> for Connection {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Connection, Infallible> {
+        Ok(Connection(
+            parts
+                .extensions
+                .get::<ConnectInfo<PeerAddr>>()
+                .map(|ConnectInfo(peer)| peer.connection),
+        ))
+    }
+}
+
+/// Address of the client. If the proxy is trusted, this is the rightmost
+/// entry of `X-Forwarded-For`, as appended by the proxy itself. Otherwise,
+/// or if the header is missing, it is the address of the peer.
+fn client_ip<B>(req: &Request<B>, trust_proxy: bool) -> Option<IpAddr> {
+    let forwarded = || {
+        req.headers()
+            .get_all("x-forwarded-for")
+            .iter()
+            .next_back()?
+            .to_str()
+            .ok()?
+            .rsplit(',')
+            .next()?
+            .trim()
+            .parse()
+            .ok()
+    };
+    trust_proxy.then(forwarded).flatten().or_else(|| {
+        req.extensions()
+            .get::<ConnectInfo<PeerAddr>>()
+            .and_then(|ConnectInfo(peer)| peer.ip)
+    })
+}
+
+/// Rejects provider requests from addresses that already hold too many
+/// connections. The slot is held until the response body is complete, which
+/// covers long-polls and streamed responses.
+async fn limit_peer_connections(
+    State((peers, trust_proxy)): State<(&'static PeerLimit, bool)>,
+    req: Request,
+    next: Next,
+) -> Result<Response, Error> {
+    let Some(ip) = client_ip(&req, trust_proxy) else {
+        return Ok(next.run(req).await);
+    };
+    let permit = peers.try_acquire(ip).ok_or(Error::TooManyConnections)?;
+    Ok(next.run(req).await.map(|body| {
+        Body::from_stream(body.into_data_stream().inspect(move |_| {
+            let _permit = &permit;
+        }))
+    }))
+}
+
+/// Serves until a shutdown signal is received or a remote shutdown is
+/// requested, then releases waiting providers, stops accepting connections
+/// and gives jobs in flight up to `grace` to complete.
+async fn serve<L>(listener: L, app: Router, state: &AppState, grace: Duration)
+where
+    L: Listener,
+    L::Addr: fmt::Debug,
+    for<'a> PeerAddr: Connected<IncomingStream<'a, L>>,
+{
+    let (stop, stopped) = oneshot::channel::<()>();
+    let server = task::spawn(
+        axum::serve(
+            listener,
+            app.into_make_service_with_connect_info::<PeerAddr>(),
+        )
+        .with_graceful_shutdown(async move {
+            let _: Result<_, _> = stopped.await;
+        })
+        .into_future(),
+    );
+    select! {
+        res = server => {
+            res.expect("join").expect("serve");
+            return;
+        }
+        () = shutdown::signal_received() => {}
+        () = async {
+            match state.remote_shutdown {
+                Some(remote_shutdown) => remote_shutdown.requested().await,
+                None => future::pending().await,
+            }
+        } => {}
     }
+    log::info!(
+        "shutting down, draining {} job(s) in flight",
+        state.in_flight.jobs()
+    );
+    state.hub.shutdown();
+    let _: Result<_, _> = stop.send(());
+    let report = state.in_flight.drain(grace).await;
+    log::info!(
+        "shutdown completed, drained {} job(s), forced {}",
+        report.drained,
+        report.forced
+    );
+}
+
+#[derive(TypedPath, Deserialize)]
+#[typed_path("/openapi.json")]
+struct OpenApiPath;
+
+async fn openapi(_: OpenApiPath) -> JsonResponse<utoipa::openapi::OpenApi> {
+    JsonResponse(ApiDoc::openapi())
+}
+
+/// Finds an engine for new analysis requests.
+async fn find_enabled(
+    repo: &'static dyn EngineStore,
+    id: EngineId,
+    client_secret: ClientSecret,
+) -> Result<(Engine, ProviderSelector), Error> {
+    let engine = repo
+        .find(id, client_secret)
+        .await?
+        .ok_or(Error::EngineNotFound)?;
+    if !engine.is_enabled() {
+        return Err(Error::Unavailable(Unavailable::EngineDisabled));
+    }
+    Ok(engine.into_engine_and_selector())
 }
 
 #[derive(TypedPath, Deserialize)]
@@ -196,111 +937,4847 @@ struct AnalysePath {
 #[axum_macros::debug_handler(state = AppState)]
 async fn analyse(
     AnalysePath { id }: AnalysePath,
-    State(hub): State<&'static Hub<ProviderSelector, Job>>,
-    State(repo): State<&'static Repo>,
+    State(clients): State<Clients>,
+    deadline: Option<TypedHeader<RequestDeadline>>,
+    headers: HeaderMap,
     Json(req): Json<AnalyseRequest>,
-) -> Result<JsonLines<impl Stream<Item = Result<Emit, Infallible>>, json_lines::AsResponse>, Error>
-{
-    let (engine, provider_selector) = repo
-        .find(id, req.client_secret)
-        .await?
-        .ok_or(Error::EngineNotFound)?
-        .into_engine_and_selector();
-    let (work, pos) = req.work.sanitize(&engine)?;
+) -> Result<
+    Either<
+        JsonLines<impl Stream<Item = Result<Frame, Infallible>>, json_lines::AsResponse>,
+        Response,
+    >,
+    Error,
+> {
+    if clients.maintenance.is_enabled() {
+        return Err(Error::Unavailable(Unavailable::Maintenance));
+    }
+    if !clients.work_opt.accepts_client_secret(&req.client_secret) {
+        return Err(Error::ShortClientSecret);
+    }
+    let deadline = deadline.map(|TypedHeader(RequestDeadline(left))| Instant::now() + left);
+    let permit = clients
+        .streams
+        .try_acquire()
+        .ok_or(Error::Unavailable(Unavailable::TooManyStreams))?;
+    let (engine, provider_selector) = find_enabled(clients.repo, id, req.client_secret).await?;
+    let (mut work, pos) = req
+        .work
+        .sanitize(&engine, clients.work_opt)
+        .inspect_err(|err| clients.metrics.record_invalid_work(err))?;
+    if let Some(deadline) = deadline {
+        work.set_deadline(deadline);
+    }
+    // The requester does not wait for the result.
+    if let Some(url) = work.callback_url().cloned() {
+        drop(permit);
+        let requested = work.clone();
+        task::spawn(async move {
+            let res = dispatch(clients, provider_selector, engine, work, pos).await;
+            deliver_callback(clients.webhooks, url, &requested, res).await;
+        });
+        return Ok(Either::E2(StatusCode::ACCEPTED.into_response()));
+    }
+    let rx = dispatch(clients, provider_selector, engine, work, pos).await?;
+    if accepts_binary(&headers) {
+        return Ok(Either::E2(
+            (
+                [(header::CONTENT_TYPE, "application/octet-stream")],
+                Body::from_stream(frames(rx).map(move |frame| {
+                    let _permit = &permit;
+                    Ok::<_, Infallible>(frame.to_binary())
+                })),
+            )
+                .into_response(),
+        ));
+    }
+    Ok(Either::E1(JsonLines::new(frames(rx).map(move |frame| {
+        let _permit = &permit;
+        Ok::<_, Infallible>(frame)
+    }))))
+}
+
+/// Result of a job, posted to the `callbackUrl` of the work.
+#[derive(Serialize)]
+struct CallbackBody {
+    /// The last analysis, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    analysis: Option<Emit>,
+    /// How the job ended, like the last frame of the stream.
+    #[serde(flatten)]
+    result: Frame,
+}
+
+/// Follows a job for a requester that asked for a callback, and posts its
+/// result once it ends, however it ends.
+async fn deliver_callback(
+    webhooks: &'static Webhooks,
+    url: Url,
+    work: &Work,
+    res: Result<feed::Receiver<Frame>, Error>,
+) {
+    let mut analysis = None;
+    let result = match res {
+        Ok(mut rx) => loop {
+            match rx.recv().await {
+                Some(Frame::Acquired { .. }) => {}
+                Some(Frame::Emit(emit)) => analysis = Some(emit),
+                Some(frame) => break frame,
+                None => {
+                    break Frame::error(
+                        StreamError::Unavailable,
+                        "no provider completed the analysis".to_owned(),
+                        work,
+                    )
+                }
+            }
+        },
+        Err(err) => Frame::error(StreamError::Unavailable, err.to_string(), work),
+    };
+    webhooks
+        .deliver(url, &CallbackBody { analysis, result })
+        .await;
+}
+
+/// Whether the client asked for the binary stream format, instead of the
+/// default JSON lines. Media types with `q=0` are refused.
+fn accepts_binary(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|range| {
+            let mut params = range.split(';');
+            let media = params.next().unwrap_or_default();
+            media
+                .trim()
+                .eq_ignore_ascii_case("application/octet-stream")
+                && !params.any(|param| {
+                    param.split_once('=').is_some_and(|(name, value)| {
+                        name.trim().eq_ignore_ascii_case("q")
+                            && value.trim().parse::<f32>().is_ok_and(|q| q == 0.0)
+                    })
+                })
+        })
+}
+
+#[derive(TypedPath, Deserialize)]
+#[typed_path("/api/external-engine/{id}/analyse-batch")]
+struct AnalyseBatchPath {
+    id: EngineId,
+}
+
+#[axum_macros::debug_handler(state = AppState)]
+async fn analyse_batch(
+    AnalyseBatchPath { id }: AnalyseBatchPath,
+    State(clients): State<Clients>,
+    Json(req): Json<AnalyseBatchRequest>,
+) -> Result<
+    JsonLines<impl Stream<Item = Result<BatchEmit, Infallible>>, json_lines::AsResponse>,
+    Error,
+> {
+    if clients.maintenance.is_enabled() {
+        return Err(Error::Unavailable(Unavailable::Maintenance));
+    }
+    if !clients.work_opt.accepts_client_secret(&req.client_secret) {
+        return Err(Error::ShortClientSecret);
+    }
+    if req.work.len() > clients.work_opt.max_batch_size {
+        return Err(Error::BatchTooLarge);
+    }
+    let permit = clients
+        .streams
+        .try_acquire()
+        .ok_or(Error::Unavailable(Unavailable::TooManyStreams))?;
+    let (engine, provider_selector) = find_enabled(clients.repo, id, req.client_secret).await?;
+    Ok(JsonLines::new(
+        batch_stream(clients, provider_selector, engine, req.work).map(move |emit| {
+            let _permit = &permit;
+            Ok(emit)
+        }),
+    ))
+}
+
+async fn dispatch(
+    clients: Clients,
+    provider_selector: ProviderSelector,
+    engine: Engine,
+    work: Work,
+    pos: VariantPosition,
+) -> Result<feed::Receiver<Frame>, Error> {
+    dispatch_after(
+        clients,
+        provider_selector,
+        engine,
+        work,
+        pos,
+        future::ready(()),
+    )
+    .await
+}
+
+/// Like `dispatch`, but the match timeout only starts once `idle`
+/// completes. The job can be picked up before that.
+async fn dispatch_after(
+    clients: Clients,
+    provider_selector: ProviderSelector,
+    engine: Engine,
+    work: Work,
+    pos: VariantPosition,
+    idle: impl Future<Output = ()>,
+) -> Result<feed::Receiver<Frame>, Error> {
+    let Clients {
+        hub,
+        sessions,
+        audit,
+        work_opt,
+        ..
+    } = clients;
+    // Nothing to search, so answer without a provider.
+    if let Some(frame) = Frame::game_over(&pos, &work) {
+        let (tx, rx) = feed::channel(MAX_QUEUED_FRAMES, Frame::coalesce);
+        let _: Result<_, _> = tx.send(frame);
+        return Ok(rx);
+    }
+    let queued_at = Instant::now();
+    let (engine_id, variant) = (engine.id.clone(), work.variant());
+    // Record jobs that never reach a provider.
+    let fail = |outcome: Reason, err: Error| {
+        audit.record(AuditEntry {
+            id: None,
+            engine: engine_id.clone(),
+            variant: variant.uci(),
+            outcome,
+            duration_ms: millis(queued_at.elapsed()),
+        });
+        err
+    };
+    if hub.is_paused(&provider_selector) && !hub.is_online(&provider_selector) {
+        return Err(fail(
+            Reason::ProviderPaused,
+            Error::Unavailable(Unavailable::ProviderPaused),
+        ));
+    }
+    if !hub.is_online(&provider_selector) {
+        return Err(fail(
+            Reason::NoProvider,
+            Error::Unavailable(Unavailable::NoProvider),
+        ));
+    }
+    let session = sessions.join(
+        engine.config.client_secret.clone(),
+        work.session_id().clone(),
+    );
+    let deadline = work.deadline();
+    let match_timeout = work_opt.match_timeout(&work);
     let (tx, rx) = oneshot::channel();
     hub.submit(
-        provider_selector,
+        provider_selector.clone(),
         Job {
             tx,
+            info_frames_left: work.max_info_frames(),
             engine,
             work,
             pos,
+            selector: provider_selector,
+            redispatches: 0,
+            played: session.played(),
+            queued_at,
+            session,
+            deeper: Arc::default(),
         },
-    );
-    let rx = timeout(Duration::from_secs(15), rx)
-        .await
-        .map_err(|_: Elapsed| Error::ProviderTimeout)??;
-    Ok(JsonLines::new(
-        ReceiverStream::new(rx).map(Ok::<_, Infallible>),
-    ))
+    )
+    .map_err(|err| fail(Reason::QueueFull, err.into()))?;
+    let picked_up = async {
+        select! {
+            res = rx => Some(res),
+            () = async {
+                idle.await;
+                sleep(match_timeout).await;
+            } => None,
+        }
+    };
+    let res = match deadline {
+        Some(deadline) => timeout_at(deadline, picked_up).await.ok().flatten(),
+        None => picked_up.await,
+    };
+    match res {
+        Some(Ok(rx)) => Ok(rx),
+        _ if deadline.is_some_and(|deadline| deadline <= Instant::now()) => {
+            Err(fail(Reason::Deadline, Error::DeadlineExceeded))
+        }
+        Some(Err(err)) => Err(fail(Reason::Cancel, err.into())),
+        None => Err(fail(
+            Reason::NotPickedUp,
+            Error::Unavailable(Unavailable::NotPickedUp),
+        )),
+    }
 }
 
-#[derive(TypedPath, Deserialize)]
-#[typed_path("/api/external-engine/work")]
-struct AcquirePath;
+/// Frames for a single subscriber. Emits that no subscriber has seen yet are
+/// already coalesced when sent, so a subscriber that falls behind skips to
+/// the latest line for each multipv slot.
+fn frames(rx: feed::Receiver<Frame>) -> impl Stream<Item = Frame> {
+    stream::unfold(rx, |mut rx| async move {
+        let frame = rx.recv().await?;
+        Some((frame, rx))
+    })
+}
+
+/// Analyses all items concurrently, but hands them to providers in order.
+/// The match timeout of an item only starts once it is next in line and no
+/// earlier item is still being analysed, so that items beyond the capacity
+/// of the providers wait for a free slot instead of failing.
+fn batch_stream(
+    clients: Clients,
+    provider_selector: ProviderSelector,
+    engine: Engine,
+    works: Vec<Work>,
+) -> impl Stream<Item = BatchEmit> {
+    let head = Arc::new(Mutex::new(()));
+    let running = Arc::new(watch::Sender::new(0usize));
+    stream::select_all(works.into_iter().enumerate().map(|(index, work)| {
+        let sanitized = work
+            .sanitize(&engine, clients.work_opt)
+            .inspect_err(|err| clients.metrics.record_invalid_work(err));
+        let provider_selector = provider_selector.clone();
+        let engine = engine.clone();
+        let head = Arc::clone(&head);
+        let running = Arc::clone(&running);
+        async move {
+            let (work, pos) = sanitized?;
+            let _head = head.lock().await;
+            let mut idle = running.subscribe();
+            let rx = dispatch_after(clients, provider_selector, engine, work, pos, async move {
+                let _: Result<_, _> = idle.wait_for(|running| *running == 0).await;
+            })
+            .await?;
+            Ok::<_, Error>((rx, Running::new(running)))
+        }
+        .map(move |res| match res {
+            Ok((rx, running)) => frames(rx)
+                .map(move |frame| {
+                    let _running = &running;
+                    BatchEmit::frame(index, frame)
+                })
+                .left_stream(),
+            Err(err) => {
+                stream::once(async move { BatchEmit::error(index, err.to_string()) }).right_stream()
+            }
+        })
+        .flatten_stream()
+        .boxed()
+    }))
+}
 
-struct AcquireTimeout;
+/// Counts a batch item as being analysed, until dropped.
+struct Running(Arc<watch::Sender<usize>>);
 
-impl IntoResponse for AcquireTimeout {
-    fn into_response(self) -> Response {
-        StatusCode::NO_CONTENT.into_response()
+impl Running {
+    fn new(running: Arc<watch::Sender<usize>>) -> Running {
+        running.send_modify(|running| *running += 1);
+        Running(running)
     }
 }
 
-#[axum_macros::debug_handler(state = AppState)]
-async fn acquire(
-    _: AcquirePath,
-    State(hub): State<&'static Hub<ProviderSelector, Job>>,
-    State(ongoing): State<&'static Ongoing<JobId, Job>>,
-    Json(req): Json<AcquireRequest>,
-) -> Result<Json<AcquireResponse>, AcquireTimeout> {
-    let selector = req.provider_secret.selector();
-    let job = timeout(Duration::from_secs(10), hub.acquire(selector))
-        .await
-        .map_err(|_: Elapsed| AcquireTimeout)?;
-    let id = JobId::random();
-    let response = AcquireResponse {
-        id: id.clone(),
-        engine: job.engine.clone(),
-        work: job.work.clone(),
-    };
-    ongoing.add(id, job);
-    Ok(Json(response))
+impl Drop for Running {
+    fn drop(&mut self) {
+        self.0.send_modify(|running| *running -= 1);
+    }
 }
 
 #[derive(TypedPath, Deserialize)]
-#[typed_path("/api/external-engine/work/{id}")]
-struct SubmitPath {
-    id: JobId,
-}
+#[typed_path("/api/external-engine/compare")]
+struct ComparePath;
 
+/// Analyses the same position with two engines of the same user, and
+/// multiplexes both streams. If one engine is unknown, disabled or
+/// unavailable, the other continues alone.
 #[axum_macros::debug_handler(state = AppState)]
-async fn submit(
-    SubmitPath { id }: SubmitPath,
-    State(ongoing): State<&'static Ongoing<JobId, Job>>,
-    body: Body,
-) -> Result<(), Error> {
-    let work = ongoing.remove(&id).ok_or(Error::WorkNotFound)?;
-    let (tx, rx) = mpsc::channel(1);
-    let _: Result<(), _> = work.tx.send(rx);
+async fn compare(
+    _: ComparePath,
+    State(clients): State<Clients>,
+    Json(req): Json<CompareRequest>,
+) -> Result<
+    JsonLines<impl Stream<Item = Result<CompareEmit, Infallible>>, json_lines::AsResponse>,
+    Error,
+> {
+    if clients.maintenance.is_enabled() {
+        return Err(Error::Unavailable(Unavailable::Maintenance));
+    }
+    if req.engines.iter().any(|engine| {
+        !clients
+            .work_opt
+            .accepts_client_secret(&engine.client_secret)
+    }) {
+        return Err(Error::ShortClientSecret);
+    }
+    let permit = clients
+        .streams
+        .try_acquire()
+        .ok_or(Error::Unavailable(Unavailable::TooManyStreams))?;
+    let mut engines = Vec::with_capacity(req.engines.len());
+    for CompareEngine { id, client_secret } in req.engines {
+        // Unknown or disabled engines are reported in their own stream.
+        engines.push(
+            match find_enabled(clients.repo, id.clone(), client_secret).await {
+                Ok(found) => Ok(found),
+                Err(
+                    err @ (Error::EngineNotFound | Error::Unavailable(Unavailable::EngineDisabled)),
+                ) => Err((id, err)),
+                Err(err) => return Err(err),
+            },
+        );
+    }
+    if engines
+        .iter()
+        .filter_map(|found| found.as_ref().ok())
+        .collect::<Vec<_>>()
+        .windows(2)
+        .any(|pair| pair[0].0.config.user_id != pair[1].0.config.user_id)
+    {
+        return Err(Error::Forbidden);
+    }
+    let work = req.work;
+    Ok(JsonLines::new(
+        stream::select_all(engines.into_iter().map(|found| {
+            let (engine, provider_selector) = match found {
+                Ok(found) => found,
+                Err((id, err)) => {
+                    return stream::once(async move { CompareEmit::error(id, err.to_string()) })
+                        .boxed();
+                }
+            };
+            let id = engine.id.clone();
+            let sanitized = work
+                .clone()
+                .sanitize(&engine, clients.work_opt)
+                .inspect_err(|err| clients.metrics.record_invalid_work(err));
+            async move {
+                let (work, pos) = sanitized?;
+                dispatch(clients, provider_selector, engine, work, pos).await
+            }
+            .map(move |res| match res {
+                Ok(rx) => {
+                    let id = id.clone();
+                    frames(rx)
+                        .map(move |frame| CompareEmit::frame(id.clone(), frame))
+                        .left_stream()
+                }
+                Err(err) => stream::once(async move { CompareEmit::error(id, err.to_string()) })
+                    .right_stream(),
+            })
+            .flatten_stream()
+            .boxed()
+        }))
+        .map(move |emit| {
+            let _permit = &permit;
+            Ok(emit)
+        }),
+    ))
+}
+
+#[derive(TypedPath, Deserialize)]
+#[typed_path("/api/external-engine/challenge")]
+struct ChallengePath;
+
+/// Issues a nonce for providers that authenticate with their provider key
+/// rather than the provider secret.
+#[axum_macros::debug_handler(state = AppState)]
+async fn challenge(
+    _: ChallengePath,
+    State(challenges): State<&'static Challenges>,
+) -> JsonResponse<ChallengeResponse> {
+    JsonResponse(ChallengeResponse {
+        nonce: challenges.issue(),
+    })
+}
+
+/// Resolves the selector of an authenticated provider. The plain provider
+/// secret takes precedence over a challenge answer.
+async fn authenticate(
+    repo: &'static dyn EngineStore,
+    challenges: &Challenges,
+    auth: &ProviderAuth,
+) -> Result<ProviderSelector, Error> {
+    if let Some(ref secret) = auth.provider_secret {
+        return Ok(secret.selector());
+    }
+    let answer = auth.challenge.as_ref().ok_or(Error::MissingProviderAuth)?;
+    if !challenges.redeem(&answer.nonce) {
+        return Err(Error::InvalidSignature);
+    }
+    let key = repo
+        .provider_key(answer.provider_selector.clone())
+        .await?
+        .ok_or(Error::InvalidSignature)?;
+    if key.verify(answer.nonce.as_bytes(), &answer.signature) {
+        Ok(answer.provider_selector.clone())
+    } else {
+        Err(Error::InvalidSignature)
+    }
+}
+
+#[derive(TypedPath, Deserialize)]
+#[typed_path("/api/external-engine/work")]
+struct AcquirePath;
+
+#[axum_macros::debug_handler(state = AppState)]
+async fn acquire(
+    _: AcquirePath,
+    State(providers): State<Providers>,
+    State(job_ids): State<&'static dyn JobIdSource>,
+    State(challenges): State<&'static Challenges>,
+    Connection(connection): Connection,
+    Json(req): Json<AcquireRequest>,
+) -> Result<Either<JsonResponse<AcquireResponse>, Response>, Error> {
+    let selector = authenticate(providers.repo, challenges, &req.auth).await?;
+    if let Some(ref handshake) = req.handshake {
+        check_handshake(
+            providers.repo,
+            providers.metrics,
+            &selector,
+            handshake,
+            &req,
+        )
+        .await?;
+    }
+    if !req.keep_alive {
+        return match acquire_job(providers, job_ids, connection, selector, req).await {
+            Some(res) => Ok(Either::E1(JsonResponse(res))),
+            None => match providers.work_opt.acquire_empty_status {
+                AcquireEmptyStatus::NoContent => Err(Error::NoWork),
+                AcquireEmptyStatus::Ok => Ok(Either::E2(
+                    JsonResponse(serde_json::Map::new()).into_response(),
+                )),
+            },
+        };
+    }
+
+    // Dropping the body, e.g. when the provider disconnects, also stops
+    // waiting for work.
+    let every = Duration::from_secs(providers.work_opt.acquire_keep_alive);
+    let state = (
+        acquire_job(providers, job_ids, connection, selector, req).boxed(),
+        interval_at(Instant::now() + every, every),
+    );
+    let lines = stream::unfold(Some(state), |state| async move {
+        let (mut acquired, mut keep_alive) = state?;
+        select! {
+            res = &mut acquired => res.map(|res| {
+                let mut line = serde_json::to_vec(&res).expect("serialize acquire response");
+                line.push(b'\n');
+                (line, None)
+            }),
+            _ = keep_alive.tick() => Some((b"\n".to_vec(), Some((acquired, keep_alive)))),
+        }
+    });
+    Ok(Either::E2(
+        (
+            [(header::CONTENT_TYPE, "application/x-ndjson")],
+            Body::from_stream(lines.map(Ok::<_, Infallible>)),
+        )
+            .into_response(),
+    ))
+}
+
+/// Checks the engine reported by a provider against the engines it acquires
+/// work for, and records it for telemetry. Also records the options of the
+/// engine.
+async fn check_handshake(
+    repo: &'static dyn EngineStore,
+    metrics: &Metrics,
+    selector: &ProviderSelector,
+    handshake: &ProviderHandshake,
+    req: &AcquireRequest,
+) -> Result<(), Error> {
+    for engine in repo.list_by_provider(selector.clone()).await? {
+        let (engine, _) = engine.into_engine_and_selector();
+        if !req.serves(&engine.config) {
+            continue;
+        }
+        handshake.check(&engine.config, req)?;
+        if !handshake.options.is_empty() && engine.config.allowed_options != handshake.options {
+            repo.update_options(engine.id.clone(), handshake.options.clone())
+                .await?;
+        }
+        if engine.config.name != handshake.name {
+            log::warn!(
+                "provider reports {:?} for engine {} registered as {:?}",
+                handshake.name,
+                engine.id.0,
+                engine.config.name
+            );
+        }
+    }
+    metrics.record_provider(selector, &handshake.name, handshake.version.as_deref());
+    Ok(())
+}
+
+/// Waits up to `--acquire-timeout` for a job the provider accepts, and
+/// starts it.
+async fn acquire_job(
+    providers: Providers,
+    job_ids: &'static dyn JobIdSource,
+    connection: Option<ConnectionId>,
+    selector: ProviderSelector,
+    req: AcquireRequest,
+) -> Option<AcquireResponse> {
+    let wait = Duration::from_secs(providers.work_opt.acquire_timeout);
+    let job = timeout(
+        wait,
+        providers
+            .hub
+            .acquire(selector.clone(), |job| req.accepts(&job.work)),
+    )
+    .await
+    .ok()??;
+    let id = job_ids.next_id();
+    let now = Instant::now();
+    let response = AcquireResponse {
+        id: id.clone(),
+        engine: job.engine.clone(),
+        work: job.work.clone(),
+        queued_ms: millis(now.duration_since(job.queued_at)),
+        deadline_ms: job
+            .work
+            .deadline()
+            .map(|deadline| millis(deadline.saturating_duration_since(now))),
+    };
+    if let Some(ponder) = job.work.ponder() {
+        providers.ponders.add(
+            id.clone(),
+            selector.clone(),
+            job.played.clone(),
+            ponder.clone(),
+        );
+    }
+    let client_secret = job.engine.config.client_secret.clone();
+    let depth = job.work.depth();
+    let acquired = AcquiredJob {
+        connection,
+        ..job.start(&id, providers.hub.busy(selector))
+    };
+    providers.subscriptions.add(
+        id.clone(),
+        Subscription {
+            tx: acquired.tx.downgrade(),
+            client_secret,
+            depth,
+            deeper: Arc::clone(&acquired.deeper),
+        },
+    );
+    providers.ongoing.add(id.clone(), acquired);
+    task::spawn(release_unstarted_job(providers, id));
+    Some(response)
+}
+
+/// Hands the job to another provider if the provider that acquired it does
+/// not start submitting in time, e.g. because it stalled.
+async fn release_unstarted_job(providers: Providers, id: JobId) {
+    sleep(Duration::from_secs(providers.work_opt.start_timeout)).await;
+    if let Some(held) = providers.ongoing.remove(&id) {
+        log::warn!(
+            "provider {} did not start job {id} in time",
+            held.selector.as_str()
+        );
+        providers.ponders.remove(&id);
+        providers.hub.record_outcome(held.selector.clone(), false);
+        requeue_held_job(
+            providers,
+            id,
+            held,
+            StreamError::StartTimeout,
+            "provider did not start in time",
+        );
+    }
+}
+
+fn millis(duration: Duration) -> u64 {
+    u64::try_from(duration.as_millis()).unwrap_or(u64::MAX)
+}
+
+#[derive(TypedPath, Deserialize)]
+#[typed_path("/api/external-engine/heartbeat")]
+struct HeartbeatPath;
+
+#[axum_macros::debug_handler(state = AppState)]
+async fn heartbeat(
+    _: HeartbeatPath,
+    State(hub): State<&'static Hub<ProviderSelector, Job>>,
+    State(repo): State<&'static dyn EngineStore>,
+    State(challenges): State<&'static Challenges>,
+    Json(req): Json<HeartbeatRequest>,
+) -> Result<StatusCode, Error> {
+    hub.heartbeat(authenticate(repo, challenges, &req.auth).await?);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(TypedPath, Deserialize)]
+#[typed_path("/api/external-engine/work/{id}")]
+struct SubmitPath {
+    id: JobId,
+}
+
+#[axum_macros::debug_handler(state = AppState)]
+async fn submit(
+    SubmitPath { id }: SubmitPath,
+    State(providers): State<Providers>,
+    State(in_flight): State<&'static InFlight>,
+    body: Body,
+) -> Result<(), Error> {
+    let Providers {
+        ongoing,
+        ponders,
+        metrics,
+        audit,
+        work_opt,
+        ..
+    } = providers;
+    let _in_flight = in_flight.track();
+    let work = ongoing.remove(&id).ok_or_else(|| {
+        if ongoing.is_gone(&id) {
+            Error::WorkGone
+        } else {
+            Error::WorkNotFound
+        }
+    })?;
+    // Also on early returns, so that a provider that reuses its connection
+    // for the next job does not leave anything behind.
+    let pondering = ponders.guard(id.clone());
+    let tx = &work.tx;
+    let nps = metrics.track_nps(id.clone(), work.engine.id.clone());
+    metrics.record_job(work.work.tag());
 
-    let stream = body
-        .into_data_stream()
-        .map_err(|err| io::Error::new(io::ErrorKind::Other, err));
+    let stream = body.into_data_stream().map_err(io::Error::other);
     let read = StreamReader::new(stream);
-    let mut lines = read.lines();
+    let mut lines = BoundedLines::new(read, work_opt.max_line_len);
 
-    let mut emit = Emit::default();
+    let mut emit = Emit::new(&work.work, work_opt.max_pv_len);
+    let mut throttle =
+        Throttle::new(work_opt.info_interval()).with_max_frames(work.info_frames_left);
+    let mut summary =
+        JobSummary::new(work.engine.id.clone(), &work.work).with_audit(audit, id.clone());
+    let mut malformed = 0;
+    let mut ending = Ending::Disconnect;
+    let mut started = false;
+    let start_deadline = work.acquired_at + Duration::from_secs(work_opt.start_timeout);
+    let analysis_deadline = work_opt
+        .analysis_timeout(&work.work)
+        .map(|timeout| work.acquired_at + timeout);
 
-    while let Some(line) = select! {
-        maybe_line = lines.next_line() => maybe_line?,
+    'lines: while let Some(line) = select! {
+        maybe_line = lines.next_line() => match maybe_line {
+            Ok(maybe_line) => maybe_line,
+            Err(err) => {
+                ending = Ending::ReadFailed(err.into());
+                None
+            }
+        },
         _ = tx.closed() => {
             log::info!("requester gone away");
+            ending = Ending::Cancel;
+            None
+        },
+        _ = work.session.cancelled() => {
+            log::info!("session cancelled");
+            ending = Ending::Cancel;
+            None
+        },
+        _ = sleep_until(work.work.deadline().unwrap_or_else(Instant::now)), if work.work.deadline().is_some() => {
+            log::info!("request deadline passed");
+            ending = Ending::Deadline;
+            None
+        },
+        _ = sleep_until(analysis_deadline.unwrap_or_else(Instant::now)), if analysis_deadline.is_some() => {
+            log::info!("provider {} did not complete analysis in time", work.selector.as_str());
+            ending = Ending::AnalysisTimeout;
             None
         },
+        _ = sleep_until(start_deadline), if !started => {
+            log::warn!("provider {} sent no analysis in time", work.selector.as_str());
+            ending = Ending::Requeue { stalled: true };
+            None
+        },
+        _ = sleep_until(throttle.due().unwrap_or_else(Instant::now)), if throttle.due().is_some() => {
+            throttle.take_pending();
+            let _: Result<_, _> = tx.send_merged(emit.clone().into());
+            continue 'lines;
+        },
     } {
-        if let Some(uci) = UciOut::from_line(&line)? {
+        let Ok(line) = line else {
+            log::warn!("dropping line longer than {} bytes", work_opt.max_line_len);
+            continue;
+        };
+        if uci::is_requeue(&line) {
+            log::info!("provider requeued job");
+            ending = Ending::Requeue { stalled: false };
+            break 'lines;
+        }
+        let ucis = match UciOut::from_submitted_line(&line, &work.pos) {
+            Ok(ucis) => ucis,
+            Err(err) => {
+                malformed += 1;
+                if let Some(total) = metrics.record_malformed(&work.selector) {
+                    log::warn!(
+                        "malformed line from provider {} ({total} so far): {err}",
+                        work.selector.as_str()
+                    );
+                }
+                // Skipping a best move would leave the job hanging.
+                if malformed >= work_opt.max_malformed_lines
+                    || matches!(err, uci::ProtocolError::IllegalBestmove(_))
+                {
+                    ending = Ending::Malformed(err.into());
+                    break 'lines;
+                }
+                continue;
+            }
+        };
+        for uci in ucis {
+            started = true;
             emit.update(&uci, &work.pos);
+            summary.update(&uci);
+            if let UciOut::Info { nps: Some(n), .. } = uci {
+                nps.record(n);
+            }
 
-            if matches!(uci, UciOut::Bestmove { .. }) {
-                break;
+            if let UciOut::Bestmove { ref m, .. } = uci {
+                if work
+                    .work
+                    .ensure_depth()
+                    .is_some_and(|depth| emit.depth() < depth)
+                    && work.redispatches < work_opt.max_redispatches
+                {
+                    ending = Ending::TooShallow;
+                    break 'lines;
+                }
+                let deeper = work.deeper.load(Ordering::Relaxed);
+                if work.work.depth().is_some_and(|depth| depth < deeper) {
+                    ending = Ending::Deeper(deeper);
+                    if throttle.take_pending() {
+                        let _: Result<_, _> = tx.send_merged(emit.clone().into());
+                    }
+                    break 'lines;
+                }
+                ending = Ending::Bestmove;
+                if throttle.take_pending() {
+                    let _: Result<_, _> = tx.send_merged(emit.clone().into());
+                }
+                let _: Result<_, _> =
+                    tx.send(Frame::done(m.as_ref(), &emit, &work.pos, &work.work));
+                break 'lines;
             }
 
-            if emit.should_emit() && tx.send(emit.clone()).await.is_err() {
+            if emit.should_emit()
+                && throttle.admit()
+                && tx.send_merged(emit.clone().into()).is_err()
+            {
                 log::info!("requester suddenly gone away");
-                break;
+                ending = Ending::Cancel;
+                break 'lines;
+            }
+
+            // Safety net for providers that would search forever.
+            if emit.should_emit() && work_opt.max_depth.is_some_and(|max| emit.depth() >= max) {
+                log::info!("max depth reached");
+                ending = Ending::MaxDepth;
+                if throttle.take_pending() {
+                    let _: Result<_, _> = tx.send_merged(emit.clone().into());
+                }
+                let _: Result<_, _> =
+                    tx.send(Frame::done(emit.best_move(), &emit, &work.pos, &work.work));
+                break 'lines;
             }
         }
     }
-    Ok(())
+
+    drop(pondering);
+
+    let floor = emit.depth();
+    let info_frames_left = throttle.remaining();
+    end_submission(
+        providers,
+        work,
+        &mut summary,
+        ending,
+        floor,
+        info_frames_left,
+    )
+    .await
+}
+
+/// Ends a job once the provider stopped submitting: tells the requester why
+/// the stream ends early, records the outcome, and hands the job to another
+/// provider if it is not done yet.
+async fn end_submission(
+    providers: Providers,
+    mut work: AcquiredJob,
+    summary: &mut JobSummary,
+    ending: Ending,
+    floor: u32,
+    info_frames_left: Option<u32>,
+) -> Result<(), Error> {
+    let Providers {
+        hub,
+        repo,
+        work_opt,
+        ..
+    } = providers;
+    let reason = ending.reason();
+    summary.set_reason(reason);
+    let may_redispatch = work.redispatches < work_opt.max_redispatches;
+
+    // Tell the requester why the stream ends early.
+    let last = match ending {
+        Ending::Disconnect => Some(Frame::error(
+            StreamError::Disconnected,
+            "provider disconnected before bestmove".to_owned(),
+            &work.work,
+        )),
+        Ending::ReadFailed(ref err) => Some(Frame::error(
+            StreamError::Provider,
+            err.to_string(),
+            &work.work,
+        )),
+        Ending::Malformed(ref err) => Some(Frame::error(
+            StreamError::Protocol,
+            err.to_string(),
+            &work.work,
+        )),
+        Ending::Deadline => Some(Frame::timeout(&work.work)),
+        Ending::AnalysisTimeout => Some(Frame::error(
+            StreamError::AnalysisTimeout,
+            "analysis did not complete in time".to_owned(),
+            &work.work,
+        )),
+        Ending::Requeue { .. } if !may_redispatch => Some(Frame::error(
+            StreamError::Redispatch,
+            "job requeued too often".to_owned(),
+            &work.work,
+        )),
+        Ending::Requeue { .. }
+        | Ending::TooShallow
+        | Ending::Deeper(_)
+        | Ending::Cancel
+        | Ending::Bestmove
+        | Ending::MaxDepth => None,
+    };
+    if let Some(frame) = last {
+        let _: Result<_, _> = work.tx.send(frame);
+    }
+    if matches!(reason, Reason::Disconnect | Reason::Protocol) {
+        release_held_jobs(providers, work.connection);
+    }
+
+    match reason {
+        Reason::Bestmove | Reason::MaxDepth => hub.record_outcome(work.selector.clone(), true),
+        Reason::Disconnect | Reason::Protocol | Reason::Redispatch => {
+            hub.record_outcome(work.selector.clone(), false)
+        }
+        // Not the fault of the provider.
+        Reason::Cancel | Reason::Deadline | Reason::AnalysisTimeout => {}
+        // Only for jobs that were never picked up.
+        Reason::NoProvider | Reason::ProviderPaused | Reason::QueueFull | Reason::NotPickedUp => {}
+    }
+
+    if matches!(
+        ending,
+        Ending::Bestmove | Ending::MaxDepth | Ending::Deeper(_)
+    ) {
+        if let Err(err) = repo.record_analysis(work.engine.id.clone()).await {
+            log::warn!("failed to record analysis: {err}");
+        }
+    }
+
+    let (redispatch, redispatches) = match ending {
+        Ending::TooShallow => (true, work.redispatches + 1),
+        Ending::Requeue { .. } => (may_redispatch, work.redispatches + 1),
+        // Not a retry, so it does not count against the limit.
+        Ending::Deeper(depth) => {
+            work.work.set_depth(depth);
+            (true, work.redispatches)
+        }
+        _ => (false, work.redispatches),
+    };
+    if redispatch {
+        let match_timeout = work_opt.match_timeout(&work.work);
+        let (job_tx, job_rx) = oneshot::channel();
+        let failed = Frame::error(
+            StreamError::Redispatch,
+            Unavailable::QueueFull.to_string(),
+            &work.work,
+        );
+        let tx = work.tx;
+        hub.submit(
+            work.selector.clone(),
+            Job {
+                tx: job_tx,
+                pos: work.pos,
+                engine: work.engine,
+                work: work.work,
+                selector: work.selector,
+                redispatches,
+                played: work.session.played(),
+                queued_at: Instant::now(),
+                session: work.session,
+                info_frames_left,
+                deeper: work.deeper,
+            },
+        )
+        .inspect_err(|_| {
+            let _: Result<_, _> = tx.send(failed);
+        })?;
+        task::spawn(relay(job_rx, tx, match_timeout, floor));
+    }
+
+    match ending {
+        Ending::ReadFailed(err) | Ending::Malformed(err) => Err(err),
+        // The job is no longer for this provider.
+        Ending::Requeue { stalled: true } => Err(Error::WorkGone),
+        _ => Ok(()),
+    }
+}
+
+/// How the submission of a job ended.
+enum Ending {
+    /// The provider ended its stream before `bestmove`.
+    Disconnect,
+    /// Reading from the provider failed.
+    ReadFailed(Error),
+    /// The provider broke the protocol.
+    Malformed(Error),
+    Cancel,
+    Deadline,
+    AnalysisTimeout,
+    /// Handed back by the provider, or it did not start in time.
+    Requeue {
+        stalled: bool,
+    },
+    /// `bestmove` before the ensured depth was reached.
+    TooShallow,
+    /// `bestmove`, but clients asked to continue to this depth.
+    Deeper(u32),
+    Bestmove,
+    MaxDepth,
+}
+
+impl Ending {
+    fn reason(&self) -> Reason {
+        match self {
+            Ending::Disconnect | Ending::ReadFailed(_) => Reason::Disconnect,
+            Ending::Malformed(_) => Reason::Protocol,
+            Ending::Cancel => Reason::Cancel,
+            Ending::Deadline => Reason::Deadline,
+            Ending::AnalysisTimeout => Reason::AnalysisTimeout,
+            Ending::Requeue { .. } | Ending::TooShallow => Reason::Redispatch,
+            Ending::Bestmove | Ending::Deeper(_) => Reason::Bestmove,
+            Ending::MaxDepth => Reason::MaxDepth,
+        }
+    }
+}
+
+#[derive(TypedPath, Deserialize)]
+#[typed_path("/api/external-engine/session/{session_id}/cancel")]
+struct CancelSessionPath {
+    session_id: SessionId,
+}
+
+/// Stops all jobs of the session, e.g. when the analysis board is closed.
+/// Requesters that are still subscribed see their streams end.
+#[axum_macros::debug_handler(state = AppState)]
+async fn cancel_session(
+    CancelSessionPath { session_id }: CancelSessionPath,
+    State(sessions): State<&'static Sessions>,
+    Json(req): Json<CancelSessionRequest>,
+) -> StatusCode {
+    sessions.cancel(req.client_secret, session_id);
+    StatusCode::NO_CONTENT
+}
+
+#[derive(TypedPath, Deserialize)]
+#[typed_path("/api/external-engine/job/{id}/subscribe")]
+struct SubscribePath {
+    id: JobId,
+}
+
+/// Attaches another stream to a running job, as announced in its `acquired`
+/// frame, e.g. for analysis shared with other viewers. Requires the client
+/// secret of the engine. The job continues while any subscriber remains.
+#[axum_macros::debug_handler(state = AppState)]
+async fn subscribe(
+    SubscribePath { id }: SubscribePath,
+    State(subscriptions): State<&'static Ongoing<JobId, Subscription>>,
+    State(streams): State<&'static StreamLimit>,
+    Json(req): Json<SubscribeRequest>,
+) -> Result<JsonLines<impl Stream<Item = Result<Frame, Infallible>>, json_lines::AsResponse>, Error>
+{
+    let tx = subscriptions
+        .get(&id)
+        .filter(|subscription| subscription.client_secret == req.client_secret)
+        .and_then(|subscription| subscription.tx.upgrade())
+        .ok_or(Error::WorkNotFound)?;
+    let permit = streams
+        .try_acquire()
+        .ok_or(Error::Unavailable(Unavailable::TooManyStreams))?;
+    Ok(JsonLines::new(frames(tx.subscribe()).map(move |frame| {
+        let _permit = &permit;
+        Ok::<_, Infallible>(frame)
+    })))
+}
+
+#[derive(TypedPath, Deserialize)]
+#[typed_path("/api/external-engine/job/{id}/deeper")]
+struct DeeperPath {
+    id: JobId,
+}
+
+/// Continues a running job to a greater depth, e.g. after a quick first
+/// look. Once the provider reaches its best move, the same position is
+/// handed out again with the new depth, and the streams of the job continue
+/// with the deeper analysis instead of ending. Requires the client secret of
+/// the engine. Jobs that complete before the provider learns about the
+/// request end as usual.
+#[axum_macros::debug_handler(state = AppState)]
+async fn deeper(
+    DeeperPath { id }: DeeperPath,
+    State(subscriptions): State<&'static Ongoing<JobId, Subscription>>,
+    Json(req): Json<DeeperRequest>,
+) -> Result<StatusCode, Error> {
+    let subscription = subscriptions
+        .get(&id)
+        .filter(|subscription| {
+            subscription.client_secret == req.client_secret && !subscription.tx.is_closed()
+        })
+        .ok_or(Error::WorkNotFound)?;
+    if subscription.depth.is_none_or(|depth| depth >= req.depth) {
+        return Err(Error::NotDeeper);
+    }
+    subscription.deeper.fetch_max(req.depth, Ordering::Relaxed);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(TypedPath, Deserialize)]
+#[typed_path("/api/external-engine/session/{session_id}/play")]
+struct PlayPath {
+    session_id: SessionId,
+}
+
+/// Tells providers that are pondering in the session which move was played.
+#[axum_macros::debug_handler(state = AppState)]
+async fn play(
+    PlayPath { session_id }: PlayPath,
+    State(sessions): State<&'static Sessions>,
+    Json(req): Json<PlayRequest>,
+) -> Result<StatusCode, Error> {
+    if sessions.play(req.client_secret, session_id, req.uci) {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(Error::WorkNotFound)
+    }
+}
+
+#[derive(TypedPath, Deserialize)]
+#[typed_path("/api/external-engine/work/{id}/ponder")]
+struct PonderPath {
+    id: JobId,
+}
+
+/// Long-polls for the move played while the provider is pondering on work
+/// that it is still submitting. Responds with 204 No Content if no move was
+/// played in time, so that the provider can poll again. Only the provider
+/// that acquired the work may poll.
+#[axum_macros::debug_handler(state = AppState)]
+async fn ponder(
+    PonderPath { id }: PonderPath,
+    State(ponders): State<&'static Ponders>,
+    State(repo): State<&'static dyn EngineStore>,
+    State(challenges): State<&'static Challenges>,
+    State(work_opt): State<&'static WorkOpt>,
+    Json(req): Json<PonderRequest>,
+) -> Result<Either<JsonResponse<PonderResponse>, StatusCode>, Error> {
+    let selector = authenticate(repo, challenges, &req.auth).await?;
+    let wait = Duration::from_secs(work_opt.acquire_timeout);
+    match timeout(wait, ponders.wait(&id, &selector)).await {
+        Ok(Some(ponderhit)) => Ok(Either::E1(JsonResponse(PonderResponse { ponderhit }))),
+        Ok(None) => Err(Error::WorkNotFound),
+        Err(_) => Ok(Either::E2(StatusCode::NO_CONTENT)),
+    }
+}
+
+#[derive(TypedPath, Deserialize)]
+#[typed_path("/api/external-engine/{id}/stats")]
+struct StatsPath {
+    id: EngineId,
+}
+
+/// Usage of an engine, for dashboards. Authenticated with the client secret
+/// as a bearer token.
+#[axum_macros::debug_handler(state = AppState)]
+async fn stats(
+    StatsPath { id }: StatsPath,
+    State(repo): State<&'static dyn EngineStore>,
+    State(work_opt): State<&'static WorkOpt>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+) -> Result<JsonResponse<StatsResponse>, Error> {
+    let client_secret = ClientSecret::try_from(bearer.token().to_owned())?;
+    if !work_opt.accepts_client_secret(&client_secret) {
+        return Err(Error::ShortClientSecret);
+    }
+    repo.find(id.clone(), client_secret)
+        .await?
+        .ok_or(Error::EngineNotFound)?;
+    let stats = repo.stats(id).await?;
+    Ok(JsonResponse(StatsResponse {
+        analyses: stats.analyses,
+        last_used: stats.last_used.map(|at| at.timestamp_millis()),
+    }))
+}
+
+#[derive(TypedPath, Deserialize)]
+#[typed_path("/api/external-engine/{id}/rotate-secret")]
+struct RotateSecretPath {
+    id: EngineId,
+}
+
+/// Replaces a client secret that may have leaked. Authenticated with the
+/// current secret as a bearer token. Jobs of the old secret are cancelled.
+#[axum_macros::debug_handler(state = AppState)]
+async fn rotate_secret(
+    RotateSecretPath { id }: RotateSecretPath,
+    State(repo): State<&'static dyn EngineStore>,
+    State(sessions): State<&'static Sessions>,
+    State(work_opt): State<&'static WorkOpt>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+) -> Result<JsonResponse<RotateSecretResponse>, Error> {
+    let client_secret = ClientSecret::try_from(bearer.token().to_owned())?;
+    if !work_opt.accepts_client_secret(&client_secret) {
+        return Err(Error::ShortClientSecret);
+    }
+    let new_secret = ClientSecret::random();
+    if !repo
+        .rotate_client_secret(id, client_secret.clone(), new_secret.clone())
+        .await?
+    {
+        return Err(Error::EngineNotFound);
+    }
+    sessions.cancel_client(&client_secret);
+    Ok(JsonResponse(RotateSecretResponse {
+        client_secret: new_secret,
+    }))
+}
+
+#[derive(TypedPath, Deserialize)]
+#[typed_path("/api/external-engine/{id}/enabled")]
+struct SetEnabledPath {
+    id: EngineId,
+}
+
+/// Takes an engine offline without deleting it, or brings it back.
+/// Authenticated with the client secret as a bearer token. Jobs of a
+/// disabled engine are cancelled, so that providers stop receiving them.
+#[axum_macros::debug_handler(state = AppState)]
+async fn set_enabled(
+    SetEnabledPath { id }: SetEnabledPath,
+    State(repo): State<&'static dyn EngineStore>,
+    State(sessions): State<&'static Sessions>,
+    State(work_opt): State<&'static WorkOpt>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Json(req): Json<SetEnabledRequest>,
+) -> Result<StatusCode, Error> {
+    let client_secret = ClientSecret::try_from(bearer.token().to_owned())?;
+    if !work_opt.accepts_client_secret(&client_secret) {
+        return Err(Error::ShortClientSecret);
+    }
+    if !repo
+        .set_enabled(id.clone(), client_secret.clone(), req.enabled)
+        .await?
+    {
+        return Err(Error::EngineNotFound);
+    }
+    if !req.enabled {
+        sessions.cancel_client(&client_secret);
+    }
+    log::info!(
+        "engine {id} {}",
+        if req.enabled { "enabled" } else { "disabled" }
+    );
+    Ok(StatusCode::NO_CONTENT)
+}
+
+fn authorize_admin(
+    admin_token: Option<&AdminToken>,
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+) -> Result<(), Error> {
+    if admin_token
+        .zip(bearer)
+        .is_some_and(|(token, TypedHeader(Authorization(bearer)))| token.matches(bearer.token()))
+    {
+        Ok(())
+    } else {
+        Err(Error::Forbidden)
+    }
+}
+
+#[derive(TypedPath, Deserialize)]
+#[typed_path("/metrics")]
+struct MetricsPath;
+
+/// Metrics in the Prometheus text format, for scraping with the admin token.
+#[axum_macros::debug_handler(state = AppState)]
+async fn metrics(
+    _: MetricsPath,
+    State(metrics): State<&'static Metrics>,
+    State(admin_token): State<Option<&'static AdminToken>>,
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+) -> Result<impl IntoResponse, Error> {
+    authorize_admin(admin_token, bearer)?;
+    Ok((
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        metrics.render(),
+    ))
+}
+
+#[derive(TypedPath, Deserialize)]
+#[typed_path("/api/admin/maintenance")]
+struct MaintenancePath;
+
+/// Toggles maintenance mode. New analysis requests are rejected, but
+/// providers keep draining the jobs that were already dispatched.
+#[axum_macros::debug_handler(state = AppState)]
+async fn maintenance(
+    _: MaintenancePath,
+    State(maintenance): State<&'static Maintenance>,
+    State(admin_token): State<Option<&'static AdminToken>>,
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+    Json(req): Json<MaintenanceRequest>,
+) -> Result<StatusCode, Error> {
+    authorize_admin(admin_token, bearer)?;
+    maintenance.set_enabled(req.enabled);
+    log::warn!(
+        "maintenance mode {}",
+        if req.enabled { "enabled" } else { "disabled" }
+    );
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(TypedPath, Deserialize)]
+#[typed_path("/api/admin/shutdown")]
+struct ShutdownPath;
+
+/// Shuts down gracefully, like on a signal. Not found unless started with
+/// `--allow-remote-shutdown`.
+#[axum_macros::debug_handler(state = AppState)]
+async fn remote_shutdown(
+    _: ShutdownPath,
+    State(remote_shutdown): State<Option<&'static RemoteShutdown>>,
+    State(admin_token): State<Option<&'static AdminToken>>,
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+) -> Result<StatusCode, Error> {
+    let Some(remote_shutdown) = remote_shutdown else {
+        return Ok(StatusCode::NOT_FOUND);
+    };
+    authorize_admin(admin_token, bearer)?;
+    log::warn!("remote shutdown requested");
+    remote_shutdown.request();
+    Ok(StatusCode::ACCEPTED)
+}
+
+#[derive(TypedPath, Deserialize)]
+#[typed_path("/api/admin/engines/purge")]
+struct PurgePath;
+
+/// Deletes engines that were neither used nor registered within the given
+/// number of days.
+#[axum_macros::debug_handler(state = AppState)]
+async fn purge(
+    _: PurgePath,
+    State(repo): State<&'static dyn EngineStore>,
+    State(admin_token): State<Option<&'static AdminToken>>,
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+    Query(query): Query<PurgeQuery>,
+) -> Result<JsonResponse<PurgeResponse>, Error> {
+    authorize_admin(admin_token, bearer)?;
+    let age = Duration::from_secs(u64::from(query.days) * 24 * 60 * 60);
+    let before = SystemTime::now()
+        .checked_sub(age)
+        .map_or(DateTime::MIN, DateTime::from_system_time);
+    let stale = repo.stale(before).await?;
+    if !query.dry_run {
+        for id in &stale {
+            repo.delete(id.clone()).await?;
+        }
+        log::warn!("purged {} stale engines", stale.len());
+    }
+    Ok(JsonResponse(PurgeResponse {
+        purged: stale.len(),
+        dry_run: query.dry_run,
+    }))
+}
+
+#[derive(TypedPath, Deserialize)]
+#[typed_path("/api/admin/engines/health")]
+struct HealthPath;
+
+/// Overview of all providers known to this instance, ordered by selector.
+#[axum_macros::debug_handler(state = AppState)]
+async fn health(
+    _: HealthPath,
+    State(hub): State<&'static Hub<ProviderSelector, Job>>,
+    State(admin_token): State<Option<&'static AdminToken>>,
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+    Query(query): Query<HealthQuery>,
+) -> Result<JsonResponse<HealthResponse>, Error> {
+    authorize_admin(admin_token, bearer)?;
+
+    let mut providers = hub.health();
+    providers.sort_unstable_by(|(a, _), (b, _)| a.as_str().cmp(b.as_str()));
+    let total = providers.len();
+    let now = SystemTime::now();
+    Ok(JsonResponse(HealthResponse {
+        total,
+        providers: providers
+            .into_iter()
+            .skip(query.offset)
+            .take(query.limit())
+            .map(|(selector, queue)| ProviderHealth {
+                selector,
+                connected: queue.connected,
+                last_seen: queue.last_seen.and_then(|at| {
+                    let since_epoch = now
+                        .checked_sub(at.elapsed())?
+                        .duration_since(UNIX_EPOCH)
+                        .ok()?;
+                    u64::try_from(since_epoch.as_millis()).ok()
+                }),
+                queued: queue.queued,
+                completion_rate: queue.completion_rate,
+                paused: queue.paused,
+            })
+            .collect(),
+    }))
+}
+
+#[derive(TypedPath, Deserialize)]
+#[typed_path("/api/admin/jobs/recent")]
+struct RecentJobsPath;
+
+/// Most recently finished jobs, newest first.
+#[axum_macros::debug_handler(state = AppState)]
+async fn recent_jobs(
+    _: RecentJobsPath,
+    State(audit): State<&'static AuditLog>,
+    State(admin_token): State<Option<&'static AdminToken>>,
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+) -> Result<JsonResponse<RecentJobsResponse>, Error> {
+    authorize_admin(admin_token, bearer)?;
+    Ok(JsonResponse(RecentJobsResponse {
+        jobs: audit.recent(),
+    }))
+}
+
+/// Once a provider disconnects, hands the jobs that it acquired on the same
+/// connection but did not start submitting to other providers. Jobs that
+/// were already handed over too often end with an error frame instead.
+///
+/// Other providers of the same engine are not affected.
+fn release_held_jobs(providers: Providers, connection: Option<ConnectionId>) {
+    let Some(connection) = connection else {
+        return;
+    };
+    for (id, held) in providers
+        .ongoing
+        .remove_matching(|held| held.connection == Some(connection))
+    {
+        providers.ponders.remove(&id);
+        providers.hub.record_outcome(held.selector.clone(), false);
+        requeue_held_job(
+            providers,
+            id,
+            held,
+            StreamError::Disconnected,
+            "provider disconnected before starting",
+        );
+    }
+}
+
+/// Hands a job that was acquired but not started to another provider, or
+/// ends it with an error frame if it was already handed over too often.
+fn requeue_held_job(
+    providers: Providers,
+    id: JobId,
+    held: AcquiredJob,
+    code: StreamError,
+    reason: &str,
+) {
+    let requeue = held.redispatches < providers.work_opt.max_redispatches;
+    providers.audit.record(AuditEntry {
+        id: Some(id),
+        engine: held.engine.id.clone(),
+        variant: held.work.variant().uci(),
+        outcome: if requeue {
+            Reason::Redispatch
+        } else {
+            Reason::Disconnect
+        },
+        duration_ms: millis(held.acquired_at.elapsed()),
+    });
+    if !requeue {
+        let _: Result<_, _> = held
+            .tx
+            .send(Frame::error(code, reason.to_owned(), &held.work));
+        return;
+    }
+    let failed = Frame::error(
+        StreamError::Redispatch,
+        Unavailable::QueueFull.to_string(),
+        &held.work,
+    );
+    let match_timeout = providers.work_opt.match_timeout(&held.work);
+    let (job_tx, job_rx) = oneshot::channel();
+    let tx = held.tx;
+    let submitted = providers.hub.submit(
+        held.selector.clone(),
+        Job {
+            tx: job_tx,
+            pos: held.pos,
+            engine: held.engine,
+            work: held.work,
+            selector: held.selector,
+            redispatches: held.redispatches + 1,
+            played: held.session.played(),
+            queued_at: Instant::now(),
+            session: held.session,
+            info_frames_left: held.info_frames_left,
+            deeper: held.deeper,
+        },
+    );
+    if submitted.is_err() {
+        let _: Result<_, _> = tx.send(failed);
+        return;
+    }
+    task::spawn(relay(job_rx, tx, match_timeout, 0));
+}
+
+/// Forwards analysis from a redispatched job to the original requester,
+/// skipping everything that is not deeper than what was already sent. Gives
+/// up if no provider picks up the job within `match_timeout`, like
+/// `dispatch`.
+async fn relay(
+    job_rx: oneshot::Receiver<feed::Receiver<Frame>>,
+    tx: feed::Sender<Frame>,
+    match_timeout: Duration,
+    floor: u32,
+) {
+    let mut rx = select! {
+        res = timeout(match_timeout, job_rx) => match res {
+            Ok(Ok(rx)) => rx,
+            Ok(Err(_)) | Err(_) => return,
+        },
+        _ = tx.closed() => return,
+    };
+    loop {
+        let frame = select! {
+            res = rx.recv() => match res {
+                Some(frame) => frame,
+                None => break,
+            },
+            _ = tx.closed() => break,
+        };
+        if matches!(frame, Frame::Emit(ref emit) if emit.depth() <= floor) {
+            continue;
+        }
+        if tx.send_merged(frame).is_err() {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{future::Future, num::NonZeroU32, ops::RangeInclusive};
+
+    use axum::{body::to_bytes, extract::FromRequest, http::Request};
+    use hmac::{Hmac, Mac};
+    use serde_json::{json, Value};
+    use sha2::Sha256;
+    use shakmaty::variant::Variant;
+    use tokio::sync::mpsc;
+    use tokio_stream::wrappers::ReceiverStream;
+    use tower::ServiceExt as _;
+
+    use super::*;
+    use crate::{
+        api::tests::{engine, work},
+        emit::{BINARY_EMIT, BINARY_JSON},
+        model::{ProviderSecret, SequentialJobIds},
+        repo::{tests::MemoryStore, ExternalEngine},
+    };
+
+    async fn frames_of(res: Response) -> Vec<Value> {
+        let body = timeout(
+            Duration::from_secs(1),
+            to_bytes(res.into_body(), usize::MAX),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        body.split(|b| *b == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| serde_json::from_slice(line).unwrap())
+            .collect()
+    }
+
+    /// The full application with an in-memory engine store, driven through
+    /// its HTTP interface.
+    struct Harness {
+        app: Router,
+        store: &'static MemoryStore,
+        ongoing: &'static Ongoing<JobId, AcquiredJob>,
+        ponders: &'static Ponders,
+    }
+
+    impl Harness {
+        async fn new() -> Harness {
+            Harness::with_trust_proxy(false).await
+        }
+
+        async fn with_trust_proxy(trust_proxy: bool) -> Harness {
+            Harness::with(trust_proxy, WorkOpt::default()).await
+        }
+
+        async fn with(trust_proxy: bool, work_opt: WorkOpt) -> Harness {
+            Harness::build(trust_proxy, work_opt, None).await
+        }
+
+        async fn build(
+            trust_proxy: bool,
+            work_opt: WorkOpt,
+            remote_shutdown: Option<&'static RemoteShutdown>,
+        ) -> Harness {
+            let store: &'static MemoryStore = Box::leak(Box::default());
+            let mut engine = engine();
+            engine.config.supports_ponder = true;
+            store
+                .create(
+                    ExternalEngine::new(engine, selector())
+                        .with_provider_key(serde_json::from_value(json!("key")).unwrap()),
+                )
+                .await
+                .unwrap();
+            // Another engine of the same user, with its own provider.
+            let mut other = api::tests::engine();
+            other.id = EngineId("eei_other".to_owned());
+            store
+                .create(ExternalEngine::new(
+                    other,
+                    serde_json::from_value::<ProviderSecret>(json!("other"))
+                        .unwrap()
+                        .selector(),
+                ))
+                .await
+                .unwrap();
+            let state = AppState {
+                repo: store,
+                work_opt: Box::leak(Box::new(work_opt)),
+                remote_shutdown,
+                trust_proxy,
+                ..app_state()
+            };
+            Harness {
+                store,
+                ongoing: state.ongoing,
+                ponders: state.ponders,
+                app: app(state),
+            }
+        }
+
+        fn post(&self, uri: &str, body: Body) -> impl Future<Output = Response> + 'static {
+            let req = Request::post(uri)
+                .header("content-type", "application/json")
+                .body(body)
+                .unwrap();
+            self.app.clone().oneshot(req).map(Result::unwrap)
+        }
+
+        fn get(&self, uri: &str, bearer: &str) -> impl Future<Output = Response> + 'static {
+            let req = Request::get(uri)
+                .header("authorization", format!("Bearer {bearer}"))
+                .body(Body::empty())
+                .unwrap();
+            self.app.clone().oneshot(req).map(Result::unwrap)
+        }
+
+        fn post_admin(
+            &self,
+            uri: &str,
+            bearer: &str,
+            body: Value,
+        ) -> impl Future<Output = Response> + 'static {
+            let req = Request::post(uri)
+                .header("content-type", "application/json")
+                .header("authorization", format!("Bearer {bearer}"))
+                .body(Body::from(body.to_string()))
+                .unwrap();
+            self.app.clone().oneshot(req).map(Result::unwrap)
+        }
+
+        fn post_json(&self, uri: &str, body: Value) -> impl Future<Output = Response> + 'static {
+            self.post(uri, Body::from(body.to_string()))
+        }
+
+        /// Like `post_json`, but as if connected from `ip`.
+        fn post_json_from(
+            &self,
+            ip: &str,
+            uri: &str,
+            body: Value,
+        ) -> impl Future<Output = Response> + 'static {
+            self.post_json_forwarded(ip, None, uri, body)
+        }
+
+        /// Like `post_json_from`, but via a proxy that forwards for
+        /// `forwarded_for`.
+        fn post_json_forwarded(
+            &self,
+            ip: &str,
+            forwarded_for: Option<&str>,
+            uri: &str,
+            body: Value,
+        ) -> impl Future<Output = Response> + 'static {
+            let mut req = Request::post(uri)
+                .header("content-type", "application/json")
+                .extension(ConnectInfo(PeerAddr {
+                    ip: Some(ip.parse().unwrap()),
+                    connection: ConnectionId::next(),
+                }));
+            if let Some(forwarded_for) = forwarded_for {
+                req = req.header("x-forwarded-for", forwarded_for);
+            }
+            let req = req.body(Body::from(body.to_string())).unwrap();
+            self.app.clone().oneshot(req).map(Result::unwrap)
+        }
+
+        fn ponder(
+            &self,
+            id: &JobId,
+            provider_secret: &str,
+        ) -> impl Future<Output = Response> + 'static {
+            self.post_json(
+                &format!("/api/external-engine/work/{id}/ponder"),
+                json!({ "providerSecret": provider_secret }),
+            )
+        }
+
+        async fn heartbeat(&self) {
+            let res = self
+                .post_json(
+                    "/api/external-engine/heartbeat",
+                    json!({ "providerSecret": "secret" }),
+                )
+                .await;
+            assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        }
+
+        fn analyse(&self) -> impl Future<Output = Response> + 'static {
+            self.analyse_with(json!({}))
+        }
+
+        fn analyse_with(&self, extra: Value) -> impl Future<Output = Response> + 'static {
+            let mut work = serde_json::to_value(work(json!({}))).unwrap();
+            work.as_object_mut()
+                .unwrap()
+                .extend(extra.as_object().unwrap().clone());
+            self.post_json(
+                "/api/external-engine/eei_test/analyse",
+                json!({ "clientSecret": "ees_clientsecret", "work": work }),
+            )
+        }
+
+        fn analyse_with_deadline(
+            &self,
+            deadline: &str,
+        ) -> impl Future<Output = Response> + 'static {
+            self.analyse_with_header("request-deadline", deadline)
+        }
+
+        fn analyse_with_header(
+            &self,
+            name: &str,
+            value: &str,
+        ) -> impl Future<Output = Response> + 'static {
+            let body = json!({ "clientSecret": "ees_clientsecret", "work": work(json!({})) });
+            let req = Request::post("/api/external-engine/eei_test/analyse")
+                .header("content-type", "application/json")
+                .header(name, value)
+                .body(Body::from(body.to_string()))
+                .unwrap();
+            self.app.clone().oneshot(req).map(Result::unwrap)
+        }
+
+        async fn acquire(&self) -> JobId {
+            let res = self
+                .post_json(
+                    "/api/external-engine/work",
+                    json!({ "providerSecret": "secret" }),
+                )
+                .await;
+            assert_eq!(res.status(), StatusCode::OK);
+            let body: Value = json_of(res).await;
+            serde_json::from_value(body["id"].clone()).unwrap()
+        }
+
+        /// Like `acquire`, but on the given connection.
+        async fn acquire_on(&self, connection: ConnectionId) -> JobId {
+            let body = json!({ "providerSecret": "secret" });
+            let req = Request::post("/api/external-engine/work")
+                .header("content-type", "application/json")
+                .extension(ConnectInfo(PeerAddr {
+                    ip: None,
+                    connection,
+                }))
+                .body(Body::from(body.to_string()))
+                .unwrap();
+            let res = self.app.clone().oneshot(req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            let body: Value = json_of(res).await;
+            serde_json::from_value(body["id"].clone()).unwrap()
+        }
+
+        /// Answers a fresh challenge, signed with `key`.
+        async fn sign_challenge(&self, key: &str) -> Value {
+            let res = self
+                .post("/api/external-engine/challenge", Body::empty())
+                .await;
+            assert_eq!(res.status(), StatusCode::OK);
+            let body: Value = json_of(res).await;
+            let nonce = body["nonce"].as_str().unwrap();
+            let mut mac = Hmac::<Sha256>::new_from_slice(key.as_bytes()).unwrap();
+            mac.update(nonce.as_bytes());
+            json!({
+                "providerSelector": selector(),
+                "nonce": nonce,
+                "signature": hex::encode(mac.finalize().into_bytes()),
+            })
+        }
+
+        fn submit(&self, id: &JobId, body: Body) -> impl Future<Output = Response> + 'static {
+            self.post(&format!("/api/external-engine/work/{id}"), body)
+        }
+    }
+
+    fn selector() -> ProviderSelector {
+        serde_json::from_value::<ProviderSecret>(json!("secret"))
+            .unwrap()
+            .selector()
+    }
+
+    fn job(selector: ProviderSelector) -> (Job, oneshot::Receiver<feed::Receiver<Frame>>) {
+        let (work, pos) = work(json!({}))
+            .sanitize(&engine(), &WorkOpt::default())
+            .unwrap();
+        let (tx, rx) = oneshot::channel();
+        let job = Job {
+            tx,
+            pos,
+            engine: engine(),
+            work,
+            selector,
+            redispatches: 0,
+            session: Arc::default(),
+            played: watch::channel(None).1,
+            queued_at: Instant::now(),
+            info_frames_left: None,
+            deeper: Arc::default(),
+        };
+        (job, rx)
+    }
+
+    /// Fresh application state, with an empty in-memory engine store.
+    fn app_state() -> AppState {
+        AppState {
+            repo: Box::leak(Box::<MemoryStore>::default()),
+            hub: Box::leak(Box::default()),
+            ongoing: Box::leak(Box::default()),
+            subscriptions: Box::leak(Box::default()),
+            job_ids: Box::leak(Box::<SequentialJobIds>::default()),
+            sessions: Box::leak(Box::default()),
+            ponders: Box::leak(Box::default()),
+            challenges: Box::leak(Box::default()),
+            streams: Box::leak(Box::new(StreamLimit::new(10))),
+            peers: Some(Box::leak(Box::new(PeerLimit::new(
+                2,
+                vec!["10.0.0.1".parse().unwrap()],
+            )))),
+            in_flight: Box::leak(Box::default()),
+            maintenance: Box::leak(Box::default()),
+            webhooks: Box::leak(Box::default()),
+            metrics: Box::leak(Box::default()),
+            audit: Box::leak(Box::new(AuditLog::new(2))),
+            work_opt: Box::leak(Box::default()),
+            admin_token: Some(Box::leak(Box::new("admin".parse().unwrap()))),
+            remote_shutdown: None,
+            trust_proxy: false,
+        }
+    }
+
+    /// Submits the body for an acquired job, as the provider would.
+    fn submit_to(
+        state: &AppState,
+        id: JobId,
+        body: Body,
+    ) -> impl Future<Output = Result<(), Error>> + 'static {
+        submit(
+            SubmitPath { id },
+            State(Providers::from_ref(state)),
+            State(state.in_flight),
+            body,
+        )
+    }
+
+    /// Acquires the next job with the provider secret.
+    async fn acquire_from(state: &AppState) -> AcquireResponse {
+        let req: AcquireRequest =
+            serde_json::from_value(json!({ "providerSecret": "secret" })).unwrap();
+        let Ok(Either::E1(JsonResponse(res))) = acquire(
+            AcquirePath,
+            State(Providers::from_ref(state)),
+            State(state.job_ids),
+            State(state.challenges),
+            Connection(None),
+            Json(req),
+        )
+        .await
+        else {
+            panic!("acquire timed out");
+        };
+        res
+    }
+
+    async fn json_of(res: Response) -> Value {
+        serde_json::from_slice(&to_bytes(res.into_body(), usize::MAX).await.unwrap()).unwrap()
+    }
+
+    async fn text_of(res: Response) -> String {
+        String::from_utf8(
+            to_bytes(res.into_body(), usize::MAX)
+                .await
+                .unwrap()
+                .to_vec(),
+        )
+        .unwrap()
+    }
+
+    async fn extract<T>(body: &'static str) -> Result<T, Response>
+    where
+        T: serde::de::DeserializeOwned,
+    {
+        let req = Request::builder()
+            .header("content-type", "application/json")
+            .body(Body::from(body))
+            .unwrap();
+        Json::<T>::from_request(req, &app_state())
+            .await
+            .map(|Json(req)| req)
+            .map_err(IntoResponse::into_response)
+    }
+
+    async fn assert_bad_request(res: Response, expected: &str) {
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(body, expected);
+    }
+
+    #[tokio::test]
+    async fn test_zero_threads_rejected() {
+        let res = extract::<AnalyseRequest>(
+            r#"{
+                "clientSecret": "secret",
+                "work": {
+                    "sessionId": "session",
+                    "threads": 0,
+                    "hash": 16,
+                    "depth": 20,
+                    "multiPv": 1,
+                    "variant": "chess",
+                    "initialFen": "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+                    "moves": []
+                }
+            }"#,
+        )
+        .await
+        .unwrap_err();
+        assert_bad_request(
+            res,
+            "invalid work: threads, hash and timeouts must be at least 1",
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_zero_match_timeout_rejected() {
+        let res = extract::<AnalyseRequest>(
+            r#"{
+                "clientSecret": "secret",
+                "work": {
+                    "sessionId": "session",
+                    "depth": 20,
+                    "variant": "chess",
+                    "initialFen": "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+                    "moves": [],
+                    "matchTimeout": 0
+                }
+            }"#,
+        )
+        .await
+        .unwrap_err();
+        assert_bad_request(
+            res,
+            "invalid work: threads, hash and timeouts must be at least 1",
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_unknown_variant_rejected() {
+        let res = extract::<AnalyseRequest>(
+            r#"{
+                "clientSecret": "secret",
+                "work": {
+                    "sessionId": "session",
+                    "depth": 20,
+                    "variant": "duckchess",
+                    "initialFen": "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+                    "moves": []
+                }
+            }"#,
+        )
+        .await
+        .unwrap_err();
+        assert_bad_request(res, "invalid work: unsupported variant").await;
+    }
+
+    #[tokio::test]
+    async fn test_empty_secrets_rejected() {
+        let res = extract::<AcquireRequest>(r#"{ "providerSecret": "" }"#)
+            .await
+            .unwrap_err();
+        assert_bad_request(res, "invalid request: secret must not be empty").await;
+
+        let res = extract::<AcquireRequest>(r#"{ "providerSecret": "  " }"#)
+            .await
+            .unwrap_err();
+        assert_bad_request(res, "invalid request: secret must not be empty").await;
+
+        let res = extract::<AnalyseRequest>(r#"{ "clientSecret": "", "work": {} }"#)
+            .await
+            .unwrap_err();
+        assert_bad_request(res, "invalid request: secret must not be empty").await;
+    }
+
+    #[tokio::test]
+    async fn test_rejection_not_guessed_from_message() {
+        let res = extract::<AnalyseRequest>(
+            r#"{
+                "clientSecret": "secret",
+                "work": {
+                    "sessionId": "session",
+                    "depth": 20,
+                    "perspective": "secret must not be empty"
+                }
+            }"#,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains("unknown variant"), "{body}");
+    }
+
+    #[tokio::test]
+    async fn test_batch_reports_per_item_errors() {
+        let state = app_state();
+        let hub = state.hub;
+        let selector = selector();
+        hub.heartbeat(selector.clone());
+
+        let works = vec![work(json!({})), work(json!({ "moves": ["e2e5"] }))];
+        let frames = batch_stream(Clients::from_ref(&state), selector.clone(), engine(), works);
+
+        task::spawn(async move {
+            let job = hub.acquire(selector.clone(), |_| true).await.unwrap();
+            let job = job.start(&JobId::random(), hub.busy(selector));
+            let mut emit = Emit::new(&job.work, WorkOpt::default().max_pv_len);
+            let uci = UciOut::from_line("info depth 1 score cp 20 pv e2e4").unwrap();
+            emit.update(&uci.unwrap(), &job.pos);
+            job.tx.send(emit.into()).unwrap();
+        });
+
+        let mut frames: Vec<Value> = frames
+            .map(|frame| serde_json::to_value(frame).unwrap())
+            .collect()
+            .await;
+        frames.sort_by_key(|frame| frame["index"].as_u64());
+        assert_eq!(frames.len(), 3);
+        assert_eq!(frames[0]["index"], 0);
+        assert_eq!(frames[0]["acquired"], true);
+        assert_eq!(frames[1]["index"], 0);
+        assert_eq!(frames[1]["depth"], 1);
+        assert_eq!(frames[2]["index"], 1);
+        assert!(frames[2]["error"]
+            .as_str()
+            .unwrap()
+            .starts_with("invalid work: illegal uci move"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_batch_match_timeout_starts_at_head() {
+        let state = app_state();
+        let hub = state.hub;
+        let selector = selector();
+        hub.heartbeat(selector.clone());
+
+        // A single provider that takes longer than the match timeout for
+        // each item, and then stops picking up work.
+        let works = vec![work(json!({ "matchTimeout": 2000 })); 3];
+        let started = Instant::now();
+        let frames = batch_stream(Clients::from_ref(&state), selector.clone(), engine(), works);
+        task::spawn(async move {
+            for _ in 0..2 {
+                let job = hub.acquire(selector.clone(), |_| true).await.unwrap();
+                let job = job.start(&JobId::random(), hub.busy(selector.clone()));
+                sleep(Duration::from_secs(5)).await;
+                let mut emit = Emit::new(&job.work, WorkOpt::default().max_pv_len);
+                let uci = UciOut::from_line("info depth 1 score cp 20 pv e2e4").unwrap();
+                emit.update(&uci.unwrap(), &job.pos);
+                job.tx.send(emit.into()).unwrap();
+            }
+        });
+
+        let frames: Vec<Value> = frames
+            .map(|frame| serde_json::to_value(frame).unwrap())
+            .collect()
+            .await;
+        assert_eq!(frames.len(), 5);
+        assert_eq!(frames[1]["index"], 0);
+        assert_eq!(frames[1]["depth"], 1);
+        assert_eq!(frames[3]["index"], 1);
+        assert_eq!(frames[3]["depth"], 1);
+        assert_eq!(frames[4]["index"], 2);
+        assert_eq!(frames[4]["error"], "provider did not pick up work in time");
+        assert_eq!(started.elapsed(), Duration::from_secs(12));
+    }
+
+    #[tokio::test]
+    async fn test_redispatch_until_depth() {
+        let state = app_state();
+        let AppState { hub, ongoing, .. } = state;
+        let selector = selector();
+        hub.heartbeat(selector.clone());
+
+        let (work, pos) = work(json!({ "depth": 20, "ensureDepth": true }))
+            .sanitize(&engine(), state.work_opt)
+            .unwrap();
+        let client = task::spawn(dispatch(
+            Clients::from_ref(&state),
+            selector.clone(),
+            engine(),
+            work,
+            pos,
+        ));
+
+        for (lines, redispatches) in [
+            ("info depth 5 score cp 10 pv e2e4\nbestmove e2e4\n", 0),
+            (
+                "info depth 3 score cp 5 pv d2d4\ninfo depth 20 score cp 30 pv e2e4\nbestmove e2e4\n",
+                1,
+            ),
+        ] {
+            let job = hub.acquire(selector.clone(), |_| true).await.unwrap();
+            assert_eq!(job.redispatches, redispatches);
+            let id = JobId::random();
+            ongoing.add(id.clone(), job.start(&id, hub.busy(selector.clone())));
+            submit_to(&state, id, Body::from(lines)).await.unwrap();
+        }
+
+        let depths: Vec<u32> = frames(client.await.unwrap().unwrap())
+            .filter_map(|frame| async move {
+                match frame {
+                    Frame::Emit(emit) => Some(emit.depth()),
+                    Frame::Acquired { .. }
+                    | Frame::Done { .. }
+                    | Frame::GameOver { .. }
+                    | Frame::Timeout { .. }
+                    | Frame::Error { .. } => None,
+                }
+            })
+            .collect()
+            .await;
+        assert_eq!(depths, [5, 20]);
+    }
+
+    #[tokio::test]
+    async fn test_redispatch_keeps_info_frame_budget() {
+        let state = app_state();
+        let AppState { hub, ongoing, .. } = state;
+        let selector = selector();
+        hub.heartbeat(selector.clone());
+
+        let (work, pos) = work(json!({ "depth": 20, "ensureDepth": true, "maxInfoFrames": 2 }))
+            .sanitize(&engine(), state.work_opt)
+            .unwrap();
+        let client = task::spawn(dispatch(
+            Clients::from_ref(&state),
+            selector.clone(),
+            engine(),
+            work,
+            pos,
+        ));
+
+        for lines in [
+            "info depth 5 score cp 10 pv e2e4\nbestmove e2e4\n",
+            "info depth 10 score cp 20 pv e2e4\n\
+             info depth 15 score cp 25 pv e2e4\n\
+             info depth 20 score cp 30 pv e2e4\n\
+             bestmove e2e4\n",
+        ] {
+            let job = hub.acquire(selector.clone(), |_| true).await.unwrap();
+            let id = JobId::random();
+            ongoing.add(id.clone(), job.start(&id, hub.busy(selector.clone())));
+            submit_to(&state, id, Body::from(lines)).await.unwrap();
+        }
+
+        // One frame from the first provider, one from the second.
+        let depths: Vec<u32> = frames(client.await.unwrap().unwrap())
+            .filter_map(|frame| async move {
+                match frame {
+                    Frame::Emit(emit) => Some(emit.depth()),
+                    Frame::Acquired { .. }
+                    | Frame::Done { .. }
+                    | Frame::GameOver { .. }
+                    | Frame::Timeout { .. }
+                    | Frame::Error { .. } => None,
+                }
+            })
+            .collect()
+            .await;
+        assert_eq!(depths, [5, 10]);
+    }
+
+    #[tokio::test]
+    async fn test_harness_requeue() {
+        let harness = Harness::new().await;
+        harness.heartbeat().await;
+        let client = task::spawn(harness.analyse());
+        let first = harness.acquire().await;
+        let analysis = client.await.unwrap();
+        assert_eq!(analysis.status(), StatusCode::OK);
+
+        // The engine of the first provider crashed.
+        let res = harness
+            .submit(&first, Body::from("{\"requeue\": true}\n"))
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let second = harness.acquire().await;
+        assert_ne!(first, second);
+        let res = harness
+            .submit(
+                &second,
+                Body::from("info depth 20 score cp 30 pv e2e4\nbestmove e2e4\n"),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let frames = frames_of(analysis).await;
+        assert!(frames.iter().all(|frame| frame.get("error").is_none()));
+        assert_eq!(frames[frames.len() - 2]["depth"], 20);
+        assert_eq!(frames.last().unwrap()["bestmove"], "e2e4");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_harness_requeue_match_timeout() {
+        let harness = Harness::new().await;
+        harness.heartbeat().await;
+        let client = task::spawn(harness.analyse_with(json!({ "matchTimeout": 2000 })));
+        let first = harness.acquire().await;
+        let analysis = client.await.unwrap();
+        let res = harness
+            .submit(&first, Body::from("{\"requeue\": true}\n"))
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        // No other provider picks up the job within the match timeout of
+        // the work.
+        tokio::time::sleep(Duration::from_millis(2001)).await;
+        let frames = frames_of(analysis).await;
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0]["acquired"], true);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_harness_start_timeout() {
+        let harness = Harness::new().await;
+        harness.heartbeat().await;
+        let client = task::spawn(harness.analyse());
+        let silent = harness.acquire().await;
+        let analysis = client.await.unwrap();
+        assert_eq!(analysis.status(), StatusCode::OK);
+
+        // The first provider never starts submitting.
+        tokio::time::sleep(Duration::from_secs(WorkOpt::default().start_timeout)).await;
+        let stalled = harness.acquire().await;
+        assert_ne!(silent, stalled);
+        let res = harness.submit(&silent, Body::empty()).await;
+        assert_eq!(res.status(), StatusCode::GONE);
+
+        // The second provider starts submitting, but sends no analysis.
+        let (lines, body) = mpsc::channel::<Result<&'static str, io::Error>>(1);
+        let res = harness
+            .submit(&stalled, Body::from_stream(ReceiverStream::new(body)))
+            .await;
+        assert_eq!(res.status(), StatusCode::GONE);
+
+        let third = harness.acquire().await;
+        let res = harness
+            .submit(
+                &third,
+                Body::from("info depth 20 score cp 30 pv e2e4\nbestmove e2e4\n"),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        drop(lines);
+
+        let frames = frames_of(analysis).await;
+        assert!(frames.iter().all(|frame| frame.get("error").is_none()));
+        assert_eq!(frames.last().unwrap()["bestmove"], "e2e4");
+
+        // Both stalled providers count against the completion rate.
+        let res = harness.get("/api/admin/engines/health", "admin").await;
+        let body: Value = json_of(res).await;
+        let rate = body["providers"][0]["completionRate"].as_f64().unwrap();
+        assert!((rate - 1.0 / 3.0).abs() < 1e-9);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_harness_start_timeout_exhausted() {
+        let harness = Harness::with(
+            false,
+            WorkOpt {
+                max_redispatches: 0,
+                ..WorkOpt::default()
+            },
+        )
+        .await;
+        harness.heartbeat().await;
+        let client = task::spawn(harness.analyse());
+        harness.acquire().await;
+        let analysis = client.await.unwrap();
+        sleep(Duration::from_secs(WorkOpt::default().start_timeout + 1)).await;
+
+        let frames = frames_of(analysis).await;
+        let last = frames.last().unwrap();
+        assert_eq!(last["code"], "start-timeout");
+        assert_eq!(last["error"], "provider did not start in time");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_harness_busy_provider_online() {
+        let harness = Harness::new().await;
+        harness.heartbeat().await;
+        let client = task::spawn(harness.analyse());
+        let id = harness.acquire().await;
+        let analysis = client.await.unwrap();
+        let (lines, body) = mpsc::channel::<Result<&'static str, io::Error>>(1);
+        let submission =
+            task::spawn(harness.submit(&id, Body::from_stream(ReceiverStream::new(body))));
+        lines
+            .send(Ok("info depth 1 score cp 20 pv e2e4\n"))
+            .await
+            .unwrap();
+
+        // A long analysis, without heartbeats in the meantime.
+        sleep(Duration::from_secs(60)).await;
+        let client = task::spawn(harness.analyse());
+        sleep(Duration::from_secs(1)).await;
+        harness.acquire().await;
+        assert_eq!(client.await.unwrap().status(), StatusCode::OK);
+
+        drop(lines);
+        assert_eq!(submission.await.unwrap().status(), StatusCode::OK);
+        drop(analysis);
+    }
+
+    #[tokio::test]
+    async fn test_acquired_precedes_info() {
+        let state = app_state();
+        let hub = state.hub;
+        hub.heartbeat(selector());
+
+        let (work, pos) = work(json!({})).sanitize(&engine(), state.work_opt).unwrap();
+        let client = task::spawn(dispatch(
+            Clients::from_ref(&state),
+            selector(),
+            engine(),
+            work,
+            pos,
+        ));
+
+        let res = acquire_from(&state).await;
+        let mut rx = client.await.unwrap().unwrap();
+        let frame = serde_json::to_value(rx.recv().await.unwrap()).unwrap();
+        assert_eq!(
+            frame,
+            json!({
+                "acquired": true,
+                "jobId": res.id,
+                "engine": engine().config.name,
+                "position": {
+                    "material": [39, 39],
+                    "phase": "opening",
+                    "tablebase": false,
+                    "repetition": false,
+                    "halfmoveClock": 0,
+                    "moveNumber": 1,
+                },
+            })
+        );
+
+        task::spawn(submit_to(
+            &state,
+            res.id,
+            Body::from("info depth 1 score cp 20 pv e2e4\nbestmove e2e4\n"),
+        ));
+        let frame = serde_json::to_value(rx.recv().await.unwrap()).unwrap();
+        assert_eq!(frame["depth"], 1);
+    }
+
+    #[tokio::test]
+    async fn test_acquired_reports_clamping() {
+        let state = app_state();
+        let hub = state.hub;
+        hub.heartbeat(selector());
+
+        let (work, pos) = work(json!({ "threads": 16, "hash": 512 }))
+            .sanitize(&engine(), &WorkOpt::default())
+            .unwrap();
+        let client = task::spawn(dispatch(
+            Clients::from_ref(&state),
+            selector(),
+            engine(),
+            work,
+            pos,
+        ));
+        let job = hub.acquire(selector(), |_| true).await.unwrap();
+        let _job = job.start(&JobId::random(), hub.busy(selector()));
+        let mut rx = client.await.unwrap().unwrap();
+        let frame = serde_json::to_value(rx.recv().await.unwrap()).unwrap();
+        assert_eq!(frame["clamped"], json!({ "threads": 8 }));
+    }
+
+    #[tokio::test]
+    async fn test_shared_subscribers() {
+        let state = app_state();
+        let AppState { hub, ongoing, .. } = state;
+        hub.heartbeat(selector());
+
+        let (work, pos) = work(json!({})).sanitize(&engine(), state.work_opt).unwrap();
+        let client = task::spawn(dispatch(
+            Clients::from_ref(&state),
+            selector(),
+            engine(),
+            work,
+            pos,
+        ));
+        let job = hub.acquire(selector(), |_| true).await.unwrap();
+        let job = job.start(&JobId::random(), hub.busy(selector()));
+        let mut first = client.await.unwrap().unwrap();
+        assert!(matches!(first.recv().await, Some(Frame::Acquired { .. })));
+        let mut second = first.resubscribe();
+        let id = JobId::random();
+        ongoing.add(id.clone(), job);
+
+        let (lines, body) = mpsc::channel::<Result<&'static str, io::Error>>(1);
+        let submission = task::spawn(submit_to(
+            &state,
+            id,
+            Body::from_stream(ReceiverStream::new(body)),
+        ));
+
+        lines
+            .send(Ok("info depth 1 score cp 20 pv e2e4\n"))
+            .await
+            .unwrap();
+        for rx in [&mut first, &mut second] {
+            let frame = serde_json::to_value(rx.recv().await.unwrap()).unwrap();
+            assert_eq!(frame["depth"], 1);
+        }
+
+        // The job continues for the remaining subscriber.
+        drop(first);
+        lines
+            .send(Ok("info depth 2 score cp 20 pv e2e4\n"))
+            .await
+            .unwrap();
+        let frame = serde_json::to_value(second.recv().await.unwrap()).unwrap();
+        assert_eq!(frame["depth"], 2);
+        assert!(!submission.is_finished());
+
+        // The job ends once the last subscriber is gone.
+        drop(second);
+        timeout(Duration::from_secs(1), submission)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        drop(lines);
+    }
+
+    #[tokio::test]
+    async fn test_harness_analysis_roundtrip() {
+        let harness = Harness::new().await;
+        harness.heartbeat().await;
+
+        let client = task::spawn(harness.analyse());
+        let id = harness.acquire().await;
+        let analysis = client.await.unwrap();
+        assert_eq!(analysis.status(), StatusCode::OK);
+
+        let res = harness
+            .submit(
+                &id,
+                Body::from(
+                    "info depth 1 score cp 20 pv e2e4\n\
+                     info depth 2 score cp 25 pv e2e4 e7e5\n\
+                     bestmove e2e4\n",
+                ),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        // The analysis stream completes once the provider is done. The
+        // client was not reading in the meantime, so it skips to the latest
+        // emit.
+        let frames = frames_of(analysis).await;
+        assert_eq!(frames.len(), 3);
+        assert_eq!(frames[0]["acquired"], true);
+        assert_eq!(frames[1]["depth"], 2);
+        assert_eq!(frames[1]["pvs"][0]["moves"], json!(["e2e4", "e7e5"]));
+        assert_eq!(frames[2]["done"], true);
+        assert_eq!(frames[2]["bestmove"], "e2e4");
+        assert_eq!(frames[2]["digest"].as_str().unwrap().len(), 16);
+    }
+
+    #[tokio::test]
+    async fn test_harness_error_frame() {
+        let harness = Harness::new().await;
+        harness.heartbeat().await;
+
+        let client = task::spawn(harness.analyse());
+        let id = harness.acquire().await;
+        let analysis = client.await.unwrap();
+        assert_eq!(analysis.status(), StatusCode::OK);
+
+        let (lines, body) = mpsc::channel::<Result<&'static str, io::Error>>(1);
+        let submission =
+            task::spawn(harness.submit(&id, Body::from_stream(ReceiverStream::new(body))));
+        lines
+            .send(Ok("info depth 1 score cp 20 pv e2e4\n"))
+            .await
+            .unwrap();
+        lines
+            .send(Err(io::Error::from(io::ErrorKind::TimedOut)))
+            .await
+            .unwrap();
+        assert_eq!(submission.await.unwrap().status(), StatusCode::BAD_REQUEST);
+
+        // The stream ends with an error frame rather than a done frame.
+        let frames = frames_of(analysis).await;
+        assert_eq!(frames.len(), 3);
+        assert_eq!(frames[1]["depth"], 1);
+        assert_eq!(frames[2]["code"], "provider");
+        assert!(frames[2]["error"].is_string());
+        assert!(frames[2].get("done").is_none());
+
+        // Also when the provider just goes away.
+        let client = task::spawn(harness.analyse());
+        let id = harness.acquire().await;
+        let analysis = client.await.unwrap();
+        let res = harness
+            .submit(&id, Body::from("info depth 1 score cp 20 pv e2e4\n"))
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let frames = frames_of(analysis).await;
+        assert_eq!(
+            frames.last().unwrap(),
+            &json!({
+                "error": "provider disconnected before bestmove",
+                "code": "disconnected",
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_harness_release_held_jobs() {
+        let harness = Harness::new().await;
+        harness.heartbeat().await;
+
+        // The provider acquires two jobs on the same connection, but
+        // disconnects while submitting the first. Another provider of the
+        // same engine holds a third job.
+        let connection = ConnectionId::next();
+        let first_client = task::spawn(harness.analyse());
+        let first = harness.acquire_on(connection).await;
+        let second_client = task::spawn(harness.analyse());
+        let second = harness.acquire_on(connection).await;
+        let third_client = task::spawn(harness.analyse());
+        let third = harness.acquire_on(ConnectionId::next()).await;
+        let first_analysis = first_client.await.unwrap();
+        let second_analysis = second_client.await.unwrap();
+        let third_analysis = third_client.await.unwrap();
+        let res = harness
+            .submit(&first, Body::from("info depth 1 score cp 20 pv e2e4\n"))
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let frames = frames_of(first_analysis).await;
+        assert_eq!(frames.last().unwrap()["code"], "disconnected");
+
+        // The second job is handed to another provider.
+        assert_eq!(
+            harness.submit(&second, Body::empty()).await.status(),
+            StatusCode::GONE
+        );
+        let redispatched = harness.acquire().await;
+        assert_ne!(redispatched, second);
+        let res = harness
+            .submit(
+                &redispatched,
+                Body::from("info depth 3 score cp 25 pv e2e4\nbestmove e2e4\n"),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let frames = frames_of(second_analysis).await;
+        assert_eq!(frames.last().unwrap()["done"], true);
+
+        // The healthy provider keeps its job.
+        let res = harness
+            .submit(
+                &third,
+                Body::from("info depth 2 score cp 25 pv e2e4\nbestmove e2e4\n"),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let frames = frames_of(third_analysis).await;
+        assert_eq!(frames.last().unwrap()["done"], true);
+    }
+
+    #[tokio::test]
+    async fn test_purge_stale_engines() {
+        let store: &'static MemoryStore = Box::leak(Box::default());
+        let admin_token: &'static AdminToken = Box::leak(Box::new("admin".parse().unwrap()));
+        let days_ago = |days: u64| {
+            DateTime::from_system_time(SystemTime::now() - Duration::from_secs(days * 24 * 60 * 60))
+        };
+
+        let mut stale = engine();
+        stale.id = EngineId("eei_stale".to_owned());
+        let mut used = engine();
+        used.id = EngineId("eei_used".to_owned());
+        let fresh = engine();
+        for (engine, created_at) in [(&stale, 400), (&used, 400), (&fresh, 1)] {
+            store
+                .create(
+                    ExternalEngine::new(engine.clone(), selector())
+                        .with_created_at(days_ago(created_at)),
+                )
+                .await
+                .unwrap();
+        }
+        store.record_analysis(used.id.clone()).await.unwrap();
+
+        let purge_with = |dry_run| {
+            purge(
+                PurgePath,
+                State(store),
+                State(Some(admin_token)),
+                Some(TypedHeader(Authorization::bearer("admin").unwrap())),
+                Query(PurgeQuery { days: 30, dry_run }),
+            )
+        };
+        let exists = async |engine: &Engine| {
+            store
+                .find(engine.id.clone(), engine.config.client_secret.clone())
+                .await
+                .unwrap()
+                .is_some()
+        };
+
+        let JsonResponse(res) = purge_with(true).await.unwrap();
+        assert_eq!(res.purged, 1);
+        assert!(res.dry_run);
+        assert!(exists(&stale).await);
+
+        let JsonResponse(res) = purge_with(false).await.unwrap();
+        assert_eq!(res.purged, 1);
+        assert!(!exists(&stale).await);
+        assert!(exists(&used).await);
+        assert!(exists(&fresh).await);
+    }
+
+    #[tokio::test]
+    async fn test_harness_malformed_lines() {
+        let harness = Harness::new().await;
+        harness.heartbeat().await;
+
+        let client = task::spawn(harness.analyse());
+        let id = harness.acquire().await;
+        let analysis = client.await.unwrap();
+        assert_eq!(analysis.status(), StatusCode::OK);
+
+        let (lines, body) = mpsc::channel::<Result<&'static str, io::Error>>(1);
+        let submission =
+            task::spawn(harness.submit(&id, Body::from_stream(ReceiverStream::new(body))));
+        lines
+            .send(Ok("info depth 1 score cp 20 pv e2e4\n"))
+            .await
+            .unwrap();
+
+        // Skipped until the threshold is reached.
+        for _ in 0..WorkOpt::default().max_malformed_lines {
+            lines.send(Ok("info depth garbage\n")).await.unwrap();
+        }
+        assert_eq!(submission.await.unwrap().status(), StatusCode::BAD_REQUEST);
+        let frames = frames_of(analysis).await;
+        assert_eq!(frames.len(), 3);
+        assert_eq!(frames[1]["depth"], 1);
+        assert_eq!(frames[2]["code"], "protocol");
+
+        let res = harness.get("/metrics", "admin").await;
+        let metrics = text_of(res).await;
+        assert!(metrics.contains(&format!(
+            "lila_engine_malformed_lines_total{{selector=\"{}\"}} 5\n",
+            selector().as_str()
+        )));
+
+        // Counts against the completion rate of the provider.
+        let res = harness.get("/api/admin/engines/health", "admin").await;
+        let body: Value = json_of(res).await;
+        assert_eq!(body["providers"][0]["completionRate"], 0.0);
+        let res = harness.get("/api/admin/jobs/recent", "admin").await;
+        let body: Value = json_of(res).await;
+        assert_eq!(body["jobs"][0]["outcome"], "protocol");
+    }
+
+    #[tokio::test]
+    async fn test_harness_callback() {
+        let (posted, mut callbacks) = mpsc::unbounded_channel::<Value>();
+        let app = Router::new()
+            .route(
+                "/callback",
+                axum::routing::post(
+                    |State(posted): State<mpsc::UnboundedSender<Value>>,
+                     axum::Json(body): axum::Json<Value>| async move {
+                        posted.send(body).unwrap();
+                        StatusCode::OK
+                    },
+                ),
+            )
+            .with_state(posted);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        task::spawn(async move { axum::serve(listener, app).await });
+
+        let harness = Harness::with(
+            false,
+            WorkOpt {
+                callback_domains: vec!["127.0.0.1".to_owned()],
+                ..WorkOpt::default()
+            },
+        )
+        .await;
+        harness.heartbeat().await;
+        let callback_url = format!("http://{addr}/callback");
+
+        // Acknowledged before any provider picks up the work.
+        let res = harness
+            .analyse_with(json!({ "callbackUrl": callback_url }))
+            .await;
+        assert_eq!(res.status(), StatusCode::ACCEPTED);
+        let id = harness.acquire().await;
+        let res = harness
+            .submit(
+                &id,
+                Body::from("info depth 20 score cp 30 pv e2e4\nbestmove e2e4\n"),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = callbacks.recv().await.unwrap();
+        assert_eq!(body["done"], true);
+        assert_eq!(body["bestmove"], "e2e4");
+        assert_eq!(body["analysis"]["depth"], 20);
+
+        // Also delivered when the provider fails.
+        let res = harness
+            .analyse_with(json!({ "callbackUrl": callback_url }))
+            .await;
+        assert_eq!(res.status(), StatusCode::ACCEPTED);
+        let id = harness.acquire().await;
+        let garbage =
+            "info depth garbage\n".repeat(WorkOpt::default().max_malformed_lines as usize);
+        let res = harness.submit(&id, Body::from(garbage)).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let body = callbacks.recv().await.unwrap();
+        assert_eq!(body["code"], "protocol");
+        assert!(body.get("analysis").is_none());
+
+        // And when no provider picks up the work at all.
+        let res = harness
+            .analyse_with(json!({ "callbackUrl": callback_url, "matchTimeout": 1 }))
+            .await;
+        assert_eq!(res.status(), StatusCode::ACCEPTED);
+        let body = callbacks.recv().await.unwrap();
+        assert_eq!(body["code"], "unavailable");
+    }
+
+    #[tokio::test]
+    async fn test_harness_short_client_secret() {
+        let harness = Harness::new().await;
+        harness.heartbeat().await;
+
+        let res = harness
+            .post_json(
+                "/api/external-engine/eei_test/analyse",
+                json!({ "clientSecret": "ees_short", "work": work(json!({})) }),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(body, "invalid request: clientSecret too short");
+
+        let res = harness
+            .get("/api/external-engine/eei_test/stats", "ees_short")
+            .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_harness_session_prefixes() {
+        let harness = Harness::new().await;
+        let mut engine = engine();
+        engine.config.allowed_session_prefixes = Some(vec!["study-".to_owned()]);
+        assert!(harness
+            .store
+            .update(ExternalEngine::new(engine, selector()))
+            .await
+            .unwrap());
+        harness.heartbeat().await;
+
+        let res = harness.analyse_with(json!({ "sessionId": "game-1" })).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(body, "invalid work: session not allowed for this engine");
+
+        let client = task::spawn(harness.analyse_with(json!({ "sessionId": "study-1" })));
+        harness.acquire().await;
+        assert_eq!(client.await.unwrap().status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_harness_remote_shutdown() {
+        let harness = Harness::new().await;
+        let res = harness
+            .post_admin("/api/admin/shutdown", "admin", json!({}))
+            .await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        let remote_shutdown: &'static RemoteShutdown = Box::leak(Box::default());
+        let harness = Harness::build(false, WorkOpt::default(), Some(remote_shutdown)).await;
+        let res = harness
+            .post_admin("/api/admin/shutdown", "wrong", json!({}))
+            .await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        assert!(
+            timeout(Duration::from_millis(10), remote_shutdown.requested())
+                .await
+                .is_err()
+        );
+
+        let res = harness
+            .post_admin("/api/admin/shutdown", "admin", json!({}))
+            .await;
+        assert_eq!(res.status(), StatusCode::ACCEPTED);
+        timeout(Duration::from_secs(1), remote_shutdown.requested())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_harness_maintenance() {
+        let harness = Harness::new().await;
+        harness.heartbeat().await;
+
+        let client = task::spawn(harness.analyse());
+        let id = harness.acquire().await;
+        let analysis = client.await.unwrap();
+        assert_eq!(analysis.status(), StatusCode::OK);
+
+        let (lines, body) = mpsc::channel::<Result<&'static str, io::Error>>(1);
+        let submission =
+            task::spawn(harness.submit(&id, Body::from_stream(ReceiverStream::new(body))));
+        lines
+            .send(Ok("info depth 1 score cp 20 pv e2e4\n"))
+            .await
+            .unwrap();
+
+        let res = harness
+            .post_admin(
+                "/api/admin/maintenance",
+                "wrong",
+                json!({ "enabled": true }),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        let res = harness
+            .post_admin(
+                "/api/admin/maintenance",
+                "admin",
+                json!({ "enabled": true }),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+
+        // New work is rejected.
+        let res = harness.analyse().await;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body: Value = json_of(res).await;
+        assert_eq!(body["code"], "maintenance");
+
+        // The existing stream continues, and providers keep polling.
+        lines.send(Ok("bestmove e2e4\n")).await.unwrap();
+        drop(lines);
+        assert_eq!(submission.await.unwrap().status(), StatusCode::OK);
+        let frames = frames_of(analysis).await;
+        assert_eq!(frames.last().unwrap()["done"], true);
+        assert_eq!(frames.last().unwrap()["bestmove"], "e2e4");
+        harness.heartbeat().await;
+
+        let res = harness
+            .post_admin(
+                "/api/admin/maintenance",
+                "admin",
+                json!({ "enabled": false }),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        let client = task::spawn(harness.analyse());
+        harness.acquire().await;
+        assert_eq!(client.await.unwrap().status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_harness_nps_metrics() {
+        let harness = Harness::new().await;
+        harness.heartbeat().await;
+
+        let res = harness.get("/metrics", "wrong").await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        let client = task::spawn(harness.analyse());
+        let id = harness.acquire().await;
+        let _analysis = client.await.unwrap();
+
+        let (lines, body) = mpsc::channel::<Result<&'static str, io::Error>>(1);
+        let submission =
+            task::spawn(harness.submit(&id, Body::from_stream(ReceiverStream::new(body))));
+        let scrape = async || {
+            let res = harness.get("/metrics", "admin").await;
+            assert_eq!(res.status(), StatusCode::OK);
+            text_of(res).await
+        };
+
+        lines
+            .send(Ok("info depth 1 nps 100000 score cp 20 pv e2e4\n"))
+            .await
+            .unwrap();
+        lines
+            .send(Ok("info depth 2 nps 300000 score cp 25 pv e2e4\n"))
+            .await
+            .unwrap();
+        // The second line is only known to be processed once the next one
+        // is accepted.
+        lines
+            .send(Ok("info depth 3 score cp 25 pv e2e4\n"))
+            .await
+            .unwrap();
+        let metrics = scrape().await;
+        assert!(metrics.contains("\nlila_engine_nps 300000\n"));
+        assert!(metrics.contains("\nlila_engine_engine_nps_avg{engine=\"eei_test\"} 200000\n"));
+
+        lines.send(Ok("bestmove e2e4\n")).await.unwrap();
+        drop(lines);
+        assert_eq!(submission.await.unwrap().status(), StatusCode::OK);
+        let metrics = scrape().await;
+        assert!(metrics.contains("\nlila_engine_nps 0\n"));
+        assert!(metrics.contains("\nlila_engine_engine_nps_avg{engine=\"eei_test\"} 200000\n"));
+    }
+
+    #[tokio::test]
+    async fn test_harness_invalid_work_metrics() {
+        let harness = Harness::new().await;
+
+        let res = harness.analyse_with(json!({ "moves": ["e2e5"] })).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let res = harness.analyse_with(json!({ "moves": ["e7e5"] })).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        let res = harness.get("/metrics", "admin").await;
+        let metrics = text_of(res).await;
+        assert!(metrics.contains("\nlila_engine_invalid_work_total{kind=\"illegalUci\"} 1\n"));
+        assert!(metrics.contains("\nlila_engine_invalid_work_total{kind=\"wrongSideToMove\"} 1\n"));
+    }
+
+    #[tokio::test]
+    async fn test_harness_tag_metrics() {
+        let harness = Harness::new().await;
+        harness.heartbeat().await;
+
+        let res = harness.analyse_with(json!({ "tag": "user-123" })).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        let client = task::spawn(harness.analyse_with(json!({ "tag": "study" })));
+        let id = harness.acquire().await;
+        let _analysis = client.await.unwrap();
+        let res = harness.submit(&id, Body::from("bestmove e2e4\n")).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let res = harness.get("/metrics", "admin").await;
+        let metrics = text_of(res).await;
+        assert!(metrics.contains("\nlila_engine_jobs_total{tag=\"study\"} 1\n"));
+        assert!(!metrics.contains("user-123"));
+    }
+
+    #[tokio::test]
+    async fn test_harness_deserialization_metrics() {
+        let harness = Harness::new().await;
+
+        // Rejected while deserializing the request, before validation.
+        let res = harness.analyse_with(json!({ "threads": 0 })).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let res = harness.analyse_with(json!({ "variant": "bughouse" })).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        let res = harness.get("/metrics", "admin").await;
+        let metrics = text_of(res).await;
+        assert!(metrics.contains("\nlila_engine_invalid_work_total{kind=\"notAtLeastOne\"} 1\n"));
+        assert!(
+            metrics.contains("\nlila_engine_invalid_work_total{kind=\"unsupportedVariant\"} 1\n")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_harness_backpressure_coalesces_multipv() {
+        let harness = Harness::new().await;
+        harness.heartbeat().await;
+
+        let client = task::spawn(harness.analyse_with(json!({ "multiPv": 2 })));
+        let id = harness.acquire().await;
+        let analysis = client.await.unwrap();
+
+        let lines: String = (1..=12)
+            .map(|depth| {
+                format!(
+                    "info multipv 1 depth {depth} score cp {} pv e2e4\n\
+                     info multipv 2 depth {depth} score cp {} pv d2d4\n",
+                    20 + depth,
+                    10 + depth
+                )
+            })
+            .chain(["bestmove e2e4\n".to_owned()])
+            .collect();
+        let res = harness.submit(&id, Body::from(lines)).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let frames = frames_of(analysis).await;
+        assert_eq!(frames.len(), 3);
+        assert_eq!(frames[0]["acquired"], true);
+        assert_eq!(
+            frames[1]["pvs"],
+            json!([
+                { "moves": ["e2e4"], "cp": 32, "depth": 12 },
+                { "moves": ["d2d4"], "cp": 22, "depth": 12 },
+            ])
+        );
+        assert_eq!(frames[2]["done"], true);
+        assert_eq!(frames[2]["bestmove"], "e2e4");
+    }
+
+    #[tokio::test]
+    async fn test_harness_eval_only_submit() {
+        let harness = Harness::new().await;
+        harness.heartbeat().await;
+
+        let client = task::spawn(harness.analyse());
+        let id = harness.acquire().await;
+        let analysis = client.await.unwrap();
+        let res = harness
+            .submit(&id, Body::from(r#"{"cp":34,"bestmove":"e2e4","depth":20}"#))
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let frames = frames_of(analysis).await;
+        assert_eq!(frames.len(), 3);
+        assert_eq!(frames[1]["depth"], 20);
+        assert_eq!(
+            frames[1]["pvs"],
+            json!([{ "moves": ["e2e4"], "cp": 34, "depth": 20 }])
+        );
+        assert_eq!(frames[2]["done"], true);
+        assert_eq!(frames[2]["bestmove"], "e2e4");
+
+        // The best move must be legal.
+        let client = task::spawn(harness.analyse());
+        let id = harness.acquire().await;
+        let _analysis = client.await.unwrap();
+        let res = harness
+            .submit(&id, Body::from(r#"{"cp":34,"bestmove":"e2e5","depth":20}"#))
+            .await;
+        assert_bad_request(res, "uci protocol error: illegal bestmove e2e5").await;
+    }
+
+    #[tokio::test]
+    async fn test_harness_drops_oversized_lines() {
+        let harness = Harness::new().await;
+        harness.heartbeat().await;
+
+        let client = task::spawn(harness.analyse());
+        let id = harness.acquire().await;
+        let analysis = client.await.unwrap();
+        let oversized = format!(
+            "info depth 1 score cp 20 pv {}\n",
+            "e2e4 e7e5 ".repeat(WorkOpt::default().max_line_len)
+        );
+        let res = harness
+            .submit(
+                &id,
+                Body::from(oversized + "info depth 2 score cp 25 pv e2e4\nbestmove e2e4\n"),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let frames = frames_of(analysis).await;
+        assert_eq!(frames.len(), 3);
+        assert_eq!(frames[1]["depth"], 2);
+        assert_eq!(frames[2]["done"], true);
+    }
+
+    #[tokio::test]
+    async fn test_harness_submit_unknown_or_gone() {
+        let harness = Harness::new().await;
+        harness.heartbeat().await;
+
+        let res = harness.submit(&JobId::random(), Body::empty()).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        let client = task::spawn(harness.analyse());
+        let id = harness.acquire().await;
+        let _analysis = client.await.unwrap();
+        let res = harness.submit(&id, Body::from("bestmove e2e4\n")).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = harness.submit(&id, Body::from("bestmove e2e4\n")).await;
+        assert_eq!(res.status(), StatusCode::GONE);
+    }
+
+    #[test]
+    fn test_accepts_binary() {
+        let accepts = |accept: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::ACCEPT, accept.parse().unwrap());
+            accepts_binary(&headers)
+        };
+        assert!(accepts("application/octet-stream"));
+        assert!(accepts("application/json, Application/Octet-Stream; q=0.5"));
+        assert!(!accepts("application/json"));
+        assert!(!accepts("application/octet-stream;q=0"));
+        assert!(!accepts(
+            "application/json, application/octet-stream; Q = 0.000"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_harness_binary_frames() {
+        let harness = Harness::new().await;
+        harness.heartbeat().await;
+
+        let client = task::spawn(
+            harness
+                .analyse_with_header("accept", "application/json;q=0.5, application/octet-stream"),
+        );
+        let id = harness.acquire().await;
+        let analysis = client.await.unwrap();
+        assert_eq!(analysis.status(), StatusCode::OK);
+        assert_eq!(
+            analysis.headers()[header::CONTENT_TYPE],
+            "application/octet-stream"
+        );
+        let res = harness
+            .submit(
+                &id,
+                Body::from("info depth 1 score cp 20 pv e2e4\nbestmove e2e4\n"),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let body = to_bytes(analysis.into_body(), usize::MAX).await.unwrap();
+        let mut kinds = Vec::new();
+        let mut buf = &body[..];
+        while !buf.is_empty() {
+            let len = u32::from_le_bytes(buf[1..5].try_into().unwrap()) as usize;
+            kinds.push(buf[0]);
+            buf = &buf[5 + len..];
+        }
+        assert_eq!(kinds, [BINARY_JSON, BINARY_EMIT, BINARY_JSON]);
+
+        // JSON lines by default.
+        let client = task::spawn(harness.analyse());
+        harness.acquire().await;
+        let mut analysis = client.await.unwrap().into_body().into_data_stream();
+        let chunk = analysis.next().await.unwrap().unwrap();
+        let frame: Value = serde_json::from_slice(&chunk).unwrap();
+        assert_eq!(frame["acquired"], true);
+    }
+
+    #[tokio::test]
+    async fn test_harness_subscribe() {
+        let harness = Harness::new().await;
+        harness.heartbeat().await;
+
+        let client = task::spawn(harness.analyse());
+        let id = harness.acquire().await;
+        let mut analysis = client.await.unwrap().into_body().into_data_stream();
+        let chunk = analysis.next().await.unwrap().unwrap();
+        let frame: Value = serde_json::from_slice(&chunk).unwrap();
+        assert_eq!(frame["jobId"], json!(id));
+
+        let uri = format!("/api/external-engine/job/{id}/subscribe");
+        let res = harness
+            .post_json(&uri, json!({ "clientSecret": "ees_wrongsecret" }))
+            .await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let subscription = harness
+            .post_json(&uri, json!({ "clientSecret": "ees_clientsecret" }))
+            .await;
+        assert_eq!(subscription.status(), StatusCode::OK);
+
+        // The job continues for the subscriber alone, which receives every
+        // line it keeps up with.
+        drop(analysis);
+        let mut subscription = subscription.into_body().into_data_stream();
+        let mut next_frame = async || -> Value {
+            let chunk = timeout(Duration::from_secs(1), subscription.next())
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+            serde_json::from_slice(&chunk).unwrap()
+        };
+        let (lines, body) = mpsc::channel::<Result<&'static str, io::Error>>(1);
+        let submission =
+            task::spawn(harness.submit(&id, Body::from_stream(ReceiverStream::new(body))));
+        for (line, depth) in [
+            ("info depth 1 score cp 20 pv e2e4\n", 1),
+            ("info depth 2 score cp 25 pv d2d4\n", 2),
+        ] {
+            lines.send(Ok(line)).await.unwrap();
+            assert_eq!(next_frame().await["depth"], depth);
+        }
+        lines.send(Ok("bestmove d2d4\n")).await.unwrap();
+        assert_eq!(next_frame().await["bestmove"], "d2d4");
+        drop(lines);
+        assert_eq!(submission.await.unwrap().status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_harness_deeper() {
+        let harness = Harness::new().await;
+        harness.heartbeat().await;
+
+        let client = task::spawn(harness.analyse_with(json!({ "depth": 5 })));
+        let first = harness.acquire().await;
+        let analysis = client.await.unwrap();
+        assert_eq!(analysis.status(), StatusCode::OK);
+
+        let uri = format!("/api/external-engine/job/{first}/deeper");
+        let res = harness
+            .post_json(
+                &uri,
+                json!({ "clientSecret": "ees_wrongsecret", "depth": 10 }),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let res = harness
+            .post_json(
+                &uri,
+                json!({ "clientSecret": "ees_clientsecret", "depth": 5 }),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let res = harness
+            .post_json(
+                &uri,
+                json!({ "clientSecret": "ees_clientsecret", "depth": 10 }),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+
+        let res = harness
+            .submit(
+                &first,
+                Body::from("info depth 5 score cp 20 pv e2e4\nbestmove e2e4\n"),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        // The same position is handed out again, to the requested depth.
+        let res = harness
+            .post_json(
+                "/api/external-engine/work",
+                json!({ "providerSecret": "secret" }),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body: Value = json_of(res).await;
+        assert_eq!(body["work"]["depth"], 10);
+        let second: JobId = serde_json::from_value(body["id"].clone()).unwrap();
+        let res = harness
+            .submit(
+                &second,
+                Body::from(
+                    "info depth 5 score cp 25 pv e2e4\n\
+                     info depth 10 score cp 30 pv d2d4\n\
+                     bestmove d2d4\n",
+                ),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        // One stream, without repeating the shallow analysis.
+        let frames = frames_of(analysis).await;
+        let depths: Vec<_> = frames
+            .iter()
+            .filter_map(|frame| frame.get("depth"))
+            .collect();
+        assert_eq!(depths, [5, 10]);
+        assert_eq!(frames.last().unwrap()["bestmove"], "d2d4");
+        assert_eq!(
+            frames
+                .iter()
+                .filter(|frame| frame.get("done").is_some())
+                .count(),
+            1
+        );
+
+        // Not after the job ended.
+        let res = harness
+            .post_json(
+                &format!("/api/external-engine/job/{second}/deeper"),
+                json!({ "clientSecret": "ees_clientsecret", "depth": 20 }),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_harness_stalled_stream() {
+        let harness = Harness::new().await;
+        harness.heartbeat().await;
+        let client = task::spawn(harness.analyse_with(json!({ "depth": 200 })));
+        let id = harness.acquire().await;
+        let analysis = client.await.unwrap();
+
+        // The requester does not read while the provider submits.
+        let mut lines: String = (1..=200)
+            .map(|depth| format!("info depth {depth} score cp 20 pv e2e4\n"))
+            .collect();
+        lines.push_str("bestmove e2e4\n");
+        let res = harness.submit(&id, Body::from(lines)).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let frames = frames_of(analysis).await;
+        assert!(frames.len() <= MAX_QUEUED_FRAMES);
+        assert_eq!(frames[0]["acquired"], true);
+        assert_eq!(frames[frames.len() - 2]["depth"], 200);
+        assert_eq!(frames.last().unwrap()["bestmove"], "e2e4");
+    }
+
+    #[tokio::test]
+    async fn test_harness_json_lines_forwarded_incrementally() {
+        let harness = Harness::new().await;
+        harness.heartbeat().await;
+
+        let client = task::spawn(harness.analyse());
+        let id = harness.acquire().await;
+        let mut analysis = client.await.unwrap().into_body().into_data_stream();
+        let mut next_frame = async || -> Value {
+            let chunk = timeout(Duration::from_secs(1), analysis.next())
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+            serde_json::from_slice(&chunk).unwrap()
+        };
+        assert_eq!(next_frame().await["acquired"], true);
+
+        let (lines, body) = mpsc::channel::<Result<&'static str, io::Error>>(1);
+        let submission = harness.submit(&id, Body::from_stream(ReceiverStream::new(body)));
+        let submission = task::spawn(submission);
+
+        lines
+            .send(Ok(
+                "{\"type\":\"info\",\"depth\":1,\"cp\":20,\"pv\":[\"e2e4\"]}\n",
+            ))
+            .await
+            .unwrap();
+        assert_eq!(next_frame().await["depth"], 1);
+
+        lines
+            .send(Ok(
+                "{\"type\":\"info\",\"depth\":2,\"cp\":25,\"pv\":[\"d2d4\"]}\n",
+            ))
+            .await
+            .unwrap();
+        let frame = next_frame().await;
+        assert_eq!(frame["depth"], 2);
+        assert_eq!(frame["pvs"][0]["moves"], json!(["d2d4"]));
+
+        lines
+            .send(Ok("{\"type\":\"bestmove\",\"bestmove\":\"d2d4\"}\n"))
+            .await
+            .unwrap();
+        assert_eq!(next_frame().await["bestmove"], "d2d4");
+        assert_eq!(submission.await.unwrap().status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_harness_challenge() {
+        let harness = Harness::new().await;
+
+        let answer = harness.sign_challenge("key").await;
+        let res = harness
+            .post_json(
+                "/api/external-engine/heartbeat",
+                json!({ "challenge": answer }),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+
+        let client = task::spawn(harness.analyse());
+        let answer = harness.sign_challenge("key").await;
+        let res = harness
+            .post_json(
+                "/api/external-engine/work",
+                json!({ "challenge": answer.clone() }),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(client.await.unwrap().status(), StatusCode::OK);
+
+        // Nonces can not be replayed.
+        let res = harness
+            .post_json("/api/external-engine/work", json!({ "challenge": answer }))
+            .await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        let answer = harness.sign_challenge("wrong").await;
+        let res = harness
+            .post_json("/api/external-engine/work", json!({ "challenge": answer }))
+            .await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        let res = harness
+            .post_json("/api/external-engine/work", json!({}))
+            .await;
+        assert_bad_request(res, "invalid request: providerSecret or challenge required").await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_harness_request_deadline() {
+        let harness = Harness::new().await;
+        harness.heartbeat().await;
+
+        // Not picked up in time.
+        let res = harness.analyse_with_deadline("1").await;
+        assert_eq!(res.status(), StatusCode::GATEWAY_TIMEOUT);
+
+        let started = Instant::now();
+        let client = task::spawn(harness.analyse_with_deadline("3"));
+        let id = harness.acquire().await;
+        let analysis = client.await.unwrap();
+        assert_eq!(analysis.status(), StatusCode::OK);
+
+        let (lines, body) = mpsc::channel::<Result<&'static str, io::Error>>(1);
+        let submission =
+            task::spawn(harness.submit(&id, Body::from_stream(ReceiverStream::new(body))));
+        lines
+            .send(Ok("info depth 1 score cp 20 pv e2e4\n"))
+            .await
+            .unwrap();
+
+        // The provider is stopped at the deadline, even though it would
+        // continue.
+        assert_eq!(submission.await.unwrap().status(), StatusCode::OK);
+        assert_eq!(started.elapsed(), Duration::from_secs(3));
+        let frames = frames_of(analysis).await;
+        assert_eq!(frames.len(), 3);
+        assert_eq!(frames[1]["depth"], 1);
+        assert_eq!(frames[2], json!({ "timeout": true }));
+        drop(lines);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_harness_acquire_queued_and_deadline() {
+        let harness = Harness::new().await;
+        harness.heartbeat().await;
+
+        let client = task::spawn(harness.analyse_with_deadline("10"));
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        let res = harness
+            .post_json(
+                "/api/external-engine/work",
+                json!({ "providerSecret": "secret" }),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body: Value = json_of(res).await;
+        assert_eq!(body["queuedMs"], 2000);
+        assert_eq!(body["deadlineMs"], 8000);
+        drop(client);
+
+        // Without a deadline.
+        let client = task::spawn(harness.analyse());
+        let res = harness
+            .post_json(
+                "/api/external-engine/work",
+                json!({ "providerSecret": "secret" }),
+            )
+            .await;
+        let body: Value = json_of(res).await;
+        assert_eq!(body["queuedMs"], 0);
+        assert!(body.get("deadlineMs").is_none());
+        drop(client);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_harness_match_timeout() {
+        let harness = Harness::new().await;
+        harness.heartbeat().await;
+
+        // Not picked up in time.
+        let started = Instant::now();
+        let res = harness.analyse_with(json!({ "matchTimeout": 2000 })).await;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(started.elapsed(), Duration::from_secs(2));
+        let body: Value = json_of(res).await;
+        assert_eq!(body["code"], "provider-unavailable");
+
+        // Once picked up, the search may take longer.
+        let client = task::spawn(harness.analyse_with(json!({ "matchTimeout": 2000 })));
+        let id = harness.acquire().await;
+        let analysis = client.await.unwrap();
+        assert_eq!(analysis.status(), StatusCode::OK);
+
+        let (lines, body) = mpsc::channel::<Result<&'static str, io::Error>>(1);
+        let submission =
+            task::spawn(harness.submit(&id, Body::from_stream(ReceiverStream::new(body))));
+        lines
+            .send(Ok("info depth 1 score cp 20 pv e2e4\n"))
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        lines.send(Ok("bestmove e2e4\n")).await.unwrap();
+        drop(lines);
+        assert_eq!(submission.await.unwrap().status(), StatusCode::OK);
+        let frames = frames_of(analysis).await;
+        assert_eq!(frames.last().unwrap()["done"], true);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_harness_analysis_timeout() {
+        let harness = Harness::new().await;
+        harness.heartbeat().await;
+        let timeouts = json!({ "matchTimeout": 2000, "analysisTimeout": 3000 });
+
+        // The match timeout passes first if nobody picks up the work.
+        let started = Instant::now();
+        let res = harness.analyse_with(timeouts.clone()).await;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(started.elapsed(), Duration::from_secs(2));
+        let body: Value = json_of(res).await;
+        assert_eq!(body["code"], "provider-unavailable");
+
+        // The analysis timeout counts from picking up the work.
+        let client = task::spawn(harness.analyse_with(timeouts));
+        sleep(Duration::from_secs(1)).await;
+        let acquired = Instant::now();
+        let id = harness.acquire().await;
+        let analysis = client.await.unwrap();
+        assert_eq!(analysis.status(), StatusCode::OK);
+
+        let (lines, body) = mpsc::channel::<Result<&'static str, io::Error>>(1);
+        let submission =
+            task::spawn(harness.submit(&id, Body::from_stream(ReceiverStream::new(body))));
+        lines
+            .send(Ok("info depth 1 score cp 20 pv e2e4\n"))
+            .await
+            .unwrap();
+        let res = submission.await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(acquired.elapsed(), Duration::from_secs(3));
+        drop(lines);
+
+        let frames = frames_of(analysis).await;
+        let last = frames.last().unwrap();
+        assert_eq!(last["code"], "analysis-timeout");
+        assert_eq!(last["error"], "analysis did not complete in time");
+    }
+
+    #[tokio::test]
+    async fn test_harness_compare() {
+        let harness = Harness::new().await;
+        harness.heartbeat().await;
+        let req = json!({
+            "engines": [
+                { "id": "eei_test", "clientSecret": "ees_clientsecret" },
+                { "id": "eei_other", "clientSecret": "ees_clientsecret" },
+            ],
+            "work": work(json!({})),
+        });
+
+        // The provider of the other engine is offline.
+        let res = harness
+            .post_json("/api/external-engine/compare", req.clone())
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let client = task::spawn(frames_of(res));
+        let id = harness.acquire().await;
+        let res = harness
+            .submit(
+                &id,
+                Body::from("info depth 1 score cp 20 pv e2e4\nbestmove e2e4\n"),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let frames = client.await.unwrap();
+        let (ours, others): (Vec<_>, Vec<_>) = frames
+            .into_iter()
+            .partition(|frame| frame["engineId"] == "eei_test");
+        assert_eq!(others.len(), 1);
+        assert_eq!(others[0]["error"], "no provider online");
+        assert_eq!(ours.last().unwrap()["done"], true);
+
+        // Both online.
+        let res = harness
+            .post_json(
+                "/api/external-engine/heartbeat",
+                json!({ "providerSecret": "other" }),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        let res = harness.post_json("/api/external-engine/compare", req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let client = task::spawn(frames_of(res));
+        for secret in ["secret", "other"] {
+            let res = harness
+                .post_json(
+                    "/api/external-engine/work",
+                    json!({ "providerSecret": secret }),
+                )
+                .await;
+            assert_eq!(res.status(), StatusCode::OK);
+            let job: Value = json_of(res).await;
+            let id: JobId = serde_json::from_value(job["id"].clone()).unwrap();
+            let res = harness
+                .submit(
+                    &id,
+                    Body::from("info depth 1 score cp 20 pv e2e4\nbestmove e2e4\n"),
+                )
+                .await;
+            assert_eq!(res.status(), StatusCode::OK);
+        }
+        let frames = client.await.unwrap();
+        for engine in ["eei_test", "eei_other"] {
+            let last = frames
+                .iter()
+                .rev()
+                .find(|frame| frame["engineId"] == engine)
+                .unwrap();
+            assert_eq!(last["done"], true);
+        }
+
+        // Engines of the requester are analysed even if another is unknown
+        // or disabled.
+        let other = EngineId("eei_other".to_owned());
+        let secret = ClientSecret::try_from("ees_clientsecret".to_owned()).unwrap();
+        assert!(harness
+            .store
+            .set_enabled(other, secret, false)
+            .await
+            .unwrap());
+        for (other_secret, expected) in [
+            (
+                "ees_wrongclientsecret",
+                "engine not found or invalid clientSecret",
+            ),
+            ("ees_clientsecret", "engine disabled"),
+        ] {
+            let res = harness
+                .post_json(
+                    "/api/external-engine/compare",
+                    json!({
+                        "engines": [
+                            { "id": "eei_test", "clientSecret": "ees_clientsecret" },
+                            { "id": "eei_other", "clientSecret": other_secret },
+                        ],
+                        "work": work(json!({})),
+                    }),
+                )
+                .await;
+            assert_eq!(res.status(), StatusCode::OK);
+            let client = task::spawn(frames_of(res));
+            let id = harness.acquire().await;
+            let res = harness
+                .submit(
+                    &id,
+                    Body::from("info depth 1 score cp 20 pv e2e4\nbestmove e2e4\n"),
+                )
+                .await;
+            assert_eq!(res.status(), StatusCode::OK);
+            let frames = client.await.unwrap();
+            let (ours, others): (Vec<_>, Vec<_>) = frames
+                .into_iter()
+                .partition(|frame| frame["engineId"] == "eei_test");
+            assert_eq!(others.len(), 1);
+            assert_eq!(others[0]["error"], expected);
+            assert_eq!(ours.last().unwrap()["done"], true);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_harness_max_depth() {
+        let harness = Harness::with(
+            false,
+            WorkOpt {
+                max_depth: Some(5),
+                ..WorkOpt::default()
+            },
+        )
+        .await;
+        harness.heartbeat().await;
+        let client = task::spawn(harness.analyse());
+        let id = harness.acquire().await;
+        let analysis = client.await.unwrap();
+        assert_eq!(analysis.status(), StatusCode::OK);
+
+        // The provider is stopped without waiting for the rest of its
+        // output.
+        let (lines, body) = mpsc::channel::<Result<&'static str, io::Error>>(4);
+        let submission =
+            task::spawn(harness.submit(&id, Body::from_stream(ReceiverStream::new(body))));
+        lines
+            .send(Ok("info depth 4 score cp 20 pv e2e4\n"))
+            .await
+            .unwrap();
+        lines
+            .send(Ok("info depth 5 score cp 30 pv d2d4\n"))
+            .await
+            .unwrap();
+        let res = timeout(Duration::from_secs(1), submission)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        drop(lines);
+
+        let frames = frames_of(analysis).await;
+        assert_eq!(frames[frames.len() - 2]["depth"], 5);
+        assert_eq!(frames.last().unwrap()["done"], true);
+        assert_eq!(frames.last().unwrap()["bestmove"], "d2d4");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_harness_max_info_rate() {
+        let harness = Harness::with(
+            false,
+            WorkOpt {
+                max_info_rate: NonZeroU32::new(2),
+                ..WorkOpt::default()
+            },
+        )
+        .await;
+        harness.heartbeat().await;
+        let client = task::spawn(harness.analyse());
+        let id = harness.acquire().await;
+        // Read frames as they arrive, so that the stream does not coalesce
+        // them for a slow reader.
+        let mut analysis = client.await.unwrap().into_body().into_data_stream();
+        let reader = task::spawn(async move {
+            let mut frames = Vec::new();
+            while let Some(chunk) = analysis.next().await {
+                for line in chunk.unwrap().split(|b| *b == b'\n') {
+                    if !line.is_empty() {
+                        frames.push(serde_json::from_slice::<Value>(line).unwrap());
+                    }
+                }
+            }
+            frames
+        });
+
+        let (lines, body) = mpsc::channel::<Result<String, io::Error>>(32);
+        let submission =
+            task::spawn(harness.submit(&id, Body::from_stream(ReceiverStream::new(body))));
+        let burst = |depths: RangeInclusive<u32>| {
+            let lines = lines.clone();
+            async move {
+                for depth in depths {
+                    lines
+                        .send(Ok(format!("info depth {depth} score cp 20 pv e2e4\n")))
+                        .await
+                        .unwrap();
+                }
+            }
+        };
+        burst(1..=10).await;
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        burst(11..=20).await;
+        lines.send(Ok("bestmove e2e4\n".to_owned())).await.unwrap();
+        drop(lines);
+        assert_eq!(submission.await.unwrap().status(), StatusCode::OK);
+
+        // The first frame is sent right away, the latest of the rest once
+        // the interval passed, and pending frames before the best move.
+        let frames = reader.await.unwrap();
+        let depths: Vec<_> = frames
+            .iter()
+            .filter_map(|frame| frame.get("depth"))
+            .collect();
+        assert_eq!(depths, [1, 10, 20]);
+        assert_eq!(frames.last().unwrap()["bestmove"], "e2e4");
+    }
+
+    #[tokio::test]
+    async fn test_harness_provider_connections_per_ip() {
+        let harness = Harness::new().await;
+        let acquire = json!({ "providerSecret": "secret", "keepAlive": true });
+        let heartbeat = json!({ "providerSecret": "secret" });
+
+        // Waiting for work holds a connection until the body is dropped.
+        let mut held = Vec::new();
+        for _ in 0..2 {
+            let res = harness
+                .post_json_from("192.0.2.1", "/api/external-engine/work", acquire.clone())
+                .await;
+            assert_eq!(res.status(), StatusCode::OK);
+            held.push(res);
+        }
+        let res = harness
+            .post_json_from(
+                "192.0.2.1",
+                "/api/external-engine/heartbeat",
+                heartbeat.clone(),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        let res = harness
+            .post_json_from(
+                "192.0.2.2",
+                "/api/external-engine/heartbeat",
+                heartbeat.clone(),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        for _ in 0..3 {
+            let res = harness
+                .post_json_from("10.0.0.1", "/api/external-engine/work", acquire.clone())
+                .await;
+            assert_eq!(res.status(), StatusCode::OK);
+            held.push(res);
+        }
+
+        // Clients are not limited.
+        let res = harness
+            .post_json_from(
+                "192.0.2.1",
+                "/api/external-engine/session/session/cancel",
+                json!({ "clientSecret": "ees_clientsecret" }),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+
+        held.remove(0);
+        let res = harness
+            .post_json_from("192.0.2.1", "/api/external-engine/heartbeat", heartbeat)
+            .await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_harness_trust_proxy() {
+        let acquire = json!({ "providerSecret": "secret", "keepAlive": true });
+        let heartbeat = json!({ "providerSecret": "secret" });
+
+        for trust_proxy in [false, true] {
+            let harness = Harness::with_trust_proxy(trust_proxy).await;
+
+            // Distinct providers behind the same proxy.
+            let mut held = Vec::new();
+            for forwarded_for in ["192.0.2.1", "192.0.2.2"] {
+                let res = harness
+                    .post_json_forwarded(
+                        "10.0.0.2",
+                        Some(forwarded_for),
+                        "/api/external-engine/work",
+                        acquire.clone(),
+                    )
+                    .await;
+                assert_eq!(res.status(), StatusCode::OK);
+                held.push(res);
+            }
+            let res = harness
+                .post_json_forwarded(
+                    "10.0.0.2",
+                    Some("spoofed, 192.0.2.3"),
+                    "/api/external-engine/heartbeat",
+                    heartbeat.clone(),
+                )
+                .await;
+            assert_eq!(
+                res.status(),
+                if trust_proxy {
+                    StatusCode::NO_CONTENT
+                } else {
+                    StatusCode::TOO_MANY_REQUESTS
+                }
+            );
+
+            // Falls back to the peer without the header.
+            let res = harness
+                .post_json_from(
+                    "10.0.0.2",
+                    "/api/external-engine/heartbeat",
+                    heartbeat.clone(),
+                )
+                .await;
+            assert_eq!(
+                res.status(),
+                if trust_proxy {
+                    StatusCode::NO_CONTENT
+                } else {
+                    StatusCode::TOO_MANY_REQUESTS
+                }
+            );
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_harness_acquire_handshake() {
+        let harness = Harness::new().await;
+        let handshake = json!({
+            "name": "Stockfish",
+            "version": "17",
+            "variants": ["chess", "crazyhouse", "atomic", "shogi"],
+            "maxThreads": 16,
+            "maxHash": 1024,
+            "supportsPonder": true,
+            "options": [
+                "option name Threads type spin default 1 min 1 max 16",
+                "option name Skill Level type spin default 20 min 0 max 20",
+            ],
+        });
+
+        let res = harness
+            .post_json(
+                "/api/external-engine/work",
+                json!({ "providerSecret": "secret", "handshake": handshake }),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        let res = harness.get("/metrics", "admin").await;
+        let metrics = text_of(res).await;
+        assert!(metrics.contains(&format!(
+            "\nlila_engine_provider_info{{selector=\"{}\",name=\"Stockfish\",version=\"17\"}} 1\n",
+            selector().as_str()
+        )));
+        let served = harness.store.list_by_provider(selector()).await.unwrap();
+        assert!(!served.is_empty());
+        for engine in served {
+            let (engine, _) = engine.into_engine_and_selector();
+            let options = engine.config.allowed_options;
+            assert_eq!(options.len(), 2);
+            assert_eq!(options[1].name, "Skill Level");
+            assert_eq!(options[1].max, Some(20));
+        }
+
+        let mut fewer_threads = handshake.clone();
+        fewer_threads["maxThreads"] = json!(4);
+        let res = harness
+            .post_json(
+                "/api/external-engine/work",
+                json!({ "providerSecret": "secret", "handshake": fewer_threads }),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(
+            body,
+            "handshake mismatch: registered with 8 threads, but the engine supports 4"
+        );
+
+        let mut no_ponder = handshake;
+        no_ponder["supportsPonder"] = json!(false);
+        let res = harness
+            .post_json(
+                "/api/external-engine/work",
+                json!({ "providerSecret": "secret", "handshake": no_ponder }),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+    }
+
+    #[tokio::test]
+    async fn test_harness_engines_per_variant() {
+        let harness = Harness::new().await;
+        let shared: ProviderSecret = serde_json::from_value(json!("shared")).unwrap();
+        for (id, variant) in [
+            ("eei_chess", Variant::Chess),
+            ("eei_zh", Variant::Crazyhouse),
+        ] {
+            let mut engine = engine();
+            engine.id = EngineId(id.to_owned());
+            engine.config.variants = vec![variant];
+            harness
+                .store
+                .create(ExternalEngine::new(engine, shared.selector()))
+                .await
+                .unwrap();
+        }
+        let res = harness
+            .post_json(
+                "/api/external-engine/heartbeat",
+                json!({ "providerSecret": "shared" }),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+
+        let crazyhouse = work(json!({
+            "variant": "crazyhouse",
+            "initialFen": "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR[] w KQkq - 0 1",
+        }));
+        let zh_client = task::spawn(harness.post_json(
+            "/api/external-engine/eei_zh/analyse",
+            json!({ "clientSecret": "ees_clientsecret", "work": crazyhouse }),
+        ));
+        let chess_client = task::spawn(harness.post_json(
+            "/api/external-engine/eei_chess/analyse",
+            json!({ "clientSecret": "ees_clientsecret", "work": work(json!({})) }),
+        ));
+
+        // Each provider behind the shared secret describes the engine it
+        // runs, and is checked only against the matching registration.
+        for (variant, engine) in [("crazyhouse", "eei_zh"), ("chess", "eei_chess")] {
+            let res = harness
+                .post_json(
+                    "/api/external-engine/work",
+                    json!({
+                        "providerSecret": "shared",
+                        "variants": [variant],
+                        "handshake": {
+                            "name": "Stockfish",
+                            "variants": [variant],
+                            "maxThreads": 8,
+                            "maxHash": 512,
+                        },
+                    }),
+                )
+                .await;
+            assert_eq!(res.status(), StatusCode::OK);
+            let body: Value = json_of(res).await;
+            assert_eq!(body["engine"]["id"], engine);
+            assert_eq!(body["work"]["variant"], variant);
+        }
+        assert_eq!(zh_client.await.unwrap().status(), StatusCode::OK);
+        assert_eq!(chess_client.await.unwrap().status(), StatusCode::OK);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_harness_acquire_empty_status() {
+        let acquire = json!({ "providerSecret": "secret" });
+
+        let harness = Harness::new().await;
+        let res = harness
+            .post_json("/api/external-engine/work", acquire.clone())
+            .await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert!(to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap()
+            .is_empty());
+
+        let harness = Harness::with(
+            false,
+            WorkOpt {
+                acquire_empty_status: AcquireEmptyStatus::Ok,
+                ..WorkOpt::default()
+            },
+        )
+        .await;
+        let res = harness
+            .post_json("/api/external-engine/work", acquire)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body: Value = json_of(res).await;
+        assert_eq!(body, json!({}));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_harness_acquire_keep_alive() {
+        let harness = Harness::new().await;
+        harness.heartbeat().await;
+
+        let res = harness
+            .post_json(
+                "/api/external-engine/work",
+                json!({ "providerSecret": "secret", "keepAlive": true }),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let mut lines = res.into_body().into_data_stream();
+
+        // Quiet wait.
+        let started = Instant::now();
+        assert_eq!(lines.next().await.unwrap().unwrap(), "\n");
+        assert_eq!(
+            started.elapsed(),
+            Duration::from_secs(WorkOpt::default().acquire_keep_alive)
+        );
+
+        let client = task::spawn(harness.analyse());
+        let line = lines.next().await.unwrap().unwrap();
+        let acquired: Value = serde_json::from_slice(&line).unwrap();
+        assert_eq!(acquired["id"], "job0");
+        assert_eq!(acquired["engine"]["name"], "Stockfish");
+        assert!(lines.next().await.is_none());
+        assert_eq!(client.await.unwrap().status(), StatusCode::OK);
+
+        // Without work, the stream ends after the usual timeout.
+        let res = harness
+            .post_json(
+                "/api/external-engine/work",
+                json!({ "providerSecret": "secret", "keepAlive": true }),
+            )
+            .await;
+        let started = Instant::now();
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        assert!(body.iter().all(|b| *b == b'\n'));
+        assert_eq!(
+            started.elapsed(),
+            Duration::from_secs(WorkOpt::default().acquire_timeout)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_harness_health() {
+        let harness = Harness::new().await;
+        harness.heartbeat().await;
+
+        let client = task::spawn(harness.analyse());
+        let id = harness.acquire().await;
+        let _analysis = client.await.unwrap();
+        let res = harness.submit(&id, Body::from("bestmove e2e4\n")).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let unauthenticated = Request::get("/api/admin/engines/health")
+            .body(Body::empty())
+            .unwrap();
+        let res = harness.app.clone().oneshot(unauthenticated).await.unwrap();
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        let res = harness.get("/api/admin/engines/health", "wrong").await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        let res = harness.get("/api/admin/engines/health", "admin").await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body: Value = json_of(res).await;
+        assert_eq!(body["total"], 1);
+        let provider = &body["providers"][0];
+        assert_eq!(provider["selector"], json!(selector()));
+        assert_eq!(provider["connected"], false);
+        assert_eq!(provider["queued"], 0);
+        assert_eq!(provider["completionRate"], 1.0);
+        assert!(provider["lastSeen"].is_u64());
+
+        let res = harness
+            .get("/api/admin/engines/health?offset=1&limit=10", "admin")
+            .await;
+        let body: Value = json_of(res).await;
+        assert_eq!(body, json!({ "total": 1, "providers": [] }));
+    }
+
+    #[tokio::test]
+    async fn test_harness_recent_jobs() {
+        let harness = Harness::new().await;
+
+        let res = harness.get("/api/admin/jobs/recent", "wrong").await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        let mut ids = Vec::new();
+        for _ in 0..3 {
+            harness.heartbeat().await;
+            let client = task::spawn(harness.analyse());
+            let id = harness.acquire().await;
+            let _analysis = client.await.unwrap();
+            let res = harness.submit(&id, Body::from("bestmove e2e4\n")).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            ids.push(id);
+        }
+
+        // Only the last jobs are kept, newest first.
+        let res = harness.get("/api/admin/jobs/recent", "admin").await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let bytes = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        assert!(!String::from_utf8_lossy(&bytes).contains("secret"));
+        let body: Value = serde_json::from_slice(&bytes).unwrap();
+        let jobs = body["jobs"].as_array().unwrap();
+        assert_eq!(jobs.len(), 2);
+        assert_eq!(jobs[0]["id"], json!(ids[2]));
+        assert_eq!(jobs[1]["id"], json!(ids[1]));
+        assert_eq!(jobs[0]["engine"], "eei_test");
+        assert_eq!(jobs[0]["variant"], "chess");
+        assert_eq!(jobs[0]["outcome"], "bestmove");
+        assert!(jobs[0]["durationMs"].is_u64());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_harness_recent_failed_jobs() {
+        let harness = Harness::new().await;
+        harness.heartbeat().await;
+
+        // Never picked up.
+        let res = harness.analyse_with(json!({ "matchTimeout": 1000 })).await;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        // Picked up, but handed to another provider since it did not start.
+        let client = task::spawn(harness.analyse());
+        let id = harness.acquire().await;
+        let _analysis = client.await.unwrap();
+        sleep(Duration::from_secs(WorkOpt::default().start_timeout + 1)).await;
+
+        let res = harness.get("/api/admin/jobs/recent", "admin").await;
+        let body: Value = json_of(res).await;
+        let jobs = body["jobs"].as_array().unwrap();
+        assert_eq!(jobs.len(), 2);
+        assert_eq!(jobs[0]["id"], json!(id));
+        assert_eq!(jobs[0]["outcome"], "redispatch");
+        assert_eq!(jobs[0]["durationMs"], 30_000);
+        assert!(jobs[1].get("id").is_none());
+        assert_eq!(jobs[1]["engine"], "eei_test");
+        assert_eq!(jobs[1]["outcome"], "not-picked-up");
+        assert_eq!(jobs[1]["durationMs"], 1000);
+    }
+
+    #[tokio::test]
+    async fn test_harness_cancel_session() {
+        let harness = Harness::new().await;
+        harness.heartbeat().await;
+
+        let mut jobs = Vec::new();
+        for _ in 0..2 {
+            let client = task::spawn(harness.analyse());
+            let id = harness.acquire().await;
+            jobs.push((id, client.await.unwrap()));
+        }
+
+        let (lines, body) = mpsc::channel::<Result<&'static str, io::Error>>(1);
+        let submission =
+            task::spawn(harness.submit(&jobs[0].0, Body::from_stream(ReceiverStream::new(body))));
+        lines
+            .send(Ok("info depth 1 score cp 20 pv e2e4\n"))
+            .await
+            .unwrap();
+
+        // Sessions are scoped to the client secret.
+        let res = harness
+            .post_json(
+                "/api/external-engine/session/session/cancel",
+                json!({ "clientSecret": "ees_other" }),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert!(!submission.is_finished());
+
+        let res = harness
+            .post_json(
+                "/api/external-engine/session/session/cancel",
+                json!({ "clientSecret": "ees_clientsecret" }),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+
+        // Both providers are told to stop, even though they would continue.
+        let res = timeout(Duration::from_secs(1), submission)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let (_more_lines, body) = mpsc::channel::<Result<&'static str, io::Error>>(1);
+        let res = timeout(
+            Duration::from_secs(1),
+            harness.submit(&jobs[1].0, Body::from_stream(ReceiverStream::new(body))),
+        )
+        .await
+        .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        for (_, analysis) in jobs {
+            let frames = frames_of(analysis).await;
+            assert!(frames.iter().all(|frame| frame.get("done").is_none()));
+        }
+        drop(lines);
+    }
+
+    #[tokio::test]
+    async fn test_harness_ponderhit() {
+        let harness = Harness::new().await;
+        harness.heartbeat().await;
+
+        let client = task::spawn(harness.analyse_with(json!({ "ponder": "e2e4" })));
+        let id = harness.acquire().await;
+        let analysis = client.await.unwrap();
+        assert_eq!(analysis.status(), StatusCode::OK);
+
+        // The provider searches the position after the expected move.
+        let (lines, body) = mpsc::channel::<Result<&'static str, io::Error>>(1);
+        let submission =
+            task::spawn(harness.submit(&id, Body::from_stream(ReceiverStream::new(body))));
+        lines
+            .send(Ok("info depth 1 score cp 20 pv e7e5\n"))
+            .await
+            .unwrap();
+        let poll = task::spawn(harness.ponder(&id, "secret"));
+
+        // Only the provider that acquired the work may poll.
+        let res = harness.ponder(&id, "other").await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let res = harness
+            .post_json(&format!("/api/external-engine/work/{id}/ponder"), json!({}))
+            .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        let res = harness
+            .post_json(
+                "/api/external-engine/session/other/play",
+                json!({ "clientSecret": "ees_clientsecret", "move": "e2e4" }),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let res = harness
+            .post_json(
+                "/api/external-engine/session/session/play",
+                json!({ "clientSecret": "ees_clientsecret", "move": "e2e4" }),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+
+        let res = timeout(Duration::from_secs(1), poll)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let body: Value = json_of(res).await;
+        assert_eq!(body, json!({ "ponderhit": true }));
+
+        // After ponderhit, the same search continues to the end.
+        lines
+            .send(Ok("info depth 2 score cp 25 pv e7e5 g1f3\nbestmove e7e5\n"))
+            .await
+            .unwrap();
+        drop(lines);
+        assert_eq!(submission.await.unwrap().status(), StatusCode::OK);
+        let frames = frames_of(analysis).await;
+        assert_eq!(frames.last().unwrap()["done"], true);
+        assert_eq!(
+            frames[frames.len() - 2]["pvs"][0]["moves"],
+            json!(["e7e5", "g1f3"])
+        );
+
+        let res = harness.ponder(&id, "secret").await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_harness_game_over() {
+        // Answered without any provider.
+        let harness = Harness::new().await;
+
+        let res = harness
+            .analyse_with(json!({ "moves": ["f2f3", "e7e5", "g2g4", "d8h4"] }))
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            frames_of(res).await,
+            [json!({ "done": true, "outcome": "0-1", "termination": "checkmate" })]
+        );
+
+        let res = harness
+            .analyse_with(json!({ "initialFen": "7k/5Q2/6K1/8/8/8/8/8 b - - 0 1" }))
+            .await;
+        assert_eq!(
+            frames_of(res).await,
+            [json!({ "done": true, "outcome": "1/2-1/2", "termination": "stalemate" })]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_provider_connections_unlimited_by_default() {
+        let app = app(AppState {
+            peers: None,
+            ..app_state()
+        });
+        let mut held = Vec::new();
+        for _ in 0..3 {
+            let body = json!({ "providerSecret": "secret", "keepAlive": true });
+            let req = Request::post("/api/external-engine/work")
+                .header("content-type", "application/json")
+                .extension(ConnectInfo(PeerAddr {
+                    ip: Some("192.0.2.1".parse().unwrap()),
+                    connection: ConnectionId::next(),
+                }))
+                .body(Body::from(body.to_string()))
+                .unwrap();
+            let res = app.clone().oneshot(req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            held.push(res);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_harness_reused_provider_connection() {
+        let harness = Harness::new().await;
+        harness.heartbeat().await;
+
+        // The same provider runs two acquire-submit cycles. The first ends
+        // early with malformed lines while pondering.
+        let mut ids = Vec::new();
+        for (extra, lines, expected) in [
+            (
+                json!({ "ponder": "e2e4" }),
+                "info depth garbage\n".repeat(WorkOpt::default().max_malformed_lines as usize),
+                StatusCode::BAD_REQUEST,
+            ),
+            (json!({}), "bestmove e2e4\n".to_owned(), StatusCode::OK),
+        ] {
+            let client = task::spawn(harness.analyse_with(extra));
+            let id = harness.acquire().await;
+            let _analysis = client.await.unwrap();
+            let res = harness.submit(&id, Body::from(lines)).await;
+            assert_eq!(res.status(), expected);
+            ids.push(id);
+        }
+
+        for id in &ids {
+            let res = harness.submit(id, Body::from("bestmove e2e4\n")).await;
+            assert_eq!(res.status(), StatusCode::GONE);
+            let res = timeout(Duration::from_secs(1), harness.ponder(id, "secret"))
+                .await
+                .unwrap();
+            assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        }
+
+        assert_eq!(harness.ongoing.len(), 0);
+        assert_eq!(harness.ponders.len(), 0);
+        let res = harness.get("/api/admin/engines/health", "admin").await;
+        let body: Value = json_of(res).await;
+        let provider = &body["providers"][0];
+        assert_eq!(provider["connected"], false);
+        assert_eq!(provider["queued"], 0);
+    }
+
+    #[tokio::test]
+    async fn test_harness_stats() {
+        let harness = Harness::new().await;
+        harness.heartbeat().await;
+
+        let stats = async |bearer| -> Response {
+            harness
+                .get("/api/external-engine/eei_test/stats", bearer)
+                .await
+        };
+        let res = stats("ees_clientsecret").await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body: Value = json_of(res).await;
+        assert_eq!(body, json!({ "analyses": 0, "lastUsed": null }));
+
+        for _ in 0..2 {
+            let client = task::spawn(harness.analyse());
+            let id = harness.acquire().await;
+            let _analysis = client.await.unwrap();
+            let res = harness.submit(&id, Body::from("bestmove e2e4\n")).await;
+            assert_eq!(res.status(), StatusCode::OK);
+        }
+
+        let res = stats("ees_clientsecret").await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body: Value = json_of(res).await;
+        assert_eq!(body["analyses"], 2);
+        assert!(body["lastUsed"].is_i64());
+
+        assert_eq!(
+            stats("ees_wrongwrongwrong").await.status(),
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[tokio::test]
+    async fn test_harness_rotate_secret() {
+        let harness = Harness::new().await;
+        harness.heartbeat().await;
+
+        let client = task::spawn(harness.analyse());
+        let id = harness.acquire().await;
+        let _analysis = client.await.unwrap();
+        let (lines, body) = mpsc::channel::<Result<&'static str, io::Error>>(1);
+        let submission =
+            task::spawn(harness.submit(&id, Body::from_stream(ReceiverStream::new(body))));
+        lines
+            .send(Ok("info depth 1 score cp 20 pv e2e4\n"))
+            .await
+            .unwrap();
+
+        let rotate = async |bearer| -> Response {
+            harness
+                .post_admin(
+                    "/api/external-engine/eei_test/rotate-secret",
+                    bearer,
+                    json!({}),
+                )
+                .await
+        };
+        assert_eq!(
+            rotate("ees_wrongwrongwrong").await.status(),
+            StatusCode::NOT_FOUND
+        );
+        let res = rotate("ees_clientsecret").await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body: Value = json_of(res).await;
+        let new_secret = body["clientSecret"].as_str().unwrap().to_owned();
+        assert_ne!(new_secret, "ees_clientsecret");
+
+        // The job of the old secret is stopped.
+        let res = timeout(Duration::from_secs(1), submission)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        drop(lines);
+
+        let stats = async |bearer| -> StatusCode {
+            harness
+                .get("/api/external-engine/eei_test/stats", bearer)
+                .await
+                .status()
+        };
+        assert_eq!(stats("ees_clientsecret").await, StatusCode::NOT_FOUND);
+        assert_eq!(stats(&new_secret).await, StatusCode::OK);
+        assert_eq!(
+            rotate("ees_clientsecret").await.status(),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(harness.analyse().await.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_harness_disable_engine() {
+        let harness = Harness::new().await;
+        harness.heartbeat().await;
+
+        let set_enabled = async |bearer, enabled| -> StatusCode {
+            harness
+                .post_admin(
+                    "/api/external-engine/eei_test/enabled",
+                    bearer,
+                    json!({ "enabled": enabled }),
+                )
+                .await
+                .status()
+        };
+        assert_eq!(
+            set_enabled("ees_wrongwrongwrong", false).await,
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            set_enabled("ees_clientsecret", false).await,
+            StatusCode::NO_CONTENT
+        );
+
+        // New work is rejected.
+        let res = harness.analyse().await;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body: Value = json_of(res).await;
+        assert_eq!(body["code"], "engine-disabled");
+        assert_eq!(body["error"], "engine disabled");
+
+        // The registration is intact.
+        let engine = harness
+            .store
+            .find(
+                EngineId("eei_test".to_owned()),
+                ClientSecret::try_from("ees_clientsecret".to_owned()).unwrap(),
+            )
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(!engine.is_enabled());
+        assert_eq!(
+            harness
+                .get("/api/external-engine/eei_test/stats", "ees_clientsecret")
+                .await
+                .status(),
+            StatusCode::OK
+        );
+
+        assert_eq!(
+            set_enabled("ees_clientsecret", true).await,
+            StatusCode::NO_CONTENT
+        );
+        let client = task::spawn(harness.analyse());
+        let id = harness.acquire().await;
+        let _analysis = client.await.unwrap();
+        let res = harness.submit(&id, Body::from("bestmove e2e4\n")).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_harness_client_ref() {
+        let harness = Harness::new().await;
+        harness.heartbeat().await;
+
+        let client = task::spawn(harness.analyse_with(json!({ "clientRef": "board-1" })));
+        let id = harness.acquire().await;
+        let analysis = client.await.unwrap();
+        let res = harness
+            .submit(
+                &id,
+                Body::from("info depth 1 score cp 20 pv e2e4\nbestmove e2e4\n"),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let frames = frames_of(analysis).await;
+        assert_eq!(frames.first().unwrap()["clientRef"], "board-1");
+        assert_eq!(frames.last().unwrap()["clientRef"], "board-1");
+        assert!(frames[1..frames.len() - 1]
+            .iter()
+            .all(|frame| frame.get("clientRef").is_none()));
+    }
+
+    #[tokio::test]
+    async fn test_harness_max_info_frames() {
+        let harness = Harness::new().await;
+        harness.heartbeat().await;
+
+        let client = task::spawn(harness.analyse_with(json!({ "maxInfoFrames": 2 })));
+        let id = harness.acquire().await;
+        let analysis = client.await.unwrap();
+        let res = harness
+            .submit(
+                &id,
+                Body::from(
+                    "info depth 1 score cp 20 pv e2e4\n\
+                     info depth 2 score cp 25 pv e2e4\n\
+                     info depth 3 score cp 30 pv d2d4\n\
+                     info depth 4 score cp 35 pv d2d4\n\
+                     bestmove d2d4\n",
+                ),
+            )
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let frames = frames_of(analysis).await;
+        let depths: Vec<&Value> = frames
+            .iter()
+            .filter_map(|frame| frame.get("depth"))
+            .collect();
+        assert!(!depths.is_empty() && depths.len() <= 2);
+        assert!(depths.iter().all(|depth| depth.as_u64().unwrap() <= 2));
+        let last = frames.last().unwrap();
+        assert_eq!(last["done"], true);
+        assert_eq!(last["bestmove"], "d2d4");
+    }
+
+    #[tokio::test]
+    async fn test_harness_client_disconnect() {
+        let harness = Harness::new().await;
+        harness.heartbeat().await;
+
+        let client = task::spawn(harness.analyse());
+        let id = harness.acquire().await;
+        drop(client.await.unwrap());
+
+        // The provider is released even though it keeps its stream open.
+        let (lines, body) = mpsc::channel::<Result<&'static str, io::Error>>(1);
+        let submission = harness.submit(&id, Body::from_stream(ReceiverStream::new(body)));
+        lines
+            .send(Ok("info depth 1 score cp 20 pv e2e4\n"))
+            .await
+            .unwrap();
+        let res = timeout(Duration::from_secs(1), submission).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let res = harness.submit(&id, Body::empty()).await;
+        assert_eq!(res.status(), StatusCode::GONE);
+        drop(lines);
+    }
+
+    #[tokio::test]
+    async fn test_cancel_after_last_subscriber() {
+        let state = app_state();
+        let AppState { hub, ongoing, .. } = state;
+        hub.heartbeat(selector());
+
+        let (work, pos) = work(json!({})).sanitize(&engine(), state.work_opt).unwrap();
+        let client = task::spawn(dispatch(
+            Clients::from_ref(&state),
+            selector(),
+            engine(),
+            work,
+            pos,
+        ));
+        let job = hub.acquire(selector(), |_| true).await.unwrap();
+        let job = job.start(&JobId::random(), hub.busy(selector()));
+        let first = client.await.unwrap().unwrap();
+        let second = first.resubscribe();
+
+        // One subscriber cancelling does not affect the other.
+        drop(first);
+        assert!(job.is_valid());
+        drop(second);
+        assert!(!job.is_valid());
+
+        // The provider is told to stop right away, even though it would
+        // continue streaming.
+        let id = JobId::random();
+        ongoing.add(id.clone(), job);
+        let (lines, body) = mpsc::channel::<Result<&'static str, io::Error>>(1);
+        timeout(
+            Duration::from_secs(1),
+            submit_to(&state, id, Body::from_stream(ReceiverStream::new(body))),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        drop(lines);
+    }
+
+    #[tokio::test]
+    async fn test_acquire_assigns_ids() {
+        let state = app_state();
+        let AppState { hub, ongoing, .. } = state;
+
+        let mut waiting = Vec::new();
+        for expected in ["job0", "job1"] {
+            let (job, rx) = job(selector());
+            waiting.push(rx);
+            hub.submit(selector(), job).unwrap();
+            let res = acquire_from(&state).await;
+            assert_eq!(res.id.to_string(), expected);
+            assert!(ongoing.remove(&res.id).is_some());
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_unavailable_causes() {
+        let state = app_state();
+        let hub = state.hub;
+        let (work, pos) = work(json!({}))
+            .sanitize(&engine(), &WorkOpt::default())
+            .unwrap();
+
+        // Offline provider is detected without waiting.
+        let started = tokio::time::Instant::now();
+        let err = dispatch(
+            Clients::from_ref(&state),
+            selector(),
+            engine(),
+            work.clone(),
+            pos.clone(),
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(err, Error::Unavailable(Unavailable::NoProvider)));
+        assert_eq!(started.elapsed(), Duration::ZERO);
+
+        // Online provider that does not pick up work in time.
+        hub.heartbeat(selector());
+        let err = dispatch(
+            Clients::from_ref(&state),
+            selector(),
+            engine(),
+            work.clone(),
+            pos.clone(),
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(err, Error::Unavailable(Unavailable::NotPickedUp)));
+        assert_eq!(started.elapsed(), Duration::from_secs(15));
+        let res = err.into_response();
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(res.headers()[header::RETRY_AFTER], "10");
+        let body: Value = json_of(res).await;
+        assert_eq!(body["code"], "provider-unavailable");
+        assert_eq!(body["retryAfter"], 10);
+
+        hub.heartbeat(selector());
+        let mut waiting = Vec::new();
+        loop {
+            let (job, rx) = job(selector());
+            waiting.push(rx);
+            if hub.submit(selector(), job).is_err() {
+                break;
+            }
+        }
+        let err = dispatch(Clients::from_ref(&state), selector(), engine(), work, pos)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::Unavailable(Unavailable::QueueFull)));
+        let res = err.into_response();
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(res.headers()[header::RETRY_AFTER], "5");
+        let body: Value = json_of(res).await;
+        assert_eq!(body["code"], "queue-full");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_unavailable_paused() {
+        let mut state = app_state();
+        state.hub = Box::leak(Box::new(
+            Hub::default().with_min_completion_rate(0.5, Duration::from_secs(60)),
+        ));
+        let (work, pos) = work(json!({}))
+            .sanitize(&engine(), &WorkOpt::default())
+            .unwrap();
+        for _ in 0..10 {
+            state.hub.record_outcome(selector(), false);
+        }
+
+        // Providers stay away while paused, but the engine is not offline.
+        tokio::time::sleep(Duration::from_secs(45)).await;
+        let err = dispatch(Clients::from_ref(&state), selector(), engine(), work, pos)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            Error::Unavailable(Unavailable::ProviderPaused)
+        ));
+        let body: Value = json_of(err.into_response()).await;
+        assert_eq!(body["code"], "provider-paused");
+    }
 }
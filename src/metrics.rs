@@ -0,0 +1,410 @@
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    fmt::Write as _,
+    sync::Mutex,
+    time::Duration,
+};
+
+use tokio::time::Instant;
+
+use crate::{
+    api::InvalidWorkError,
+    model::{EngineId, JobId, ProviderSelector},
+};
+
+/// Window for the average nps of each engine.
+const NPS_WINDOW: Duration = Duration::from_secs(60);
+
+/// Bound on the samples kept per engine within the window.
+const MAX_NPS_SAMPLES: usize = 1024;
+
+/// Minimum interval between warnings about malformed lines from the same
+/// provider.
+const MALFORMED_WARN_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Operational metrics, rendered in the Prometheus text format.
+#[derive(Default)]
+pub struct Metrics {
+    nps: Mutex<Nps>,
+    malformed: Mutex<BTreeMap<String, Malformed>>,
+    providers: Mutex<BTreeMap<String, ProviderInfo>>,
+    /// Keyed by the tag of the work, which is validated against an
+    /// allowlist to bound cardinality.
+    jobs: Mutex<BTreeMap<String, u64>>,
+    /// Keyed by `InvalidWorkError::kind()`.
+    invalid_work: Mutex<BTreeMap<&'static str, u64>>,
+}
+
+/// Engine last reported by a provider in its handshake.
+struct ProviderInfo {
+    name: String,
+    version: Option<String>,
+}
+
+#[derive(Default)]
+struct Malformed {
+    lines: u64,
+    warned: Option<Instant>,
+}
+
+#[derive(Default)]
+struct Nps {
+    active: HashMap<JobId, u64>,
+    recent: BTreeMap<EngineId, VecDeque<(Instant, u64)>>,
+}
+
+impl Nps {
+    fn prune(&mut self, now: Instant) {
+        self.recent.retain(|_, samples| {
+            while samples
+                .front()
+                .is_some_and(|(at, _)| now.duration_since(*at) >= NPS_WINDOW)
+            {
+                samples.pop_front();
+            }
+            !samples.is_empty()
+        });
+    }
+}
+
+impl Metrics {
+    /// Starts tracking the nps of a job, until the returned handle is
+    /// dropped.
+    pub fn track_nps(&self, job: JobId, engine: EngineId) -> JobNps<'_> {
+        JobNps {
+            metrics: self,
+            job,
+            engine,
+        }
+    }
+
+    fn record_nps(&self, job: &JobId, engine: &EngineId, nps: u64) {
+        let now = Instant::now();
+        let mut state = self.nps.lock().unwrap();
+        state.active.insert(job.clone(), nps);
+        let samples = state.recent.entry(engine.clone()).or_default();
+        if samples.len() >= MAX_NPS_SAMPLES {
+            samples.pop_front();
+        }
+        samples.push_back((now, nps));
+        state.prune(now);
+    }
+
+    /// Counts a malformed line submitted by a provider. Returns the total so
+    /// far, if it is time to log another warning.
+    pub fn record_malformed(&self, selector: &ProviderSelector) -> Option<u64> {
+        let now = Instant::now();
+        let mut malformed = self.malformed.lock().unwrap();
+        let entry = malformed.entry(selector.as_str().to_owned()).or_default();
+        entry.lines += 1;
+        if entry
+            .warned
+            .is_some_and(|at| now.duration_since(at) < MALFORMED_WARN_INTERVAL)
+        {
+            return None;
+        }
+        entry.warned = Some(now);
+        Some(entry.lines)
+    }
+
+    pub fn record_provider(&self, selector: &ProviderSelector, name: &str, version: Option<&str>) {
+        self.providers.lock().unwrap().insert(
+            selector.as_str().to_owned(),
+            ProviderInfo {
+                name: name.to_owned(),
+                version: version.map(str::to_owned),
+            },
+        );
+    }
+
+    pub fn record_job(&self, tag: Option<&str>) {
+        *self
+            .jobs
+            .lock()
+            .unwrap()
+            .entry(tag.unwrap_or_default().to_owned())
+            .or_default() += 1;
+    }
+
+    pub fn record_invalid_work(&self, err: &InvalidWorkError) {
+        *self
+            .invalid_work
+            .lock()
+            .unwrap()
+            .entry(err.kind())
+            .or_default() += 1;
+    }
+
+    pub fn render(&self) -> String {
+        let mut state = self.nps.lock().unwrap();
+        state.prune(Instant::now());
+
+        let mut out = String::new();
+        out.push_str("# HELP lila_engine_nps Sum of the latest nps of all active jobs.\n");
+        out.push_str("# TYPE lila_engine_nps gauge\n");
+        let _ = writeln!(
+            out,
+            "lila_engine_nps {}",
+            state
+                .active
+                .values()
+                .copied()
+                .fold(0u64, u64::saturating_add)
+        );
+
+        out.push_str(
+            "# HELP lila_engine_engine_nps_avg Average nps reported for each engine in the last minute.\n",
+        );
+        out.push_str("# TYPE lila_engine_engine_nps_avg gauge\n");
+        for (engine, samples) in &state.recent {
+            let avg = samples
+                .iter()
+                .map(|(_, nps)| u128::from(*nps))
+                .sum::<u128>()
+                / samples.len() as u128;
+            let _ = writeln!(
+                out,
+                "lila_engine_engine_nps_avg{{engine=\"{}\"}} {avg}",
+                escape_label(&engine.0)
+            );
+        }
+        drop(state);
+
+        out.push_str(
+            "# HELP lila_engine_malformed_lines_total Malformed lines submitted by each provider.\n",
+        );
+        out.push_str("# TYPE lila_engine_malformed_lines_total counter\n");
+        for (selector, malformed) in self.malformed.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "lila_engine_malformed_lines_total{{selector=\"{}\"}} {}",
+                escape_label(selector),
+                malformed.lines
+            );
+        }
+
+        out.push_str("# HELP lila_engine_provider_info Engine last reported by each provider.\n");
+        out.push_str("# TYPE lila_engine_provider_info gauge\n");
+        for (selector, info) in self.providers.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "lila_engine_provider_info{{selector=\"{}\",name=\"{}\",version=\"{}\"}} 1",
+                escape_label(selector),
+                escape_label(&info.name),
+                escape_label(info.version.as_deref().unwrap_or_default())
+            );
+        }
+
+        out.push_str(
+            "# HELP lila_engine_jobs_total Jobs submitted by providers, by analysis tag.\n",
+        );
+        out.push_str("# TYPE lila_engine_jobs_total counter\n");
+        for (tag, jobs) in self.jobs.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "lila_engine_jobs_total{{tag=\"{}\"}} {jobs}",
+                escape_label(tag)
+            );
+        }
+
+        out.push_str(
+            "# HELP lila_engine_invalid_work_total Work rejected by validation, by reason.\n",
+        );
+        out.push_str("# TYPE lila_engine_invalid_work_total counter\n");
+        for (kind, rejected) in self.invalid_work.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "lila_engine_invalid_work_total{{kind=\"{kind}\"}} {rejected}"
+            );
+        }
+        out
+    }
+}
+
+pub struct JobNps<'a> {
+    metrics: &'a Metrics,
+    job: JobId,
+    engine: EngineId,
+}
+
+impl JobNps<'_> {
+    pub fn record(&self, nps: u64) {
+        self.metrics.record_nps(&self.job, &self.engine, nps);
+    }
+}
+
+impl Drop for JobNps<'_> {
+    fn drop(&mut self) {
+        self.metrics.nps.lock().unwrap().active.remove(&self.job);
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use shakmaty::uci::UciMove;
+    use tokio::time::sleep;
+
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_nps() {
+        let metrics = Metrics::default();
+        let engine = EngineId("eei_a".to_owned());
+        let first = metrics.track_nps(JobId::random(), engine.clone());
+        let second = metrics.track_nps(JobId::random(), engine);
+
+        first.record(100_000);
+        first.record(200_000);
+        second.record(300_000);
+        let rendered = metrics.render();
+        assert!(rendered.contains("\nlila_engine_nps 500000\n"));
+        assert!(rendered.contains("\nlila_engine_engine_nps_avg{engine=\"eei_a\"} 200000\n"));
+
+        drop(first);
+        assert!(metrics.render().contains("\nlila_engine_nps 300000\n"));
+
+        // Averages only cover the window.
+        sleep(NPS_WINDOW).await;
+        second.record(600_000);
+        assert!(metrics
+            .render()
+            .contains("\nlila_engine_engine_nps_avg{engine=\"eei_a\"} 600000\n"));
+    }
+
+    #[test]
+    fn test_nps_overflow() {
+        let metrics = Metrics::default();
+        let engine = EngineId("eei_a".to_owned());
+        let first = metrics.track_nps(JobId::random(), engine.clone());
+        let second = metrics.track_nps(JobId::random(), engine);
+
+        first.record(u64::MAX);
+        second.record(u64::MAX - 1);
+        let rendered = metrics.render();
+        assert!(rendered.contains(&format!("\nlila_engine_nps {}\n", u64::MAX)));
+        assert!(rendered.contains(&format!(
+            "\nlila_engine_engine_nps_avg{{engine=\"eei_a\"}} {}\n",
+            u64::MAX - 1
+        )));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_malformed() {
+        let metrics = Metrics::default();
+        let selector: ProviderSelector = serde_json::from_value(json!("sel")).unwrap();
+
+        assert_eq!(metrics.record_malformed(&selector), Some(1));
+        assert_eq!(metrics.record_malformed(&selector), None);
+        sleep(MALFORMED_WARN_INTERVAL).await;
+        assert_eq!(metrics.record_malformed(&selector), Some(3));
+        assert!(metrics
+            .render()
+            .contains("\nlila_engine_malformed_lines_total{selector=\"sel\"} 3\n"));
+    }
+
+    #[test]
+    fn test_provider_info() {
+        let metrics = Metrics::default();
+        let selector: ProviderSelector = serde_json::from_value(json!("sel")).unwrap();
+
+        metrics.record_provider(&selector, "Stockfish 16", None);
+        metrics.record_provider(&selector, "Stockfish \"17\"", Some("17"));
+        assert!(metrics.render().contains(
+            "\nlila_engine_provider_info{selector=\"sel\",name=\"Stockfish \\\"17\\\"\",version=\"17\"} 1\n"
+        ));
+        assert!(!metrics.render().contains("Stockfish 16"));
+    }
+
+    #[test]
+    fn test_jobs_by_tag() {
+        let metrics = Metrics::default();
+        metrics.record_job(Some("study"));
+        metrics.record_job(Some("study"));
+        metrics.record_job(None);
+        let rendered = metrics.render();
+        assert!(rendered.contains("\nlila_engine_jobs_total{tag=\"study\"} 2\n"));
+        assert!(rendered.contains("\nlila_engine_jobs_total{tag=\"\"} 1\n"));
+    }
+
+    #[test]
+    fn test_invalid_work_by_kind() {
+        use shakmaty::{
+            fen::Fen,
+            variant::{Variant, VariantPosition},
+            CastlingMode, Color, Setup,
+        };
+
+        let uci: UciMove = "e2e4".parse().unwrap();
+        let errors = [
+            InvalidWorkError::FenTooLong,
+            InvalidWorkError::Fen("garbage".parse::<Fen>().unwrap_err()),
+            InvalidWorkError::Position(Box::new(
+                VariantPosition::from_setup(Variant::Chess, Setup::empty(), CastlingMode::Standard)
+                    .unwrap_err(),
+            )),
+            InvalidWorkError::InvalidCastlingRights,
+            InvalidWorkError::IllegalCastlingRights,
+            InvalidWorkError::IllegalUciMove {
+                ply: 0,
+                uci: uci.clone(),
+            },
+            InvalidWorkError::WrongSideToMove {
+                ply: 0,
+                uci,
+                turn: Color::Black,
+            },
+            InvalidWorkError::TooManyMoves,
+            InvalidWorkError::UnsupportedVariant,
+            InvalidWorkError::NotAtLeastOne,
+            InvalidWorkError::CallbackUrlNotAllowed,
+            InvalidWorkError::DuplicateSearchmove,
+            InvalidWorkError::SeedOutOfRange,
+            InvalidWorkError::InvalidStartMoveNumber,
+            InvalidWorkError::InvalidMaxInfoFrames,
+            InvalidWorkError::ClientRefTooLong,
+            InvalidWorkError::DisallowedTag,
+            InvalidWorkError::TablebaseUnsupported,
+            InvalidWorkError::PonderUnsupported,
+            InvalidWorkError::DisallowedPosition,
+            InvalidWorkError::DisallowedSession,
+            InvalidWorkError::NegativeClock,
+            InvalidWorkError::MissingSearch,
+            InvalidWorkError::AmbiguousSearch,
+            InvalidWorkError::UnknownStrength,
+        ];
+
+        let metrics = Metrics::default();
+        for err in &errors {
+            metrics.record_invalid_work(err);
+        }
+        metrics.record_invalid_work(&InvalidWorkError::TooManyMoves);
+        let rendered = metrics.render();
+        for err in &errors {
+            let expected = if matches!(err, InvalidWorkError::TooManyMoves) {
+                2
+            } else {
+                1
+            };
+            assert!(
+                rendered.contains(&format!(
+                    "\nlila_engine_invalid_work_total{{kind=\"{}\"}} {expected}\n",
+                    err.kind()
+                )),
+                "{}",
+                err.kind()
+            );
+        }
+        assert_eq!(
+            rendered.matches("lila_engine_invalid_work_total{").count(),
+            errors.len()
+        );
+    }
+}
@@ -0,0 +1,21 @@
+use std::{convert::Infallible, str::FromStr};
+
+use crate::model::constant_time_eq;
+
+/// Token for administrative endpoints, configured on the command line.
+#[derive(Debug, Clone)]
+pub struct AdminToken(String);
+
+impl FromStr for AdminToken {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<AdminToken, Infallible> {
+        Ok(AdminToken(s.to_owned()))
+    }
+}
+
+impl AdminToken {
+    pub fn matches(&self, token: &str) -> bool {
+        !self.0.is_empty() && constant_time_eq(&self.0, token)
+    }
+}
@@ -1,17 +1,55 @@
+use std::hash::{Hash, Hasher};
+
+use rand::{
+    distributions::{Alphanumeric, DistString},
+    thread_rng,
+};
 use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::model::{constant_time_eq, non_blank, EmptySecretError};
 
-#[derive(Deserialize, Serialize, Debug, Eq, Clone)]
+#[derive(Deserialize, Serialize, Debug, Eq, Clone, ToSchema)]
+#[serde(try_from = "String")]
+#[schema(value_type = String)]
 pub struct ClientSecret(String);
 
+impl TryFrom<String> for ClientSecret {
+    type Error = EmptySecretError;
+
+    fn try_from(secret: String) -> Result<ClientSecret, EmptySecretError> {
+        non_blank(secret).map(ClientSecret)
+    }
+}
+
+/// Number of random alphanumeric characters in generated secrets, for about
+/// 143 bits of entropy.
+const CLIENT_SECRET_LEN: usize = 24;
+
+impl ClientSecret {
+    /// Generates a fresh secret, e.g. to rotate one that leaked.
+    pub fn random() -> ClientSecret {
+        ClientSecret(format!(
+            "ees_{}",
+            Alphanumeric.sample_string(&mut thread_rng(), CLIENT_SECRET_LEN)
+        ))
+    }
+
+    /// Length in characters. Secrets are generated by lila, so the minimum
+    /// can only be enforced when they are used.
+    pub fn char_count(&self) -> usize {
+        self.0.chars().count()
+    }
+}
+
 impl PartialEq for ClientSecret {
     fn eq(&self, other: &ClientSecret) -> bool {
-        // Best effort constant time equality
-        self.0.len() == other.0.len()
-            && self
-                .0
-                .bytes()
-                .zip(other.0.bytes())
-                .fold(0, |acc, (left, right)| acc | (left ^ right))
-                == 0
+        constant_time_eq(&self.0, &other.0)
+    }
+}
+
+impl Hash for ClientSecret {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.hash(state);
     }
 }
@@ -1,12 +1,22 @@
-use std::{fmt, num::NonZeroU32};
+use std::{collections::BTreeMap, fmt, num::NonZeroU32};
 
 use serde::{Deserialize, Serialize};
-use serde_with::{serde_as, FromInto};
-use shakmaty::variant::Variant;
+use serde_with::{serde_as, DisplayFromStr, TryFromInto};
+use shakmaty::{
+    fen::Fen,
+    variant::{Variant, VariantPosition},
+    CastlingMode,
+};
+use thiserror::Error;
+use utoipa::ToSchema;
 
-use crate::model::{ClientSecret, UciVariant, UserId};
+use crate::{
+    model::{ClientSecret, MultiPv, SessionId, UciVariant, UserId},
+    uci::UciOption,
+};
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, ToSchema)]
+#[schema(value_type = String, example = "eei_aTKImBJOnv6j")]
 pub struct EngineId(pub String);
 
 impl fmt::Display for EngineId {
@@ -15,7 +25,7 @@ impl fmt::Display for EngineId {
     }
 }
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, ToSchema)]
 pub struct Engine {
     pub id: EngineId,
     #[serde(flatten)]
@@ -23,15 +33,193 @@ pub struct Engine {
 }
 
 #[serde_as]
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct EngineConfig {
+    #[schema(example = "Stockfish 17")]
     pub name: String,
     pub client_secret: ClientSecret,
     pub user_id: UserId,
+    #[schema(value_type = u32, minimum = 1, example = 8)]
     pub max_threads: NonZeroU32,
+    #[schema(value_type = u32, minimum = 1, example = 2048)]
     pub max_hash: NonZeroU32,
-    #[serde_as(as = "Vec<FromInto<UciVariant>>")]
+    #[serde_as(as = "Vec<TryFromInto<UciVariant>>")]
+    #[schema(value_type = Vec<UciVariant>)]
     pub variants: Vec<Variant>,
+    /// Whether the provider has endgame tablebases available.
+    #[serde(default)]
+    pub tablebase: bool,
+    /// Whether the provider can ponder on an expected move and continue the
+    /// search once it is played.
+    #[serde(default)]
+    pub supports_ponder: bool,
+    /// Restricts analysis to games from these initial positions, ignoring
+    /// move counters. Any position is allowed if absent.
+    #[serde_as(as = "Option<Vec<DisplayFromStr>>")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<Vec<String>>, example = json!(["rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1"]))]
+    pub allowed_fens: Option<Vec<Fen>>,
+    /// Restricts analysis to sessions whose id starts with one of these
+    /// prefixes, for private deployments. Any session is allowed if absent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(example = json!(["study-"]))]
+    pub allowed_session_prefixes: Option<Vec<String>>,
+    /// Options that the engine reported in the last provider handshake,
+    /// i.e. what `setoption` can be applied to.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allowed_options: Vec<UciOption>,
+    /// Analysis parameters for clients that omit them.
+    #[serde(default, skip_serializing_if = "EngineDefaults::is_empty")]
+    pub defaults: EngineDefaults,
+    /// Named strength levels that clients can request instead of explicit
+    /// search limits, calibrated for this engine.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    #[schema(example = json!({ "1500": { "depth": 5 }, "2500": { "movetime": 1000 } }))]
+    pub strength_levels: BTreeMap<String, StrengthLimit>,
     pub provider_data: Option<String>,
 }
+
+/// Inconsistencies between the declared capabilities of an engine.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum InvalidEngineConfig {
+    #[error("no variants declared")]
+    NoVariants,
+    #[error("defaults exceed the declared limits")]
+    DefaultsExceedLimits,
+    #[error("strength level {0} does not limit the search")]
+    UnlimitedStrength(String),
+    #[error("allowed position {0} is not valid in any declared variant")]
+    InvalidAllowedFen(String),
+}
+
+impl EngineConfig {
+    /// Checks that the declared capabilities are consistent with each
+    /// other, e.g. defaults are within the limits of the engine itself.
+    pub fn validate(&self) -> Result<(), InvalidEngineConfig> {
+        if self.variants.is_empty() {
+            return Err(InvalidEngineConfig::NoVariants);
+        }
+        if self
+            .defaults
+            .threads
+            .is_some_and(|threads| threads > self.max_threads)
+            || self.defaults.hash.is_some_and(|hash| hash > self.max_hash)
+        {
+            return Err(InvalidEngineConfig::DefaultsExceedLimits);
+        }
+        if let Some((name, _)) = self.strength_levels.iter().find(|(_, limit)| {
+            matches!(limit, StrengthLimit::Depth(0) | StrengthLimit::Movetime(0))
+        }) {
+            return Err(InvalidEngineConfig::UnlimitedStrength(name.clone()));
+        }
+        if let Some(fen) = self.allowed_fens.iter().flatten().find(|fen| {
+            !self.variants.iter().any(|&variant| {
+                VariantPosition::from_setup(variant, fen.as_setup().clone(), CastlingMode::Chess960)
+                    .is_ok()
+            })
+        }) {
+            return Err(InvalidEngineConfig::InvalidAllowedFen(fen.to_string()));
+        }
+        Ok(())
+    }
+
+    pub fn allows_session(&self, session_id: &SessionId) -> bool {
+        self.allowed_session_prefixes
+            .as_ref()
+            .is_none_or(|prefixes| {
+                prefixes
+                    .iter()
+                    .any(|prefix| session_id.as_str().starts_with(prefix.as_str()))
+            })
+    }
+}
+
+#[serde_as]
+#[derive(Debug, Clone, Default, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct EngineDefaults {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<u32>, minimum = 1, example = 4)]
+    pub threads: Option<NonZeroU32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<u32>, minimum = 1, example = 256)]
+    pub hash: Option<NonZeroU32>,
+    #[serde_as(as = "Option<TryFromInto<u32>>")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<u32>, minimum = 1, maximum = 5, example = 1)]
+    pub multi_pv: Option<MultiPv>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(example = 20)]
+    pub depth: Option<u32>,
+}
+
+/// Concrete search limit for a named strength level.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum StrengthLimit {
+    Depth(u32),
+    Movetime(u32),
+}
+
+impl EngineDefaults {
+    pub fn is_empty(&self) -> bool {
+        self.threads.is_none()
+            && self.hash.is_none()
+            && self.multi_pv.is_none()
+            && self.depth.is_none()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn config(extra: serde_json::Value) -> EngineConfig {
+        let mut config = json!({
+            "name": "Stockfish",
+            "clientSecret": "ees_clientsecret",
+            "userId": "user",
+            "maxThreads": 8,
+            "maxHash": 512,
+            "variants": ["chess"],
+            "providerData": null,
+        });
+        config
+            .as_object_mut()
+            .unwrap()
+            .extend(extra.as_object().unwrap().clone());
+        serde_json::from_value(config).unwrap()
+    }
+
+    #[test]
+    fn test_validate() {
+        assert_eq!(config(json!({})).validate(), Ok(()));
+        assert_eq!(
+            config(json!({ "variants": [] })).validate(),
+            Err(InvalidEngineConfig::NoVariants)
+        );
+        assert_eq!(
+            config(json!({ "defaults": { "threads": 8, "hash": 1024 } })).validate(),
+            Err(InvalidEngineConfig::DefaultsExceedLimits)
+        );
+        assert_eq!(
+            config(json!({ "strengthLevels": { "1500": { "depth": 0 } } })).validate(),
+            Err(InvalidEngineConfig::UnlimitedStrength("1500".to_owned()))
+        );
+
+        // Horde positions require declaring horde.
+        let horde = json!({
+            "allowedFens": ["rnbqkbnr/pppppppp/8/1PP2PP1/PPPPPPPP/PPPPPPPP/PPPPPPPP/PPPPPPPP w kq - 0 1"],
+        });
+        assert!(matches!(
+            config(horde.clone()).validate(),
+            Err(InvalidEngineConfig::InvalidAllowedFen(_))
+        ));
+        let mut with_horde = horde;
+        with_horde["variants"] = json!(["chess", "horde"]);
+        assert_eq!(config(with_horde).validate(), Ok(()));
+    }
+}
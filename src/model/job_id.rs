@@ -5,8 +5,10 @@ use rand::{
     thread_rng,
 };
 use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
 
-#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, Hash, ToSchema)]
+#[schema(value_type = String)]
 pub struct JobId(String);
 
 impl fmt::Display for JobId {
@@ -15,8 +17,53 @@ impl fmt::Display for JobId {
     }
 }
 
+/// Number of alphanumeric characters in random job ids, for about 131 bits
+/// of entropy.
+const JOB_ID_LEN: usize = 22;
+
 impl JobId {
+    /// Job ids are the only credential needed to submit analysis for a job,
+    /// so they must be unguessable. `thread_rng` is a CSPRNG (ChaCha12)
+    /// seeded and periodically reseeded from the operating system.
     pub fn random() -> JobId {
-        JobId(Alphanumeric.sample_string(&mut thread_rng(), 16))
+        JobId(Alphanumeric.sample_string(&mut thread_rng(), JOB_ID_LEN))
+    }
+}
+
+/// Source of ids for acquired jobs.
+pub trait JobIdSource: Send + Sync {
+    fn next_id(&self) -> JobId;
+}
+
+pub struct RandomJobIds;
+
+impl JobIdSource for RandomJobIds {
+    fn next_id(&self) -> JobId {
+        JobId::random()
+    }
+}
+
+#[cfg(test)]
+#[derive(Default)]
+pub struct SequentialJobIds(std::sync::atomic::AtomicU64);
+
+#[cfg(test)]
+impl JobIdSource for SequentialJobIds {
+    fn next_id(&self) -> JobId {
+        let n = self.0.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        JobId(format!("job{n}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_random() {
+        let id = JobId::random();
+        assert_eq!(id.0.len(), JOB_ID_LEN);
+        assert!(id.0.chars().all(|c| c.is_ascii_alphanumeric()));
+        assert_ne!(id, JobId::random());
     }
 }
@@ -1,21 +1,74 @@
+use std::fmt;
+
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use utoipa::ToSchema;
 
+mod admin_token;
 mod client_secret;
 mod engine;
 mod job_id;
 mod multi_pv;
 mod provider_secret;
+mod rejection;
 mod uci_variant;
 
+pub use admin_token::AdminToken;
 pub use client_secret::ClientSecret;
-pub use engine::{Engine, EngineConfig, EngineId};
-pub use job_id::JobId;
+pub use engine::{Engine, EngineConfig, EngineId, InvalidEngineConfig, StrengthLimit};
+#[cfg(test)]
+pub use job_id::SequentialJobIds;
+pub use job_id::{JobId, JobIdSource, RandomJobIds};
 pub use multi_pv::{InvalidMultiPvError, MultiPv};
-pub use provider_secret::{ProviderSecret, ProviderSelector};
+pub use provider_secret::{ProviderKey, ProviderSecret, ProviderSelector};
+pub use rejection::{record_rejection, recording_rejection, Rejection};
 pub use uci_variant::UciVariant;
 
-#[derive(Deserialize, Serialize, Debug, Clone)]
+#[derive(Error, Debug)]
+#[error("secret must not be empty")]
+pub struct EmptySecretError;
+
+fn non_blank(secret: String) -> Result<String, EmptySecretError> {
+    if secret.trim().is_empty() {
+        record_rejection(Rejection::EmptySecret);
+        Err(EmptySecretError)
+    } else {
+        Ok(secret)
+    }
+}
+
+/// Best effort constant time equality.
+fn constant_time_eq(left: &str, right: &str) -> bool {
+    left.len() == right.len()
+        && left
+            .bytes()
+            .zip(right.bytes())
+            .fold(0, |acc, (left, right)| acc | (left ^ right))
+            == 0
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+#[schema(value_type = String)]
 pub struct UserId(String);
 
-#[derive(Deserialize, Serialize, Debug, Clone)]
+impl UserId {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, Hash, ToSchema)]
+#[schema(value_type = String)]
 pub struct SessionId(String);
+
+impl SessionId {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for SessionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
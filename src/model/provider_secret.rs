@@ -1,9 +1,23 @@
-use serde::Deserialize;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
+use utoipa::ToSchema;
 
-#[derive(Deserialize, Debug)]
+use crate::model::{non_blank, EmptySecretError};
+
+#[derive(Deserialize, Debug, ToSchema)]
+#[serde(try_from = "String")]
+#[schema(value_type = String)]
 pub struct ProviderSecret(String);
 
+impl TryFrom<String> for ProviderSecret {
+    type Error = EmptySecretError;
+
+    fn try_from(secret: String) -> Result<ProviderSecret, EmptySecretError> {
+        non_blank(secret).map(ProviderSecret)
+    }
+}
+
 impl ProviderSecret {
     pub fn selector(&self) -> ProviderSelector {
         let mut hasher = Sha256::new();
@@ -13,5 +27,40 @@ impl ProviderSecret {
     }
 }
 
-#[derive(Deserialize, Eq, PartialEq, Hash, Debug, Clone)]
+#[derive(Deserialize, Serialize, Eq, PartialEq, Hash, Debug, Clone, ToSchema)]
+#[schema(value_type = String)]
 pub struct ProviderSelector(String);
+
+impl ProviderSelector {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+/// Key registered for a provider, used to sign challenges instead of sending
+/// the provider secret.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(try_from = "String")]
+pub struct ProviderKey(String);
+
+impl TryFrom<String> for ProviderKey {
+    type Error = EmptySecretError;
+
+    fn try_from(key: String) -> Result<ProviderKey, EmptySecretError> {
+        non_blank(key).map(ProviderKey)
+    }
+}
+
+impl ProviderKey {
+    /// Checks a hex encoded HMAC-SHA256 of `message` with this key, in
+    /// constant time.
+    pub fn verify(&self, message: &[u8], signature: &str) -> bool {
+        let Ok(signature) = hex::decode(signature) else {
+            return false;
+        };
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(self.0.as_bytes()).expect("hmac with any key length");
+        mac.update(message);
+        mac.verify_slice(&signature).is_ok()
+    }
+}
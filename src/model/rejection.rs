@@ -0,0 +1,55 @@
+use std::{cell::Cell, future::Future};
+
+/// Errors while deserializing a request that are reported with their own
+/// code. Serde errors only carry a message, so deserializers record the
+/// typed reason on the side.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Rejection {
+    NotAtLeastOne,
+    UnsupportedVariant,
+    EmptySecret,
+}
+
+tokio::task_local! {
+    static REJECTION: Cell<Option<Rejection>>;
+}
+
+/// Records why deserialization failed, unless outside of
+/// `recording_rejection`. Deserialization stops at the first error, so
+/// later records are ignored.
+pub fn record_rejection(rejection: Rejection) {
+    let _ = REJECTION.try_with(|slot| {
+        if slot.get().is_none() {
+            slot.set(Some(rejection));
+        }
+    });
+}
+
+/// Runs `fut`, also returning the rejection recorded while it ran, if any.
+pub async fn recording_rejection<F: Future>(fut: F) -> (F::Output, Option<Rejection>) {
+    REJECTION
+        .scope(Cell::new(None), async move {
+            let output = fut.await;
+            (output, REJECTION.with(Cell::get))
+        })
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_recording_rejection() {
+        record_rejection(Rejection::EmptySecret);
+        let ((), rejection) = recording_rejection(async {
+            record_rejection(Rejection::NotAtLeastOne);
+            record_rejection(Rejection::UnsupportedVariant);
+        })
+        .await;
+        assert_eq!(rejection, Some(Rejection::NotAtLeastOne));
+
+        let ((), rejection) = recording_rejection(async {}).await;
+        assert_eq!(rejection, None);
+    }
+}
@@ -1,7 +1,15 @@
 use serde::{Deserialize, Serialize};
 use shakmaty::variant::Variant;
+use thiserror::Error;
+use utoipa::ToSchema;
 
-#[derive(Deserialize, Serialize)]
+use crate::model::{record_rejection, Rejection};
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[error("unsupported variant")]
+pub struct UnsupportedVariantError;
+
+#[derive(Deserialize, Serialize, ToSchema, Debug, Copy, Clone, PartialEq, Eq)]
 pub enum UciVariant {
     #[serde(
         rename = "chess",
@@ -24,11 +32,17 @@ pub enum UciVariant {
     RacingKings,
     #[serde(rename = "3check", alias = "threeCheck")]
     ThreeCheck,
+    /// Any other name, e.g. of a variant added after this version.
+    #[serde(other, skip_serializing)]
+    #[schema(ignore)]
+    Unknown,
 }
 
-impl From<UciVariant> for Variant {
-    fn from(value: UciVariant) -> Variant {
-        match value {
+impl TryFrom<UciVariant> for Variant {
+    type Error = UnsupportedVariantError;
+
+    fn try_from(value: UciVariant) -> Result<Variant, UnsupportedVariantError> {
+        Ok(match value {
             UciVariant::Chess => Variant::Chess,
             UciVariant::Antichess => Variant::Antichess,
             UciVariant::Atomic => Variant::Atomic,
@@ -37,7 +51,11 @@ impl From<UciVariant> for Variant {
             UciVariant::KingOfTheHill => Variant::KingOfTheHill,
             UciVariant::RacingKings => Variant::RacingKings,
             UciVariant::ThreeCheck => Variant::ThreeCheck,
-        }
+            UciVariant::Unknown => {
+                record_rejection(Rejection::UnsupportedVariant);
+                return Err(UnsupportedVariantError);
+            }
+        })
     }
 }
 
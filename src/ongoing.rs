@@ -6,47 +6,195 @@ use std::{
     time::Duration,
 };
 
-use tokio::time::sleep;
+use tokio::time::{sleep, Instant};
 
 use crate::hub::IsValid;
 
 const NUM_SHARDS: usize = 128;
 
+/// How long removed items are remembered as gone.
+const TOMBSTONE_TTL: Duration = Duration::from_secs(10 * 60);
+
 pub struct Ongoing<S, R> {
     random_state: RandomState,
-    shards: [Mutex<HashMap<S, R>>; NUM_SHARDS],
+    shards: [Mutex<Shard<S, R>>; NUM_SHARDS],
+}
+
+struct Shard<S, R> {
+    items: HashMap<S, R>,
+    tombstones: HashMap<S, Instant>,
 }
 
 impl<S: Hash + Eq, R> Default for Ongoing<S, R> {
     fn default() -> Ongoing<S, R> {
         Ongoing {
             random_state: RandomState::new(),
-            shards: array::from_fn(|_| Mutex::new(HashMap::new())),
+            shards: array::from_fn(|_| {
+                Mutex::new(Shard {
+                    items: HashMap::new(),
+                    tombstones: HashMap::new(),
+                })
+            }),
         }
     }
 }
 
-impl<S: Hash + Eq, R> Ongoing<S, R> {
+impl<S: Hash + Eq + Clone, R> Ongoing<S, R> {
     pub fn add(&self, selector: S, item: R) {
-        self.shard(&selector).lock().unwrap().insert(selector, item);
+        self.shard(&selector)
+            .lock()
+            .unwrap()
+            .items
+            .insert(selector, item);
+    }
+
+    pub fn get(&self, selector: &S) -> Option<R>
+    where
+        R: Clone,
+    {
+        self.shard(selector)
+            .lock()
+            .unwrap()
+            .items
+            .get(selector)
+            .cloned()
     }
 
+    /// Removes the item, and remembers that it existed for a while.
     pub fn remove(&self, selector: &S) -> Option<R> {
-        self.shard(selector).lock().unwrap().remove(selector)
+        let mut shard = self.shard(selector).lock().unwrap();
+        let item = shard.items.remove(selector)?;
+        shard.tombstones.insert(selector.clone(), Instant::now());
+        Some(item)
+    }
+
+    /// Removes all items matching the predicate, like `remove`.
+    pub fn remove_matching(&self, mut f: impl FnMut(&R) -> bool) -> Vec<(S, R)> {
+        let now = Instant::now();
+        let mut removed = Vec::new();
+        for shard in &self.shards {
+            let mut shard = shard.lock().unwrap();
+            let Shard { items, tombstones } = &mut *shard;
+            let matching: Vec<S> = items
+                .iter()
+                .filter(|(_, item)| f(item))
+                .map(|(selector, _)| selector.clone())
+                .collect();
+            for selector in matching {
+                if let Some(item) = items.remove(&selector) {
+                    tombstones.insert(selector.clone(), now);
+                    removed.push((selector, item));
+                }
+            }
+        }
+        removed
     }
 
-    fn shard(&self, selector: &S) -> &Mutex<HashMap<S, R>> {
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.lock().unwrap().items.len())
+            .sum()
+    }
+
+    /// Whether an item with this selector existed, but was recently removed.
+    pub fn is_gone(&self, selector: &S) -> bool {
+        self.shard(selector)
+            .lock()
+            .unwrap()
+            .tombstones
+            .get(selector)
+            .is_some_and(|removed| removed.elapsed() < TOMBSTONE_TTL)
+    }
+
+    fn shard(&self, selector: &S) -> &Mutex<Shard<S, R>> {
         &self.shards[self.random_state.hash_one(selector) as usize % NUM_SHARDS]
     }
 }
 
-impl<S, R: IsValid> Ongoing<S, R> {
+impl<S: Hash + Eq + Clone, R: IsValid> Ongoing<S, R> {
     pub async fn garbage_collect(&self) {
         loop {
             for shard in &self.shards {
-                shard.lock().unwrap().retain(|_, item| item.is_valid());
+                shard.lock().unwrap().garbage_collect();
                 sleep(Duration::from_secs(7)).await;
             }
         }
     }
 }
+
+impl<S: Hash + Eq + Clone, R: IsValid> Shard<S, R> {
+    fn garbage_collect(&mut self) {
+        let now = Instant::now();
+        let Shard { items, tombstones } = self;
+        items.retain(|selector, item| {
+            let valid = item.is_valid();
+            if !valid {
+                tombstones.insert(selector.clone(), now);
+            }
+            valid
+        });
+        tombstones.retain(|_, removed| now.duration_since(*removed) < TOMBSTONE_TTL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Item(bool);
+
+    impl IsValid for Item {
+        fn is_valid(&self) -> bool {
+            self.0
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_tombstones() {
+        let ongoing = Ongoing::<&str, Item>::default();
+        assert!(ongoing.remove(&"unknown").is_none());
+        assert!(!ongoing.is_gone(&"unknown"));
+
+        ongoing.add("done", Item(true));
+        assert!(ongoing.remove(&"done").is_some());
+        assert!(ongoing.remove(&"done").is_none());
+        assert!(ongoing.is_gone(&"done"));
+
+        ongoing.add("cancelled", Item(false));
+        for shard in &ongoing.shards {
+            shard.lock().unwrap().garbage_collect();
+        }
+        assert!(ongoing.remove(&"cancelled").is_none());
+        assert!(ongoing.is_gone(&"cancelled"));
+
+        sleep(TOMBSTONE_TTL).await;
+        assert!(!ongoing.is_gone(&"done"));
+        for shard in &ongoing.shards {
+            shard.lock().unwrap().garbage_collect();
+        }
+        assert!(ongoing
+            .shards
+            .iter()
+            .all(|shard| shard.lock().unwrap().tombstones.is_empty()));
+    }
+
+    #[tokio::test]
+    async fn test_remove_matching() {
+        let ongoing = Ongoing::<&str, Item>::default();
+        ongoing.add("first", Item(true));
+        ongoing.add("second", Item(true));
+        ongoing.add("other", Item(false));
+
+        let mut removed: Vec<&str> = ongoing
+            .remove_matching(|item| item.0)
+            .into_iter()
+            .map(|(selector, _)| selector)
+            .collect();
+        removed.sort_unstable();
+        assert_eq!(removed, ["first", "second"]);
+        assert!(ongoing.is_gone(&"first"));
+        assert!(ongoing.remove(&"other").is_some());
+    }
+}
@@ -1,20 +1,78 @@
-use mongodb::{bson::doc, error::Error, options::ClientOptions, Client, Collection};
-use serde::Deserialize;
+use std::collections::HashMap;
+
+use futures::{
+    future::{BoxFuture, FutureExt as _},
+    TryStreamExt as _,
+};
+use mongodb::{
+    bson::{doc, to_bson, to_document, Bson, DateTime, Document},
+    error::Error,
+    options::ClientOptions,
+    Client, Collection,
+};
+use serde::{Deserialize, Serialize};
 use tokio::task;
 
-use crate::model::{ClientSecret, Engine, EngineConfig, EngineId, ProviderSelector};
+use crate::{
+    model::{
+        ClientSecret, Engine, EngineConfig, EngineId, InvalidEngineConfig, ProviderKey,
+        ProviderSelector, UserId,
+    },
+    uci::UciOption,
+};
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct ExternalEngine {
     #[serde(rename = "_id")]
     id: EngineId,
     provider_selector: ProviderSelector,
+    /// Allows the provider to authenticate by signing challenges.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    provider_key: Option<ProviderKey>,
     #[serde(flatten)]
     config: EngineConfig,
+    /// When the engine was registered or last updated. Absent for engines
+    /// registered elsewhere.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    created_at: Option<DateTime>,
+    /// Disabled engines keep their registration, but reject new analysis
+    /// requests.
+    #[serde(default = "enabled_by_default")]
+    enabled: bool,
+}
+
+fn enabled_by_default() -> bool {
+    true
 }
 
 impl ExternalEngine {
+    pub fn new(engine: Engine, provider_selector: ProviderSelector) -> ExternalEngine {
+        ExternalEngine {
+            id: engine.id,
+            provider_selector,
+            provider_key: None,
+            config: engine.config,
+            created_at: Some(DateTime::now()),
+            enabled: true,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn with_created_at(mut self, created_at: DateTime) -> ExternalEngine {
+        self.created_at = Some(created_at);
+        self
+    }
+
+    pub fn with_provider_key(mut self, provider_key: ProviderKey) -> ExternalEngine {
+        self.provider_key = Some(provider_key);
+        self
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
     pub fn into_engine_and_selector(self) -> (Engine, ProviderSelector) {
         (
             Engine {
@@ -26,8 +84,134 @@ impl ExternalEngine {
     }
 }
 
+/// Usage of an engine, kept separately from its registration so that
+/// updates do not reset it.
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct EngineStats {
+    /// Number of analyses that were completed with a `bestmove`.
+    pub analyses: u64,
+    pub last_used: Option<DateTime>,
+}
+
+/// Whether an engine was neither used nor registered since `before`.
+/// Engines without any timestamp are kept, since their age is unknown.
+fn is_stale(created_at: Option<DateTime>, last_used: Option<DateTime>, before: DateTime) -> bool {
+    last_used.or(created_at).is_some_and(|at| at < before)
+}
+
+#[derive(Deserialize)]
+struct EngineAge {
+    #[serde(rename = "_id")]
+    id: EngineId,
+    #[serde(rename = "createdAt")]
+    created_at: Option<DateTime>,
+}
+
+#[derive(Deserialize)]
+struct LastUsed {
+    #[serde(rename = "_id")]
+    id: EngineId,
+    #[serde(rename = "lastUsed")]
+    last_used: Option<DateTime>,
+}
+
+/// Fields of `EngineConfig` that are omitted when empty, so that updates
+/// have to remove them explicitly.
+const OPTIONAL_CONFIG_FIELDS: [&str; 5] = [
+    "allowedFens",
+    "allowedSessionPrefixes",
+    "allowedOptions",
+    "defaults",
+    "strengthLevels",
+];
+
+/// Failure to create or update an engine.
+#[derive(thiserror::Error, Debug)]
+pub enum WriteError {
+    #[error("mongodb error: {0}")]
+    MongoDb(#[from] Error),
+    #[error("invalid engine configuration: {0}")]
+    InvalidEngine(#[from] InvalidEngineConfig),
+}
+
+/// Storage of registered external engines.
+pub trait EngineStore: Send + Sync {
+    /// Finds the engine with the given id, if the client secret matches.
+    fn find(
+        &'static self,
+        id: EngineId,
+        client_secret: ClientSecret,
+    ) -> BoxFuture<'static, Result<Option<ExternalEngine>, Error>>;
+
+    /// Rejects engines with inconsistent capabilities.
+    fn create(&'static self, engine: ExternalEngine) -> BoxFuture<'static, Result<(), WriteError>>;
+
+    /// Replaces the configuration of the engine with the same id, keeping
+    /// its provider and state, e.g. whether it is enabled. Returns `false`
+    /// if there was none. Rejects engines with inconsistent capabilities.
+    fn update(
+        &'static self,
+        engine: ExternalEngine,
+    ) -> BoxFuture<'static, Result<bool, WriteError>>;
+
+    /// Replaces the client secret of the engine, if `current` matches.
+    /// Returns `false` otherwise.
+    fn rotate_client_secret(
+        &'static self,
+        id: EngineId,
+        current: ClientSecret,
+        new: ClientSecret,
+    ) -> BoxFuture<'static, Result<bool, Error>>;
+
+    /// Enables or disables the engine, if the client secret matches.
+    /// Returns `false` otherwise.
+    fn set_enabled(
+        &'static self,
+        id: EngineId,
+        client_secret: ClientSecret,
+        enabled: bool,
+    ) -> BoxFuture<'static, Result<bool, Error>>;
+
+    /// Returns `false` if there was no engine with the given id.
+    fn delete(&'static self, id: EngineId) -> BoxFuture<'static, Result<bool, Error>>;
+
+    fn list_by_user(
+        &'static self,
+        user_id: UserId,
+    ) -> BoxFuture<'static, Result<Vec<ExternalEngine>, Error>>;
+
+    /// Engines served by the provider.
+    fn list_by_provider(
+        &'static self,
+        selector: ProviderSelector,
+    ) -> BoxFuture<'static, Result<Vec<ExternalEngine>, Error>>;
+
+    /// Finds the key registered for the provider, if any.
+    fn provider_key(
+        &'static self,
+        selector: ProviderSelector,
+    ) -> BoxFuture<'static, Result<Option<ProviderKey>, Error>>;
+
+    /// Records the options reported by the engine.
+    fn update_options(
+        &'static self,
+        id: EngineId,
+        options: Vec<UciOption>,
+    ) -> BoxFuture<'static, Result<(), Error>>;
+
+    /// Counts a completed analysis and marks the engine as used now.
+    fn record_analysis(&'static self, id: EngineId) -> BoxFuture<'static, Result<(), Error>>;
+
+    fn stats(&'static self, id: EngineId) -> BoxFuture<'static, Result<EngineStats, Error>>;
+
+    /// Finds engines that were neither used nor registered since `before`.
+    fn stale(&'static self, before: DateTime) -> BoxFuture<'static, Result<Vec<EngineId>, Error>>;
+}
+
 pub struct Repo {
     coll: Collection<ExternalEngine>,
+    stats: Collection<EngineStats>,
 }
 
 impl Repo {
@@ -36,27 +220,577 @@ impl Repo {
             Client::with_options(ClientOptions::parse(url).await.expect("mongodb options"))
                 .expect("mongodb client");
 
+        let db = client
+            .default_database()
+            .unwrap_or_else(|| client.database("lichess"));
+
         Repo {
-            coll: client
-                .default_database()
-                .unwrap_or_else(|| client.database("lichess"))
-                .collection("external_engine"),
+            coll: db.collection("external_engine"),
+            stats: db.collection("external_engine_stats"),
         }
     }
+}
 
-    pub async fn find(
+// MongoDB driver does not support cancellation, so all operations are
+// spawned to run to completion.
+impl EngineStore for Repo {
+    fn find(
         &'static self,
         id: EngineId,
         client_secret: ClientSecret,
-    ) -> Result<Option<ExternalEngine>, Error> {
-        // MongoDB driver does not support cancellation.
+    ) -> BoxFuture<'static, Result<Option<ExternalEngine>, Error>> {
         task::spawn(async move {
             self.coll
                 .find_one(doc! { "_id": id.0 })
                 .await
                 .map(|engine| engine.filter(|e| e.config.client_secret == client_secret))
         })
-        .await
-        .expect("join mongodb find")
+        .map(|res| res.expect("join mongodb find"))
+        .boxed()
+    }
+
+    fn create(&'static self, engine: ExternalEngine) -> BoxFuture<'static, Result<(), WriteError>> {
+        task::spawn(async move {
+            engine.config.validate()?;
+            self.coll.insert_one(engine).await?;
+            Ok(())
+        })
+        .map(|res| res.expect("join mongodb insert"))
+        .boxed()
+    }
+
+    fn update(
+        &'static self,
+        engine: ExternalEngine,
+    ) -> BoxFuture<'static, Result<bool, WriteError>> {
+        task::spawn(async move {
+            engine.config.validate()?;
+            let config = to_document(&engine.config).map_err(Error::from)?;
+            let unset: Document = OPTIONAL_CONFIG_FIELDS
+                .into_iter()
+                .filter(|field| !config.contains_key(field))
+                .map(|field| (field.to_owned(), Bson::from("")))
+                .collect();
+            let mut update = doc! { "$set": config };
+            if !unset.is_empty() {
+                update.insert("$unset", unset);
+            }
+            Ok(self
+                .coll
+                .update_one(doc! { "_id": engine.id.0 }, update)
+                .await?
+                .matched_count
+                > 0)
+        })
+        .map(|res| res.expect("join mongodb update"))
+        .boxed()
+    }
+
+    fn rotate_client_secret(
+        &'static self,
+        id: EngineId,
+        current: ClientSecret,
+        new: ClientSecret,
+    ) -> BoxFuture<'static, Result<bool, Error>> {
+        task::spawn(async move {
+            self.coll
+                .update_one(
+                    doc! { "_id": id.0, "clientSecret": to_bson(&current)? },
+                    doc! { "$set": { "clientSecret": to_bson(&new)? } },
+                )
+                .await
+                .map(|res| res.matched_count > 0)
+        })
+        .map(|res| res.expect("join mongodb update"))
+        .boxed()
+    }
+
+    fn set_enabled(
+        &'static self,
+        id: EngineId,
+        client_secret: ClientSecret,
+        enabled: bool,
+    ) -> BoxFuture<'static, Result<bool, Error>> {
+        task::spawn(async move {
+            self.coll
+                .update_one(
+                    doc! { "_id": id.0, "clientSecret": to_bson(&client_secret)? },
+                    doc! { "$set": { "enabled": enabled } },
+                )
+                .await
+                .map(|res| res.matched_count > 0)
+        })
+        .map(|res| res.expect("join mongodb update"))
+        .boxed()
+    }
+
+    fn delete(&'static self, id: EngineId) -> BoxFuture<'static, Result<bool, Error>> {
+        task::spawn(async move {
+            self.coll
+                .delete_one(doc! { "_id": id.0 })
+                .await
+                .map(|res| res.deleted_count > 0)
+        })
+        .map(|res| res.expect("join mongodb delete"))
+        .boxed()
+    }
+
+    fn list_by_user(
+        &'static self,
+        user_id: UserId,
+    ) -> BoxFuture<'static, Result<Vec<ExternalEngine>, Error>> {
+        task::spawn(async move {
+            self.coll
+                .find(doc! { "userId": user_id.as_str() })
+                .await?
+                .try_collect()
+                .await
+        })
+        .map(|res| res.expect("join mongodb find"))
+        .boxed()
+    }
+
+    fn list_by_provider(
+        &'static self,
+        selector: ProviderSelector,
+    ) -> BoxFuture<'static, Result<Vec<ExternalEngine>, Error>> {
+        task::spawn(async move {
+            self.coll
+                .find(doc! { "providerSelector": selector.as_str() })
+                .await?
+                .try_collect()
+                .await
+        })
+        .map(|res| res.expect("join mongodb find"))
+        .boxed()
+    }
+
+    fn provider_key(
+        &'static self,
+        selector: ProviderSelector,
+    ) -> BoxFuture<'static, Result<Option<ProviderKey>, Error>> {
+        task::spawn(async move {
+            self.coll
+                .find_one(doc! {
+                    "providerSelector": selector.as_str(),
+                    "providerKey": { "$exists": true },
+                })
+                .await
+                .map(|engine| engine.and_then(|e| e.provider_key))
+        })
+        .map(|res| res.expect("join mongodb find"))
+        .boxed()
+    }
+
+    fn update_options(
+        &'static self,
+        id: EngineId,
+        options: Vec<UciOption>,
+    ) -> BoxFuture<'static, Result<(), Error>> {
+        task::spawn(async move {
+            self.coll
+                .update_one(
+                    doc! { "_id": id.0 },
+                    doc! { "$set": { "allowedOptions": to_bson(&options)? } },
+                )
+                .await
+                .map(|_| ())
+        })
+        .map(|res| res.expect("join mongodb update"))
+        .boxed()
+    }
+
+    fn record_analysis(&'static self, id: EngineId) -> BoxFuture<'static, Result<(), Error>> {
+        task::spawn(async move {
+            self.stats
+                .update_one(
+                    doc! { "_id": id.0 },
+                    doc! {
+                        "$inc": { "analyses": 1_i64 },
+                        "$set": { "lastUsed": DateTime::now() },
+                    },
+                )
+                .upsert(true)
+                .await
+                .map(|_| ())
+        })
+        .map(|res| res.expect("join mongodb update"))
+        .boxed()
+    }
+
+    fn stats(&'static self, id: EngineId) -> BoxFuture<'static, Result<EngineStats, Error>> {
+        task::spawn(async move {
+            self.stats
+                .find_one(doc! { "_id": id.0 })
+                .await
+                .map(Option::unwrap_or_default)
+        })
+        .map(|res| res.expect("join mongodb find"))
+        .boxed()
+    }
+
+    fn stale(&'static self, before: DateTime) -> BoxFuture<'static, Result<Vec<EngineId>, Error>> {
+        task::spawn(async move {
+            let last_used: HashMap<EngineId, DateTime> = self
+                .stats
+                .clone_with_type::<LastUsed>()
+                .find(doc! {})
+                .projection(doc! { "lastUsed": 1 })
+                .await?
+                .try_filter_map(|stats| async move { Ok(stats.last_used.map(|at| (stats.id, at))) })
+                .try_collect()
+                .await?;
+            self.coll
+                .clone_with_type::<EngineAge>()
+                .find(doc! {})
+                .projection(doc! { "createdAt": 1 })
+                .await?
+                .try_filter_map(|engine| {
+                    let stale = is_stale(
+                        engine.created_at,
+                        last_used.get(&engine.id).copied(),
+                        before,
+                    );
+                    async move { Ok(stale.then_some(engine.id)) }
+                })
+                .try_collect()
+                .await
+        })
+        .map(|res| res.expect("join mongodb find"))
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{num::NonZeroU32, sync::Mutex};
+
+    use futures::future;
+    use serde_json::json;
+
+    use super::*;
+    use crate::api::tests::engine;
+
+    /// In-memory store for tests that do not need MongoDB.
+    #[derive(Default)]
+    pub struct MemoryStore {
+        engines: Mutex<HashMap<String, ExternalEngine>>,
+        stats: Mutex<HashMap<String, EngineStats>>,
+    }
+
+    impl EngineStore for MemoryStore {
+        fn find(
+            &'static self,
+            id: EngineId,
+            client_secret: ClientSecret,
+        ) -> BoxFuture<'static, Result<Option<ExternalEngine>, Error>> {
+            let engine = self.engines.lock().unwrap().get(&id.0).cloned();
+            future::ready(Ok(
+                engine.filter(|e| e.config.client_secret == client_secret)
+            ))
+            .boxed()
+        }
+
+        fn create(
+            &'static self,
+            engine: ExternalEngine,
+        ) -> BoxFuture<'static, Result<(), WriteError>> {
+            if let Err(err) = engine.config.validate() {
+                return future::ready(Err(err.into())).boxed();
+            }
+            self.engines
+                .lock()
+                .unwrap()
+                .insert(engine.id.0.clone(), engine);
+            future::ready(Ok(())).boxed()
+        }
+
+        fn update(
+            &'static self,
+            engine: ExternalEngine,
+        ) -> BoxFuture<'static, Result<bool, WriteError>> {
+            if let Err(err) = engine.config.validate() {
+                return future::ready(Err(err.into())).boxed();
+            }
+            let mut engines = self.engines.lock().unwrap();
+            let found = match engines.get_mut(&engine.id.0) {
+                Some(existing) => {
+                    existing.config = engine.config;
+                    true
+                }
+                None => false,
+            };
+            future::ready(Ok(found)).boxed()
+        }
+
+        fn rotate_client_secret(
+            &'static self,
+            id: EngineId,
+            current: ClientSecret,
+            new: ClientSecret,
+        ) -> BoxFuture<'static, Result<bool, Error>> {
+            let mut engines = self.engines.lock().unwrap();
+            let found = match engines
+                .get_mut(&id.0)
+                .filter(|e| e.config.client_secret == current)
+            {
+                Some(existing) => {
+                    existing.config.client_secret = new;
+                    true
+                }
+                None => false,
+            };
+            future::ready(Ok(found)).boxed()
+        }
+
+        fn set_enabled(
+            &'static self,
+            id: EngineId,
+            client_secret: ClientSecret,
+            enabled: bool,
+        ) -> BoxFuture<'static, Result<bool, Error>> {
+            let mut engines = self.engines.lock().unwrap();
+            let found = match engines
+                .get_mut(&id.0)
+                .filter(|e| e.config.client_secret == client_secret)
+            {
+                Some(existing) => {
+                    existing.enabled = enabled;
+                    true
+                }
+                None => false,
+            };
+            future::ready(Ok(found)).boxed()
+        }
+
+        fn delete(&'static self, id: EngineId) -> BoxFuture<'static, Result<bool, Error>> {
+            let found = self.engines.lock().unwrap().remove(&id.0).is_some();
+            future::ready(Ok(found)).boxed()
+        }
+
+        fn list_by_user(
+            &'static self,
+            user_id: UserId,
+        ) -> BoxFuture<'static, Result<Vec<ExternalEngine>, Error>> {
+            let engines = self
+                .engines
+                .lock()
+                .unwrap()
+                .values()
+                .filter(|e| e.config.user_id == user_id)
+                .cloned()
+                .collect();
+            future::ready(Ok(engines)).boxed()
+        }
+
+        fn list_by_provider(
+            &'static self,
+            selector: ProviderSelector,
+        ) -> BoxFuture<'static, Result<Vec<ExternalEngine>, Error>> {
+            let engines = self
+                .engines
+                .lock()
+                .unwrap()
+                .values()
+                .filter(|e| e.provider_selector == selector)
+                .cloned()
+                .collect();
+            future::ready(Ok(engines)).boxed()
+        }
+
+        fn provider_key(
+            &'static self,
+            selector: ProviderSelector,
+        ) -> BoxFuture<'static, Result<Option<ProviderKey>, Error>> {
+            let key = self
+                .engines
+                .lock()
+                .unwrap()
+                .values()
+                .filter(|e| e.provider_selector == selector)
+                .find_map(|e| e.provider_key.clone());
+            future::ready(Ok(key)).boxed()
+        }
+
+        fn update_options(
+            &'static self,
+            id: EngineId,
+            options: Vec<UciOption>,
+        ) -> BoxFuture<'static, Result<(), Error>> {
+            if let Some(engine) = self.engines.lock().unwrap().get_mut(&id.0) {
+                engine.config.allowed_options = options;
+            }
+            future::ready(Ok(())).boxed()
+        }
+
+        fn record_analysis(&'static self, id: EngineId) -> BoxFuture<'static, Result<(), Error>> {
+            let mut stats = self.stats.lock().unwrap();
+            let stats = stats.entry(id.0).or_default();
+            stats.analyses += 1;
+            stats.last_used = Some(DateTime::now());
+            future::ready(Ok(())).boxed()
+        }
+
+        fn stats(&'static self, id: EngineId) -> BoxFuture<'static, Result<EngineStats, Error>> {
+            let stats = self.stats.lock().unwrap().get(&id.0).cloned();
+            future::ready(Ok(stats.unwrap_or_default())).boxed()
+        }
+
+        fn stale(
+            &'static self,
+            before: DateTime,
+        ) -> BoxFuture<'static, Result<Vec<EngineId>, Error>> {
+            let stats = self.stats.lock().unwrap();
+            let stale = self
+                .engines
+                .lock()
+                .unwrap()
+                .values()
+                .filter(|e| {
+                    let last_used = stats.get(&e.id.0).and_then(|stats| stats.last_used);
+                    is_stale(e.created_at, last_used, before)
+                })
+                .map(|e| e.id.clone())
+                .collect();
+            future::ready(Ok(stale)).boxed()
+        }
+    }
+
+    fn provider_selector() -> ProviderSelector {
+        serde_json::from_value(json!("selector")).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_memory_store() {
+        let store: &'static MemoryStore = Box::leak(Box::default());
+        let engine = engine();
+        let secret = engine.config.client_secret.clone();
+        let user_id = engine.config.user_id.clone();
+        store
+            .create(ExternalEngine::new(engine.clone(), provider_selector()))
+            .await
+            .unwrap();
+
+        let found = store.find(engine.id.clone(), secret.clone()).await.unwrap();
+        assert_eq!(found.unwrap().config.name, "Stockfish");
+        let wrong_secret = serde_json::from_value(json!("wrong")).unwrap();
+        assert!(store
+            .find(engine.id.clone(), wrong_secret)
+            .await
+            .unwrap()
+            .is_none());
+
+        let mut renamed = engine.clone();
+        renamed.config.name = "Stockfish 17".to_owned();
+        assert!(store
+            .update(ExternalEngine::new(renamed, provider_selector()))
+            .await
+            .unwrap());
+        let listed = store.list_by_user(user_id.clone()).await.unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].config.name, "Stockfish 17");
+        let served = store.list_by_provider(provider_selector()).await.unwrap();
+        assert_eq!(served.len(), 1);
+        let other_selector = serde_json::from_value(json!("other")).unwrap();
+        assert!(store
+            .list_by_provider(other_selector)
+            .await
+            .unwrap()
+            .is_empty());
+
+        assert!(store.delete(engine.id.clone()).await.unwrap());
+        assert!(!store.delete(engine.id.clone()).await.unwrap());
+        assert!(store.find(engine.id, secret).await.unwrap().is_none());
+        assert!(store.list_by_user(user_id).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_update_keeps_state() {
+        let store: &'static MemoryStore = Box::leak(Box::default());
+        let mut engine = engine();
+        let secret = engine.config.client_secret.clone();
+        store
+            .create(
+                ExternalEngine::new(engine.clone(), provider_selector())
+                    .with_provider_key(serde_json::from_value(json!("key")).unwrap()),
+            )
+            .await
+            .unwrap();
+        assert!(store
+            .set_enabled(engine.id.clone(), secret.clone(), false)
+            .await
+            .unwrap());
+
+        engine.config.name = "Stockfish 17".to_owned();
+        let other_selector = serde_json::from_value(json!("other")).unwrap();
+        assert!(store
+            .update(ExternalEngine::new(engine.clone(), other_selector))
+            .await
+            .unwrap());
+        let found = store.find(engine.id, secret).await.unwrap().unwrap();
+        assert_eq!(found.config.name, "Stockfish 17");
+        assert!(!found.is_enabled());
+        assert!(found.provider_key.is_some());
+        assert_eq!(found.provider_selector, provider_selector());
+    }
+
+    #[test]
+    fn test_optional_config_fields() {
+        let mut engine = engine();
+        let bare = to_document(&engine.config).unwrap();
+        assert!(OPTIONAL_CONFIG_FIELDS
+            .into_iter()
+            .all(|field| !bare.contains_key(field)));
+
+        engine.config.allowed_fens = Some(Vec::new());
+        engine.config.allowed_session_prefixes = Some(Vec::new());
+        engine.config.allowed_options =
+            vec!["option name MultiPV type spin default 1 min 1 max 500"
+                .parse()
+                .unwrap()];
+        engine.config.defaults.threads = NonZeroU32::new(1);
+        engine.config.strength_levels.insert(
+            "1500".to_owned(),
+            serde_json::from_value(json!({ "depth": 5 })).unwrap(),
+        );
+        let full = to_document(&engine.config).unwrap();
+        assert!(OPTIONAL_CONFIG_FIELDS
+            .into_iter()
+            .all(|field| full.contains_key(field)));
+    }
+
+    #[tokio::test]
+    async fn test_reject_invalid_config() {
+        let store: &'static MemoryStore = Box::leak(Box::default());
+        let mut engine = engine();
+        store
+            .create(ExternalEngine::new(engine.clone(), provider_selector()))
+            .await
+            .unwrap();
+
+        engine.config.variants.clear();
+        assert!(matches!(
+            store
+                .create(ExternalEngine::new(engine.clone(), provider_selector()))
+                .await,
+            Err(WriteError::InvalidEngine(InvalidEngineConfig::NoVariants))
+        ));
+        assert!(matches!(
+            store
+                .update(ExternalEngine::new(engine, provider_selector()))
+                .await,
+            Err(WriteError::InvalidEngine(InvalidEngineConfig::NoVariants))
+        ));
+    }
+
+    #[test]
+    fn test_enabled_by_default() {
+        let mut doc =
+            serde_json::to_value(ExternalEngine::new(engine(), provider_selector())).unwrap();
+        assert_eq!(doc["enabled"], true);
+        // Engines registered elsewhere have no flag.
+        doc.as_object_mut().unwrap().remove("enabled");
+        let engine: ExternalEngine = serde_json::from_value(doc).unwrap();
+        assert!(engine.is_enabled());
     }
 }
@@ -0,0 +1,273 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, Weak},
+};
+
+use shakmaty::{uci::UciMove, CastlingMode, Move};
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+
+use crate::model::{ClientSecret, JobId, ProviderSelector, SessionId};
+
+/// State shared by all jobs of a session.
+#[derive(Default)]
+pub struct Session {
+    cancel: CancellationToken,
+    played: watch::Sender<Option<UciMove>>,
+}
+
+impl Session {
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel.is_cancelled()
+    }
+
+    pub async fn cancelled(&self) {
+        self.cancel.cancelled().await
+    }
+
+    /// Moves played by the client from now on.
+    pub fn played(&self) -> watch::Receiver<Option<UciMove>> {
+        self.played.subscribe()
+    }
+}
+
+/// Jobs grouped by the session they belong to, so that they can be cancelled
+/// at once. Sessions are scoped to the client secret, since session ids are
+/// chosen by clients.
+#[derive(Default)]
+pub struct Sessions {
+    live: Mutex<HashMap<(ClientSecret, SessionId), Weak<Session>>>,
+}
+
+impl Sessions {
+    /// Returns the state shared by all jobs of the session. The session is
+    /// forgotten once no job holds on to it.
+    pub fn join(&self, client_secret: ClientSecret, session_id: SessionId) -> Arc<Session> {
+        let mut live = self.live.lock().unwrap();
+        let key = (client_secret, session_id);
+        if let Some(session) = live.get(&key).and_then(Weak::upgrade) {
+            return session;
+        }
+        live.retain(|_, session| session.strong_count() > 0);
+        let session = Arc::new(Session::default());
+        live.insert(key, Arc::downgrade(&session));
+        session
+    }
+
+    fn get(&self, client_secret: ClientSecret, session_id: SessionId) -> Option<Arc<Session>> {
+        self.live
+            .lock()
+            .unwrap()
+            .get(&(client_secret, session_id))
+            .and_then(Weak::upgrade)
+    }
+
+    /// Cancels all jobs of the session. Returns `false` if there were none.
+    pub fn cancel(&self, client_secret: ClientSecret, session_id: SessionId) -> bool {
+        let session = self
+            .live
+            .lock()
+            .unwrap()
+            .remove(&(client_secret, session_id))
+            .and_then(|session| session.upgrade());
+        session.inspect(|session| session.cancel.cancel()).is_some()
+    }
+
+    /// Cancels the jobs of all sessions of the client secret, e.g. once it
+    /// was rotated.
+    pub fn cancel_client(&self, client_secret: &ClientSecret) {
+        self.live.lock().unwrap().retain(|(secret, _), session| {
+            if secret != client_secret {
+                return true;
+            }
+            if let Some(session) = session.upgrade() {
+                session.cancel.cancel();
+            }
+            false
+        });
+    }
+
+    /// Tells pondering jobs of the session which move was actually played.
+    /// Returns `false` if the session has no jobs.
+    pub fn play(&self, client_secret: ClientSecret, session_id: SessionId, uci: UciMove) -> bool {
+        self.get(client_secret, session_id)
+            .inspect(|session| {
+                session.played.send_replace(Some(uci));
+            })
+            .is_some()
+    }
+}
+
+struct Pondering {
+    selector: ProviderSelector,
+    played: watch::Receiver<Option<UciMove>>,
+    ponder: Move,
+}
+
+/// Jobs that providers are pondering on, waiting for the client to play the
+/// expected move.
+#[derive(Default)]
+pub struct Ponders {
+    jobs: Mutex<HashMap<JobId, Pondering>>,
+}
+
+impl Ponders {
+    /// Starts waiting for the next move played in the session, on behalf
+    /// of the provider that acquired the job.
+    pub fn add(
+        &self,
+        id: JobId,
+        selector: ProviderSelector,
+        played: watch::Receiver<Option<UciMove>>,
+        ponder: Move,
+    ) {
+        let mut jobs = self.jobs.lock().unwrap();
+        jobs.retain(|_, pondering| pondering.played.has_changed().is_ok());
+        jobs.insert(
+            id,
+            Pondering {
+                selector,
+                played,
+                ponder,
+            },
+        );
+    }
+
+    pub fn remove(&self, id: &JobId) {
+        self.jobs.lock().unwrap().remove(id);
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.jobs.lock().unwrap().len()
+    }
+
+    /// Stops waiting for the job once the returned guard is dropped, however
+    /// its submission ends.
+    pub fn guard(&self, id: JobId) -> PonderGuard<'_> {
+        PonderGuard { ponders: self, id }
+    }
+
+    /// Waits until a move is played. Returns whether it was the expected
+    /// move, in either castling notation, or `None` if the job is not
+    /// pondering (anymore) or was acquired by another provider.
+    pub async fn wait(&self, id: &JobId, selector: &ProviderSelector) -> Option<bool> {
+        let mut played = self
+            .jobs
+            .lock()
+            .unwrap()
+            .get(id)
+            .filter(|pondering| pondering.selector == *selector)?
+            .played
+            .clone();
+        played.changed().await.ok()?;
+        let uci = played.borrow_and_update().clone();
+        let ponder = self.jobs.lock().unwrap().remove(id)?.ponder;
+        Some(uci.is_some_and(|uci| {
+            uci == ponder.to_uci(CastlingMode::Chess960)
+                || uci == ponder.to_uci(CastlingMode::Standard)
+        }))
+    }
+}
+
+pub struct PonderGuard<'a> {
+    ponders: &'a Ponders,
+    id: JobId,
+}
+
+impl Drop for PonderGuard<'_> {
+    fn drop(&mut self) {
+        self.ponders.remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use shakmaty::{Chess, Square};
+
+    use super::*;
+
+    fn secret(s: &str) -> ClientSecret {
+        serde_json::from_value(json!(s)).unwrap()
+    }
+
+    fn session(s: &str) -> SessionId {
+        serde_json::from_value(json!(s)).unwrap()
+    }
+
+    fn provider(s: &str) -> ProviderSelector {
+        serde_json::from_value(json!(s)).unwrap()
+    }
+
+    #[test]
+    fn test_sessions() {
+        let sessions = Sessions::default();
+
+        let first = sessions.join(secret("a"), session("board"));
+        let second = sessions.join(secret("a"), session("board"));
+        let other = sessions.join(secret("b"), session("board"));
+
+        assert!(!sessions.cancel(secret("a"), session("unknown")));
+        assert!(sessions.cancel(secret("a"), session("board")));
+        assert!(first.is_cancelled());
+        assert!(second.is_cancelled());
+        assert!(!other.is_cancelled());
+
+        // Later jobs start a fresh session.
+        assert!(!sessions.join(secret("a"), session("board")).is_cancelled());
+
+        drop(other);
+        assert!(!sessions.cancel(secret("b"), session("board")));
+    }
+
+    #[test]
+    fn test_cancel_client() {
+        let sessions = Sessions::default();
+
+        let board = sessions.join(secret("a"), session("board"));
+        let study = sessions.join(secret("a"), session("study"));
+        let other = sessions.join(secret("b"), session("board"));
+
+        sessions.cancel_client(&secret("a"));
+        assert!(board.is_cancelled());
+        assert!(study.is_cancelled());
+        assert!(!other.is_cancelled());
+    }
+
+    #[tokio::test]
+    async fn test_ponders() {
+        let sessions = Sessions::default();
+        let ponders = Ponders::default();
+        let board = sessions.join(secret("a"), session("board"));
+        let e4 = "e2e4"
+            .parse::<UciMove>()
+            .unwrap()
+            .to_move(&Chess::default())
+            .unwrap();
+
+        let hit = JobId::random();
+        let miss = JobId::random();
+        ponders.add(hit.clone(), provider("p"), board.played(), e4.clone());
+        ponders.add(miss.clone(), provider("p"), board.played(), e4);
+        assert_eq!(ponders.wait(&JobId::random(), &provider("p")).await, None);
+        assert_eq!(ponders.wait(&hit, &provider("other")).await, None);
+
+        assert!(!sessions.play(secret("b"), session("board"), "e2e4".parse().unwrap()));
+        assert!(sessions.play(secret("a"), session("board"), "e2e4".parse().unwrap()));
+        assert_eq!(ponders.wait(&hit, &provider("p")).await, Some(true));
+        assert_eq!(ponders.wait(&hit, &provider("p")).await, None);
+
+        sessions.play(secret("a"), session("board"), "d2d4".parse().unwrap());
+        assert_eq!(ponders.wait(&miss, &provider("p")).await, Some(false));
+
+        let castle = JobId::random();
+        let king_side = Move::Castle {
+            king: Square::E1,
+            rook: Square::H1,
+        };
+        ponders.add(castle.clone(), provider("p"), board.played(), king_side);
+        sessions.play(secret("a"), session("board"), "e1g1".parse().unwrap());
+        assert_eq!(ponders.wait(&castle, &provider("p")).await, Some(true));
+    }
+}
@@ -0,0 +1,156 @@
+use std::{
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use tokio::{
+    pin, select,
+    signal::unix::{signal, SignalKind},
+    sync::Notify,
+    time::{timeout_at, Instant},
+};
+use tokio_util::sync::CancellationToken;
+
+/// Tracks jobs that providers are currently submitting, so that they can be
+/// drained on shutdown.
+#[derive(Default)]
+pub struct InFlight {
+    jobs: AtomicUsize,
+    idle: Notify,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct DrainReport {
+    pub drained: usize,
+    pub forced: usize,
+}
+
+impl InFlight {
+    pub fn jobs(&self) -> usize {
+        self.jobs.load(Ordering::Acquire)
+    }
+
+    /// Marks a job as in flight until the returned guard is dropped.
+    pub fn track(&'static self) -> InFlightGuard {
+        self.jobs.fetch_add(1, Ordering::AcqRel);
+        InFlightGuard { in_flight: self }
+    }
+
+    /// Waits up to `grace` for all jobs in flight to complete. Jobs that are
+    /// still running at the deadline are reported as forced.
+    pub async fn drain(&self, grace: Duration) -> DrainReport {
+        let deadline = Instant::now() + grace;
+        let started = self.jobs();
+        loop {
+            let notified = self.idle.notified();
+            pin!(notified);
+            notified.as_mut().enable();
+            if self.jobs() == 0 || timeout_at(deadline, notified).await.is_err() {
+                break;
+            }
+        }
+        let forced = self.jobs();
+        DrainReport {
+            drained: started.saturating_sub(forced),
+            forced,
+        }
+    }
+}
+
+pub struct InFlightGuard {
+    in_flight: &'static InFlight,
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        if self.in_flight.jobs.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.in_flight.idle.notify_waiters();
+        }
+    }
+}
+
+/// Set by operators to stop accepting new work, while jobs in flight are
+/// allowed to finish.
+#[derive(Default)]
+pub struct Maintenance {
+    enabled: AtomicBool,
+}
+
+impl Maintenance {
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Acquire)
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Release);
+    }
+}
+
+/// Lets operators trigger the same graceful shutdown as a signal, e.g. to
+/// tear down instances in integration tests.
+#[derive(Default)]
+pub struct RemoteShutdown {
+    requested: CancellationToken,
+}
+
+impl RemoteShutdown {
+    pub fn request(&self) {
+        self.requested.cancel();
+    }
+
+    pub async fn requested(&self) {
+        self.requested.cancelled().await
+    }
+}
+
+/// Resolves on SIGINT or SIGTERM.
+pub async fn signal_received() {
+    let mut terminate = signal(SignalKind::terminate()).expect("install signal handler");
+    select! {
+        _ = tokio::signal::ctrl_c() => {},
+        _ = terminate.recv() => {},
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{task, time::sleep};
+
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_drain() {
+        let in_flight: &'static InFlight = Box::leak(Box::default());
+        assert_eq!(
+            in_flight.drain(Duration::from_secs(10)).await,
+            DrainReport {
+                drained: 0,
+                forced: 0
+            }
+        );
+
+        let job = in_flight.track();
+        task::spawn(async move {
+            sleep(Duration::from_secs(1)).await;
+            drop(job);
+        });
+        let started = Instant::now();
+        assert_eq!(
+            in_flight.drain(Duration::from_secs(10)).await,
+            DrainReport {
+                drained: 1,
+                forced: 0
+            }
+        );
+        assert_eq!(started.elapsed(), Duration::from_secs(1));
+
+        let _stuck = in_flight.track();
+        assert_eq!(
+            in_flight.drain(Duration::from_secs(10)).await,
+            DrainReport {
+                drained: 0,
+                forced: 1
+            }
+        );
+    }
+}
@@ -0,0 +1,174 @@
+use std::{cmp::max, time::Instant};
+
+use serde::Serialize;
+use shakmaty::variant::Variant;
+use utoipa::ToSchema;
+
+use crate::{
+    api::Work,
+    audit::{AuditEntry, AuditLog},
+    model::{EngineId, JobId},
+    uci::UciOut,
+};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum Reason {
+    Bestmove,
+    Cancel,
+    Deadline,
+    Disconnect,
+    Redispatch,
+    Protocol,
+    MaxDepth,
+    AnalysisTimeout,
+    NoProvider,
+    ProviderPaused,
+    QueueFull,
+    NotPickedUp,
+}
+
+impl Reason {
+    fn as_str(self) -> &'static str {
+        match self {
+            Reason::Bestmove => "bestmove",
+            Reason::Cancel => "cancel",
+            Reason::Deadline => "deadline",
+            Reason::Disconnect => "disconnect",
+            Reason::Redispatch => "redispatch",
+            Reason::Protocol => "protocol",
+            Reason::MaxDepth => "max-depth",
+            Reason::AnalysisTimeout => "analysis-timeout",
+            Reason::NoProvider => "no-provider",
+            Reason::ProviderPaused => "provider-paused",
+            Reason::QueueFull => "queue-full",
+            Reason::NotPickedUp => "not-picked-up",
+        }
+    }
+}
+
+/// Collects statistics about a job while it is being streamed, and logs
+/// them as a single structured line when dropped.
+pub struct JobSummary {
+    engine: EngineId,
+    variant: Variant,
+    plies: usize,
+    started: Instant,
+    depth: u32,
+    nodes: u64,
+    nps: Option<u64>,
+    reason: Reason,
+    audit: Option<(&'static AuditLog, JobId)>,
+}
+
+impl JobSummary {
+    pub fn new(engine: EngineId, work: &Work) -> JobSummary {
+        JobSummary {
+            engine,
+            variant: work.variant(),
+            plies: work.moves().len(),
+            started: Instant::now(),
+            depth: 0,
+            nodes: 0,
+            nps: None,
+            reason: Reason::Disconnect,
+            audit: None,
+        }
+    }
+
+    /// Also records the job in the audit log when dropped.
+    pub fn with_audit(mut self, audit: &'static AuditLog, id: JobId) -> JobSummary {
+        self.audit = Some((audit, id));
+        self
+    }
+
+    pub fn update(&mut self, uci: &UciOut) {
+        if let UciOut::Info {
+            depth, nodes, nps, ..
+        } = *uci
+        {
+            self.depth = max(self.depth, depth.unwrap_or(0));
+            self.nodes = max(self.nodes, nodes.unwrap_or(0));
+            self.nps = nps.or(self.nps);
+        }
+    }
+
+    pub fn set_reason(&mut self, reason: Reason) {
+        self.reason = reason;
+    }
+
+    /// The structured line that is logged for the job.
+    fn line(&self, wall_ms: u64) -> String {
+        let nps = self.nps.unwrap_or_else(|| match wall_ms {
+            0 => 0,
+            millis => self.nodes.saturating_mul(1000) / millis,
+        });
+        format!(
+            "job completed engine={} variant={} plies={} depth={} nodes={} nps={} wall_ms={} reason={}",
+            self.engine,
+            self.variant.uci(),
+            self.plies,
+            self.depth,
+            self.nodes,
+            nps,
+            wall_ms,
+            self.reason.as_str()
+        )
+    }
+}
+
+impl Drop for JobSummary {
+    fn drop(&mut self) {
+        let elapsed = self.started.elapsed();
+        let wall_ms = u64::try_from(elapsed.as_millis()).unwrap_or(u64::MAX);
+        log::info!("{}", self.line(wall_ms));
+        if let Some((audit, id)) = self.audit.take() {
+            audit.record(AuditEntry {
+                id: Some(id),
+                engine: self.engine.clone(),
+                variant: self.variant.uci(),
+                outcome: self.reason,
+                duration_ms: wall_ms,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::api::tests::work;
+
+    #[test]
+    fn test_summary_line() {
+        let mut summary = JobSummary::new(
+            EngineId("eei_test".to_owned()),
+            &work(json!({ "moves": ["e2e4", "e7e5"] })),
+        );
+        summary.update(
+            &UciOut::from_line("info depth 10 nodes 5000 score cp 20 pv g1f3")
+                .unwrap()
+                .unwrap(),
+        );
+        assert_eq!(
+            summary.line(1000),
+            "job completed engine=eei_test variant=chess plies=2 depth=10 nodes=5000 nps=5000 wall_ms=1000 reason=disconnect"
+        );
+
+        for line in [
+            "info depth 12 nodes 9000 nps 120000 score cp 25 pv g1f3",
+            "bestmove g1f3",
+        ] {
+            summary.update(&UciOut::from_line(line).unwrap().unwrap());
+        }
+        summary.set_reason(Reason::Bestmove);
+        let line = summary.line(75);
+        assert_eq!(
+            line,
+            "job completed engine=eei_test variant=chess plies=2 depth=12 nodes=9000 nps=120000 wall_ms=75 reason=bestmove"
+        );
+        assert!(!line.contains("secret"));
+    }
+}
@@ -1,9 +1,14 @@
-use std::{collections::HashMap, fmt, num::ParseIntError, ops::Neg, time::Duration};
+use std::{collections::HashMap, fmt, num::ParseIntError, ops::Neg, str::FromStr, time::Duration};
 
 use memchr::{memchr2, memchr2_iter};
-use serde::Serialize;
-use shakmaty::uci::{ParseUciMoveError, UciMove};
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr, DurationMilliSeconds, TryFromInto};
+use shakmaty::{
+    uci::{ParseUciMoveError, UciMove},
+    variant::VariantPosition,
+};
 use thiserror::Error;
+use utoipa::ToSchema;
 
 use crate::model::{InvalidMultiPvError, MultiPv};
 
@@ -21,6 +26,10 @@ pub enum ProtocolError {
     InvalidInteger(#[from] ParseIntError),
     #[error("invalid multipv: {0}")]
     InvalidMultipv(#[from] InvalidMultiPvError),
+    #[error("invalid json line: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("illegal bestmove {0}")]
+    IllegalBestmove(UciMove),
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -30,6 +39,19 @@ pub struct Score {
     pub upperbound: bool,
 }
 
+impl Neg for Score {
+    type Output = Score;
+
+    fn neg(self) -> Score {
+        // A lower bound for one side is an upper bound for the other.
+        Score {
+            eval: -self.eval,
+            lowerbound: self.upperbound,
+            upperbound: self.lowerbound,
+        }
+    }
+}
+
 impl fmt::Display for Score {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         self.eval.fmt(f)?;
@@ -102,6 +124,214 @@ impl UciOut {
     pub fn from_line(s: &str) -> Result<Option<UciOut>, ProtocolError> {
         Parser::new(s)?.parse_out()
     }
+
+    /// Parses a line that is either plain UCI output, or a JSON object
+    /// like `{"type":"info","depth":20,"cp":34,"pv":["e2e4"]}` or
+    /// `{"type":"bestmove","bestmove":"e2e4"}`.
+    ///
+    /// Providers that do not stream may instead submit only the final
+    /// evaluation, like `{"cp":34,"bestmove":"e2e4","depth":20}`. It is
+    /// expanded to an info line with the legal best move in `pos` as the
+    /// principal variation, followed by the best move.
+    pub fn from_submitted_line(
+        s: &str,
+        pos: &VariantPosition,
+    ) -> Result<Vec<UciOut>, ProtocolError> {
+        if !s.trim_start().starts_with('{') {
+            return Ok(UciOut::from_line(s)?.into_iter().collect());
+        }
+        match serde_json::from_str::<JsonOut>(s) {
+            Ok(out) => Ok(vec![out.into()]),
+            Err(err) => match serde_json::from_str::<EvalOnly>(s) {
+                Ok(eval) => eval.into_uci(pos),
+                Err(_) => Err(err.into()),
+            },
+        }
+    }
+}
+
+/// Returns whether a submitted line is `{"requeue": true}`, with which a
+/// provider hands the job back, e.g. because its engine crashed.
+pub fn is_requeue(s: &str) -> bool {
+    #[derive(Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct Requeue {
+        requeue: bool,
+    }
+
+    s.trim_start().starts_with('{')
+        && serde_json::from_str::<Requeue>(s).is_ok_and(|line| line.requeue)
+}
+
+#[serde_as]
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct EvalOnly {
+    depth: u32,
+    cp: Option<i64>,
+    mate: Option<i32>,
+    nodes: Option<u64>,
+    #[serde_as(as = "DisplayFromStr")]
+    bestmove: UciMove,
+}
+
+impl EvalOnly {
+    fn into_uci(self, pos: &VariantPosition) -> Result<Vec<UciOut>, ProtocolError> {
+        if self.bestmove.to_move(pos).is_err() {
+            return Err(ProtocolError::IllegalBestmove(self.bestmove));
+        }
+        let info = JsonOut::Info {
+            multipv: None,
+            depth: Some(self.depth),
+            seldepth: None,
+            time: None,
+            nodes: self.nodes,
+            cp: self.cp,
+            mate: self.mate,
+            lowerbound: false,
+            upperbound: false,
+            hashfull: None,
+            nps: None,
+            tbhits: None,
+            pv: Some(vec![self.bestmove.clone()]),
+            string: None,
+        };
+        Ok(vec![
+            info.into(),
+            UciOut::Bestmove {
+                m: Some(self.bestmove),
+                ponder: None,
+            },
+        ])
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum UciOptionType {
+    Check,
+    Spin,
+    Combo,
+    Button,
+    String,
+}
+
+/// An option declared by an engine in response to `uci`, like
+/// `option name Hash type spin default 16 min 1 max 33554432`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct UciOption {
+    #[schema(example = "Hash")]
+    pub name: String,
+    #[serde(rename = "type")]
+    pub kind: UciOptionType,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(example = "16")]
+    pub default: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(example = 1)]
+    pub min: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(example = 33554432)]
+    pub max: Option<i64>,
+    /// Choices of a combo option.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub vars: Vec<String>,
+}
+
+impl FromStr for UciOption {
+    type Err = ProtocolError;
+
+    fn from_str(s: &str) -> Result<UciOption, ProtocolError> {
+        Parser::new(s)?.parse_option()
+    }
+}
+
+#[serde_as]
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum JsonOut {
+    Bestmove {
+        #[serde_as(as = "Option<DisplayFromStr>")]
+        #[serde(default)]
+        bestmove: Option<UciMove>,
+        #[serde_as(as = "Option<DisplayFromStr>")]
+        #[serde(default)]
+        ponder: Option<UciMove>,
+    },
+    Info {
+        #[serde_as(as = "Option<TryFromInto<u32>>")]
+        #[serde(default)]
+        multipv: Option<MultiPv>,
+        depth: Option<u32>,
+        seldepth: Option<u32>,
+        #[serde_as(as = "Option<DurationMilliSeconds>")]
+        #[serde(default)]
+        time: Option<Duration>,
+        nodes: Option<u64>,
+        cp: Option<i64>,
+        mate: Option<i32>,
+        #[serde(default)]
+        lowerbound: bool,
+        #[serde(default)]
+        upperbound: bool,
+        hashfull: Option<u32>,
+        nps: Option<u64>,
+        tbhits: Option<u64>,
+        #[serde_as(as = "Option<Vec<DisplayFromStr>>")]
+        #[serde(default)]
+        pv: Option<Vec<UciMove>>,
+        string: Option<String>,
+    },
+}
+
+impl From<JsonOut> for UciOut {
+    fn from(out: JsonOut) -> UciOut {
+        match out {
+            JsonOut::Bestmove { bestmove, ponder } => UciOut::Bestmove {
+                m: bestmove,
+                ponder,
+            },
+            JsonOut::Info {
+                multipv,
+                depth,
+                seldepth,
+                time,
+                nodes,
+                cp,
+                mate,
+                lowerbound,
+                upperbound,
+                hashfull,
+                nps,
+                tbhits,
+                pv,
+                string,
+            } => UciOut::Info {
+                multipv,
+                depth,
+                seldepth,
+                time,
+                nodes,
+                score: mate.map(Eval::Mate).or(cp.map(Eval::Cp)).map(|eval| Score {
+                    eval,
+                    lowerbound,
+                    upperbound,
+                }),
+                currmove: None,
+                currmovenumber: None,
+                hashfull,
+                nps,
+                tbhits,
+                sbhits: None,
+                cpuload: None,
+                refutation: HashMap::new(),
+                currline: HashMap::new(),
+                pv,
+                string,
+            },
+        }
+    }
 }
 
 impl fmt::Display for UciOut {
@@ -458,6 +688,72 @@ impl<'a> Parser<'a> {
         })
     }
 
+    /// Reads a possibly empty value that may contain spaces, like an
+    /// option name or a string default.
+    fn until_option_keyword(&mut self) -> &str {
+        match self.peek() {
+            Some(token) if !is_option_keyword(token) => {
+                self.until(is_option_keyword).unwrap_or_default()
+            }
+            _ => "",
+        }
+    }
+
+    fn parse_option(&mut self) -> Result<UciOption, ProtocolError> {
+        if self.next() != Some("option") {
+            return Err(ProtocolError::UnexpectedToken);
+        }
+        let mut name = None;
+        let mut kind = None;
+        let mut default = None;
+        let mut min = None;
+        let mut max = None;
+        let mut vars = Vec::new();
+        while let Some(token) = self.next() {
+            match token {
+                "name" => name = Some(self.until_option_keyword().to_owned()),
+                "type" => {
+                    kind = Some(match self.next() {
+                        Some("check") => UciOptionType::Check,
+                        Some("spin") => UciOptionType::Spin,
+                        Some("combo") => UciOptionType::Combo,
+                        Some("button") => UciOptionType::Button,
+                        Some("string") => UciOptionType::String,
+                        Some(_) => return Err(ProtocolError::UnexpectedToken),
+                        None => return Err(ProtocolError::UnexpectedEndOfLine),
+                    })
+                }
+                "default" => default = Some(self.until_option_keyword().to_owned()),
+                "min" => {
+                    min = Some(
+                        self.next()
+                            .ok_or(ProtocolError::UnexpectedEndOfLine)?
+                            .parse()?,
+                    )
+                }
+                "max" => {
+                    max = Some(
+                        self.next()
+                            .ok_or(ProtocolError::UnexpectedEndOfLine)?
+                            .parse()?,
+                    )
+                }
+                "var" => vars.push(self.until_option_keyword().to_owned()),
+                _ => return Err(ProtocolError::UnexpectedToken),
+            }
+        }
+        Ok(UciOption {
+            name: name
+                .filter(|name| !name.is_empty())
+                .ok_or(ProtocolError::UnexpectedEndOfLine)?,
+            kind: kind.ok_or(ProtocolError::UnexpectedEndOfLine)?,
+            default,
+            min,
+            max,
+            vars,
+        })
+    }
+
     fn parse_out(&mut self) -> Result<Option<UciOut>, ProtocolError> {
         Ok(Some(match self.next() {
             Some("bestmove") => self.parse_bestmove()?,
@@ -467,6 +763,10 @@ impl<'a> Parser<'a> {
     }
 }
 
+fn is_option_keyword(token: &str) -> bool {
+    matches!(token, "name" | "type" | "default" | "min" | "max" | "var")
+}
+
 fn is_separator(c: char) -> bool {
     c == ' ' || c == '\t'
 }
@@ -505,6 +805,97 @@ where
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_parse_options() {
+        let block = "option name Debug Log File type string default\n\
+            option name Threads type spin default 1 min 1 max 1024\n\
+            option name Clear Hash type button\n\
+            option name Ponder type check default false\n\
+            option name Style type combo default Normal var Solid var Normal var Risky\n\
+            option name UCI_Variant type combo default chess var chess var crazyhouse";
+        let options: Vec<UciOption> = block.lines().map(|line| line.parse().unwrap()).collect();
+        assert_eq!(options.len(), 6);
+
+        assert_eq!(options[0].name, "Debug Log File");
+        assert_eq!(options[0].kind, UciOptionType::String);
+        assert_eq!(options[0].default.as_deref(), Some(""));
+
+        assert_eq!(
+            options[1],
+            UciOption {
+                name: "Threads".to_owned(),
+                kind: UciOptionType::Spin,
+                default: Some("1".to_owned()),
+                min: Some(1),
+                max: Some(1024),
+                vars: Vec::new(),
+            }
+        );
+
+        assert_eq!(options[2].name, "Clear Hash");
+        assert_eq!(options[2].kind, UciOptionType::Button);
+        assert_eq!(options[2].default, None);
+
+        assert_eq!(options[4].default.as_deref(), Some("Normal"));
+        assert_eq!(options[4].vars, ["Solid", "Normal", "Risky"]);
+
+        assert!("option type spin".parse::<UciOption>().is_err());
+        assert!("option name Foo type table".parse::<UciOption>().is_err());
+        assert!("info depth 1".parse::<UciOption>().is_err());
+    }
+
+    #[test]
+    fn test_from_submitted_line() {
+        let pos = VariantPosition::Chess(Default::default());
+        let json = UciOut::from_submitted_line(
+            r#"{"type":"info","multipv":2,"depth":20,"time":1500,"cp":-34,"upperbound":true,"pv":["e2e4","e7e5"]}"#,
+            &pos,
+        )
+        .unwrap();
+        let uci = UciOut::from_line(
+            "info multipv 2 depth 20 time 1500 score cp -34 upperbound pv e2e4 e7e5",
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(json.len(), 1);
+        assert_eq!(json[0].to_string(), uci.to_string());
+
+        let bestmove =
+            UciOut::from_submitted_line(r#"{"type":"bestmove","bestmove":"e2e4"}"#, &pos).unwrap();
+        assert_eq!(bestmove[0].to_string(), "bestmove e2e4");
+
+        assert_eq!(
+            UciOut::from_submitted_line("bestmove e2e4", &pos)
+                .unwrap()
+                .len(),
+            1
+        );
+        assert!(matches!(
+            UciOut::from_submitted_line(r#"{"type":"go"}"#, &pos),
+            Err(ProtocolError::Json(_))
+        ));
+    }
+
+    #[test]
+    fn test_eval_only() {
+        let pos = VariantPosition::Chess(Default::default());
+        let lines: Vec<String> =
+            UciOut::from_submitted_line(r#"{"cp":34,"bestmove":"e2e4","depth":20}"#, &pos)
+                .unwrap()
+                .iter()
+                .map(UciOut::to_string)
+                .collect();
+        assert_eq!(
+            lines,
+            ["info depth 20 score cp 34 pv e2e4", "bestmove e2e4"]
+        );
+
+        assert!(matches!(
+            UciOut::from_submitted_line(r#"{"cp":34,"bestmove":"e2e5","depth":20}"#, &pos),
+            Err(ProtocolError::IllegalBestmove(_))
+        ));
+    }
+
     #[test]
     fn test_read() {
         assert_eq!(read(""), (None, ""));
@@ -512,6 +903,63 @@ mod tests {
         assert_eq!(read("  end"), (Some("end"), ""));
     }
 
+    fn parse_score(line: &str) -> Score {
+        match UciOut::from_line(line).unwrap() {
+            Some(UciOut::Info {
+                score: Some(score), ..
+            }) => score,
+            _ => panic!("no score in {line:?}"),
+        }
+    }
+
+    #[test]
+    fn test_parse_score() {
+        assert_eq!(
+            parse_score("info depth 20 score cp 34 lowerbound pv e2e4"),
+            Score {
+                eval: Eval::Cp(34),
+                lowerbound: true,
+                upperbound: false,
+            }
+        );
+        assert_eq!(
+            parse_score("info depth 20 score cp -12 upperbound nodes 1000"),
+            Score {
+                eval: Eval::Cp(-12),
+                lowerbound: false,
+                upperbound: true,
+            }
+        );
+        assert_eq!(
+            parse_score("info score mate -3 depth 5"),
+            Score {
+                eval: Eval::Mate(-3),
+                lowerbound: false,
+                upperbound: false,
+            }
+        );
+        assert_eq!(
+            parse_score("info score cp 34 lowerbound").to_string(),
+            "cp 34 lowerbound"
+        );
+    }
+
+    #[test]
+    fn test_neg_score() {
+        assert_eq!(
+            -parse_score("info score cp 34 lowerbound"),
+            Score {
+                eval: Eval::Cp(-34),
+                lowerbound: false,
+                upperbound: true,
+            }
+        );
+        assert_eq!(
+            -parse_score("info score mate -3"),
+            parse_score("info score mate 3")
+        );
+    }
+
     #[test]
     fn test_read_until() {
         assert_eq!(
@@ -0,0 +1,96 @@
+use std::time::Duration;
+
+use reqwest::{Client, Url};
+use serde::Serialize;
+use tokio::time::sleep;
+
+const MAX_ATTEMPTS: u32 = 4;
+
+pub struct Webhooks {
+    client: Client,
+    backoff: Duration,
+}
+
+impl Default for Webhooks {
+    fn default() -> Webhooks {
+        Webhooks::with_backoff(Duration::from_secs(1))
+    }
+}
+
+impl Webhooks {
+    fn with_backoff(backoff: Duration) -> Webhooks {
+        Webhooks {
+            client: Client::builder()
+                .timeout(Duration::from_secs(10))
+                .redirect(reqwest::redirect::Policy::none())
+                .build()
+                .expect("webhook client"),
+            backoff,
+        }
+    }
+
+    /// Posts the body, retrying with exponential backoff. Returns whether it
+    /// was accepted.
+    pub async fn deliver<T: Serialize>(&self, url: Url, body: &T) -> bool {
+        let mut backoff = self.backoff;
+        for attempt in 1..=MAX_ATTEMPTS {
+            match self
+                .client
+                .post(url.clone())
+                .json(body)
+                .send()
+                .await
+                .and_then(|res| res.error_for_status())
+            {
+                Ok(_) => return true,
+                Err(err) => log::warn!("webhook attempt {attempt}/{MAX_ATTEMPTS} failed: {err}"),
+            }
+            if attempt < MAX_ATTEMPTS {
+                sleep(backoff).await;
+                backoff *= 2;
+            }
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use axum::{extract::State, http::StatusCode, routing::post, Json, Router};
+    use tokio::{net::TcpListener, task};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_deliver_retries() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let app = Router::new()
+            .route(
+                "/callback",
+                post(
+                    |State(calls): State<Arc<AtomicUsize>>, Json(body): Json<Vec<u32>>| async move {
+                        assert_eq!(body, [1, 2, 3]);
+                        if calls.fetch_add(1, Ordering::SeqCst) == 0 {
+                            StatusCode::INTERNAL_SERVER_ERROR
+                        } else {
+                            StatusCode::OK
+                        }
+                    },
+                ),
+            )
+            .with_state(Arc::clone(&calls));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        task::spawn(async move { axum::serve(listener, app).await });
+
+        let webhooks = Webhooks::with_backoff(Duration::from_millis(10));
+        let url: Url = format!("http://{addr}/callback").parse().unwrap();
+        assert!(webhooks.deliver(url, &vec![1, 2, 3]).await);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+}